@@ -0,0 +1,32 @@
+//! Compresses the stylesheet with Brotli ahead of time, at the highest quality, which is too
+//! slow to do per response. `layout::stylesheet` serves it to clients that accept `br`.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::Write,
+    path::Path,
+};
+
+const STYLESHEET: &str = "src/pages/app.css";
+
+fn main() {
+    println!("cargo:rerun-if-changed={STYLESHEET}");
+    let css = std::fs::read(STYLESHEET).expect("reading the stylesheet");
+
+    let out = std::env::var("OUT_DIR").expect("OUT_DIR is set for build scripts");
+    let file = std::fs::File::create(Path::new(&out).join("app.css.br"))
+        .expect("creating the compressed stylesheet");
+    let mut compressor = brotli::CompressorWriter::new(file, 4096, 11, 22);
+    compressor
+        .write_all(&css)
+        .expect("writing the compressed stylesheet");
+    compressor
+        .flush()
+        .expect("writing the compressed stylesheet");
+
+    // Changes with the stylesheet, for its URL.
+    let mut hasher = DefaultHasher::new();
+    css.hash(&mut hasher);
+    println!("cargo:rustc-env=APP_CSS_VERSION={:016x}", hasher.finish());
+}
@@ -0,0 +1,9 @@
+-- Create the `image_metadata` table holding EXIF/XMP fields read at upload.
+CREATE TABLE IF NOT EXISTS image_metadata
+(
+  image_id  INTEGER NOT NULL REFERENCES images (id),
+  tag       TEXT    NOT NULL,
+  value     TEXT    NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS image_metadata_image_id ON image_metadata (image_id);
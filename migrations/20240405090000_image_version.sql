@@ -0,0 +1,5 @@
+-- Track edits to `images` so concurrent tag updates can be detected.
+ALTER TABLE images ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE images ADD COLUMN updated_at INTEGER NOT NULL DEFAULT 0;
+
+UPDATE images SET updated_at = CAST(strftime('%s', 'now') AS INTEGER);
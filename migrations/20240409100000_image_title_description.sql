@@ -0,0 +1,3 @@
+-- Free-form title and markdown description for each image.
+ALTER TABLE images ADD COLUMN title TEXT NOT NULL DEFAULT '';
+ALTER TABLE images ADD COLUMN description TEXT NOT NULL DEFAULT '';
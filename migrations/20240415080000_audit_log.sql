@@ -0,0 +1,11 @@
+-- Create the `audit_log` table recording notable actions taken on images.
+CREATE TABLE IF NOT EXISTS audit_log
+(
+  id        INTEGER PRIMARY KEY NOT NULL,
+  at        INTEGER             NOT NULL,
+  action    TEXT                NOT NULL,
+  image_id  INTEGER,
+  detail    TEXT                NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_image_id ON audit_log (image_id);
@@ -0,0 +1,14 @@
+-- Create the `jobs` table backing the persistent background job queue.
+CREATE TABLE IF NOT EXISTS jobs
+(
+  id          INTEGER PRIMARY KEY NOT NULL,
+  kind        TEXT                NOT NULL,
+  image_id    INTEGER             NOT NULL,
+  state       TEXT                NOT NULL DEFAULT 'queued',
+  attempts    INTEGER             NOT NULL DEFAULT 0,
+  error       TEXT,
+  created_at  INTEGER             NOT NULL,
+  updated_at  INTEGER             NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS jobs_state ON jobs (state, id);
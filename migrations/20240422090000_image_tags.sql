@@ -0,0 +1,22 @@
+-- Create the `image_tags` table with one row per tag, flagging tags suggested at upload.
+CREATE TABLE IF NOT EXISTS image_tags
+(
+  image_id  INTEGER NOT NULL REFERENCES images (id),
+  tag       TEXT    NOT NULL,
+  auto      INTEGER NOT NULL DEFAULT 0,
+  PRIMARY KEY (image_id, tag)
+);
+
+-- Backfill from the comma-separated `images.tags` strings.
+WITH RECURSIVE split (image_id, tag, rest) AS
+(
+  SELECT id, '', tags || ',' FROM images
+  UNION ALL
+  SELECT image_id,
+         trim(substr(rest, 1, instr(rest, ',') - 1)),
+         substr(rest, instr(rest, ',') + 1)
+  FROM split
+  WHERE rest <> ''
+)
+INSERT OR IGNORE INTO image_tags (image_id, tag)
+SELECT image_id, tag FROM split WHERE tag <> '';
@@ -0,0 +1,5 @@
+-- Add the SHA-256 of each original, used in content-addressed CDN URLs. Existing rows are
+-- hashed on startup.
+ALTER TABLE images ADD COLUMN content_hash TEXT;
+
+CREATE INDEX IF NOT EXISTS images_content_hash ON images (content_hash);
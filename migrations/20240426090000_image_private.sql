@@ -0,0 +1,2 @@
+-- Add the `private` flag hiding images from anonymous visitors of a public gallery.
+ALTER TABLE images ADD COLUMN private INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,6 @@
+-- Record when each image was uploaded, for keyset pagination on `(created_at, id)`.
+ALTER TABLE images ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+
+UPDATE images SET created_at = updated_at;
+
+CREATE INDEX IF NOT EXISTS images_created_at ON images (created_at, id);
@@ -0,0 +1,9 @@
+-- Create the `image_versions` table recording previous originals of replaced images.
+CREATE TABLE IF NOT EXISTS image_versions
+(
+  image_id     INTEGER NOT NULL REFERENCES images (id),
+  n            INTEGER NOT NULL,
+  content_hash TEXT,
+  archived_at  INTEGER NOT NULL,
+  PRIMARY KEY (image_id, n)
+);
@@ -0,0 +1,13 @@
+-- Create the `quarantine` table describing rejected uploads kept under `quarantine/`.
+CREATE TABLE IF NOT EXISTS quarantine
+(
+  id            INTEGER PRIMARY KEY NOT NULL,
+  at            INTEGER             NOT NULL,
+  reason        TEXT                NOT NULL,
+  detail        TEXT                NOT NULL,
+  file_name     TEXT,
+  size          INTEGER             NOT NULL,
+  stored_bytes  INTEGER             NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS quarantine_at ON quarantine (at);
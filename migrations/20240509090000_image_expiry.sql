@@ -0,0 +1,10 @@
+-- Add `expires_at` to images and the `image_tombstones` table remembering expired ones.
+ALTER TABLE images ADD COLUMN expires_at INTEGER;
+
+CREATE INDEX IF NOT EXISTS images_expires_at ON images (expires_at) WHERE expires_at IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS image_tombstones
+(
+  image_id    INTEGER PRIMARY KEY NOT NULL,
+  expired_at  INTEGER             NOT NULL
+);
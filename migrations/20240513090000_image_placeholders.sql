@@ -0,0 +1,6 @@
+-- Create the `image_placeholders` table holding each image's tiny blurred preview (LQIP).
+CREATE TABLE IF NOT EXISTS image_placeholders
+(
+  image_id  INTEGER PRIMARY KEY NOT NULL REFERENCES images (id),
+  jpeg      BLOB                NOT NULL
+);
@@ -0,0 +1,6 @@
+-- Create the `image_palettes` table holding each image's dominant colors.
+CREATE TABLE IF NOT EXISTS image_palettes
+(
+  image_id  INTEGER PRIMARY KEY NOT NULL REFERENCES images (id),
+  colors    TEXT                NOT NULL
+);
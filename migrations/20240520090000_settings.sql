@@ -0,0 +1,7 @@
+-- Create the `settings` table overriding configuration while the service runs.
+CREATE TABLE IF NOT EXISTS settings
+(
+  name        TEXT PRIMARY KEY NOT NULL,
+  value       TEXT             NOT NULL,
+  updated_at  INTEGER          NOT NULL
+);
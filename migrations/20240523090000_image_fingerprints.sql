@@ -0,0 +1,6 @@
+-- Create the `image_fingerprints` table holding each image's perceptual hash.
+CREATE TABLE IF NOT EXISTS image_fingerprints
+(
+  image_id  INTEGER PRIMARY KEY NOT NULL REFERENCES images (id),
+  dhash     INTEGER             NOT NULL
+);
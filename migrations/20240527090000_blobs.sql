@@ -0,0 +1,15 @@
+-- Create the `blobs` table of stored original files, shared by identical uploads, and
+-- `blob_links` recording which paths under `images/` point at each.
+CREATE TABLE IF NOT EXISTS blobs
+(
+  hash  TEXT PRIMARY KEY NOT NULL,
+  size  INTEGER          NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS blob_links
+(
+  path  TEXT PRIMARY KEY NOT NULL,
+  hash  TEXT             NOT NULL REFERENCES blobs (hash)
+);
+
+CREATE INDEX IF NOT EXISTS blob_links_hash ON blob_links (hash);
@@ -0,0 +1,12 @@
+-- Create the `backfills` table tracking the progress of data migrations run in the background.
+-- `last_id` is the highest image id processed, so a backfill resumes after it on restart.
+CREATE TABLE IF NOT EXISTS backfills
+(
+  name        TEXT PRIMARY KEY NOT NULL,
+  state       TEXT             NOT NULL DEFAULT 'pending',
+  last_id     INTEGER          NOT NULL DEFAULT 0,
+  done        INTEGER          NOT NULL DEFAULT 0,
+  total       INTEGER          NOT NULL DEFAULT 0,
+  error       TEXT,
+  updated_at  INTEGER          NOT NULL
+);
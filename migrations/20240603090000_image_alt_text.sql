@@ -0,0 +1,2 @@
+-- Add `alt_text`, the description of an image read out by screen readers.
+ALTER TABLE images ADD COLUMN alt_text TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,11 @@
+-- Record each original's dimensions, size and format, so searches can filter on them.
+-- Existing images are filled in by the `file_info` backfill.
+ALTER TABLE images ADD COLUMN width INTEGER;
+ALTER TABLE images ADD COLUMN height INTEGER;
+ALTER TABLE images ADD COLUMN byte_size INTEGER;
+ALTER TABLE images ADD COLUMN format TEXT;
+
+CREATE INDEX IF NOT EXISTS images_width ON images (width);
+CREATE INDEX IF NOT EXISTS images_height ON images (height);
+CREATE INDEX IF NOT EXISTS images_byte_size ON images (byte_size);
+CREATE INDEX IF NOT EXISTS images_format ON images (format);
@@ -0,0 +1,4 @@
+-- Add `starred`, for marking favorite images.
+ALTER TABLE images ADD COLUMN starred INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX IF NOT EXISTS images_starred ON images (starred);
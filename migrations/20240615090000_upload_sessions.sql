@@ -0,0 +1,14 @@
+-- Upload sessions, grouping uploads that are kept or discarded together.
+CREATE TABLE IF NOT EXISTS upload_sessions (
+    id TEXT PRIMARY KEY,
+    -- `open`, `committed` or `aborted`.
+    state TEXT NOT NULL DEFAULT 'open',
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    closed_at INTEGER
+);
+
+-- The open session an image was uploaded into; it's hidden until the session is committed.
+ALTER TABLE images ADD COLUMN session_id TEXT REFERENCES upload_sessions (id);
+
+CREATE INDEX IF NOT EXISTS images_session_id ON images (session_id);
@@ -0,0 +1,6 @@
+-- Add `taken_at`, when the photo was taken according to its EXIF, for the timeline.
+-- Existing images are filled in by the `taken_at` backfill.
+ALTER TABLE images ADD COLUMN taken_at INTEGER;
+
+-- The timeline orders and groups images by this, falling back to the upload time.
+CREATE INDEX IF NOT EXISTS images_timeline ON images (COALESCE(taken_at, created_at), id);
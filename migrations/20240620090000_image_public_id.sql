@@ -0,0 +1,8 @@
+-- Add `public_id`, the image's id in URLs and responses under IMAGE_ID_SCHEME=uuid or ulid.
+-- Tombstones keep it, so an expired image's link still answers 410 Gone.
+ALTER TABLE images ADD COLUMN public_id TEXT;
+ALTER TABLE image_tombstones ADD COLUMN public_id TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS images_public_id ON images (public_id) WHERE public_id IS NOT NULL;
+CREATE INDEX IF NOT EXISTS image_tombstones_public_id ON image_tombstones (public_id)
+  WHERE public_id IS NOT NULL;
@@ -0,0 +1,10 @@
+-- Add `upload_journal`, the uploads being stored, so one interrupted by a crash can be undone.
+CREATE TABLE IF NOT EXISTS upload_journal
+(
+  id          INTEGER PRIMARY KEY NOT NULL,
+  -- Set in the transaction creating the image, so an image never exists without it.
+  image_id    INTEGER,
+  step        TEXT    NOT NULL,
+  started_at  INTEGER NOT NULL,
+  updated_at  INTEGER NOT NULL
+);
@@ -0,0 +1,5 @@
+-- Add `status`, for holding uploads back until a moderator approves them. Images from
+-- before moderation existed are approved.
+ALTER TABLE images ADD COLUMN status TEXT NOT NULL DEFAULT 'approved';
+
+CREATE INDEX IF NOT EXISTS images_status ON images (status);
@@ -0,0 +1,12 @@
+-- Classify each image as landscape, portrait or square from its dimensions, so listings can
+-- filter on it. Within 5% of each other counts as square; NULL while the dimensions are.
+ALTER TABLE images ADD COLUMN orientation TEXT GENERATED ALWAYS AS (
+    CASE
+        WHEN width IS NULL OR height IS NULL THEN NULL
+        WHEN width * 20 > height * 21 THEN 'landscape'
+        WHEN height * 20 > width * 21 THEN 'portrait'
+        ELSE 'square'
+    END
+) VIRTUAL;
+
+CREATE INDEX IF NOT EXISTS images_orientation ON images (orientation);
@@ -0,0 +1,4 @@
+-- Add `pinned`, for images that must never be deleted.
+ALTER TABLE images ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX IF NOT EXISTS images_pinned ON images (pinned);
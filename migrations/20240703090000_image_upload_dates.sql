@@ -0,0 +1,11 @@
+-- Images stored before `created_at` existed were dated by the migrations that added
+-- `updated_at` and `created_at`, rather than by their upload. Flag them so the
+-- `upload_dates` backfill can date them by their original's modification time instead.
+ALTER TABLE images ADD COLUMN dated_by_upload INTEGER NOT NULL DEFAULT 1;
+
+UPDATE images SET dated_by_upload = 0
+WHERE created_at <= COALESCE(
+    (SELECT CAST(strftime('%s', installed_on) AS INTEGER)
+     FROM _sqlx_migrations WHERE version = 20240429090000),
+    0
+);
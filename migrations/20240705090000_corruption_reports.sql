@@ -0,0 +1,19 @@
+-- Add `verified_at`, when the original was last checked against `content_hash`, and the
+-- `corruption_reports` table of originals found not to match it.
+ALTER TABLE images ADD COLUMN verified_at INTEGER;
+
+CREATE INDEX IF NOT EXISTS images_verified_at ON images (verified_at, id);
+
+CREATE TABLE IF NOT EXISTS corruption_reports
+(
+  id             INTEGER PRIMARY KEY NOT NULL,
+  image_id       INTEGER NOT NULL REFERENCES images (id),
+  path           TEXT    NOT NULL,
+  expected_hash  TEXT    NOT NULL,
+  -- NULL when the file was missing.
+  actual_hash    TEXT,
+  detected_at    INTEGER NOT NULL,
+  restored_at    INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS corruption_reports_image_id ON corruption_reports (image_id);
@@ -0,0 +1,12 @@
+-- Add `burst_frames`, the images of each burst sequence, and `burst_size` on the burst's
+-- first image, whose card shows the animated preview.
+ALTER TABLE images ADD COLUMN burst_size INTEGER;
+
+CREATE TABLE IF NOT EXISTS burst_frames (
+    burst_id INTEGER NOT NULL,
+    image_id INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    PRIMARY KEY (burst_id, position)
+);
+
+CREATE INDEX IF NOT EXISTS burst_frames_image_id ON burst_frames (image_id);
@@ -0,0 +1,11 @@
+-- Add `served_files`: size, hash and time of the files made from originals (thumbnails,
+-- stripped and converted copies), so they can be served with integrity headers.
+CREATE TABLE IF NOT EXISTS served_files (
+    path TEXT PRIMARY KEY NOT NULL,
+    image_id INTEGER NOT NULL,
+    byte_size INTEGER NOT NULL,
+    sha256 TEXT NOT NULL,
+    modified_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS served_files_image_id ON served_files (image_id);
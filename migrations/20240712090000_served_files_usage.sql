@@ -0,0 +1,13 @@
+-- Add what each file in `served_files` is (`thumb`, `thumb_center`, `stripped`, ...) and when
+-- it was last served, for accounting per size and evicting the least recently served.
+ALTER TABLE served_files ADD COLUMN variant TEXT NOT NULL DEFAULT '';
+ALTER TABLE served_files ADD COLUMN last_served_at INTEGER NOT NULL DEFAULT 0;
+
+-- `images/<id>_<variant>.<ext>`, all extensions being three letters.
+UPDATE served_files
+SET variant = substr(path,
+                     length('images/' || image_id || '_') + 1,
+                     length(path) - length('images/' || image_id || '_') - 4),
+    last_served_at = modified_at;
+
+CREATE INDEX IF NOT EXISTS served_files_last_served_at ON served_files (last_served_at);
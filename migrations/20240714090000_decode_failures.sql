@@ -0,0 +1,9 @@
+-- Add `decode_failures`: images refused for declaring too many pixels or taking too long to
+-- decode, by what was being made of them.
+CREATE TABLE IF NOT EXISTS decode_failures (
+    image_id INTEGER NOT NULL REFERENCES images(id),
+    code TEXT NOT NULL,
+    detail TEXT NOT NULL,
+    failed_at INTEGER NOT NULL,
+    PRIMARY KEY (image_id, code)
+);
@@ -0,0 +1,24 @@
+-- Add `tag_hierarchy`: every tag and each of its ancestors, with its parent (NULL at the top),
+-- for hierarchical tags like `animal/cat/siamese`.
+CREATE TABLE IF NOT EXISTS tag_hierarchy
+(
+  tag     TEXT PRIMARY KEY NOT NULL,
+  parent  TEXT
+);
+
+CREATE INDEX IF NOT EXISTS tag_hierarchy_parent ON tag_hierarchy (parent);
+
+-- Backfill from the stored tags. `rtrim(tag, replace(tag, '/', ''))` strips the last
+-- segment, leaving the parent and a trailing `/`.
+WITH RECURSIVE ancestry (tag) AS
+(
+  SELECT DISTINCT tag FROM image_tags
+  UNION
+  SELECT rtrim(rtrim(tag, replace(tag, '/', '')), '/') FROM ancestry WHERE instr(tag, '/') > 0
+)
+INSERT OR IGNORE INTO tag_hierarchy (tag, parent)
+SELECT tag,
+       CASE WHEN instr(tag, '/') > 0
+            THEN rtrim(rtrim(tag, replace(tag, '/', '')), '/') END
+FROM ancestry
+WHERE tag <> '';
@@ -0,0 +1,2 @@
+-- Index `image_tags` by tag, for finding tags by prefix as they're typed.
+CREATE INDEX IF NOT EXISTS image_tags_tag ON image_tags (tag);
@@ -0,0 +1,4 @@
+-- Add the license an image is published under (an SPDX identifier, see `licenses`) and who
+-- to credit for it.
+ALTER TABLE images ADD COLUMN license TEXT NOT NULL DEFAULT '';
+ALTER TABLE images ADD COLUMN attribution TEXT NOT NULL DEFAULT '';
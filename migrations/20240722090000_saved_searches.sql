@@ -0,0 +1,22 @@
+-- Add `saved_searches`, search queries checked against each new image, and
+-- `saved_search_matches`, the new images each one matched.
+CREATE TABLE IF NOT EXISTS saved_searches (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    -- The query string of the search, as `GET /search/export` takes it.
+    query TEXT NOT NULL,
+    webhook TEXT,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS saved_search_matches (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    search_id INTEGER NOT NULL REFERENCES saved_searches(id),
+    image_id INTEGER NOT NULL REFERENCES images(id),
+    matched_at INTEGER NOT NULL,
+    -- When the webhook took it; NULL until then, and for searches without one.
+    delivered_at INTEGER,
+    UNIQUE (search_id, image_id)
+);
+
+CREATE INDEX IF NOT EXISTS saved_search_matches_image ON saved_search_matches (image_id);
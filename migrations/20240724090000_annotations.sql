@@ -0,0 +1,15 @@
+-- Labeled regions of images, as rectangles in the original's pixels.
+CREATE TABLE IF NOT EXISTS annotations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    image_id INTEGER NOT NULL REFERENCES images (id),
+    label TEXT NOT NULL,
+    x REAL NOT NULL,
+    y REAL NOT NULL,
+    width REAL NOT NULL,
+    height REAL NOT NULL,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS annotations_image_id ON annotations (image_id, id);
+CREATE INDEX IF NOT EXISTS annotations_label ON annotations (label);
@@ -0,0 +1,21 @@
+-- Add `storage_migrations`, the progress of copying the stored originals to another storage
+-- backend, and record in `blobs` where each one was copied to.
+CREATE TABLE IF NOT EXISTS storage_migrations
+(
+  target         TEXT PRIMARY KEY NOT NULL,
+  state          TEXT             NOT NULL DEFAULT 'pending',
+  -- The highest blob hash handled, so an interrupted migration resumes after it.
+  last_hash      TEXT             NOT NULL DEFAULT '',
+  done           INTEGER          NOT NULL DEFAULT 0,
+  total          INTEGER          NOT NULL DEFAULT 0,
+  copied         INTEGER          NOT NULL DEFAULT 0,
+  copied_bytes   INTEGER          NOT NULL DEFAULT 0,
+  failed         INTEGER          NOT NULL DEFAULT 0,
+  error          TEXT,
+  started_at     INTEGER,
+  updated_at     INTEGER          NOT NULL
+);
+
+-- The object key of the copy in `STORAGE_S3_BUCKET`, and when it was verified.
+ALTER TABLE blobs ADD COLUMN s3_key TEXT;
+ALTER TABLE blobs ADD COLUMN s3_verified_at INTEGER;
@@ -0,0 +1,26 @@
+-- Add `albums`, their `album_rules` (images with a tag belong to the album) and
+-- `album_images`, the images the rules currently put in each album.
+CREATE TABLE IF NOT EXISTS albums (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS album_rules (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    album_id INTEGER NOT NULL REFERENCES albums (id),
+    -- Images with this tag, or one below it, belong to the album.
+    tag TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    UNIQUE (album_id, tag)
+);
+
+CREATE TABLE IF NOT EXISTS album_images (
+    album_id INTEGER NOT NULL REFERENCES albums (id),
+    image_id INTEGER NOT NULL REFERENCES images (id),
+    added_at INTEGER NOT NULL,
+    PRIMARY KEY (album_id, image_id)
+);
+
+CREATE INDEX IF NOT EXISTS album_rules_tag ON album_rules (tag);
+CREATE INDEX IF NOT EXISTS album_images_image_id ON album_images (image_id);
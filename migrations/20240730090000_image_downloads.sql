@@ -0,0 +1,4 @@
+-- Count downloads of each original, for ranking search results by popularity.
+ALTER TABLE images ADD COLUMN downloads INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX IF NOT EXISTS images_downloads ON images (downloads);
@@ -0,0 +1,10 @@
+-- Add `pending_uploads`, presigned uploads to the bucket not completed yet.
+CREATE TABLE IF NOT EXISTS pending_uploads (
+    id TEXT PRIMARY KEY,
+    -- Key of the object at `UPLOAD_S3_BUCKET` the client puts the image to.
+    object_key TEXT NOT NULL,
+    -- Tags the image gets, comma-separated.
+    tags TEXT NOT NULL DEFAULT '',
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Record the address of the client whose request an audit entry is for; NULL for entries
+-- written by background tasks.
+ALTER TABLE audit_log ADD COLUMN client_ip TEXT;
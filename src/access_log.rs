@@ -0,0 +1,295 @@
+//! An Apache-style access log, for tools that read one, kept apart from the application's
+//! own logging and from `tracing`.
+//!
+//! With `ACCESS_LOG` set, every request is written to that file in the combined log format,
+//! followed by the time taken in microseconds (Apache's `%D`):
+//!
+//! ```text
+//! 203.0.113.7 - - [16/Jul/2024:10:04:31 +0000] "GET /thumb/3 HTTP/1.1" 200 5120 "-" "curl/8.5.0" 1843
+//! ```
+//!
+//! A line is written once the response body has been sent (or the client went away), so
+//! both the bytes and the time are of the whole response. The file is rotated when it would
+//! grow past `ACCESS_LOG_MAX_BYTES` and, with `ACCESS_LOG_DAILY`, when the day (UTC)
+//! changes: it's renamed to `<name>.<YYYYMMDD-HHMMSS>` and a new one started, and only the
+//! newest `ACCESS_LOG_KEEP` rotated files are kept.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::Request,
+    http::{header, HeaderMap, HeaderName},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use http_body::{Frame, SizeHint};
+
+use crate::{api, client_ip::ClientIp, config::Config};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+struct Writer {
+    file: File,
+    /// Bytes in the current file.
+    size: u64,
+    /// Day (since 1970-01-01) the current file was started.
+    day: i64,
+}
+
+pub struct AccessLog {
+    path: PathBuf,
+    max_bytes: u64,
+    daily: bool,
+    keep: u32,
+    writer: Mutex<Writer>,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn open(path: &Path) -> std::io::Result<Writer> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    // A file left from before a restart belongs to the day it was last written.
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or_else(now, |elapsed| elapsed.as_secs() as i64);
+
+    Ok(Writer {
+        file,
+        size: metadata.len(),
+        day: modified.div_euclid(86_400),
+    })
+}
+
+impl AccessLog {
+    /// The access log `config` asks for, if any.
+    pub fn open(config: &Config) -> anyhow::Result<Option<Arc<AccessLog>>> {
+        let Some(path) = &config.access_log else {
+            return Ok(None);
+        };
+        let writer = open(path)
+            .map_err(|e| anyhow::anyhow!("Opening ACCESS_LOG {}: {e}", path.display()))?;
+
+        Ok(Some(Arc::new(AccessLog {
+            path: path.clone(),
+            max_bytes: config.access_log_max_bytes,
+            daily: config.access_log_daily,
+            keep: config.access_log_keep,
+            writer: Mutex::new(writer),
+        })))
+    }
+
+    /// Appends `line`, rotating the file first if it's due.
+    fn write(&self, line: &str) {
+        let mut writer = self.writer.lock().unwrap();
+        let today = now().div_euclid(86_400);
+        let full = self.max_bytes > 0
+            && writer.size > 0
+            && writer.size + line.len() as u64 > self.max_bytes;
+        if full || (self.daily && writer.day != today) {
+            match self.rotate() {
+                Ok(rotated) => *writer = rotated,
+                Err(e) => eprintln!("Rotating the access log failed: {e}"),
+            }
+        }
+
+        match writer.file.write_all(line.as_bytes()) {
+            Ok(()) => writer.size += line.len() as u64,
+            Err(e) => eprintln!("Writing the access log failed: {e}"),
+        }
+    }
+
+    /// Moves the current file aside, drops the oldest rotated files and starts a new one.
+    fn rotate(&self) -> std::io::Result<Writer> {
+        let stamp = api::rfc3339(now())
+            .replace(['-', ':', 'Z'], "")
+            .replace('T', "-");
+        let name = self
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut rotated = self.path.with_file_name(format!("{name}.{stamp}"));
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = self.path.with_file_name(format!("{name}.{stamp}.{n}"));
+            n += 1;
+        }
+        std::fs::rename(&self.path, &rotated)?;
+
+        if self.keep > 0 {
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            let prefix = format!("{name}.");
+            let mut old: Vec<PathBuf> = std::fs::read_dir(dir)?
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+                .map(|entry| entry.path())
+                .collect();
+            // The stamps sort oldest first.
+            old.sort();
+            let excess = old.len().saturating_sub(self.keep as usize);
+            for path in &old[..excess] {
+                std::fs::remove_file(path)?;
+            }
+        }
+
+        open(&self.path)
+    }
+}
+
+/// `secs` as the log's `[16/Jul/2024:10:04:31 +0000]`.
+fn timestamp(secs: i64) -> String {
+    let (year, month, day) = api::civil_from_days(secs.div_euclid(86_400));
+    let secs_of_day = secs.rem_euclid(86_400);
+    format!(
+        "[{day:02}/{}/{year:04}:{:02}:{:02}:{:02} +0000]",
+        MONTHS[month as usize - 1],
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// `value` for a quoted field, with quotes, backslashes and control characters escaped as
+/// Apache does.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn header_field(headers: &HeaderMap, name: HeaderName) -> String {
+    headers
+        .get(name)
+        .map(|value| escape(&String::from_utf8_lossy(value.as_bytes())))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// The line for a request, less the bytes sent and time taken, which come once the body has
+/// been sent.
+struct Entry {
+    log: Arc<AccessLog>,
+    start: Instant,
+    /// Up to and including the status.
+    head: String,
+    /// The referrer and user agent.
+    tail: String,
+    bytes: u64,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        self.log.write(&format!(
+            "{} {bytes} {} {}\n",
+            self.head,
+            self.tail,
+            self.start.elapsed().as_micros()
+        ));
+    }
+}
+
+/// A response body counting the bytes sent for its [`Entry`], which is written when it's
+/// dropped.
+struct Counted {
+    inner: Body,
+    entry: Entry,
+}
+
+impl http_body::Body for Counted {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if let Some(data) = frame.data_ref() {
+                self.entry.bytes += data.len() as u64;
+            }
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Logs each request once its response has been sent.
+pub async fn record(
+    Extension(log): Extension<Arc<AccessLog>>,
+    client: Option<ClientIp>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let at = now();
+    let client = client.map_or_else(|| "-".to_string(), |ClientIp(ip)| ip.to_string());
+    let request_line = escape(&format!(
+        "{} {} {:?}",
+        request.method(),
+        request.uri(),
+        request.version()
+    ));
+    let tail = format!(
+        "\"{}\" \"{}\"",
+        header_field(request.headers(), header::REFERER),
+        header_field(request.headers(), header::USER_AGENT)
+    );
+
+    let response = next.run(request).await;
+
+    let entry = Entry {
+        log,
+        start,
+        head: format!(
+            "{client} - - {} \"{request_line}\" {}",
+            timestamp(at),
+            response.status().as_u16()
+        ),
+        tail,
+        bytes: 0,
+    };
+    let (parts, inner) = response.into_parts();
+
+    Response::from_parts(parts, Body::new(Counted { inner, entry }))
+}
@@ -0,0 +1,310 @@
+//! Albums kept current by tag rules.
+//!
+//! A rule says images with a tag belong to an album: `POST /api/v1/album-rules` with
+//! `{"album": "Vacation", "tag": "vacation-2024"}` creates the album if there's none by that
+//! name. Images tagged below the rule's tag (`vacation-2024/beach`) belong too. Membership is
+//! worked out again whenever an image's tags change, on upload and on every tag edit, so
+//! images join and leave albums on their own. Rules only apply to images tagged after they
+//! were made; `thumbnail_service --backfill-albums` applies every rule to the images already
+//! stored.
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Sqlite, SqlitePool, Transaction};
+
+use crate::{
+    auth::Viewer,
+    config::SharedConfig,
+    error::AppError,
+    pagination::{Page, PageQuery, AFTER_CURSOR},
+    tags, ImageRecord, IMAGE_COLUMNS,
+};
+
+/// Whether the `image_tags` row is the `album_rules` row's tag or one below it.
+const TAG_MATCHES: &str = "(image_tags.tag = album_rules.tag \
+     OR substr(image_tags.tag, 1, length(album_rules.tag) + 1) = album_rules.tag || '/')";
+
+/// Removes memberships no rule accounts for any more, of the images `?1` (all when NULL).
+fn prune_sql() -> String {
+    format!(
+        "DELETE FROM album_images WHERE (?1 IS NULL OR image_id = ?1) AND NOT EXISTS \
+             (SELECT 1 FROM album_rules JOIN image_tags ON {TAG_MATCHES} \
+              WHERE album_rules.album_id = album_images.album_id \
+                  AND image_tags.image_id = album_images.image_id)"
+    )
+}
+
+/// Adds the memberships the rules call for, of the images `?1` (all when NULL).
+fn add_sql() -> String {
+    format!(
+        "INSERT OR IGNORE INTO album_images (album_id, image_id, added_at) \
+         SELECT DISTINCT album_rules.album_id, image_tags.image_id, \
+             CAST(strftime('%s', 'now') AS INTEGER) \
+         FROM album_rules JOIN image_tags ON {TAG_MATCHES} \
+         WHERE ?1 IS NULL OR image_tags.image_id = ?1"
+    )
+}
+
+/// Puts image `image_id` in the albums whose rules its tags match, and takes it out of the
+/// rest. Called by `tags` in the transaction that changed them.
+pub async fn assign(tx: &mut Transaction<'_, Sqlite>, image_id: i64) -> anyhow::Result<()> {
+    sqlx::query(&prune_sql())
+        .bind(image_id)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query(&add_sql())
+        .bind(image_id)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Applies every rule to every image, returning how many memberships were added and
+/// removed.
+pub async fn backfill(pool: &SqlitePool) -> anyhow::Result<(u64, u64)> {
+    let mut tx = pool.begin().await?;
+    let removed = sqlx::query(&prune_sql())
+        .bind(None::<i64>)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    let added = sqlx::query(&add_sql())
+        .bind(None::<i64>)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    tx.commit().await?;
+
+    Ok((added, removed))
+}
+
+fn album_not_found(id: i64) -> AppError {
+    AppError::new(
+        StatusCode::NOT_FOUND,
+        "album_not_found",
+        format!("No album {id}"),
+    )
+    .with_param("id", id.to_string())
+}
+
+fn rule_not_found(id: i64) -> AppError {
+    AppError::new(
+        StatusCode::NOT_FOUND,
+        "album_rule_not_found",
+        format!("No album rule {id}"),
+    )
+    .with_param("id", id.to_string())
+}
+
+#[derive(Deserialize)]
+pub struct NewRule {
+    /// Name of the album, created if there's none.
+    album: String,
+    tag: String,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct AlbumRule {
+    id: i64,
+    album_id: i64,
+    album: String,
+    tag: String,
+    created_at: i64,
+}
+
+const RULE_COLUMNS: &str = "album_rules.id, album_id, albums.name AS album, tag, \
+     album_rules.created_at";
+
+/// `POST /api/v1/album-rules`
+pub async fn create_rule(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Json(rule): Json<NewRule>,
+) -> Result<(StatusCode, Json<AlbumRule>), AppError> {
+    let album = rule.album.trim();
+    if album.is_empty() {
+        return Err(AppError::bad_request(
+            "invalid_album",
+            "Album names can't be empty",
+        ));
+    }
+    let tag = tags::new_tag(&rule.tag, config.load().tag_limits)?;
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        "INSERT OR IGNORE INTO albums (name, created_at) \
+         VALUES (?, CAST(strftime('%s', 'now') AS INTEGER))",
+    )
+    .bind(album)
+    .execute(&mut *tx)
+    .await?;
+    let album_id: i64 = sqlx::query_scalar("SELECT id FROM albums WHERE name = ?")
+        .bind(album)
+        .fetch_one(&mut *tx)
+        .await?;
+    let inserted = sqlx::query(
+        "INSERT OR IGNORE INTO album_rules (album_id, tag, created_at) \
+         VALUES (?, ?, CAST(strftime('%s', 'now') AS INTEGER))",
+    )
+    .bind(album_id)
+    .bind(&tag)
+    .execute(&mut *tx)
+    .await?;
+    if inserted.rows_affected() == 0 {
+        return Err(AppError::new(
+            StatusCode::CONFLICT,
+            "album_rule_exists",
+            format!("Album {album} already has a rule for tag {tag}"),
+        )
+        .with_param("album", album)
+        .with_param("tag", tag));
+    }
+    let saved = sqlx::query_as::<_, AlbumRule>(&format!(
+        "SELECT {RULE_COLUMNS} FROM album_rules JOIN albums ON albums.id = album_id \
+         WHERE album_rules.id = ?"
+    ))
+    .bind(inserted.last_insert_rowid())
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::CREATED, Json(saved)))
+}
+
+/// `GET /api/v1/album-rules`, by album and tag.
+pub async fn list_rules(
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<Json<Vec<AlbumRule>>, AppError> {
+    Ok(Json(
+        sqlx::query_as(&format!(
+            "SELECT {RULE_COLUMNS} FROM album_rules JOIN albums ON albums.id = album_id \
+             ORDER BY albums.name, tag"
+        ))
+        .fetch_all(&pool)
+        .await?,
+    ))
+}
+
+/// `DELETE /api/v1/album-rules/:id`. Images only the rule put in its album leave it; the
+/// album stays, if empty.
+pub async fn delete_rule(
+    Extension(pool): Extension<SqlitePool>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    let mut tx = pool.begin().await?;
+    let deleted = sqlx::query("DELETE FROM album_rules WHERE id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(rule_not_found(id));
+    }
+    sqlx::query(&prune_sql())
+        .bind(None::<i64>)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(FromRow, Serialize)]
+pub struct Album {
+    id: i64,
+    name: String,
+    created_at: i64,
+    /// Images in the album the viewer may see.
+    images: i64,
+    /// Tags of its rules, comma-separated.
+    #[serde(skip)]
+    rule_tags: Option<String>,
+    #[sqlx(skip)]
+    tags: Vec<String>,
+}
+
+/// `GET /api/v1/albums`, by name.
+pub async fn list(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+) -> Result<Json<Vec<Album>>, AppError> {
+    let mut albums = sqlx::query_as::<_, Album>(&format!(
+        "SELECT id, name, created_at, \
+             (SELECT COUNT(*) FROM album_images JOIN images ON images.id = image_id \
+              WHERE album_id = albums.id AND {}) AS images, \
+             (SELECT group_concat(tag, ',') FROM \
+                 (SELECT tag FROM album_rules WHERE album_id = albums.id ORDER BY tag)) \
+                 AS rule_tags \
+         FROM albums ORDER BY name",
+        viewer.visible()
+    ))
+    .fetch_all(&pool)
+    .await?;
+    for album in &mut albums {
+        album.tags = tags::split(album.rule_tags.as_deref().unwrap_or_default());
+    }
+
+    Ok(Json(albums))
+}
+
+/// `GET /api/v1/albums/:id/images`, paged like `GET /images`.
+pub async fn images(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    Path(id): Path<i64>,
+    Query(query): Query<PageQuery>,
+) -> Result<Json<Page>, AppError> {
+    let exists: Option<i64> = sqlx::query_scalar("SELECT id FROM albums WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&pool)
+        .await?;
+    if exists.is_none() {
+        return Err(album_not_found(id));
+    }
+    let (cursor, limit) = query.parse()?;
+
+    let images = sqlx::query_as::<_, ImageRecord>(&format!(
+        "SELECT {IMAGE_COLUMNS} FROM images WHERE {} AND {AFTER_CURSOR} \
+             AND id IN (SELECT image_id FROM album_images WHERE album_id = ?4) \
+         ORDER BY created_at, id LIMIT ?3",
+        viewer.visible()
+    ))
+    .bind(cursor.map(|cursor| cursor.created_at))
+    .bind(cursor.map(|cursor| cursor.id))
+    .bind(limit + 1)
+    .bind(id)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(Page::from_rows(images, limit)))
+}
+
+/// `DELETE /api/v1/albums/:id`, with its rules.
+pub async fn delete(
+    Extension(pool): Extension<SqlitePool>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    let mut tx = pool.begin().await?;
+    for table in ["album_images", "album_rules"] {
+        sqlx::query(&format!("DELETE FROM {table} WHERE album_id = ?"))
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    let deleted = sqlx::query("DELETE FROM albums WHERE id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(album_not_found(id));
+    }
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
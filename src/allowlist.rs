@@ -0,0 +1,125 @@
+//! Restricts the admin routes to listed networks.
+//!
+//! With `ADMIN_ALLOWLIST` set (CIDR ranges like `10.8.0.0/16` or `2001:db8::/32`, comma
+//! separated), `/admin/*` and `/api/v1/admin/*` answer 403 to clients outside them, and the
+//! refusal is recorded in the audit log. The client is a [`ClientIp`], so behind a trusted
+//! reverse proxy it's the address the proxy forwarded for. Requests over a Unix socket are
+//! refused unless the list has `unix`.
+
+use std::net::IpAddr;
+
+use axum::{
+    extract::Request,
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use sqlx::SqlitePool;
+
+use crate::{
+    audit,
+    client_ip::{self, ClientIp},
+    config::SharedConfig,
+    error::AppError,
+};
+
+/// A network: an address and how many of its leading bits are fixed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// `192.168.1.0/24`, a single address like `192.168.1.7`, or `unix` for the peer of
+    /// requests over a Unix socket.
+    fn parse(value: &str) -> Option<Cidr> {
+        if value.eq_ignore_ascii_case("unix") {
+            return Some(Cidr {
+                network: client_ip::UNIX_PEER,
+                prefix: 32,
+            });
+        }
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let network = address.parse::<IpAddr>().ok()?.to_canonical();
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= bits)?,
+            None => bits,
+        };
+
+        Some(Cidr { network, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let mask = |bits: u32| u128::MAX.checked_shl(bits - self.prefix as u32);
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = mask(32).unwrap_or(0) as u32;
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = mask(128).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses the comma separated ranges of variable `name`.
+pub fn parse(name: &str, value: &str) -> anyhow::Result<Vec<Cidr>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|range| !range.is_empty())
+        .map(|range| {
+            Cidr::parse(range).ok_or_else(|| {
+                anyhow::anyhow!("{name} must list CIDR ranges like 10.0.0.0/8, got {range:?}")
+            })
+        })
+        .collect()
+}
+
+pub fn listed(ranges: &[Cidr], ip: IpAddr) -> bool {
+    ranges.iter().any(|range| range.contains(ip))
+}
+
+fn is_admin(path: &str) -> bool {
+    ["/admin/", "/api/v1/admin/"]
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+}
+
+/// Middleware refusing admin routes to clients outside `ADMIN_ALLOWLIST`.
+pub async fn admin_only(
+    Extension(config): Extension<SharedConfig>,
+    Extension(pool): Extension<SqlitePool>,
+    ClientIp(client): ClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = config.load();
+    let path = request.uri().path();
+    let Some(allowlist) = config.admin_allowlist.as_deref().filter(|_| is_admin(path)) else {
+        return next.run(request).await;
+    };
+    if listed(allowlist, client) {
+        return next.run(request).await;
+    }
+
+    let detail = format!("{} {path}", request.method());
+    if let Err(e) = audit::record(&pool, Some(client), "admin_denied", None, &detail).await {
+        eprintln!("Failed to record refused admin request: {e:#}");
+    }
+    AppError::new(
+        StatusCode::FORBIDDEN,
+        "address_not_allowed",
+        "The admin routes can't be used from this address",
+    )
+    .into_response()
+}
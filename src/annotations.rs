@@ -0,0 +1,525 @@
+//! Labeled regions of images, for curating datasets to train models on.
+//!
+//! An annotation is a rectangle in the original's pixels (`x` and `y` of its top left corner,
+//! `width` and `height`) with a `label`. API token holders add them with
+//! `POST /api/v1/image/:id/annotations`, change them with `PATCH` and remove them with
+//! `DELETE /api/v1/image/:id/annotations/:annotation`; whoever can see an image can list its
+//! annotations. Rectangles must lie within the image once its dimensions are known. The
+//! details page draws them over the image.
+//!
+//! `GET /api/v1/annotations/coco` exports the annotated images a search matches as a COCO
+//! object detection dataset. COCO numbers images, so they're numbered in the order exported,
+//! and named after their ids in `file_name`; categories are the labels, sorted by name.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use axum::{
+    extract::{Path, RawQuery},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{
+    api,
+    auth::Viewer,
+    config::SharedConfig,
+    error::AppError,
+    expiry, fetch_visible_image,
+    filters::SearchFilters,
+    fragments,
+    ids::{self, ImageId, ImageKey},
+    licenses, ImageRecord, IMAGE_COLUMNS,
+};
+
+/// Longest label, in characters.
+const MAX_LABEL_CHARS: usize = 100;
+
+#[derive(FromRow, Serialize)]
+pub struct Annotation {
+    pub id: i64,
+    /// As URLs show it, see `ids`.
+    #[sqlx(skip)]
+    pub image_id: ImageKey,
+    pub label: String,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+const ANNOTATION_COLUMNS: &str = "id, label, x, y, width, height, created_at, updated_at";
+
+#[derive(Deserialize)]
+pub struct NewAnnotation {
+    label: String,
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+}
+
+/// `PATCH` body; fields left out keep their value.
+#[derive(Deserialize)]
+pub struct AnnotationUpdate {
+    label: Option<String>,
+    x: Option<f64>,
+    y: Option<f64>,
+    width: Option<f64>,
+    height: Option<f64>,
+}
+
+fn invalid(error: &str) -> AppError {
+    AppError::bad_request("invalid_annotation", format!("Invalid annotation: {error}"))
+        .with_param("error", error)
+}
+
+/// Trims the label and checks the rectangle, against `image`'s dimensions if they're known.
+fn validate(image: &ImageRecord, annotation: &mut NewAnnotation) -> Result<(), AppError> {
+    annotation.label = annotation.label.trim().to_string();
+    if annotation.label.is_empty() {
+        return Err(invalid("label is empty"));
+    }
+    if annotation.label.chars().count() > MAX_LABEL_CHARS {
+        return Err(invalid(&format!(
+            "labels may be at most {MAX_LABEL_CHARS} characters"
+        )));
+    }
+    let NewAnnotation {
+        x,
+        y,
+        width,
+        height,
+        ..
+    } = *annotation;
+    if ![x, y, width, height].iter().all(|n| n.is_finite()) {
+        return Err(invalid("x, y, width and height must be numbers"));
+    }
+    if x < 0.0 || y < 0.0 || width <= 0.0 || height <= 0.0 {
+        return Err(invalid(
+            "x and y may not be negative, and width and height must be above 0",
+        ));
+    }
+    if let (Some(image_width), Some(image_height)) = (image.width, image.height) {
+        if x + width > image_width as f64 || y + height > image_height as f64 {
+            return Err(AppError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "annotation_out_of_bounds",
+                format!(
+                    "The region must lie within the image, {image_width}x{image_height} pixels"
+                ),
+            )
+            .with_param("width", image_width.to_string())
+            .with_param("height", image_height.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+/// `image`'s record, unless it doesn't exist or `viewer` may not see it.
+async fn fetch_image(
+    pool: &SqlitePool,
+    viewer: Viewer,
+    image: &ImageId,
+) -> Result<ImageRecord, AppError> {
+    match fetch_visible_image(pool, viewer, image.id).await? {
+        Some(record) => Ok(record),
+        None => {
+            expiry::gone(pool, image.id).await?;
+            Err(ids::not_found(&image.key))
+        }
+    }
+}
+
+fn not_found(image: &ImageId, id: i64) -> AppError {
+    AppError::new(
+        StatusCode::NOT_FOUND,
+        "annotation_not_found",
+        format!("Image {} has no annotation {id}", image.key),
+    )
+    .with_param("id", image.key.to_string())
+    .with_param("annotation", id.to_string())
+}
+
+pub async fn list(pool: &SqlitePool, image: &ImageId) -> sqlx::Result<Vec<Annotation>> {
+    let mut annotations: Vec<Annotation> = sqlx::query_as(&format!(
+        "SELECT {ANNOTATION_COLUMNS} FROM annotations WHERE image_id = ? ORDER BY id"
+    ))
+    .bind(image.id)
+    .fetch_all(pool)
+    .await?;
+    for annotation in &mut annotations {
+        annotation.image_id = image.key.clone();
+    }
+
+    Ok(annotations)
+}
+
+/// `GET /api/v1/image/:id/annotations`, oldest first.
+pub async fn list_annotations(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    image: ImageId,
+) -> Result<Json<Vec<Annotation>>, AppError> {
+    fetch_image(&pool, viewer, &image).await?;
+
+    Ok(Json(list(&pool, &image).await?))
+}
+
+/// `POST /api/v1/image/:id/annotations` with
+/// `{"label": "cat", "x": 10, "y": 20, "width": 200, "height": 150}`.
+pub async fn create_annotation(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    image: ImageId,
+    Json(mut annotation): Json<NewAnnotation>,
+) -> Result<(StatusCode, Json<Annotation>), AppError> {
+    let record = fetch_image(&pool, viewer, &image).await?;
+    validate(&record, &mut annotation)?;
+
+    let mut created: Annotation = sqlx::query_as(&format!(
+        "INSERT INTO annotations (image_id, label, x, y, width, height, created_at, updated_at) \
+         VALUES (?, ?, ?, ?, ?, ?, CAST(strftime('%s', 'now') AS INTEGER), \
+             CAST(strftime('%s', 'now') AS INTEGER)) \
+         RETURNING {ANNOTATION_COLUMNS}"
+    ))
+    .bind(image.id)
+    .bind(&annotation.label)
+    .bind(annotation.x)
+    .bind(annotation.y)
+    .bind(annotation.width)
+    .bind(annotation.height)
+    .fetch_one(&pool)
+    .await?;
+    created.image_id = image.key;
+
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+/// `PATCH /api/v1/image/:id/annotations/:annotation`
+pub async fn update_annotation(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    image: ImageId,
+    Path((_, id)): Path<(String, i64)>,
+    Json(update): Json<AnnotationUpdate>,
+) -> Result<Json<Annotation>, AppError> {
+    let record = fetch_image(&pool, viewer, &image).await?;
+    let current: Option<Annotation> = sqlx::query_as(&format!(
+        "SELECT {ANNOTATION_COLUMNS} FROM annotations WHERE id = ? AND image_id = ?"
+    ))
+    .bind(id)
+    .bind(image.id)
+    .fetch_optional(&pool)
+    .await?;
+    let Some(current) = current else {
+        return Err(not_found(&image, id));
+    };
+    let mut annotation = NewAnnotation {
+        label: update.label.unwrap_or(current.label),
+        x: update.x.unwrap_or(current.x),
+        y: update.y.unwrap_or(current.y),
+        width: update.width.unwrap_or(current.width),
+        height: update.height.unwrap_or(current.height),
+    };
+    validate(&record, &mut annotation)?;
+
+    let mut updated: Annotation = sqlx::query_as(&format!(
+        "UPDATE annotations SET label = ?, x = ?, y = ?, width = ?, height = ?, \
+             updated_at = CAST(strftime('%s', 'now') AS INTEGER) \
+         WHERE id = ? \
+         RETURNING {ANNOTATION_COLUMNS}"
+    ))
+    .bind(&annotation.label)
+    .bind(annotation.x)
+    .bind(annotation.y)
+    .bind(annotation.width)
+    .bind(annotation.height)
+    .bind(id)
+    .fetch_one(&pool)
+    .await?;
+    updated.image_id = image.key;
+
+    Ok(Json(updated))
+}
+
+/// `DELETE /api/v1/image/:id/annotations/:annotation`
+pub async fn delete_annotation(
+    Extension(pool): Extension<SqlitePool>,
+    image: ImageId,
+    Path((_, id)): Path<(String, i64)>,
+) -> Result<StatusCode, AppError> {
+    let deleted = sqlx::query("DELETE FROM annotations WHERE id = ? AND image_id = ?")
+        .bind(id)
+        .bind(image.id)
+        .execute(&pool)
+        .await?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(not_found(&image, id));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The boxes drawn over `image` on its details page, placed in percent of its size.
+pub async fn render(pool: &SqlitePool, image: &ImageRecord) -> Result<String, AppError> {
+    let (Some(width), Some(height)) = (image.width, image.height) else {
+        return Ok(String::new());
+    };
+    if width <= 0 || height <= 0 {
+        return Ok(String::new());
+    }
+    let image = ImageId {
+        id: image.id,
+        key: image.key.clone(),
+    };
+    let (width, height) = (width as f64, height as f64);
+
+    Ok(list(pool, &image)
+        .await?
+        .iter()
+        .map(|annotation| {
+            let label = fragments::escape_html(&annotation.label);
+            format!(
+                "<div class=\"annotation\" title=\"{label}\" style=\"left: {:.3}%; top: {:.3}%; width: {:.3}%; height: {:.3}%\"><span>{label}</span></div>",
+                annotation.x / width * 100.0,
+                annotation.y / height * 100.0,
+                annotation.width / width * 100.0,
+                annotation.height / height * 100.0,
+            )
+        })
+        .collect())
+}
+
+/// The export's own parameters; the rest of the query string are [`SearchFilters`].
+#[derive(Deserialize)]
+pub struct CocoQuery {
+    #[serde(default)]
+    q: String,
+    /// Exact tag terms, see `tag_match` in `sql_functions`.
+    filter: Option<String>,
+    /// Comma separated; only annotations with one of them are exported.
+    labels: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Coco {
+    info: CocoInfo,
+    licenses: Vec<CocoLicense>,
+    images: Vec<CocoImage>,
+    annotations: Vec<CocoAnnotation>,
+    categories: Vec<CocoCategory>,
+}
+
+#[derive(Serialize)]
+struct CocoInfo {
+    description: &'static str,
+    version: &'static str,
+    year: i64,
+    date_created: String,
+}
+
+#[derive(Serialize)]
+struct CocoLicense {
+    id: usize,
+    name: String,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct CocoImage {
+    id: usize,
+    file_name: String,
+    width: Option<i64>,
+    height: Option<i64>,
+    coco_url: String,
+    date_captured: String,
+    /// Index into `licenses`, 0 for none.
+    license: usize,
+}
+
+#[derive(Serialize)]
+struct CocoAnnotation {
+    id: i64,
+    image_id: usize,
+    category_id: usize,
+    /// `[x, y, width, height]`
+    bbox: [f64; 4],
+    area: f64,
+    segmentation: Vec<Vec<f64>>,
+    iscrowd: u8,
+}
+
+#[derive(Serialize)]
+struct CocoCategory {
+    id: usize,
+    name: String,
+    supercategory: String,
+}
+
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn parse_query(raw: &str) -> Result<(CocoQuery, SearchFilters), AppError> {
+    let invalid = |e: serde_urlencoded::de::Error| {
+        AppError::bad_request("invalid_query", format!("Invalid query: {e}"))
+            .with_param("error", e.to_string())
+    };
+    let query = serde_urlencoded::from_str(raw).map_err(invalid)?;
+    let filters = serde_urlencoded::from_str(raw).map_err(invalid)?;
+
+    Ok((query, filters))
+}
+
+/// `GET /api/v1/annotations/coco?q=&filter=&labels=`, taking the filters of `GET /images` too.
+pub async fn coco(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(viewer): Extension<Viewer>,
+    RawQuery(raw): RawQuery,
+) -> Result<Response, AppError> {
+    let (query, filters) = parse_query(raw.as_deref().unwrap_or_default())?;
+    let labels: Option<Vec<String>> = query.labels.as_deref().map(|labels| {
+        labels
+            .split(',')
+            .map(str::trim)
+            .filter(|label| !label.is_empty())
+            .map(str::to_string)
+            .collect()
+    });
+    let labels = labels.map(|labels| serde_json::to_string(&labels).unwrap_or_default());
+    let filters = filters.compile(5)?;
+    let config = config.load();
+
+    let sql = format!(
+        "SELECT {IMAGE_COLUMNS} FROM images \
+         WHERE id IN (SELECT image_id FROM annotations \
+                 WHERE ?4 IS NULL OR label IN (SELECT value FROM json_each(?4))) \
+             AND (tags LIKE ?1 OR title LIKE ?1 OR description LIKE ?1) \
+             AND (?2 IS NULL OR tag_match(tags, ?2, ?3)) AND {}{} \
+         ORDER BY created_at, id",
+        viewer.visible(),
+        filters.sql
+    );
+    let images: Vec<ImageRecord> = filters
+        .bind(
+            sqlx::query_as(&sql)
+                .bind(format!("%{}%", query.q))
+                .bind(query.filter)
+                .bind(config.tag_search_descendants)
+                .bind(&labels),
+        )
+        .fetch_all(&pool)
+        .await?;
+    let numbers: HashMap<i64, usize> = images
+        .iter()
+        .enumerate()
+        .map(|(i, image)| (image.id, i + 1))
+        .collect();
+    let ids = serde_json::to_string(&numbers.keys().collect::<Vec<_>>()).unwrap_or_default();
+
+    let rows: Vec<(i64, i64, String, f64, f64, f64, f64)> = sqlx::query_as(
+        "SELECT id, image_id, label, x, y, width, height FROM annotations \
+         WHERE image_id IN (SELECT value FROM json_each(?1)) \
+             AND (?2 IS NULL OR label IN (SELECT value FROM json_each(?2))) \
+         ORDER BY image_id, id",
+    )
+    .bind(ids)
+    .bind(&labels)
+    .fetch_all(&pool)
+    .await?;
+    let categories: BTreeMap<&str, usize> = rows
+        .iter()
+        .map(|(_, _, label, ..)| label.as_str())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .enumerate()
+        .map(|(i, label)| (label, i + 1))
+        .collect();
+
+    let mut license_ids: BTreeMap<&str, usize> = BTreeMap::new();
+    for image in &images {
+        if !image.license.is_empty() {
+            let next = license_ids.len() + 1;
+            license_ids.entry(&image.license).or_insert(next);
+        }
+    }
+    let now = now();
+    let coco = Coco {
+        info: CocoInfo {
+            description: "Annotated images exported from thumbnail_service",
+            version: "1.0",
+            year: api::civil_from_days(now.div_euclid(86_400)).0,
+            date_created: api::rfc3339(now),
+        },
+        licenses: license_ids
+            .iter()
+            .map(|(license, &id)| CocoLicense {
+                id,
+                name: license.to_string(),
+                url: licenses::url(license).unwrap_or_default().to_string(),
+            })
+            .collect(),
+        images: images
+            .iter()
+            .map(|image| CocoImage {
+                id: numbers[&image.id],
+                file_name: match &image.format {
+                    Some(format) => format!("{}.{format}", image.key),
+                    None => image.key.to_string(),
+                },
+                width: image.width,
+                height: image.height,
+                coco_url: image.original_url(config.strip_metadata),
+                date_captured: api::rfc3339(image.taken_at.unwrap_or(image.created_at)),
+                license: license_ids
+                    .get(image.license.as_str())
+                    .copied()
+                    .unwrap_or(0),
+            })
+            .collect(),
+        annotations: rows
+            .iter()
+            .map(
+                |(id, image_id, label, x, y, width, height)| CocoAnnotation {
+                    id: *id,
+                    image_id: numbers[image_id],
+                    category_id: categories[label.as_str()],
+                    bbox: [*x, *y, *width, *height],
+                    area: width * height,
+                    segmentation: Vec::new(),
+                    iscrowd: 0,
+                },
+            )
+            .collect(),
+        categories: categories
+            .iter()
+            .map(|(name, &id)| CocoCategory {
+                id,
+                name: name.to_string(),
+                supercategory: String::new(),
+            })
+            .collect(),
+    };
+
+    // COCO's field names are its own, so the v1 layer leaves them be.
+    Ok((
+        Extension(api::Verbatim),
+        [(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"annotations.coco.json\"",
+        )],
+        Json(coco),
+    )
+        .into_response())
+}
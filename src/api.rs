@@ -0,0 +1,259 @@
+//! Versioning of the JSON API.
+//!
+//! The JSON routes are served under `/api/v1/`. Their old paths still work as deprecated
+//! aliases that answer as before, with `Deprecation: true` and a `Link` to the `/api/v1`
+//! route.
+//!
+//! Handlers serialize their types as declared, with snake_case fields and timestamps in Unix
+//! seconds, and the wire format of each version is decided here instead: [`v1`] renames
+//! fields to camelCase and formats timestamps as RFC 3339, and takes camelCase fields in
+//! JSON request bodies. A breaking change can then ship as `/api/v2` with its own layer,
+//! without touching the handlers shared by both.
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
+
+use crate::{error::AppError, filters};
+
+/// Marks a response [`v1`] passes on as the handler wrote it, for JSON in a format of its own
+/// such as a COCO dataset.
+#[derive(Clone, Copy)]
+pub struct Verbatim;
+
+/// Largest JSON request body [`v1`] rewrites, as for axum's `Json`.
+const MAX_REQUEST_BYTES: usize = 2 * 1024 * 1024;
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            let mime = value.split(';').next().unwrap_or_default().trim();
+            mime == "application/json" || mime.ends_with("+json")
+        })
+}
+
+/// `created_at` → `createdAt`. Only snake_case keys are renamed.
+fn to_camel_case(key: &str) -> Option<String> {
+    let snake = key.contains('_')
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_');
+    if !snake {
+        return None;
+    }
+
+    let mut camel = String::with_capacity(key.len());
+    let mut upper = false;
+    for c in key.chars() {
+        match c {
+            '_' => upper = !camel.is_empty(),
+            c if upper => {
+                camel.push(c.to_ascii_uppercase());
+                upper = false;
+            }
+            c => camel.push(c),
+        }
+    }
+    Some(camel)
+}
+
+/// `createdAt` → `created_at`. Only camelCase keys are renamed.
+fn to_snake_case(key: &str) -> Option<String> {
+    let camel = key.bytes().any(|b| b.is_ascii_uppercase())
+        && key.bytes().all(|b| b.is_ascii_alphanumeric());
+    if !camel {
+        return None;
+    }
+
+    let mut snake = String::with_capacity(key.len() + 4);
+    for c in key.chars() {
+        if c.is_ascii_uppercase() {
+            if !snake.is_empty() {
+                snake.push('_');
+            }
+            snake.push(c.to_ascii_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    Some(snake)
+}
+
+/// Whether a field holds a Unix timestamp, going by the naming used throughout.
+fn is_timestamp(key: &str) -> bool {
+    key == "at" || key.ends_with("_at")
+}
+
+/// The civil date of days since 1970-01-01, in the proleptic Gregorian calendar.
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+/// Unix seconds as an RFC 3339 UTC timestamp, e.g. `2024-05-01T12:30:00Z`.
+pub fn rfc3339(secs: i64) -> String {
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let secs_of_day = secs.rem_euclid(86_400);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// An RFC 3339 timestamp as Unix seconds, e.g. `2024-05-01T12:30:00Z` or
+/// `2024-05-01T14:30:00.250+02:00`. Fractions of a second are dropped.
+pub fn parse_rfc3339(value: &str) -> Option<i64> {
+    let (date, time) = value.split_once(['T', 't', ' '])?;
+    let (year, rest) = date.split_once('-')?;
+    let (month, day) = rest.split_once('-')?;
+    let (year, month, day): (i64, i64, i64) =
+        (year.parse().ok()?, month.parse().ok()?, day.parse().ok()?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let (time, offset) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else {
+        let at = time.rfind(['+', '-'])?;
+        let (hours, minutes) = time[at + 1..].split_once(':')?;
+        let offset = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+        (
+            &time[..at],
+            if &time[at..at + 1] == "-" {
+                -offset
+            } else {
+                offset
+            },
+        )
+    };
+    let mut parts = time.split(':');
+    let hour: i64 = parts.next()?.parse().ok()?;
+    let minute: i64 = parts.next()?.parse().ok()?;
+    let second = parts.next()?;
+    let second: i64 = second
+        .split_once('.')
+        .map_or(second, |(whole, _)| whole)
+        .parse()
+        .ok()?;
+    if parts.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = filters::days_from_civil(year, month, day);
+    Some(days * 86_400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+pub fn response_to_v1(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| {
+                    let value = match value.as_i64() {
+                        Some(secs) if is_timestamp(&key) => Value::String(rfc3339(secs)),
+                        _ => response_to_v1(value),
+                    };
+                    (to_camel_case(&key).unwrap_or(key), value)
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(response_to_v1).collect()),
+        value => value,
+    }
+}
+
+fn request_from_v1(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (to_snake_case(&key).unwrap_or(key), request_from_v1(value)))
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(request_from_v1).collect()),
+        value => value,
+    }
+}
+
+/// Middleware for the `/api/v1` routes, converting JSON bodies to and from the v1 format.
+pub async fn v1(request: Request, next: Next) -> Response {
+    let request = if is_json(request.headers()) {
+        let (mut parts, body) = request.into_parts();
+        let Ok(bytes) = to_bytes(body, MAX_REQUEST_BYTES).await else {
+            return AppError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "body_too_large",
+                format!("JSON bodies may be at most {MAX_REQUEST_BYTES} bytes"),
+            )
+            .with_param("max", MAX_REQUEST_BYTES.to_string())
+            .into_response();
+        };
+        // Malformed JSON is left for the handler to reject as usual.
+        let bytes = match serde_json::from_slice(&bytes) {
+            Ok(value) => serde_json::to_vec(&request_from_v1(value)).unwrap_or_default(),
+            Err(_) => bytes.to_vec(),
+        };
+        parts.headers.remove(header::CONTENT_LENGTH);
+        Request::from_parts(parts, Body::from(bytes))
+    } else {
+        request
+    };
+
+    let response = next.run(request).await;
+    if !is_json(response.headers()) || response.extensions().get::<Verbatim>().is_some() {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let bytes = match to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => return AppError::from(e).into_response(),
+    };
+    let bytes = match serde_json::from_slice(&bytes) {
+        Ok(value) => serde_json::to_vec(&response_to_v1(value)).unwrap_or_default(),
+        Err(_) => bytes.to_vec(),
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+/// Middleware for the old, unversioned paths of the JSON routes.
+pub async fn deprecated(request: Request, next: Next) -> Response {
+    let successor = format!(
+        "</api/v1{}>; rel=\"successor-version\"",
+        request.uri().path()
+    );
+    let mut response = next.run(request).await;
+
+    let headers = response.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.append(header::LINK, link);
+    }
+
+    response
+}
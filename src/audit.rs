@@ -0,0 +1,27 @@
+use std::net::IpAddr;
+
+use sqlx::SqlitePool;
+
+/// Appends an entry to the audit trail. `client` is the address of the client whose request
+/// it's for (see `client_ip`), `None` for background tasks. `image_id` is `None` for actions
+/// that never produced a stored image (e.g. rejected uploads).
+pub async fn record(
+    pool: &SqlitePool,
+    client: Option<IpAddr>,
+    action: &str,
+    image_id: Option<i64>,
+    detail: &str,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT INTO audit_log (at, action, image_id, detail, client_ip) \
+         VALUES (CAST(strftime('%s', 'now') AS INTEGER), ?, ?, ?, ?)",
+    )
+    .bind(action)
+    .bind(image_id)
+    .bind(detail)
+    .bind(client.map(|client| client.to_string()))
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,256 @@
+//! Bearer-token authentication and the public gallery mode.
+//!
+//! With `API_TOKEN` set, every route needs `Authorization: Bearer <token>`. `PUBLIC_GALLERY`
+//! opens the read-only routes to anonymous visitors, who are rate limited per IP and don't
+//! see private images; writes still need the token.
+//!
+//! Browsers can't send headers with the event streams pages open (`EventSource`), so a page
+//! that needs one links it with a `?token=` from [`stream_token`] instead: the stream's path
+//! and an expiry signed with the API token. It's good for [`STREAM_TOKEN_TTL`], after which a
+//! dropped stream only reconnects once the page is reloaded.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::Request,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    client_ip::ClientIp,
+    config::{Config, SharedConfig},
+    error::AppError,
+    moderation::Status,
+};
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+/// How long a page's stream token lets it open the stream.
+pub const STREAM_TOKEN_TTL: Duration = Duration::from_secs(3600);
+
+/// Who is making the request, inserted into the request's extensions by the middleware.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Viewer {
+    Authenticated,
+    Anonymous,
+}
+
+impl Viewer {
+    /// SQL condition on `images` selecting the rows this viewer may see. Nobody sees images
+    /// in an open upload session, and anonymous visitors only see approved ones, see
+    /// `moderation`.
+    pub fn visible(self) -> &'static str {
+        match self {
+            Viewer::Authenticated => "session_id IS NULL",
+            Viewer::Anonymous => "private = 0 AND status = 'approved' AND session_id IS NULL",
+        }
+    }
+
+    pub fn can_see(self, private: bool, status: Status) -> bool {
+        self == Viewer::Authenticated || (!private && status == Status::Approved)
+    }
+}
+
+/// Requests per client IP in the current window, for anonymous readers.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    windows: Arc<Mutex<HashMap<IpAddr, (Instant, u32)>>>,
+}
+
+impl RateLimiter {
+    /// Counts a request from `ip`, returning how long to wait if it's over `limit`.
+    pub fn check(&self, ip: IpAddr, limit: u32) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() > 10_000 {
+            windows.retain(|_, (start, _)| now.duration_since(*start) < RATE_LIMIT_WINDOW);
+        }
+
+        let (start, count) = windows.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= RATE_LIMIT_WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+
+        if *count > limit {
+            Err(RATE_LIMIT_WINDOW.saturating_sub(now.duration_since(*start)))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// 429 with a `Retry-After` of `retry_after`, rounded up to a second.
+pub fn rate_limited(retry_after: Duration) -> Response {
+    (
+        [(
+            header::RETRY_AFTER,
+            retry_after.as_secs().max(1).to_string(),
+        )],
+        AppError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+            "Too many requests, try again later",
+        ),
+    )
+        .into_response()
+}
+
+/// Whether the request carries the configured token, or no token is configured at all.
+fn has_token(config: &Config, request: &Request) -> bool {
+    let Some(expected) = &config.api_token else {
+        return true;
+    };
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    // Comparing digests keeps the comparison time independent of where the tokens differ.
+    Sha256::digest(given.trim()) == Sha256::digest(expected)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// The signature of `path` until `expires`, keyed with the API token.
+fn stream_signature(api_token: &str, path: &str, expires: u64) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(api_token.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{path}\n{expires}").as_bytes());
+    mac
+}
+
+/// A token letting a page's script open the stream at `path` without the API token, for
+/// [`STREAM_TOKEN_TTL`]; empty when no token is configured.
+pub fn stream_token(config: &Config, path: &str) -> String {
+    let Some(api_token) = &config.api_token else {
+        return String::new();
+    };
+    let expires = unix_now() + STREAM_TOKEN_TTL.as_secs();
+    let signature = stream_signature(api_token, path, expires).finalize();
+    format!(
+        "{expires}.{}",
+        URL_SAFE_NO_PAD.encode(signature.into_bytes())
+    )
+}
+
+/// Whether the request's `?token=` is a current [`stream_token`] for its path.
+fn has_stream_token(config: &Config, request: &Request) -> bool {
+    let Some(api_token) = &config.api_token else {
+        return true;
+    };
+    let token = request
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="));
+    let Some((expires, signature)) = token.and_then(|token| token.split_once('.')) else {
+        return false;
+    };
+    let (Ok(expires), Ok(signature)) = (expires.parse::<u64>(), URL_SAFE_NO_PAD.decode(signature))
+    else {
+        return false;
+    };
+
+    expires >= unix_now()
+        && stream_signature(api_token, request.uri().path(), expires)
+            .verify_slice(&signature)
+            .is_ok()
+}
+
+fn unauthorized() -> Response {
+    (
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        AppError::new(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "A valid API token is required",
+        ),
+    )
+        .into_response()
+}
+
+/// Middleware for routes that change data: always needs the token, and is refused while
+/// `READ_ONLY` is on.
+pub async fn require_token(
+    Extension(config): Extension<SharedConfig>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let config = config.load();
+    if !has_token(&config, &request) {
+        return unauthorized();
+    }
+    // The admin routes stay usable, not least to turn read-only mode off again.
+    if config.read_only && !request.uri().path().starts_with("/admin/") {
+        return AppError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "read_only",
+            "The service is read-only for now, try again later",
+        )
+        .into_response();
+    }
+    request.extensions_mut().insert(Viewer::Authenticated);
+
+    next.run(request).await
+}
+
+/// Middleware for event streams opened by pages: needs the API token or a current
+/// [`stream_token`] for the stream.
+pub async fn require_stream_token(
+    Extension(config): Extension<SharedConfig>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let config = config.load();
+    if !has_token(&config, &request) && !has_stream_token(&config, &request) {
+        return unauthorized();
+    }
+    request.extensions_mut().insert(Viewer::Authenticated);
+
+    next.run(request).await
+}
+
+/// Middleware for read-only routes: needs the token unless the gallery is public, in which
+/// case requests without it are served as anonymous and rate limited.
+pub async fn allow_public(
+    Extension(config): Extension<SharedConfig>,
+    Extension(limiter): Extension<RateLimiter>,
+    ClientIp(client): ClientIp,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let config = config.load();
+    let viewer = if has_token(&config, &request) {
+        Viewer::Authenticated
+    } else if config.public_gallery {
+        if let Err(retry_after) = limiter.check(client, config.anonymous_rate_limit) {
+            return rate_limited(retry_after);
+        }
+        Viewer::Anonymous
+    } else {
+        return unauthorized();
+    };
+    request.extensions_mut().insert(viewer);
+
+    next.run(request).await
+}
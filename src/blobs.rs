@@ -0,0 +1,194 @@
+//! Stores identical originals once.
+//!
+//! The bytes live in `blobs/<sha256>`, and the paths the rest of the service reads
+//! (`images/{id}.jpg`, `images/{id}_v{n}.jpg`) are hard links to them. `blob_links` records
+//! every such path, and a blob is deleted along with its last link. Where hard links aren't
+//! possible (e.g. `images/` and `blobs/` on different filesystems) the file is copied instead,
+//! which still works but saves no space. Files stored before this existed aren't linked and
+//! are simply deleted when no longer needed, unless the storage migration adopts them first
+//! (see `storage_migration`).
+
+use std::path::{Path, PathBuf};
+
+use sqlx::SqlitePool;
+use tokio::sync::Mutex;
+
+use crate::cdn;
+
+const DIR: &str = "blobs";
+
+/// Serialises blob changes, so a blob is never deleted while another request links to it.
+static LOCK: Mutex<()> = Mutex::const_new(());
+
+fn blob_path(hash: &str) -> PathBuf {
+    Path::new(DIR).join(hash)
+}
+
+/// Points `link` at `blob`, replacing whatever `link` was, via a temporary file so readers
+/// never see it missing. The temporary file is a `.tmp`, so one left by a crash is deleted at
+/// the next startup (see `journal`).
+async fn link_to(blob: &Path, link: &str) -> anyhow::Result<()> {
+    let partial = format!("{link}.tmp");
+    let _ = tokio::fs::remove_file(&partial).await;
+    if tokio::fs::hard_link(blob, &partial).await.is_err() {
+        tokio::fs::copy(blob, &partial).await?;
+    }
+    tokio::fs::rename(&partial, link).await?;
+
+    Ok(())
+}
+
+/// Records `path` as a link to `hash`, releasing the blob it linked to before.
+async fn record_link(pool: &SqlitePool, path: &str, hash: &str) -> anyhow::Result<()> {
+    let previous: Option<String> = sqlx::query_scalar("SELECT hash FROM blob_links WHERE path = ?")
+        .bind(path)
+        .fetch_optional(pool)
+        .await?;
+    sqlx::query("INSERT OR REPLACE INTO blob_links (path, hash) VALUES (?, ?)")
+        .bind(path)
+        .bind(hash)
+        .execute(pool)
+        .await?;
+    if let Some(previous) = previous.filter(|previous| previous != hash) {
+        delete_if_unused(pool, &previous).await?;
+    }
+
+    Ok(())
+}
+
+async fn delete_if_unused(pool: &SqlitePool, hash: &str) -> anyhow::Result<()> {
+    let deleted = sqlx::query(
+        "DELETE FROM blobs WHERE hash = ?1 \
+             AND NOT EXISTS (SELECT 1 FROM blob_links WHERE hash = ?1)",
+    )
+    .bind(hash)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if deleted > 0 {
+        tokio::fs::remove_file(blob_path(hash)).await?;
+    }
+
+    Ok(())
+}
+
+/// Makes `path` hold `bytes`, sharing the blob with any identical file already stored.
+#[tracing::instrument(name = "storage.store", skip(pool, bytes), fields(size = bytes.len()))]
+pub async fn store(pool: &SqlitePool, bytes: &[u8], path: &str) -> anyhow::Result<()> {
+    let hash = cdn::content_hash(bytes);
+    let blob = blob_path(&hash);
+    let _lock = LOCK.lock().await;
+
+    let known: Option<String> = sqlx::query_scalar("SELECT hash FROM blobs WHERE hash = ?")
+        .bind(&hash)
+        .fetch_optional(pool)
+        .await?;
+    if known.is_none() {
+        tokio::fs::create_dir_all(DIR).await?;
+        let partial = blob.with_extension("tmp");
+        tokio::fs::write(&partial, bytes).await?;
+        tokio::fs::rename(&partial, &blob).await?;
+        sqlx::query("INSERT INTO blobs (hash, size) VALUES (?, ?)")
+            .bind(&hash)
+            .bind(bytes.len() as i64)
+            .execute(pool)
+            .await?;
+    }
+
+    link_to(&blob, path).await?;
+    record_link(pool, path, &hash).await
+}
+
+/// Turns `path`, stored before blobs existed, into a link to a blob as if `store` had made
+/// it. Returns whether it did: nothing is done if `path` is a link already or isn't there.
+pub async fn adopt(pool: &SqlitePool, path: &str) -> anyhow::Result<bool> {
+    let linked: Option<String> = sqlx::query_scalar("SELECT hash FROM blob_links WHERE path = ?")
+        .bind(path)
+        .fetch_optional(pool)
+        .await?;
+    if linked.is_some() {
+        return Ok(false);
+    }
+    let bytes = match tokio::fs::read(path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    store(pool, &bytes, path).await?;
+
+    Ok(true)
+}
+
+/// Makes `to` a copy of `from`, as another link to the same blob when `from` is one.
+#[tracing::instrument(name = "storage.share", skip(pool))]
+pub async fn share(pool: &SqlitePool, from: &str, to: &str) -> anyhow::Result<()> {
+    let _lock = LOCK.lock().await;
+    let hash: Option<String> = sqlx::query_scalar("SELECT hash FROM blob_links WHERE path = ?")
+        .bind(from)
+        .fetch_optional(pool)
+        .await?;
+
+    match hash {
+        Some(hash) => {
+            link_to(&blob_path(&hash), to).await?;
+            record_link(pool, to, &hash).await
+        }
+        None => {
+            tokio::fs::copy(from, to).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Puts `bytes`, the known good content of `path`, back in place of a corrupted or missing
+/// file. If it's a link, the blob is rewritten and every path linking to it linked again, as
+/// they all shared the corrupted copy.
+pub async fn restore(pool: &SqlitePool, path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+    let _lock = LOCK.lock().await;
+    let hash: Option<String> = sqlx::query_scalar("SELECT hash FROM blob_links WHERE path = ?")
+        .bind(path)
+        .fetch_optional(pool)
+        .await?;
+    let Some(hash) = hash else {
+        let partial = format!("{path}.tmp");
+        tokio::fs::write(&partial, bytes).await?;
+        tokio::fs::rename(&partial, path).await?;
+        return Ok(());
+    };
+
+    let blob = blob_path(&hash);
+    tokio::fs::create_dir_all(DIR).await?;
+    let partial = blob.with_extension("tmp");
+    tokio::fs::write(&partial, bytes).await?;
+    tokio::fs::rename(&partial, &blob).await?;
+    let links: Vec<String> = sqlx::query_scalar("SELECT path FROM blob_links WHERE hash = ?")
+        .bind(&hash)
+        .fetch_all(pool)
+        .await?;
+    for link in links {
+        link_to(&blob, &link).await?;
+    }
+
+    Ok(())
+}
+
+/// Deletes `path`, and its blob if nothing else links to it.
+#[tracing::instrument(name = "storage.remove", skip(pool))]
+pub async fn remove(pool: &SqlitePool, path: &str) -> anyhow::Result<()> {
+    let _lock = LOCK.lock().await;
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    let hash: Option<String> =
+        sqlx::query_scalar("DELETE FROM blob_links WHERE path = ? RETURNING hash")
+            .bind(path)
+            .fetch_optional(pool)
+            .await?;
+    if let Some(hash) = hash {
+        delete_if_unused(pool, &hash).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,270 @@
+//! Ingesting images dropped into an S3 or MinIO bucket, from its event notifications.
+//!
+//! The bucket is set up to send `s3:ObjectCreated:*` events as a webhook to
+//! `POST /ingest/s3` with the API token (MinIO's `notify_webhook` with `auth_token`, or
+//! anything relaying S3's notification JSON). Each object announced is fetched from
+//! `S3_ENDPOINT` (see `s3`) and goes through the upload pipeline like an upload, tagged with
+//! the folders of its key: `cats/siamese/1.jpg` gets `cats` and `siamese`.
+//!
+//! Objects already stored are reported as duplicates rather than stored again, so a
+//! notification delivered twice is harmless. When an object fails in a way that may pass (the
+//! bucket can't be reached or answers with a server error, or storing it fails on our side,
+//! say with the database or the malware scanner unavailable) the response is a 502 after the
+//! other objects are done, so the sender tries the notification again later; objects the
+//! bucket refuses, that are too large or that aren't acceptable images are only reported.
+
+use std::time::Duration;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use reqwest::Method;
+use serde::Deserialize;
+
+use crate::{
+    client_ip::ClientIp,
+    config::{Config, SharedConfig},
+    error::AppError,
+    jobs::JobQueue,
+    pipeline::{SharedPipeline, Upload},
+    quarantine::SharedQuarantine,
+    s3, Ingested, NewImage, UploadOptions,
+};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An S3 event notification; MinIO's add fields of their own, which are ignored.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Notification {
+    #[serde(default)]
+    records: Vec<Record>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Record {
+    event_name: String,
+    s3: Entity,
+}
+
+#[derive(Deserialize)]
+struct Entity {
+    bucket: Bucket,
+    object: Object,
+}
+
+#[derive(Deserialize)]
+struct Bucket {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Object {
+    /// URL-encoded, as in a form.
+    key: String,
+}
+
+/// Decodes an object key as notifications have it, `+` for spaces and `%XX` escapes.
+fn decode_key(key: &str) -> String {
+    let bytes = key.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match key
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 2;
+                }
+                None => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The folders of `key`, which the image is tagged with.
+fn tags_of(key: &str) -> String {
+    let folders = key.rsplit_once('/').map(|(folders, _)| folders);
+    folders
+        .unwrap_or_default()
+        .split('/')
+        .filter(|folder| !folder.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The bucket couldn't be reached or failed, which may pass.
+fn fetch_failed(key: &str, reason: impl Into<String>) -> AppError {
+    let reason = reason.into();
+    AppError::new(
+        StatusCode::BAD_GATEWAY,
+        "bucket_fetch_failed",
+        format!("Fetching {key} from the bucket failed: {reason}"),
+    )
+    .with_param("key", key)
+    .with_param("reason", reason)
+}
+
+/// The object can't be ingested, however often it's tried.
+fn object_refused(key: &str, reason: impl Into<String>) -> AppError {
+    let reason = reason.into();
+    AppError::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "bucket_object_refused",
+        format!("Can't ingest {key} from the bucket: {reason}"),
+    )
+    .with_param("key", key)
+    .with_param("reason", reason)
+}
+
+/// Downloads object `key` of `bucket`.
+pub async fn fetch(
+    config: &Config,
+    endpoint: &str,
+    bucket: &str,
+    key: &str,
+) -> Result<Vec<u8>, AppError> {
+    let url = s3::object_url(endpoint, bucket, key)
+        .ok_or_else(|| object_refused(key, "invalid S3_ENDPOINT"))?;
+
+    let client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .map_err(|e| fetch_failed(key, e.to_string()))?;
+    let request = s3::request(&client, config, Method::GET, &url, s3::UNSIGNED_PAYLOAD);
+    let mut response = request
+        .send()
+        .await
+        .map_err(|e| fetch_failed(key, e.to_string()))?;
+    let status = response.status();
+    if status.is_server_error() {
+        return Err(fetch_failed(key, format!("the bucket answered {status}")));
+    }
+    if !status.is_success() {
+        return Err(object_refused(key, format!("the bucket answered {status}")));
+    }
+
+    let max_bytes = config.s3_max_bytes;
+    let too_large = || object_refused(key, format!("larger than {max_bytes} bytes"));
+    if response
+        .content_length()
+        .is_some_and(|length| length > max_bytes)
+    {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| fetch_failed(key, e.to_string()))?
+    {
+        body.extend_from_slice(&chunk);
+        if body.len() as u64 > max_bytes {
+            return Err(too_large());
+        }
+    }
+
+    Ok(body)
+}
+
+fn failed(key: &str, error: &AppError) -> serde_json::Value {
+    serde_json::json!({
+        "key": key,
+        "status": "failed",
+        "code": error.code(),
+        "error": error.message(),
+    })
+}
+
+/// `POST /ingest/s3`: answers what became of each object, `stored` or `duplicate` with its
+/// image, or `failed` with why.
+pub async fn notify(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(pipeline): Extension<SharedPipeline>,
+    Extension(jobs): Extension<JobQueue>,
+    Extension(quarantine): Extension<Option<SharedQuarantine>>,
+    ClientIp(client): ClientIp,
+    Json(notification): Json<Notification>,
+) -> Result<Response, AppError> {
+    let config = config.load();
+    let endpoint = config.s3_endpoint.as_deref().ok_or_else(|| {
+        AppError::new(
+            StatusCode::NOT_FOUND,
+            "bucket_ingest_disabled",
+            "Bucket ingestion is disabled",
+        )
+    })?;
+    let options = UploadOptions {
+        client: Some(client),
+        ..UploadOptions::from_config(&config)
+    };
+
+    let mut results = Vec::new();
+    let mut retry = false;
+    for record in notification.records {
+        if !record.event_name.contains("ObjectCreated") {
+            continue;
+        }
+        let key = decode_key(&record.s3.object.key);
+        let bytes = match fetch(&config, endpoint, &record.s3.bucket.name, &key).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                retry |= e.status().is_server_error();
+                results.push(failed(&key, &e));
+                continue;
+            }
+        };
+
+        let upload = Upload {
+            bytes,
+            file_name: key.rsplit('/').next().map(str::to_string),
+            details: NewImage {
+                tags: tags_of(&key),
+                ..NewImage::default()
+            },
+            metadata: Vec::new(),
+            auto_tags: false,
+            follow_ups: Vec::new(),
+            client: options.client,
+        };
+        let ingested = crate::ingest(
+            &pool,
+            &pipeline,
+            &jobs,
+            quarantine.as_deref(),
+            &options,
+            upload,
+        )
+        .await;
+        results.push(match ingested {
+            Ok(Ingested::Stored(image)) => {
+                serde_json::json!({"key": key, "status": "stored", "id": image.key})
+            }
+            Ok(Ingested::Duplicate(image)) => {
+                serde_json::json!({"key": key, "status": "duplicate", "id": image.key})
+            }
+            Err(e) => {
+                retry |= e.status().is_server_error();
+                failed(&key, &e)
+            }
+        });
+    }
+
+    let status = if retry {
+        StatusCode::BAD_GATEWAY
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(serde_json::json!({ "results": results }))).into_response())
+}
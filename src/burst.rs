@@ -0,0 +1,266 @@
+//! Animated previews of burst sequences.
+//!
+//! With `BURST_PREVIEWS=true`, committing an upload session (see `upload_sessions`) looks
+//! for runs of its images uploaded at most `BURST_MAX_GAP_SECS` apart. Each run of two or
+//! more is a burst: its images are listed in `burst_frames` under the first one, which gets
+//! `burst_size`, and a job makes `images/<id>_burst.webp`, a small animated WebP cycling
+//! through them that the first image's gallery card shows instead of its thumbnail.
+//!
+//! Whoever may see the first image may see the preview, so when anonymous visitors may see
+//! it, frames they may not see are left out. Deleting a frame or changing who may see it
+//! deletes the preview, which is made again on the next request.
+
+use std::time::Duration;
+
+use axum::{
+    http::header,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use image::{ExtendedColorType, RgbaImage};
+use sqlx::SqlitePool;
+
+use crate::{
+    auth::Viewer,
+    error::AppError,
+    expiry,
+    ids::{self, ImageId},
+    jobs::{JobKind, JobQueue},
+    processor, served_files,
+};
+
+/// Frames are scaled down to fit within this size.
+pub const PREVIEW_SIZE: u32 = 160;
+/// How long each frame shows.
+const FRAME_MILLIS: u32 = 250;
+/// Longer runs are cut short, keeping the preview small.
+const MAX_FRAMES: usize = 12;
+
+pub fn path(id: i64) -> String {
+    format!("images/{id}_burst.webp")
+}
+
+/// Finds the bursts among `ids`, records them and queues their previews.
+pub async fn detect(
+    pool: &SqlitePool,
+    jobs: &JobQueue,
+    ids: &[i64],
+    max_gap: Duration,
+) -> anyhow::Result<()> {
+    let mut uploads: Vec<(i64, i64)> = Vec::new();
+    for &id in ids {
+        let created_at: Option<i64> =
+            sqlx::query_scalar("SELECT created_at FROM images WHERE id = ?")
+                .bind(id)
+                .fetch_optional(pool)
+                .await?;
+        uploads.extend(created_at.map(|created_at| (created_at, id)));
+    }
+    uploads.sort_unstable();
+
+    let mut runs: Vec<Vec<i64>> = Vec::new();
+    let mut last = None;
+    for (created_at, id) in uploads {
+        match (last, runs.last_mut()) {
+            (Some(previous), Some(run)) if created_at - previous <= max_gap.as_secs() as i64 => {
+                run.push(id)
+            }
+            _ => runs.push(vec![id]),
+        }
+        last = Some(created_at);
+    }
+
+    for mut run in runs.into_iter().filter(|run| run.len() >= 2) {
+        run.truncate(MAX_FRAMES);
+        let burst_id = run[0];
+        let mut tx = pool.begin().await?;
+        for (position, id) in run.iter().enumerate() {
+            sqlx::query(
+                "INSERT OR REPLACE INTO burst_frames (burst_id, image_id, position) \
+                 VALUES (?, ?, ?)",
+            )
+            .bind(burst_id)
+            .bind(id)
+            .bind(position as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query("UPDATE images SET burst_size = ? WHERE id = ?")
+            .bind(run.len() as i64)
+            .bind(burst_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        jobs.enqueue(JobKind::BurstPreview, burst_id).await?;
+    }
+
+    Ok(())
+}
+
+/// Deletes the previews of the bursts image `id` is a frame of, to be made again as it is
+/// now. Called when it's deleted or who may see it changes.
+pub async fn invalidate(pool: &SqlitePool, id: i64) -> anyhow::Result<()> {
+    let bursts: Vec<i64> =
+        sqlx::query_scalar("SELECT burst_id FROM burst_frames WHERE image_id = ?")
+            .bind(id)
+            .fetch_all(pool)
+            .await?;
+    for burst_id in bursts {
+        let path = path(burst_id);
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        sqlx::query("DELETE FROM served_files WHERE path = ?")
+            .bind(&path)
+            .execute(pool)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Makes the preview of the burst starting with image `burst_id`, returning the WebP, or
+/// `None` if fewer than two of its frames are left to show.
+pub async fn generate(pool: &SqlitePool, burst_id: i64) -> anyhow::Result<Option<Vec<u8>>> {
+    // All frames if only those who see everything may see the first, else those anyone may.
+    let frames: Vec<i64> = sqlx::query_scalar(&format!(
+        "SELECT image_id FROM burst_frames JOIN images ON images.id = image_id \
+         WHERE burst_id = ?1 AND session_id IS NULL AND (image_id = ?1 OR {anonymous} \
+             OR NOT EXISTS (SELECT 1 FROM images WHERE id = ?1 AND {anonymous})) \
+         ORDER BY position",
+        anonymous = Viewer::Anonymous.visible()
+    ))
+    .bind(burst_id)
+    .fetch_all(pool)
+    .await?;
+
+    let webp = processor::watched(move || {
+        // Images deleted since are left out.
+        let frames = frames
+            .into_iter()
+            .filter_map(|id| {
+                let source = processor::raster_source(id).ok()?;
+                processor::get()
+                    .frame(std::path::Path::new(&source), PREVIEW_SIZE)
+                    .ok()
+            })
+            .collect::<Vec<_>>();
+        if frames.len() < 2 {
+            return Ok(None);
+        }
+        animate(&frames).map(Some)
+    })
+    .await?;
+    let Some(webp) = webp else {
+        return Ok(None);
+    };
+
+    let path = path(burst_id);
+    let partial = format!("{path}.{}.tmp", rand::random::<u32>());
+    tokio::fs::write(&partial, &webp).await?;
+    tokio::fs::rename(&partial, &path).await?;
+    served_files::record(pool, burst_id, &path).await?;
+
+    Ok(Some(webp))
+}
+
+/// The chunks of a RIFF file: each four-character code with its data.
+fn chunks(riff: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut rest = riff.get(12..).unwrap_or_default();
+    std::iter::from_fn(move || {
+        let fourcc = rest.get(..4)?;
+        let size = u32::from_le_bytes(rest.get(4..8)?.try_into().ok()?) as usize;
+        let data = rest.get(8..8 + size)?;
+        // Chunks are padded to an even size.
+        rest = rest.get(8 + size + size % 2..).unwrap_or_default();
+        Some((fourcc, data))
+    })
+}
+
+fn push_chunk(out: &mut Vec<u8>, fourcc: &[u8], data: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+fn push_u24(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes()[..3]);
+}
+
+/// An animated WebP looping through `frames`, each centered on a canvas fitting them all.
+/// The `image` crate only writes still images, so each frame is encoded on its own and its
+/// bitstream put into an `ANMF` chunk of an animated container.
+fn animate(frames: &[RgbaImage]) -> anyhow::Result<Vec<u8>> {
+    let width = frames.iter().map(RgbaImage::width).max().unwrap_or(1);
+    let height = frames.iter().map(RgbaImage::height).max().unwrap_or(1);
+
+    let mut body = b"WEBP".to_vec();
+    let mut vp8x = vec![0b0001_0010, 0, 0, 0]; // Alpha and animation.
+    push_u24(&mut vp8x, width - 1);
+    push_u24(&mut vp8x, height - 1);
+    push_chunk(&mut body, b"VP8X", &vp8x);
+    // Transparent background, looping forever.
+    push_chunk(&mut body, b"ANIM", &[0, 0, 0, 0, 0, 0]);
+
+    for frame in frames {
+        let mut still = Vec::new();
+        image::codecs::webp::WebPEncoder::new_lossless(&mut still).encode(
+            frame.as_raw(),
+            frame.width(),
+            frame.height(),
+            ExtendedColorType::Rgba8,
+        )?;
+        let (_, bitstream) = chunks(&still)
+            .find(|(fourcc, _)| *fourcc == b"VP8L")
+            .ok_or_else(|| anyhow::anyhow!("Encoded frame has no VP8L chunk"))?;
+
+        // Offsets are stored halved, so they must be even.
+        let mut anmf = Vec::new();
+        push_u24(&mut anmf, (width - frame.width()) / 4);
+        push_u24(&mut anmf, (height - frame.height()) / 4);
+        push_u24(&mut anmf, frame.width() - 1);
+        push_u24(&mut anmf, frame.height() - 1);
+        push_u24(&mut anmf, FRAME_MILLIS);
+        // No blending, and cleared before the next frame, so smaller frames don't show the
+        // previous one around them.
+        anmf.push(0b0000_0011);
+        push_chunk(&mut anmf, b"VP8L", bitstream);
+        push_chunk(&mut body, b"ANMF", &anmf);
+    }
+
+    let mut webp = b"RIFF".to_vec();
+    webp.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    webp.extend_from_slice(&body);
+
+    Ok(webp)
+}
+
+/// `GET /image/:id/burst.webp`: the preview of the burst image `id` starts, made on demand
+/// if its job hasn't run yet.
+pub async fn preview(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    ImageId { id, key }: ImageId,
+) -> Result<Response, AppError> {
+    let burst = crate::fetch_visible_image(&pool, viewer, id).await?;
+    if burst.is_none_or(|image| image.burst_size.is_none()) {
+        return Ok(expiry::not_found(&pool, id, &key).await);
+    }
+
+    let webp = match tokio::fs::read(path(id)).await {
+        Ok(webp) => webp,
+        Err(_) => generate(&pool, id)
+            .await?
+            .ok_or_else(|| ids::not_found(&key))?,
+    };
+    let info = served_files::lookup(&pool, id, &path(id)).await?;
+
+    let mut response = ([(header::CONTENT_TYPE, "image/webp")], webp).into_response();
+    info.apply(response.headers_mut());
+    Ok(response)
+}
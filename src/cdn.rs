@@ -0,0 +1,252 @@
+//! Content-addressed URLs for serving images through a CDN.
+//!
+//! Originals and thumbnails are published as `/i/<sha256>.jpg` and `/t/<sha256>.jpg`. The
+//! bytes behind such a URL never change, so they're served as `immutable` and the CDN can
+//! keep them forever. The id-based `/image/:id` and `/thumb/:id` routes redirect there.
+//!
+//! Knowing the hash isn't enough to see an image: viewers get 404 for images they couldn't
+//! see by id, and images anonymous visitors can't see are served `private, no-store` so no
+//! shared cache keeps them.
+//!
+//! The images behind hashes looked up lately are remembered, so files already published
+//! keep being served while the database is unavailable.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+use axum::{
+    extract::Path,
+    http::{header, HeaderValue, Method, StatusCode},
+    response::Response,
+    Extension,
+};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+
+use crate::{
+    auth::Viewer,
+    config::SharedConfig,
+    decode_failures,
+    error::{self, AppError},
+    ranking, served_files, throttle,
+    thumbnail::{self, Crop, Priority},
+};
+
+static PUBLIC_BASE_URL: OnceLock<String> = OnceLock::new();
+
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+/// For images only some viewers may see.
+const NO_STORE: &str = "private, no-store";
+/// Hashes remembered at most; all are forgotten when it's reached.
+const KNOWN_MAX: usize = 10_000;
+
+/// Images of hashes looked up lately, with whether anonymous visitors may see them.
+static KNOWN: OnceLock<Mutex<HashMap<String, (i64, bool)>>> = OnceLock::new();
+
+fn known() -> &'static Mutex<HashMap<String, (i64, bool)>> {
+    KNOWN.get_or_init(Default::default)
+}
+
+/// Forgets the hashes of deleted image `id`.
+pub fn forget(id: i64) {
+    known().lock().unwrap().retain(|_, (known, _)| *known != id);
+}
+
+/// Sets the origin emitted URLs start with (e.g. `https://cdn.example.com`). Without it they
+/// are relative to this service.
+pub fn init(public_base_url: Option<&str>) {
+    let base = public_base_url.unwrap_or_default().trim_end_matches('/');
+    let _ = PUBLIC_BASE_URL.set(base.to_string());
+}
+
+fn base() -> &'static str {
+    PUBLIC_BASE_URL
+        .get()
+        .map(String::as_str)
+        .unwrap_or_default()
+}
+
+/// Hex SHA-256 of an original upload.
+pub fn content_hash(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+pub fn original_url(hash: &str, strip_metadata: bool) -> String {
+    let suffix = if strip_metadata { "-stripped" } else { "" };
+    format!("{}/i/{hash}{suffix}.jpg", base())
+}
+
+pub fn thumbnail_url(hash: &str, crop: Crop) -> String {
+    let suffix = match crop {
+        Crop::Fit => "",
+        Crop::Center => "-center",
+        Crop::Smart => "-smart",
+    };
+    format!("{}/t/{hash}{suffix}.jpg", base())
+}
+
+/// Splits `<hash>[-variant].jpg` from a hashed URL.
+fn parse_file(file: &str) -> Option<(&str, &str)> {
+    let name = file.strip_suffix(".jpg")?;
+    let (hash, variant) = name.split_once('-').unwrap_or((name, ""));
+    let valid = hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit());
+
+    valid.then_some((hash, variant))
+}
+
+/// The image with original `hash` that `viewer` may see, and whether anonymous visitors may
+/// see it too, from memory if the database is unavailable. Of several images with the same
+/// original, one anyone may see goes first.
+async fn image_id(
+    pool: &SqlitePool,
+    viewer: Viewer,
+    hash: &str,
+) -> Result<Option<(i64, bool)>, AppError> {
+    let hash = hash.to_ascii_lowercase();
+    let found: Result<Option<(i64, bool)>, _> = sqlx::query_as(&format!(
+        "SELECT id, {} AS public FROM images WHERE content_hash = ? AND {} \
+         ORDER BY public DESC, id LIMIT 1",
+        Viewer::Anonymous.visible(),
+        viewer.visible()
+    ))
+    .bind(&hash)
+    .fetch_optional(pool)
+    .await;
+
+    match found {
+        Ok(Some(image)) => {
+            let mut known = known().lock().unwrap();
+            if known.len() >= KNOWN_MAX {
+                known.clear();
+            }
+            known.insert(hash, image);
+            Ok(Some(image))
+        }
+        Ok(None) => Ok(None),
+        Err(e) if error::unavailable(&e) => match known().lock().unwrap().get(&hash) {
+            Some(&(id, public)) if public || viewer == Viewer::Authenticated => {
+                Ok(Some((id, public)))
+            }
+            _ => Err(e.into()),
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn not_found(file: &str) -> AppError {
+    AppError::new(
+        StatusCode::NOT_FOUND,
+        "file_not_found",
+        format!("No file {file}"),
+    )
+    .with_param("file", file)
+}
+
+/// `GET /i/:file`
+pub async fn original(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(viewer): Extension<Viewer>,
+    Path(file): Path<String>,
+    method: Method,
+) -> Result<Response, AppError> {
+    let Some((hash, variant)) = parse_file(&file) else {
+        return Err(not_found(&file));
+    };
+    let Some((id, public)) = image_id(&pool, viewer, hash).await? else {
+        return Err(not_found(&file));
+    };
+
+    let strip_metadata = match variant {
+        "" => false,
+        "stripped" => true,
+        _ => return Err(not_found(&file)),
+    };
+    let served = match method {
+        // While the database is unavailable, the file has to say.
+        Method::HEAD => match crate::fetch_image_record(&pool, id).await {
+            Ok(Some(image)) => crate::served_original_head(&pool, &image, strip_metadata).await,
+            _ => crate::served_original(&pool, id, strip_metadata).await,
+        },
+        _ => crate::served_original(&pool, id, strip_metadata).await,
+    };
+    let (path, content_type, info) =
+        served.map_err(|e| crate::missing_file(e, || not_found(&file)))?;
+
+    let bytes_per_sec = throttle::rate(&config.load(), viewer);
+
+    let response = match method {
+        Method::HEAD => crate::head_image(&path, hash, content_type, info.as_ref()).await,
+        _ => crate::stream_image(&path, hash, content_type, info.as_ref(), bytes_per_sec).await,
+    }
+    .map_err(|e| crate::missing_file(e, || not_found(&file)))?;
+    if method != Method::HEAD {
+        ranking::count_download(&pool, id).await;
+    }
+    Ok(cache_control(response, public))
+}
+
+/// `GET /t/:file`
+pub async fn thumbnail(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    Path(file): Path<String>,
+    method: Method,
+) -> Result<Response, AppError> {
+    let Some((hash, variant)) = parse_file(&file) else {
+        return Err(not_found(&file));
+    };
+    let crop = match variant {
+        "" => Crop::Fit,
+        "center" => Crop::Center,
+        "smart" => Crop::Smart,
+        _ => return Err(not_found(&file)),
+    };
+    let Some((id, public)) = image_id(&pool, viewer, hash).await? else {
+        return Err(not_found(&file));
+    };
+
+    let path = thumbnail::thumbnail_path(id, crop);
+    if !std::path::Path::new(&path).exists() {
+        if let Err(e) = thumbnail::make_thumbnail(id, crop, Priority::Interactive).await {
+            decode_failures::record(&pool, id, &e).await;
+            return Err(e.into());
+        }
+        served_files::record(&pool, id, &path).await?;
+    }
+    let info = served_files::lookup(&pool, id, &path)
+        .await
+        .map_err(|e| crate::missing_file(e, || not_found(&file)))?;
+    let response = match method {
+        Method::HEAD => crate::head_image(&path, hash, "image/jpeg", Some(&info)).await,
+        _ => crate::stream_image(&path, hash, "image/jpeg", Some(&info), None).await,
+    }
+    .map_err(|e| crate::missing_file(e, || not_found(&file)))?;
+    Ok(cache_control(response, public))
+}
+
+/// Lets caches keep the file for good if anyone may see it, else no cache at all.
+fn cache_control(mut response: Response, public: bool) -> Response {
+    let value = if public { IMMUTABLE } else { NO_STORE };
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static(value));
+    response
+}
+
+/// Hashes the original of an image uploaded before `content_hash` existed.
+pub async fn hash_original(pool: &SqlitePool, id: i64) -> anyhow::Result<()> {
+    let bytes = tokio::fs::read(format!("images/{id}.jpg")).await?;
+    sqlx::query("UPDATE images SET content_hash = ? WHERE id = ?")
+        .bind(content_hash(&bytes))
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+//! Verifying client-supplied checksums of uploaded bytes.
+
+use axum::http::{HeaderMap, StatusCode};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::error::AppError;
+
+pub const SHA256_HEADER: &str = "x-checksum-sha256";
+
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Checksums the client expects the uploaded image to have, from the `Content-MD5` /
+/// `X-Checksum-SHA256` headers or the `content_md5` / `checksum_sha256` form fields. They're
+/// always of the image itself: in a form, `Content-MD5` would be of the whole request body,
+/// so there only the field is taken (see [`ExpectedChecksums::from_form_headers`]).
+#[derive(Default)]
+pub struct ExpectedChecksums {
+    md5: Option<String>,
+    sha256: Option<String>,
+}
+
+impl ExpectedChecksums {
+    /// The checksums of a request whose body is the image.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.trim().to_string())
+        };
+
+        Self {
+            md5: header("content-md5"),
+            sha256: header(SHA256_HEADER),
+        }
+    }
+
+    /// The checksums of a form upload's headers, refusing `Content-MD5`: it's the MD5 of the
+    /// whole form, which the image's can't be checked against.
+    pub fn from_form_headers(headers: &HeaderMap) -> Result<Self, AppError> {
+        if headers.contains_key("content-md5") {
+            return Err(AppError::bad_request(
+                "content_md5_on_form",
+                "Content-MD5 is of the whole form; send the image's MD5 as the content_md5 field",
+            ));
+        }
+
+        Ok(Self::from_headers(headers))
+    }
+
+    /// Takes a multipart field if it carries a checksum, returning whether it did.
+    pub fn set_field(&mut self, name: &str, value: String) -> bool {
+        match name {
+            "content_md5" => self.md5 = Some(value.trim().to_string()),
+            "checksum_sha256" => self.sha256 = Some(value.trim().to_string()),
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn verify(&self, bytes: &[u8]) -> Result<(), AppError> {
+        if let Some(expected) = &self.md5 {
+            check("MD5", expected, &Md5::digest(bytes))?;
+        }
+        if let Some(expected) = &self.sha256 {
+            check("SHA-256", expected, &Sha256::digest(bytes))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Compares a digest against its expected value, given either as hex or base64.
+fn check(algorithm: &str, expected: &str, actual: &[u8]) -> Result<(), AppError> {
+    let decoded = if expected.len() == actual.len() * 2 {
+        (0..expected.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(expected.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+    } else {
+        STANDARD.decode(expected).ok()
+    };
+
+    let Some(decoded) = decoded.filter(|decoded| decoded.len() == actual.len()) else {
+        return Err(AppError::bad_request(
+            "invalid_checksum",
+            format!("Malformed {algorithm} checksum: {expected}"),
+        )
+        .with_param("algorithm", algorithm)
+        .with_param("expected", expected));
+    };
+
+    if decoded != actual {
+        let (expected, actual) = (to_hex(&decoded), to_hex(actual));
+        return Err(AppError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "checksum_mismatch",
+            format!("{algorithm} checksum mismatch: expected {expected}, received bytes hash to {actual}"),
+        )
+        .with_param("algorithm", algorithm)
+        .with_param("expected", expected)
+        .with_param("actual", actual));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,128 @@
+//! The address a request comes from, as rate limits, the admin allowlist, the access log and
+//! the audit log see it.
+//!
+//! Behind a reverse proxy every request comes from the proxy, so for peers in
+//! `TRUSTED_PROXIES` the client is taken from the proxies' headers instead: `Forwarded`
+//! (RFC 7239, its `for=` parameters) when there is one, otherwise `X-Forwarded-For`. Walking
+//! the hops from the nearest, the client is the first address that isn't itself a trusted
+//! proxy. A hop without a usable address (`for=unknown`, an obfuscated `for=_hidden`, or
+//! garbage) ends the walk at the last address known. The headers are ignored from anyone
+//! else, who could otherwise claim any address.
+//!
+//! Requests over a Unix socket have no address, so they come from [`UNIX_PEER`]. It isn't
+//! loopback: an allowlist of `127.0.0.1` doesn't let socket traffic in, and without `unix` in
+//! `TRUSTED_PROXIES` every socket client is one anonymous client to the rate limits.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::OnceLock,
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+    response::{IntoResponse, Response},
+};
+
+use crate::allowlist::{self, Cidr};
+
+/// The peer of requests over a Unix socket. No TCP peer can have the unspecified address, so
+/// `ADMIN_ALLOWLIST` and `TRUSTED_PROXIES` only match it when they list `unix` (or a range
+/// such as `0.0.0.0/0` that takes in every address).
+pub const UNIX_PEER: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+static TRUSTED_PROXIES: OnceLock<Vec<Cidr>> = OnceLock::new();
+
+pub fn init(trusted_proxies: &[Cidr]) {
+    let _ = TRUSTED_PROXIES.set(trusted_proxies.to_vec());
+}
+
+fn trusted_proxies() -> &'static [Cidr] {
+    TRUSTED_PROXIES.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// The client's address, see the module docs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        Ok(ClientIp(resolve(
+            trusted_proxies(),
+            peer.ip(),
+            &parts.headers,
+        )))
+    }
+}
+
+/// The address of the client behind `peer`.
+pub fn resolve(trusted_proxies: &[Cidr], peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    if !allowlist::listed(trusted_proxies, peer) {
+        return peer;
+    }
+    let hops = if headers.contains_key("forwarded") {
+        forwarded(headers)
+    } else {
+        x_forwarded_for(headers)
+    };
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        let Some(ip) = hop else {
+            break;
+        };
+        client = ip;
+        if !allowlist::listed(trusted_proxies, ip) {
+            break;
+        }
+    }
+    client
+}
+
+fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+}
+
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    header_values(headers, "x-forwarded-for")
+        .map(|hop| hop.trim().parse().ok())
+        .collect()
+}
+
+/// The `for=` address of each element of `Forwarded`, like `for=192.0.2.60;proto=https` or
+/// `for="[2001:db8::17]:4711"`.
+fn forwarded(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    header_values(headers, "forwarded")
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, node)| node_address(node.trim().trim_matches('"')))
+        })
+        .collect()
+}
+
+/// The address of a node: `192.0.2.60`, `192.0.2.60:80`, `[2001:db8::17]` or
+/// `[2001:db8::17]:4711`.
+fn node_address(node: &str) -> Option<IpAddr> {
+    if let Some(bracketed) = node.strip_prefix('[') {
+        let (address, _port) = bracketed.split_once(']')?;
+        return address.parse().ok();
+    }
+    let address = match node.split_once(':') {
+        Some((address, port)) if !port.contains(':') => address,
+        _ => node,
+    };
+    address.parse().ok()
+}
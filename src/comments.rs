@@ -0,0 +1,261 @@
+//! Comments on images, shown on the details page.
+//!
+//! Anyone who can see an image can comment on it, anonymous visitors of a public gallery
+//! included. Each client IP may post `COMMENT_RATE_LIMIT` comments a minute, whoever it is,
+//! and comments are plain text, escaped when rendered. Moderators delete comments through
+//! `DELETE /api/v1/admin/comments/:id` with the API token.
+
+use std::net::IpAddr;
+
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    Extension, Form, Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{
+    api, audit,
+    auth::{self, RateLimiter, Viewer},
+    client_ip::ClientIp,
+    config::SharedConfig,
+    error::AppError,
+    expiry, fragments, i18n,
+    ids::{self, ImageId, ImageKey},
+};
+
+/// Longest comment body, in characters.
+const MAX_BODY_CHARS: usize = 2000;
+/// Longest author name, in characters.
+const MAX_AUTHOR_CHARS: usize = 80;
+
+/// Comments posted per client IP in the current window, apart from the readers' limiter.
+#[derive(Clone, Default)]
+pub struct CommentLimiter(RateLimiter);
+
+impl CommentLimiter {
+    /// Counts a comment from `ip`, returning the 429 response if it's one too many.
+    fn refuse(&self, config: &SharedConfig, ip: IpAddr) -> Option<Response> {
+        let limit = config.load().comment_rate_limit;
+        self.0.check(ip, limit).err().map(auth::rate_limited)
+    }
+}
+
+#[derive(FromRow, Serialize)]
+pub struct Comment {
+    pub id: i64,
+    /// As URLs show it, see `ids`.
+    #[sqlx(skip)]
+    pub image_id: ImageKey,
+    /// Empty for anonymous comments.
+    pub author: String,
+    pub body: String,
+    pub created_at: i64,
+}
+
+#[derive(Deserialize)]
+pub struct NewComment {
+    #[serde(default)]
+    author: String,
+    body: String,
+}
+
+/// Errors unless `image` exists and `viewer` may see it.
+async fn check_visible(pool: &SqlitePool, viewer: Viewer, image: &ImageId) -> Result<(), AppError> {
+    if crate::fetch_visible_image(pool, viewer, image.id)
+        .await?
+        .is_none()
+    {
+        expiry::gone(pool, image.id).await?;
+        return Err(ids::not_found(&image.key));
+    }
+
+    Ok(())
+}
+
+pub async fn list(pool: &SqlitePool, image: &ImageId) -> sqlx::Result<Vec<Comment>> {
+    let mut comments: Vec<Comment> = sqlx::query_as(
+        "SELECT id, author, body, created_at FROM comments \
+         WHERE image_id = ? ORDER BY id",
+    )
+    .bind(image.id)
+    .fetch_all(pool)
+    .await?;
+    for comment in &mut comments {
+        comment.image_id = image.key.clone();
+    }
+
+    Ok(comments)
+}
+
+/// Validates and stores a comment on `image`.
+async fn post(
+    pool: &SqlitePool,
+    config: &SharedConfig,
+    viewer: Viewer,
+    image: &ImageId,
+    comment: NewComment,
+) -> Result<Comment, AppError> {
+    if config.load().read_only {
+        return Err(AppError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "read_only",
+            "The service is read-only for now, try again later",
+        ));
+    }
+    check_visible(pool, viewer, image).await?;
+
+    let author = comment.author.trim();
+    let body = comment.body.trim();
+    if body.is_empty() {
+        return Err(AppError::bad_request(
+            "comment_empty",
+            "The comment is empty",
+        ));
+    }
+    if body.chars().count() > MAX_BODY_CHARS {
+        return Err(AppError::bad_request(
+            "comment_too_long",
+            format!("Comments may be at most {MAX_BODY_CHARS} characters"),
+        )
+        .with_param("max", MAX_BODY_CHARS.to_string()));
+    }
+    if author.chars().count() > MAX_AUTHOR_CHARS {
+        return Err(AppError::bad_request(
+            "author_too_long",
+            format!("Names may be at most {MAX_AUTHOR_CHARS} characters"),
+        )
+        .with_param("max", MAX_AUTHOR_CHARS.to_string()));
+    }
+
+    let mut comment: Comment = sqlx::query_as(
+        "INSERT INTO comments (image_id, author, body, created_at) \
+         VALUES (?, ?, ?, CAST(strftime('%s', 'now') AS INTEGER)) \
+         RETURNING id, author, body, created_at",
+    )
+    .bind(image.id)
+    .bind(author)
+    .bind(body)
+    .fetch_one(pool)
+    .await?;
+    comment.image_id = image.key.clone();
+
+    Ok(comment)
+}
+
+/// `GET /api/v1/image/:id/comments`, oldest first.
+pub async fn list_comments(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    image: ImageId,
+) -> Result<Json<Vec<Comment>>, AppError> {
+    check_visible(&pool, viewer, &image).await?;
+
+    Ok(Json(list(&pool, &image).await?))
+}
+
+/// `POST /api/v1/image/:id/comments` with `{"author": "...", "body": "..."}`; `author` may be
+/// left out to comment anonymously.
+pub async fn post_comment(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(limiter): Extension<CommentLimiter>,
+    Extension(viewer): Extension<Viewer>,
+    ClientIp(client): ClientIp,
+    image: ImageId,
+    Json(comment): Json<NewComment>,
+) -> Result<Response, AppError> {
+    if let Some(rate_limited) = limiter.refuse(&config, client) {
+        return Ok(rate_limited);
+    }
+    let comment = post(&pool, &config, viewer, &image, comment).await?;
+
+    Ok((StatusCode::CREATED, Json(comment)).into_response())
+}
+
+/// `DELETE /api/v1/admin/comments/:id`
+pub async fn delete_comment(
+    Extension(pool): Extension<SqlitePool>,
+    ClientIp(client): ClientIp,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    let deleted: Option<(i64, String)> =
+        sqlx::query_as("DELETE FROM comments WHERE id = ? RETURNING image_id, author")
+            .bind(id)
+            .fetch_optional(&pool)
+            .await?;
+    let Some((image_id, author)) = deleted else {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            "comment_not_found",
+            format!("No comment with id {id}"),
+        )
+        .with_param("id", id.to_string()));
+    };
+    audit::record(
+        &pool,
+        Some(client),
+        "comment_deleted",
+        Some(image_id),
+        &format!("id={id} author={author}"),
+    )
+    .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The comments section of `image`'s details page, with the form to add one.
+pub async fn render(pool: &SqlitePool, image: &ImageId) -> Result<String, AppError> {
+    let comments = list(pool, image).await?;
+
+    let items: String = if comments.is_empty() {
+        format!(
+            "<li class=\"no-comments\">{}</li>",
+            i18n::t("comments.none", &[])
+        )
+    } else {
+        comments
+            .iter()
+            .map(|comment| {
+                let author = if comment.author.is_empty() {
+                    i18n::t("comments.anonymous", &[])
+                } else {
+                    comment.author.clone()
+                };
+                let body = fragments::escape_html(&comment.body).replace('\n', "<br>");
+                let at = api::rfc3339(comment.created_at);
+                format!(
+                    "<li id=\"comment-{}\"><strong>{}</strong> <time datetime=\"{at}\">{at}</time><p>{body}</p></li>",
+                    comment.id,
+                    fragments::escape_html(&author),
+                )
+            })
+            .collect()
+    };
+
+    // Comments go in last, so text in them is never taken for a placeholder.
+    Ok(fragments::read_template("comments.html")
+        .await
+        .replace("{id}", &image.key.to_string())
+        .replace("{comments}", &items))
+}
+
+/// `POST /fragments/comments/:id`: posts the form's comment and returns the updated section.
+pub async fn comment_form(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(limiter): Extension<CommentLimiter>,
+    Extension(viewer): Extension<Viewer>,
+    ClientIp(client): ClientIp,
+    image: ImageId,
+    Form(comment): Form<NewComment>,
+) -> Result<Response, AppError> {
+    if let Some(rate_limited) = limiter.refuse(&config, client) {
+        return Ok(rate_limited);
+    }
+    post(&pool, &config, viewer, &image, comment).await?;
+
+    Ok(Html(render(&pool, &image).await?).into_response())
+}
@@ -0,0 +1,482 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+
+use crate::{
+    allowlist::{self, Cidr},
+    fallback::Fallback,
+    ids::IdScheme,
+    ranking::RelevanceWeights,
+    retention,
+    tags::TagLimits,
+    thumbnail::Sharpen,
+};
+
+/// Service configuration, read from the environment (and `.env` via dotenv).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub database_url: String,
+    /// Times to try opening the database at startup before giving up.
+    pub database_connect_attempts: u32,
+    /// Copy of the database to restore from when the local file is missing.
+    pub database_replica: Option<PathBuf>,
+    /// Copy of the data directory to restore corrupted originals from, see `integrity`.
+    pub backup_dir: Option<PathBuf>,
+    /// How often a batch of originals is verified against their hashes.
+    pub integrity_check_interval: Duration,
+    /// Originals verified each time; 0 turns verification off.
+    pub integrity_check_batch: u32,
+    /// Images deleted once they're old enough, see `retention`.
+    pub retention_rules: Vec<retention::Rule>,
+    /// Only log what the retention rules would delete.
+    pub retention_dry_run: bool,
+    /// Interval between applications of the retention rules.
+    pub retention_check_interval: Duration,
+    /// Shell command restoring the database (e.g. `litestream restore`) when the local file is
+    /// missing and there's no usable replica; `{path}` is replaced with the database path.
+    pub database_restore_command: Option<String>,
+    /// How often to checkpoint the WAL into the main database file.
+    pub wal_checkpoint_interval: Option<Duration>,
+    /// How often to refresh the query planner's statistics with `ANALYZE`.
+    pub analyze_interval: Option<Duration>,
+    /// clamd to scan uploads with; scanning is off when unset.
+    pub clamd_address: Option<String>,
+    /// Locale for clients whose `Accept-Language` we have no catalog for.
+    pub default_locale: String,
+    /// Strip EXIF/XMP from originals served by `/image/:id` unless the request overrides it.
+    pub strip_metadata: bool,
+    /// Origin of the CDN in front of the service, used in emitted image URLs.
+    pub public_base_url: Option<String>,
+    /// Origin of the service's own pages, for links that leave it, like receipts' QR codes.
+    pub site_url: Option<String>,
+    /// Bearer token clients must send; the service is open when unset.
+    pub api_token: Option<String>,
+    /// Networks the admin routes may be used from; any when unset. See `allowlist`.
+    pub admin_allowlist: Option<Vec<Cidr>>,
+    /// Reverse proxies whose `X-Forwarded-For` is believed.
+    pub trusted_proxies: Vec<Cidr>,
+    /// Let anonymous visitors use the read-only routes.
+    pub public_gallery: bool,
+    /// Requests per minute allowed from each anonymous visitor's IP.
+    pub anonymous_rate_limit: u32,
+    /// Comments per minute allowed from each client IP.
+    pub comment_rate_limit: u32,
+    /// Most thumbnails generated at once; background jobs get half of them.
+    pub max_parallel_thumbnails: usize,
+    /// Previous originals kept per image when it's replaced.
+    pub image_versions_kept: u32,
+    /// Replaces the default `Content-Security-Policy` of HTML pages; `{nonce}` is replaced
+    /// with the request's script nonce.
+    pub content_security_policy: Option<String>,
+    /// `frame-ancestors` of the default policy, i.e. who may embed the pages.
+    pub frame_ancestors: String,
+    pub referrer_policy: String,
+    /// How long rejected uploads are kept under `quarantine/`; quarantine is off when unset.
+    pub quarantine_ttl: Option<Duration>,
+    /// Bytes of each rejected upload kept in quarantine.
+    pub quarantine_max_bytes: u32,
+    /// Backend for resizing and conversion, `image` or `vips`.
+    pub image_processor: String,
+    /// Unsharp mask applied to thumbnails after scaling them down; off when unset.
+    pub sharpen: Option<Sharpen>,
+    /// JPEG quality (1-100) of thumbnails; the processor's own default when unset.
+    pub thumbnail_quality: Option<u8>,
+    /// Images with more pixels are refused before decoding them.
+    pub max_decode_pixels: u64,
+    /// Decoding, resizing and converting an image is given up on after this long.
+    pub decode_timeout: Duration,
+    /// Check CSRF tokens on the routes the HTML forms post to.
+    pub csrf_protection: bool,
+    /// Key CSRF tokens are signed with; random per process when unset.
+    pub csrf_secret: Option<String>,
+    /// Reject every change with 503, e.g. during maintenance.
+    pub read_only: bool,
+    /// How often the `settings` table is checked for changes.
+    pub settings_poll_interval: Duration,
+    /// Names of the upload pipeline's stages, in order.
+    pub upload_stages: Vec<String>,
+    /// TCP addresses and `unix:` socket paths to serve on.
+    pub listen: Vec<String>,
+    /// PEM certificate chain and private key to serve HTTPS with.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Address of a plain HTTP listener redirecting to HTTPS.
+    pub tls_redirect_listen: Option<String>,
+    /// Finish backfilling data for existing images before serving, instead of alongside.
+    pub blocking_backfills: bool,
+    /// Read templates from `src/pages` and reload them when edited, see `templates`.
+    pub dev_templates: bool,
+    /// Refuse public images (uploads and edits) without alt text.
+    pub require_alt_text: bool,
+    /// New uploads wait for a moderator's approval before anonymous visitors see them.
+    pub moderate_uploads: bool,
+    /// Most tags an image may have and longest a tag may be, see `tags::normalize`.
+    pub tag_limits: TagLimits,
+    /// Search filters for a tag also match the tags below it, see `tags`.
+    pub tag_search_descendants: bool,
+    /// How `sort=relevance` searches weigh text matches, recency and downloads, see `ranking`.
+    pub relevance_weights: RelevanceWeights,
+    /// Make animated previews of burst sequences in committed upload sessions, see `burst`.
+    pub burst_previews: bool,
+    /// Longest time between two uploads of the same burst.
+    pub burst_max_gap: Duration,
+    /// Key `/proxy` URLs are signed with; the image proxy is off when unset.
+    pub proxy_secret: Option<String>,
+    /// Key upload policies are signed with; they're off when unset. See `upload_policy`.
+    pub upload_policy_secret: Option<String>,
+    /// S3 or MinIO endpoint objects announced to `POST /ingest/s3` are fetched from; bucket
+    /// ingestion is off when unset. See `bucket_ingest`.
+    pub s3_endpoint: Option<String>,
+    pub s3_region: String,
+    /// Credentials the objects are fetched with; anonymously when unset.
+    pub s3_access_key: Option<String>,
+    pub s3_secret_key: Option<String>,
+    /// Largest object ingested from the bucket.
+    pub s3_max_bytes: u64,
+    /// Bucket at `s3_endpoint` that `POST /admin/storage/migrate?to=s3` copies originals to.
+    pub storage_s3_bucket: Option<String>,
+    /// Bucket at `s3_endpoint` that presigned uploads go to; they're off when unset. See
+    /// `presigned_upload`.
+    pub upload_s3_bucket: Option<String>,
+    /// How long a presigned upload URL works.
+    pub presigned_upload_ttl: Duration,
+    /// Largest remote image `/proxy` downloads.
+    pub proxy_max_bytes: u32,
+    /// How long `/proxy` results are cached.
+    pub proxy_cache_ttl: Duration,
+    /// Uploads are refused while less disk space than this is free.
+    pub min_free_disk_bytes: u64,
+    /// Derived files beyond this many bytes are evicted, least recently served first; 0 for
+    /// no limit. See `served_files`.
+    pub derived_files_max_bytes: u64,
+    /// OTLP/HTTP collector to export traces to; tracing is off when unset.
+    pub otlp_endpoint: Option<String>,
+    /// `service.name` of the exported spans.
+    pub otel_service_name: String,
+    /// How long an upload session may stay open before it's aborted.
+    pub upload_session_ttl: Duration,
+    /// Requests taking longer are logged; `None` logs none.
+    pub slow_request: Option<Duration>,
+    /// Database queries taking longer are logged; `None` logs none.
+    pub slow_query: Option<Duration>,
+    /// File requests are logged to in the combined log format, see `access_log`; none when
+    /// unset.
+    pub access_log: Option<PathBuf>,
+    /// The access log is rotated when it would grow past this; 0 for never.
+    pub access_log_max_bytes: u64,
+    /// The access log is also rotated when the day changes.
+    pub access_log_daily: bool,
+    /// Rotated access logs kept; 0 keeps them all.
+    pub access_log_keep: u32,
+    /// Bytes a second each download of an original may take; 0 for no limit.
+    pub download_rate_limit: u64,
+    /// The same for anonymous visitors of a public gallery.
+    pub anonymous_download_rate_limit: u64,
+    /// What image ids in URLs and responses look like, see `ids`.
+    pub image_id_scheme: IdScheme,
+    /// What requests for missing images get when they don't say, see `fallback`.
+    pub missing_image_fallback: Fallback,
+    /// How long listings and searches are cached, see `response_cache`; `None` for not at
+    /// all.
+    pub response_cache: Option<Duration>,
+}
+
+/// The live configuration, replaced when the `settings` table changes. Handlers take a
+/// snapshot with `config.load()` at the start of each request.
+pub type SharedConfig = Arc<ArcSwap<Config>>;
+
+impl Config {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::with_overrides(&HashMap::new())
+    }
+
+    /// Reads the configuration with `overrides` (from the `settings` table) taking precedence
+    /// over the environment.
+    pub fn with_overrides(overrides: &HashMap<String, String>) -> anyhow::Result<Self> {
+        let vars = Vars { overrides };
+        let download_rate_limit = vars.bytes("DOWNLOAD_RATE_LIMIT", 0)?;
+        let config = Self {
+            database_url: vars
+                .optional("DATABASE_URL")?
+                .ok_or_else(|| anyhow::anyhow!("DATABASE_URL must be set"))?,
+            database_connect_attempts: vars.number("DATABASE_CONNECT_ATTEMPTS", 5)?.max(1),
+            database_replica: vars.optional("DATABASE_REPLICA")?.map(PathBuf::from),
+            backup_dir: vars.optional("BACKUP_DIR")?.map(PathBuf::from),
+            integrity_check_interval: vars
+                .secs("INTEGRITY_CHECK_SECS")?
+                .unwrap_or(Duration::from_secs(60 * 60)),
+            integrity_check_batch: vars.number("INTEGRITY_CHECK_BATCH", 100)?,
+            retention_rules: retention::parse(
+                &vars.optional("RETENTION_RULES")?.unwrap_or_default(),
+            )?,
+            retention_dry_run: vars.flag("RETENTION_DRY_RUN", false)?,
+            retention_check_interval: vars
+                .secs("RETENTION_CHECK_SECS")?
+                .unwrap_or(Duration::from_secs(60 * 60)),
+            database_restore_command: vars.optional("DATABASE_RESTORE_COMMAND")?,
+            wal_checkpoint_interval: vars.secs("WAL_CHECKPOINT_SECS")?,
+            analyze_interval: match vars.optional("ANALYZE_SECS")? {
+                Some(_) => vars.secs("ANALYZE_SECS")?,
+                None => Some(Duration::from_secs(24 * 60 * 60)),
+            },
+            clamd_address: vars.optional("CLAMD_ADDRESS")?,
+            default_locale: vars
+                .optional("DEFAULT_LOCALE")?
+                .unwrap_or_else(|| "en".to_string()),
+            strip_metadata: vars.flag("STRIP_METADATA", false)?,
+            public_base_url: vars.optional("PUBLIC_BASE_URL")?,
+            site_url: vars.optional("SITE_URL")?,
+            api_token: vars.optional("API_TOKEN")?,
+            admin_allowlist: vars
+                .optional("ADMIN_ALLOWLIST")?
+                .map(|ranges| allowlist::parse("ADMIN_ALLOWLIST", &ranges))
+                .transpose()?,
+            trusted_proxies: allowlist::parse(
+                "TRUSTED_PROXIES",
+                &vars.raw("TRUSTED_PROXIES")?.unwrap_or_default(),
+            )?,
+            public_gallery: vars.flag("PUBLIC_GALLERY", false)?,
+            anonymous_rate_limit: vars.number("ANONYMOUS_RATE_LIMIT", 60)?,
+            comment_rate_limit: vars.number("COMMENT_RATE_LIMIT", 5)?,
+            max_parallel_thumbnails: vars
+                .number("MAX_PARALLEL_THUMBNAILS", default_parallelism())?
+                as usize,
+            image_versions_kept: vars.number("IMAGE_VERSIONS_KEPT", 10)?,
+            content_security_policy: vars.optional("CONTENT_SECURITY_POLICY")?,
+            frame_ancestors: vars
+                .optional("FRAME_ANCESTORS")?
+                .unwrap_or_else(|| "'none'".to_string()),
+            referrer_policy: vars
+                .optional("REFERRER_POLICY")?
+                .unwrap_or_else(|| "strict-origin-when-cross-origin".to_string()),
+            quarantine_ttl: vars.secs("QUARANTINE_TTL_SECS")?,
+            quarantine_max_bytes: vars.number("QUARANTINE_MAX_BYTES", 10 * 1024 * 1024)?,
+            image_processor: vars
+                .optional("IMAGE_PROCESSOR")?
+                .unwrap_or_else(|| "image".to_string()),
+            sharpen: match vars.decimal("SHARPEN_AMOUNT")? {
+                Some(amount) => Some(Sharpen {
+                    amount,
+                    radius: vars.decimal("SHARPEN_RADIUS")?.unwrap_or(0.5),
+                    threshold: vars.number("SHARPEN_THRESHOLD", 2)?.min(255) as u8,
+                }),
+                None => None,
+            },
+            thumbnail_quality: match vars.optional("THUMBNAIL_QUALITY")? {
+                Some(value) => Some(
+                    value
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|quality| (1..=100).contains(quality))
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("THUMBNAIL_QUALITY must be 1 to 100, got {value:?}")
+                        })?,
+                ),
+                None => None,
+            },
+            max_decode_pixels: vars.number("MAX_DECODE_PIXELS", 100_000_000)?.into(),
+            decode_timeout: vars
+                .secs("DECODE_TIMEOUT_SECS")?
+                .unwrap_or(Duration::from_secs(30)),
+            csrf_protection: vars.flag("CSRF_PROTECTION", true)?,
+            csrf_secret: vars.optional("CSRF_SECRET")?,
+            read_only: vars.flag("READ_ONLY", false)?,
+            settings_poll_interval: vars
+                .secs("SETTINGS_POLL_SECS")?
+                .unwrap_or(Duration::from_secs(10)),
+            upload_stages: vars
+                .optional("UPLOAD_STAGES")?
+                .unwrap_or_else(|| "auto_tag,scan,validate".to_string())
+                .split(',')
+                .map(|stage| stage.trim().to_string())
+                .filter(|stage| !stage.is_empty())
+                .collect(),
+            listen: vars
+                .optional("LISTEN")?
+                .unwrap_or_else(|| "0.0.0.0:3000".to_string())
+                .split(',')
+                .map(|address| address.trim().to_string())
+                .filter(|address| !address.is_empty())
+                .collect(),
+            tls_cert: vars.optional("TLS_CERT")?.map(PathBuf::from),
+            tls_key: vars.optional("TLS_KEY")?.map(PathBuf::from),
+            tls_redirect_listen: vars.optional("TLS_REDIRECT_LISTEN")?,
+            blocking_backfills: vars.flag("BLOCKING_BACKFILLS", false)?,
+            dev_templates: vars.flag("DEV_TEMPLATES", false)?,
+            require_alt_text: vars.flag("REQUIRE_ALT_TEXT", false)?,
+            moderate_uploads: vars.flag("MODERATE_UPLOADS", false)?,
+            tag_limits: TagLimits {
+                max_count: vars.number("MAX_TAGS", 50)?,
+                max_length: vars.number("MAX_TAG_LENGTH", 64)?,
+            },
+            tag_search_descendants: vars.flag("TAG_SEARCH_DESCENDANTS", true)?,
+            relevance_weights: RelevanceWeights {
+                text: vars.weight("SEARCH_TEXT_WEIGHT", 1.0)?,
+                recency: vars.weight("SEARCH_RECENCY_WEIGHT", 0.3)?,
+                popularity: vars.weight("SEARCH_POPULARITY_WEIGHT", 0.3)?,
+                recency_half_life_days: vars.number("SEARCH_RECENCY_HALF_LIFE_DAYS", 30)?,
+            },
+            burst_previews: vars.flag("BURST_PREVIEWS", false)?,
+            burst_max_gap: Duration::from_secs(vars.number("BURST_MAX_GAP_SECS", 3)?.into()),
+            proxy_secret: vars.optional("PROXY_SECRET")?,
+            upload_policy_secret: vars.optional("UPLOAD_POLICY_SECRET")?,
+            s3_endpoint: vars.optional("S3_ENDPOINT")?,
+            s3_region: vars
+                .optional("S3_REGION")?
+                .unwrap_or_else(|| "us-east-1".to_string()),
+            s3_access_key: vars.optional("S3_ACCESS_KEY")?,
+            s3_secret_key: vars.optional("S3_SECRET_KEY")?,
+            s3_max_bytes: vars.bytes("S3_MAX_BYTES", 64 * 1024 * 1024)?,
+            storage_s3_bucket: vars.optional("STORAGE_S3_BUCKET")?,
+            upload_s3_bucket: vars.optional("UPLOAD_S3_BUCKET")?,
+            presigned_upload_ttl: vars
+                .secs("PRESIGNED_UPLOAD_TTL_SECS")?
+                .unwrap_or(Duration::from_secs(15 * 60)),
+            proxy_max_bytes: vars.number("PROXY_MAX_BYTES", 10 * 1024 * 1024)?,
+            proxy_cache_ttl: vars
+                .secs("PROXY_CACHE_TTL_SECS")?
+                .unwrap_or(Duration::from_secs(24 * 60 * 60)),
+            min_free_disk_bytes: vars.bytes("MIN_FREE_DISK_BYTES", 256 * 1024 * 1024)?,
+            derived_files_max_bytes: vars.bytes("DERIVED_FILES_MAX_BYTES", 0)?,
+            otlp_endpoint: vars.optional("OTEL_EXPORTER_OTLP_ENDPOINT")?,
+            otel_service_name: vars
+                .optional("OTEL_SERVICE_NAME")?
+                .unwrap_or_else(|| "thumbnail_service".to_string()),
+            upload_session_ttl: vars
+                .secs("UPLOAD_SESSION_TTL_SECS")?
+                .unwrap_or(Duration::from_secs(60 * 60)),
+            slow_request: vars.millis("SLOW_REQUEST_MS", 1000)?,
+            slow_query: vars.millis("SLOW_QUERY_MS", 100)?,
+            access_log: vars.optional("ACCESS_LOG")?.map(PathBuf::from),
+            access_log_max_bytes: vars.bytes("ACCESS_LOG_MAX_BYTES", 104_857_600)?,
+            access_log_daily: vars.flag("ACCESS_LOG_DAILY", true)?,
+            access_log_keep: vars.number("ACCESS_LOG_KEEP", 14)?,
+            download_rate_limit,
+            anonymous_download_rate_limit: vars
+                .bytes("ANONYMOUS_DOWNLOAD_RATE_LIMIT", download_rate_limit)?,
+            image_id_scheme: match vars.optional("IMAGE_ID_SCHEME")? {
+                Some(scheme) => IdScheme::parse(&scheme)?,
+                None => IdScheme::Sequential,
+            },
+            missing_image_fallback: match vars.optional("MISSING_IMAGE_FALLBACK")? {
+                Some(fallback) => Fallback::parse(&fallback)?,
+                None => Fallback::Problem,
+            },
+            response_cache: vars.millis("RESPONSE_CACHE_MS", 2000)?,
+        };
+
+        if config.public_gallery && config.api_token.is_none() {
+            anyhow::bail!("PUBLIC_GALLERY needs API_TOKEN set, or writes would be public too");
+        }
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            anyhow::bail!("TLS_CERT and TLS_KEY must be set together");
+        }
+        if config.tls_redirect_listen.is_some() && config.tls_cert.is_none() {
+            anyhow::bail!("TLS_REDIRECT_LISTEN needs TLS_CERT and TLS_KEY set");
+        }
+
+        Ok(config)
+    }
+}
+
+/// Where settings are read from: overrides first, then the environment.
+struct Vars<'a> {
+    overrides: &'a HashMap<String, String>,
+}
+
+impl Vars<'_> {
+    fn raw(&self, name: &str) -> anyhow::Result<Option<String>> {
+        if let Some(value) = self.overrides.get(name) {
+            return Ok(Some(value.clone()));
+        }
+        match std::env::var(name) {
+            Ok(value) => Ok(Some(value)),
+            Err(std::env::VarError::NotPresent) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn flag(&self, name: &str, default: bool) -> anyhow::Result<bool> {
+        match self.raw(name)? {
+            Some(value) => match value.to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => Ok(true),
+                "0" | "false" | "no" | "off" => Ok(false),
+                _ => anyhow::bail!("{name} must be a boolean, got {value:?}"),
+            },
+            None => Ok(default),
+        }
+    }
+
+    fn optional(&self, name: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.raw(name)?.filter(|value| !value.is_empty()))
+    }
+
+    fn number(&self, name: &str, default: u32) -> anyhow::Result<u32> {
+        match self.optional(name)? {
+            Some(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("{name} must be a number, got {value:?}")),
+            None => Ok(default),
+        }
+    }
+
+    /// A size in bytes, which may be more than `number` takes.
+    fn bytes(&self, name: &str, default: u64) -> anyhow::Result<u64> {
+        match self.optional(name)? {
+            Some(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("{name} must be a number of bytes, got {value:?}")),
+            None => Ok(default),
+        }
+    }
+
+    /// A positive decimal number, e.g. `0.8`.
+    fn decimal(&self, name: &str) -> anyhow::Result<Option<f32>> {
+        let Some(value) = self.optional(name)? else {
+            return Ok(None);
+        };
+        match value.parse::<f32>() {
+            Ok(number) if number.is_finite() && number > 0.0 => Ok(Some(number)),
+            _ => anyhow::bail!("{name} must be a positive number, got {value:?}"),
+        }
+    }
+
+    /// A weight, which may be `0` to leave something out.
+    fn weight(&self, name: &str, default: f32) -> anyhow::Result<f32> {
+        let Some(value) = self.optional(name)? else {
+            return Ok(default);
+        };
+        match value.parse::<f32>() {
+            Ok(number) if number.is_finite() && number >= 0.0 => Ok(number),
+            _ => anyhow::bail!("{name} must be a number of at least 0, got {value:?}"),
+        }
+    }
+
+    /// A duration in whole seconds, where unset or `0` means disabled.
+    fn secs(&self, name: &str) -> anyhow::Result<Option<Duration>> {
+        let Some(value) = self.optional(name)? else {
+            return Ok(None);
+        };
+        let secs: u64 = value
+            .parse()
+            .map_err(|_| anyhow::anyhow!("{name} must be a number of seconds, got {value:?}"))?;
+
+        Ok((secs > 0).then(|| Duration::from_secs(secs)))
+    }
+
+    /// A duration in milliseconds, where `0` means disabled.
+    fn millis(&self, name: &str, default: u64) -> anyhow::Result<Option<Duration>> {
+        let millis = match self.optional(name)? {
+            Some(value) => value.parse().map_err(|_| {
+                anyhow::anyhow!("{name} must be a number of milliseconds, got {value:?}")
+            })?,
+            None => default,
+        };
+
+        Ok((millis > 0).then(|| Duration::from_millis(millis)))
+    }
+}
+
+fn default_parallelism() -> u32 {
+    std::thread::available_parallelism().map_or(1, |n| n.get() as u32)
+}
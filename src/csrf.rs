@@ -0,0 +1,116 @@
+//! CSRF protection for the HTML forms, using signed double-submit tokens.
+//!
+//! The home page sets a `csrf_token` cookie and puts the same token in its `hx-headers`, so
+//! htmx sends it back as `X-CSRF-Token`. [`verify`] accepts a form post only when header and
+//! cookie match and carry our signature; another site can make the browser send the cookie
+//! but can't read it to fill in the header. Requests with an `Authorization` header are API
+//! clients, not browsers submitting forms, and skip the check.
+
+use std::sync::OnceLock;
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::{config::SharedConfig, error::AppError};
+
+const COOKIE: &str = "csrf_token";
+const HEADER: &str = "x-csrf-token";
+
+static SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Sets the key tokens are signed with. Without `CSRF_SECRET` a random key is used, so pages
+/// loaded before a restart need reloading before their forms work again.
+pub fn init(secret: Option<&str>) {
+    let secret = match secret {
+        Some(secret) => secret.as_bytes().to_vec(),
+        None => rand::random::<[u8; 32]>().to_vec(),
+    };
+    let _ = SECRET.set(secret);
+}
+
+fn signature(nonce: &str) -> Hmac<Sha256> {
+    let secret = SECRET.get_or_init(|| rand::random::<[u8; 32]>().to_vec());
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(nonce.as_bytes());
+    mac
+}
+
+fn new_token() -> String {
+    let nonce = URL_SAFE_NO_PAD.encode(rand::random::<[u8; 16]>());
+    let signature = URL_SAFE_NO_PAD.encode(signature(&nonce).finalize().into_bytes());
+    format!("{nonce}.{signature}")
+}
+
+fn is_signed(token: &str) -> bool {
+    let Some((nonce, signature_part)) = token.split_once('.') else {
+        return false;
+    };
+    let Ok(given) = URL_SAFE_NO_PAD.decode(signature_part) else {
+        return false;
+    };
+    signature(nonce).verify_slice(&given).is_ok()
+}
+
+fn cookie_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE)
+        .map(|(_, token)| token)
+}
+
+/// The token for a page with forms, reusing the browser's cookie when it's still valid so
+/// pages open in other tabs keep working, and the `Set-Cookie` value to send with it.
+pub fn issue(headers: &HeaderMap) -> (String, HeaderValue) {
+    let token = match cookie_token(headers) {
+        Some(token) if is_signed(token) => token.to_string(),
+        _ => new_token(),
+    };
+    let cookie = format!("{COOKIE}={token}; Path=/; SameSite=Strict; HttpOnly");
+    let cookie = HeaderValue::from_str(&cookie).expect("tokens are base64url");
+
+    (token, cookie)
+}
+
+/// Middleware for the routes the HTML forms post to.
+pub async fn verify(
+    Extension(config): Extension<SharedConfig>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = config.load();
+    if !config.csrf_protection || request.headers().contains_key(header::AUTHORIZATION) {
+        return next.run(request).await;
+    }
+
+    let headers = request.headers();
+    let submitted = headers.get(HEADER).and_then(|value| value.to_str().ok());
+    let valid = match (submitted, cookie_token(headers)) {
+        // Comparing digests keeps the comparison time independent of where the tokens differ.
+        (Some(submitted), Some(cookie)) => {
+            Sha256::digest(submitted) == Sha256::digest(cookie) && is_signed(cookie)
+        }
+        _ => false,
+    };
+    if !valid {
+        return AppError::new(
+            StatusCode::FORBIDDEN,
+            "csrf_failed",
+            "Missing or invalid CSRF token, reload the page and try again",
+        )
+        .into_response();
+    }
+
+    next.run(request).await
+}
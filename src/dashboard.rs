@@ -0,0 +1,325 @@
+//! `GET /admin/dashboard`, a page of live statistics: uploads per hour over the last day,
+//! storage over the last two weeks, the job queue, the most used tags and recent errors.
+//!
+//! Each panel is rendered here, in the page and then every [`REFRESH`] in the events of
+//! `GET /admin/dashboard/events`, a server-sent event stream htmx's `sse` extension swaps
+//! into the page: one event per panel, named after it.
+
+use std::{convert::Infallible, time::Duration};
+
+use axum::{
+    http::HeaderMap,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension,
+};
+use futures::StreamExt;
+use sqlx::SqlitePool;
+
+use crate::{
+    api, auth,
+    config::{Config, SharedConfig},
+    error::{self, AppError},
+    fragments::escape_html,
+    i18n, layout, stats,
+};
+
+const EVENTS_PATH: &str = "/admin/dashboard/events";
+/// How often the panels are sent again.
+const REFRESH: Duration = Duration::from_secs(5);
+const HOURS: i64 = 24;
+const DAYS: i64 = 14;
+const TOP_TAGS: i64 = 10;
+const ERRORS: usize = 10;
+
+struct Snapshot {
+    /// When it was taken.
+    at: i64,
+    stats: stats::Stats,
+    /// Uploads in each of the last [`HOURS`] hours, oldest first.
+    uploads_per_hour: Vec<i64>,
+    /// Bytes of originals stored at the end of each of the last [`DAYS`] days, counting the
+    /// images still stored.
+    storage_per_day: Vec<i64>,
+    queued: i64,
+    running: i64,
+    failed: i64,
+    top_tags: Vec<(String, i64)>,
+    /// Server errors and failed jobs, newest first: when, what and the message.
+    errors: Vec<(i64, String, String)>,
+}
+
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// `sum` over the images uploaded in each of `count` `period`-long buckets from `start` on.
+async fn per_period(
+    pool: &SqlitePool,
+    sum: &str,
+    start: i64,
+    period: i64,
+    count: i64,
+) -> Result<Vec<i64>, AppError> {
+    let rows: Vec<(i64, i64)> = sqlx::query_as(&format!(
+        "SELECT (created_at - ?1) / ?2 AS bucket, {sum} FROM images \
+         WHERE created_at >= ?1 GROUP BY bucket"
+    ))
+    .bind(start)
+    .bind(period)
+    .fetch_all(pool)
+    .await?;
+
+    let mut buckets = vec![0; count as usize];
+    for (bucket, value) in rows {
+        if let Some(slot) = buckets.get_mut(bucket as usize) {
+            *slot += value;
+        }
+    }
+    Ok(buckets)
+}
+
+async fn collect(pool: &SqlitePool, config: &Config) -> Result<Snapshot, AppError> {
+    let now = now();
+    let hour_start = (now / 3600 - (HOURS - 1)) * 3600;
+    let uploads_per_hour = per_period(pool, "COUNT(*)", hour_start, 3600, HOURS).await?;
+
+    let day_start = (now / 86_400 - (DAYS - 1)) * 86_400;
+    let before: i64 =
+        sqlx::query_scalar("SELECT COALESCE(SUM(byte_size), 0) FROM images WHERE created_at < ?")
+            .bind(day_start)
+            .fetch_one(pool)
+            .await?;
+    let storage_per_day = per_period(pool, "COALESCE(SUM(byte_size), 0)", day_start, 86_400, DAYS)
+        .await?
+        .into_iter()
+        .scan(before, |total, added| {
+            *total += added;
+            Some(*total)
+        })
+        .collect();
+
+    let states: Vec<(String, i64)> =
+        sqlx::query_as("SELECT state, COUNT(*) FROM jobs GROUP BY state")
+            .fetch_all(pool)
+            .await?;
+    let state = |name: &str| {
+        states
+            .iter()
+            .find(|(state, _)| state == name)
+            .map_or(0, |(_, count)| *count)
+    };
+
+    let top_tags = sqlx::query_as(
+        "SELECT tag, COUNT(*) AS uses FROM image_tags GROUP BY tag ORDER BY uses DESC, tag LIMIT ?",
+    )
+    .bind(TOP_TAGS)
+    .fetch_all(pool)
+    .await?;
+
+    let failed_jobs: Vec<(i64, String, i64, Option<String>)> = sqlx::query_as(
+        "SELECT updated_at, kind, image_id, error FROM jobs WHERE state = 'failed' \
+         ORDER BY updated_at DESC LIMIT ?",
+    )
+    .bind(ERRORS as i64)
+    .fetch_all(pool)
+    .await?;
+    let mut errors: Vec<(i64, String, String)> = error::recent()
+        .into_iter()
+        .map(|error| (error.at, error.code.to_string(), error.detail))
+        .chain(failed_jobs.into_iter().map(|(at, kind, image_id, error)| {
+            (
+                at,
+                format!("{kind} job, image {image_id}"),
+                error.unwrap_or_default(),
+            )
+        }))
+        .collect();
+    errors.sort_by_key(|(at, _, _)| std::cmp::Reverse(*at));
+    errors.truncate(ERRORS);
+
+    Ok(Snapshot {
+        at: now,
+        stats: stats::collect(pool, config).await?,
+        uploads_per_hour,
+        storage_per_day,
+        queued: state("queued"),
+        running: state("running"),
+        failed: state("failed"),
+        top_tags,
+        errors,
+    })
+}
+
+/// `bytes` for people, like `1.5 MB`.
+fn human_bytes(bytes: i64) -> String {
+    if bytes < 1000 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1000.0;
+    for unit in ["kB", "MB", "GB"] {
+        if value < 1000.0 {
+            return format!("{value:.1} {unit}");
+        }
+        value /= 1000.0;
+    }
+    format!("{value:.1} TB")
+}
+
+/// A bar chart of `values`, each labelled by `label`.
+fn chart(values: &[i64], label: impl Fn(usize, i64) -> String) -> String {
+    let max = values.iter().copied().max().unwrap_or_default().max(1);
+    let bars: String = values
+        .iter()
+        .enumerate()
+        .map(|(i, &value)| {
+            let label = escape_html(&label(i, value));
+            format!(
+                r#"<li style="height: {}%" title="{label}"><span>{label}</span></li>"#,
+                value * 100 / max
+            )
+        })
+        .collect();
+    format!(r#"<ol class="chart">{bars}</ol>"#)
+}
+
+/// Each panel's name and contents.
+fn panels(snapshot: &Snapshot) -> [(&'static str, String); 5] {
+    let hour_start = snapshot.at / 3600 - (HOURS - 1);
+    let total: i64 = snapshot.uploads_per_hour.iter().sum();
+    let uploads = format!(
+        "<p>{}</p>{}",
+        i18n::t("dashboard.uploads_total", &[("count", &total.to_string())]),
+        chart(&snapshot.uploads_per_hour, |i, count| {
+            let hour = &api::rfc3339((hour_start + i as i64) * 3600)[11..16];
+            format!("{hour}: {count}")
+        }),
+    );
+
+    let day_start = snapshot.at / 86_400 - (DAYS - 1);
+    let disk = match &snapshot.stats.disk {
+        Some(disk) => i18n::t(
+            "dashboard.disk",
+            &[
+                ("used", &human_bytes(disk.usage.used_bytes as i64)),
+                ("total", &human_bytes(disk.usage.total_bytes as i64)),
+                ("free", &human_bytes(disk.usage.free_bytes as i64)),
+            ],
+        ),
+        None => i18n::t("dashboard.disk_unknown", &[]),
+    };
+    let storage = format!(
+        "<p>{}</p>{}",
+        escape_html(&disk),
+        chart(&snapshot.storage_per_day, |i, bytes| {
+            let date = &api::rfc3339((day_start + i as i64) * 86_400)[..10];
+            format!("{date}: {}", human_bytes(bytes))
+        }),
+    );
+
+    let queue = format!(
+        "<dl><dt>{}</dt><dd>{}</dd><dt>{}</dt><dd>{}</dd><dt>{}</dt><dd>{}</dd></dl>",
+        i18n::t("dashboard.queued", &[]),
+        snapshot.queued,
+        i18n::t("dashboard.running", &[]),
+        snapshot.running,
+        i18n::t("dashboard.failed", &[]),
+        snapshot.failed,
+    );
+
+    let tags = if snapshot.top_tags.is_empty() {
+        format!("<p>{}</p>", i18n::t("dashboard.no_tags", &[]))
+    } else {
+        let items: String = snapshot
+            .top_tags
+            .iter()
+            .map(|(tag, count)| format!("<li>{} ({count})</li>", escape_html(tag)))
+            .collect();
+        format!("<ol>{items}</ol>")
+    };
+
+    let errors = if snapshot.errors.is_empty() {
+        format!("<p>{}</p>", i18n::t("dashboard.no_errors", &[]))
+    } else {
+        let items: String = snapshot
+            .errors
+            .iter()
+            .map(|(at, what, detail)| {
+                format!(
+                    "<li><time>{}</time> <strong>{}</strong> {}</li>",
+                    api::rfc3339(*at),
+                    escape_html(what),
+                    escape_html(detail)
+                )
+            })
+            .collect();
+        format!("<ul>{items}</ul>")
+    };
+
+    [
+        ("uploads", uploads),
+        ("storage", storage),
+        ("queue", queue),
+        ("tags", tags),
+        ("errors", errors),
+    ]
+}
+
+/// `GET /admin/dashboard`
+pub async fn page(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let config = config.load();
+    let snapshot = collect(&pool, &config).await?;
+
+    let mut content = crate::fragments::read_template("dashboard.html").await;
+    for (name, html) in panels(&snapshot) {
+        content = content.replace(&format!("{{{name}}}"), &html);
+    }
+    let events_url = format!(
+        "{EVENTS_PATH}?token={}",
+        auth::stream_token(&config, EVENTS_PATH)
+    );
+    content = content.replace("{events_url}", &escape_html(&events_url));
+
+    Ok(layout::page(&headers, Some(&i18n::t("dashboard.title", &[])), &content).await)
+}
+
+/// `GET /admin/dashboard/events?token=`, with the token the page was rendered with, as the
+/// browser opens it without the API token; see `auth::stream_token`.
+pub async fn events(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+) -> Response {
+    let mut interval = tokio::time::interval(REFRESH);
+    // The page was just rendered with the current figures.
+    interval.reset();
+
+    let stream = futures::stream::unfold(interval, move |mut interval| {
+        let pool = pool.clone();
+        let config = config.clone();
+        async move {
+            interval.tick().await;
+            let events = match collect(&pool, &config.load()).await {
+                Ok(snapshot) => panels(&snapshot)
+                    .into_iter()
+                    .map(|(name, html)| Event::default().event(name).data(html))
+                    .collect(),
+                // Leave the panels as they were, hoping for better luck next time.
+                Err(_) => Vec::new(),
+            };
+            Some((futures::stream::iter(events), interval))
+        }
+    });
+
+    Sse::new(stream.flatten().map(Ok::<_, Infallible>))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
@@ -0,0 +1,57 @@
+//! Images that couldn't be processed because they declare more pixels than
+//! `MAX_DECODE_PIXELS` or take longer than `DECODE_TIMEOUT_SECS`, see `processor`.
+//!
+//! Whatever was being made of the image (its thumbnail, placeholder, palette...) records the
+//! failure here, one row per image and kind of failure, listed by
+//! `GET /api/v1/admin/decode-failures` so they can be looked into.
+
+use axum::{Extension, Json};
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{error::AppError, ids::ImageKey, processor::DecodeError};
+
+#[derive(FromRow, Serialize)]
+pub struct Failure {
+    /// As URLs show it, see `ids`.
+    #[sqlx(flatten)]
+    #[serde(rename = "image_id")]
+    image: ImageKey,
+    /// `image_too_large` or `decode_timeout`.
+    code: String,
+    detail: String,
+    failed_at: i64,
+}
+
+/// Records `error` against image `id` if it's a [`DecodeError`]; other errors are left to
+/// the caller.
+pub async fn record(pool: &SqlitePool, id: i64, error: &anyhow::Error) {
+    let Some(decode) = error.downcast_ref::<DecodeError>() else {
+        return;
+    };
+    let recorded = sqlx::query(
+        "INSERT OR REPLACE INTO decode_failures (image_id, code, detail, failed_at) \
+         VALUES (?, ?, ?, CAST(strftime('%s', 'now') AS INTEGER))",
+    )
+    .bind(id)
+    .bind(decode.code())
+    .bind(decode.to_string())
+    .execute(pool)
+    .await;
+    if let Err(e) = recorded {
+        eprintln!("Recording the decode failure of image {id} failed: {e}");
+    }
+}
+
+/// `GET /api/v1/admin/decode-failures`: images that failed to decode, newest first.
+pub async fn list(Extension(pool): Extension<SqlitePool>) -> Result<Json<Vec<Failure>>, AppError> {
+    Ok(Json(
+        sqlx::query_as(
+            "SELECT image_id AS id, public_id, code, detail, failed_at \
+             FROM decode_failures LEFT JOIN images ON images.id = image_id \
+             ORDER BY failed_at DESC, image_id DESC",
+        )
+        .fetch_all(&pool)
+        .await?,
+    ))
+}
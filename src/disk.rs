@@ -0,0 +1,126 @@
+//! Free space on the storage volume.
+//!
+//! An upload that runs out of disk halfway leaves partial files and records behind, so new
+//! uploads are refused with `507 Insufficient Storage` while less than `MIN_FREE_DISK_BYTES`
+//! is free. Everything else (thumbnails, edits, deletes) carries on, and deleting images is
+//! how to get uploads going again.
+
+use std::{
+    ffi::CString,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use axum::{
+    extract::Request,
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use serde::Serialize;
+
+use crate::{config::SharedConfig, error::AppError};
+
+/// Originals live here; the rest of the storage is expected on the same volume.
+const STORAGE_DIR: &str = "blobs";
+const MONITOR_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Uploads refused for lack of space since startup.
+pub static UPLOADS_REFUSED: AtomicU64 = AtomicU64::new(0);
+static LOW: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Clone, Copy)]
+pub struct DiskUsage {
+    pub total_bytes: u64,
+    /// Space available to the service, not counting what's reserved for root.
+    pub free_bytes: u64,
+    pub used_bytes: u64,
+}
+
+/// Usage of the volume holding `STORAGE_DIR` (or the working directory before it exists).
+pub fn usage() -> std::io::Result<DiskUsage> {
+    let dir = Path::new(STORAGE_DIR);
+    let dir = if dir.exists() { dir } else { Path::new(".") };
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+
+    // SAFETY: `path` is a valid C string and `stat` is only read after `statvfs` filled it in.
+    let stat = unsafe {
+        let mut stat = std::mem::zeroed::<libc::statvfs>();
+        if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat
+    };
+    let block = stat.f_frsize as u64;
+    let total_bytes = stat.f_blocks as u64 * block;
+    let free_bytes = stat.f_bavail as u64 * block;
+
+    Ok(DiskUsage {
+        total_bytes,
+        free_bytes,
+        used_bytes: total_bytes.saturating_sub(stat.f_bfree as u64 * block),
+    })
+}
+
+/// Middleware for the upload routes, refusing them while space is short.
+pub async fn require_space(
+    Extension(config): Extension<SharedConfig>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let min_free = config.load().min_free_disk_bytes;
+    match usage() {
+        Ok(usage) if usage.free_bytes < min_free => {
+            UPLOADS_REFUSED.fetch_add(1, Ordering::Relaxed);
+            AppError::new(
+                StatusCode::INSUFFICIENT_STORAGE,
+                "insufficient_storage",
+                format!(
+                    "Only {} bytes of disk space are left, uploads are refused below {min_free}",
+                    usage.free_bytes
+                ),
+            )
+            .with_param("free", usage.free_bytes.to_string())
+            .with_param("min", min_free.to_string())
+            .into_response()
+        }
+        Ok(_) => next.run(request).await,
+        // Not knowing is no reason to stop uploads.
+        Err(e) => {
+            eprintln!("Failed to check free disk space: {e}");
+            next.run(request).await
+        }
+    }
+}
+
+/// Logs when free space drops below `MIN_FREE_DISK_BYTES` and when it recovers.
+pub fn spawn_monitor(config: SharedConfig) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(MONITOR_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let min_free = config.load().min_free_disk_bytes;
+            let usage = match usage() {
+                Ok(usage) => usage,
+                Err(e) => {
+                    eprintln!("Failed to check free disk space: {e}");
+                    continue;
+                }
+            };
+            let low = usage.free_bytes < min_free;
+            if low != LOW.swap(low, Ordering::Relaxed) {
+                if low {
+                    eprintln!(
+                        "Only {} bytes of disk space left, refusing uploads until {min_free} are free",
+                        usage.free_bytes
+                    );
+                } else {
+                    println!("Disk space recovered, accepting uploads again");
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,333 @@
+//! Self-check of a deployment, run with `--doctor` or fetched from `GET /admin/doctor`.
+//!
+//! Each check reports `ok`, `warn` or `fail`. Failures mean the service can't work as
+//! configured (the `--doctor` run exits with status 1); warnings are worth a look but the
+//! service will run.
+
+use std::{collections::HashSet, path::Path, str::FromStr};
+
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use serde::Serialize;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    SqlitePool,
+};
+
+use crate::{
+    config::{Config, SharedConfig},
+    disk, templates,
+    thumbnail::{self, Crop},
+};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Serialize)]
+pub struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    status: Status,
+    checks: Vec<Check>,
+}
+
+impl Report {
+    fn new() -> Self {
+        Self {
+            status: Status::Ok,
+            checks: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, name: &'static str, status: Status, detail: impl Into<String>) {
+        self.status = self.status.max(status);
+        self.checks.push(Check {
+            name,
+            status,
+            detail: detail.into(),
+        });
+    }
+
+    /// Records `result`, turning an error into a failed check.
+    fn add_result(&mut self, name: &'static str, result: anyhow::Result<(Status, String)>) {
+        match result {
+            Ok((status, detail)) => self.add(name, status, detail),
+            Err(e) => self.add(name, Status::Fail, format!("{e:#}")),
+        }
+    }
+
+    fn print(&self) {
+        for check in &self.checks {
+            let status = match check.status {
+                Status::Ok => "ok",
+                Status::Warn => "WARN",
+                Status::Fail => "FAIL",
+            };
+            println!("{status:<4}  {:<10}  {}", check.name, check.detail);
+        }
+    }
+}
+
+/// Runs every check against a loaded configuration and an open database.
+pub async fn run(config: &Config, pool: &SqlitePool) -> Report {
+    let mut report = Report::new();
+
+    report.add_result("config", check_config(config));
+    report.add_result("schema", check_schema(pool).await);
+    report.add_result("storage", check_storage(config).await);
+    report.add_result("templates", check_templates(config).await);
+    report.add_result("orphans", check_orphans(pool).await);
+    report.add_result("thumbnails", check_thumbnails(pool).await);
+
+    report
+}
+
+fn check_config(config: &Config) -> anyhow::Result<(Status, String)> {
+    if config.api_token.is_none() {
+        return Ok((
+            Status::Warn,
+            "API_TOKEN is unset, so anyone can upload and change images".to_string(),
+        ));
+    }
+
+    Ok((Status::Ok, "Configuration is valid".to_string()))
+}
+
+async fn check_schema(pool: &SqlitePool) -> anyhow::Result<(Status, String)> {
+    let known: HashSet<i64> = sqlx::migrate!("./migrations")
+        .iter()
+        .map(|migration| migration.version)
+        .collect();
+
+    let has_table: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')",
+    )
+    .fetch_one(pool)
+    .await?;
+    let applied: HashSet<i64> = if has_table {
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = 1")
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    let unknown = applied.difference(&known).count();
+    if unknown > 0 {
+        return Ok((
+            Status::Fail,
+            format!("{unknown} applied migrations are unknown to this build, which is older than the database"),
+        ));
+    }
+    let pending = known.difference(&applied).count();
+    if pending > 0 {
+        return Ok((
+            Status::Warn,
+            format!("{pending} migrations will be applied at startup"),
+        ));
+    }
+
+    let latest = known.iter().max().copied().unwrap_or_default();
+    Ok((Status::Ok, format!("Up to date at version {latest}")))
+}
+
+/// Checks `dir` can be created and written to.
+async fn writable(dir: &Path) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    let probe = dir.join(format!(".doctor-{}", std::process::id()));
+    tokio::fs::write(&probe, b"").await?;
+    tokio::fs::remove_file(&probe).await?;
+
+    Ok(())
+}
+
+async fn check_storage(config: &Config) -> anyhow::Result<(Status, String)> {
+    let mut dirs = vec!["images", "blobs"];
+    if config.quarantine_ttl.is_some() {
+        dirs.push("quarantine");
+    }
+    if config.proxy_secret.is_some() {
+        dirs.push("proxy_cache");
+    }
+    for dir in &dirs {
+        if let Err(e) = writable(Path::new(dir)).await {
+            return Ok((Status::Fail, format!("Can't write to {dir}/: {e:#}")));
+        }
+    }
+
+    if let Ok(usage) = disk::usage() {
+        if usage.free_bytes < config.min_free_disk_bytes {
+            return Ok((
+                Status::Warn,
+                format!(
+                    "Only {} bytes free, below MIN_FREE_DISK_BYTES, so uploads are refused",
+                    usage.free_bytes
+                ),
+            ));
+        }
+    }
+
+    Ok((Status::Ok, format!("Writable: {}", dirs.join(", "))))
+}
+
+async fn check_templates(config: &Config) -> anyhow::Result<(Status, String)> {
+    let mut missing = Vec::new();
+    // Templates are built in, unless `DEV_TEMPLATES` reads them from disk.
+    let templates = templates::names().filter(|_| config.dev_templates);
+    for template in templates {
+        if tokio::fs::metadata(Path::new("src/pages").join(template))
+            .await
+            .is_err()
+        {
+            missing.push(format!("src/pages/{template}"));
+        }
+    }
+    let catalog = format!(
+        "src/locales/{}.txt",
+        config.default_locale.to_ascii_lowercase()
+    );
+    if tokio::fs::metadata(&catalog).await.is_err() {
+        missing.push(catalog);
+    }
+
+    if missing.is_empty() {
+        Ok((
+            Status::Ok,
+            "All templates and the default catalog exist".to_string(),
+        ))
+    } else {
+        Ok((Status::Fail, format!("Missing {}", missing.join(", "))))
+    }
+}
+
+async fn check_orphans(pool: &SqlitePool) -> anyhow::Result<(Status, String)> {
+    let ids: HashSet<i64> = sqlx::query_scalar("SELECT id FROM images")
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .collect();
+
+    let mut files = HashSet::new();
+    if let Ok(mut entries) = tokio::fs::read_dir("images").await {
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            if let Some(id) = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".jpg"))
+                .and_then(|id| id.parse::<i64>().ok())
+            {
+                files.insert(id);
+            }
+        }
+    }
+
+    let without_file = ids.difference(&files).count();
+    let without_record = files.difference(&ids).count();
+    let detail = format!(
+        "{without_file} images without an original, {without_record} originals without an image"
+    );
+    let status = if without_file + without_record > 0 {
+        Status::Warn
+    } else {
+        Status::Ok
+    };
+
+    Ok((status, detail))
+}
+
+async fn check_thumbnails(pool: &SqlitePool) -> anyhow::Result<(Status, String)> {
+    let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM images")
+        .fetch_all(pool)
+        .await?;
+    let missing = ids
+        .into_iter()
+        .filter(|&id| !Path::new(&thumbnail::thumbnail_path(id, Crop::Fit)).exists())
+        .count();
+
+    let (queued, failed): (i64, i64) = sqlx::query_as(
+        "SELECT COUNT(*) FILTER (WHERE state IN ('queued', 'running')), \
+                COUNT(*) FILTER (WHERE state = 'failed') \
+         FROM jobs",
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let detail = format!("{missing} missing, {queued} jobs queued, {failed} jobs failed");
+    let status = if failed > 0 { Status::Warn } else { Status::Ok };
+
+    Ok((status, detail))
+}
+
+/// The `--doctor` mode: checks the deployment without starting the server or touching the
+/// database beyond reading it, and returns whether everything passed.
+pub async fn run_cli() -> bool {
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            let mut report = Report::new();
+            report.add("config", Status::Fail, format!("{e:#}"));
+            report.print();
+            return false;
+        }
+    };
+
+    let options = match SqliteConnectOptions::from_str(&config.database_url) {
+        Ok(options) => options.create_if_missing(false).read_only(true),
+        Err(e) => {
+            let mut report = Report::new();
+            report.add(
+                "config",
+                Status::Fail,
+                format!("Invalid DATABASE_URL: {e:#}"),
+            );
+            report.print();
+            return false;
+        }
+    };
+    let pool = match SqlitePoolOptions::new().connect_with(options).await {
+        Ok(pool) => pool,
+        Err(e) => {
+            let mut report = Report::new();
+            report.add_result("config", check_config(&config));
+            report.add(
+                "schema",
+                Status::Fail,
+                format!("Can't open the database: {e:#}"),
+            );
+            report.print();
+            return false;
+        }
+    };
+
+    let report = run(&config, &pool).await;
+    report.print();
+
+    report.status != Status::Fail
+}
+
+/// `GET /admin/doctor`: the report as JSON, with status 503 when a check failed.
+pub async fn doctor(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+) -> impl IntoResponse {
+    let config = config.load();
+    let report = run(&config, &pool).await;
+    let status = if report.status == Status::Fail {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+
+    (status, Json(report))
+}
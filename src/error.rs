@@ -0,0 +1,195 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::{i18n, metadata, pins, processor};
+
+/// How many of the latest server errors [`recent`] keeps.
+const RECENT_ERRORS: usize = 20;
+
+static RECENT: Mutex<VecDeque<RecentError>> = Mutex::new(VecDeque::new());
+
+/// A server error, with what went wrong in full rather than the client's generic message.
+#[derive(Clone, Serialize)]
+pub struct RecentError {
+    pub at: i64,
+    pub code: &'static str,
+    pub detail: String,
+}
+
+/// The latest server errors since startup, newest first.
+pub fn recent() -> Vec<RecentError> {
+    let recent = RECENT.lock().unwrap();
+    recent.iter().rev().cloned().collect()
+}
+
+fn remember(code: &'static str, detail: String) {
+    let at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default();
+    let mut recent = RECENT.lock().unwrap();
+    if recent.len() == RECENT_ERRORS {
+        recent.pop_front();
+    }
+    recent.push_back(RecentError { at, code, detail });
+}
+
+/// An error returned to the client with a status and a machine-readable `code`.
+///
+/// `message` is the English text; if the client's locale has an `error.<code>` entry it's
+/// used instead, filled in from `params`. Responses are RFC 7807 `application/problem+json`
+/// documents with the message as `detail` and `code` as an extension member, along with any
+/// added with `with_extension`.
+#[derive(Debug)]
+pub struct AppError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    params: Vec<(&'static str, String)>,
+    // Boxed, as it would double the size of every `Result<_, AppError>`.
+    extensions: Box<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// RFC 7807 problem details. `type` is always `about:blank`, so `title` is the status's
+/// reason phrase and clients tell problems apart by `code`.
+#[derive(Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: &'a str,
+    code: &'a str,
+    #[serde(flatten)]
+    extensions: &'a serde_json::Map<String, serde_json::Value>,
+}
+
+impl AppError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            params: Vec::new(),
+            extensions: Box::default(),
+        }
+    }
+
+    pub fn with_param(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.params.push((name, value.into()));
+        self
+    }
+
+    /// Adds a member to the problem document, for details a client can act on.
+    pub fn with_extension(mut self, name: &str, value: impl Serialize) -> Self {
+        let value = serde_json::to_value(value).unwrap_or_default();
+        self.extensions.insert(name.to_string(), value);
+        self
+    }
+
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+
+    /// The untranslated message.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let params: Vec<(&str, &str)> = self
+            .params
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+        let message =
+            i18n::translate(&format!("error.{}", self.code), &params).unwrap_or(self.message);
+
+        let body = Problem {
+            kind: "about:blank",
+            title: self.status.canonical_reason().unwrap_or_default(),
+            status: self.status.as_u16(),
+            detail: &message,
+            code: self.code,
+            extensions: &self.extensions,
+        };
+        let mut response = (self.status, Json(body)).into_response();
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+        response
+    }
+}
+
+/// Whether `error` means the database can't be reached right now (it's locked, its file
+/// can't be opened or read, or no connection came free in time) rather than that the query
+/// was wrong. The pool replaces broken connections, so a later request may well succeed.
+pub fn unavailable(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_) => true,
+        sqlx::Error::Database(error) => error
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            // The primary result code is the low byte of the extended one.
+            .is_some_and(|code| {
+                matches!(
+                    code & 0xff,
+                    SQLITE_BUSY | SQLITE_LOCKED | SQLITE_IOERR | SQLITE_CANTOPEN
+                )
+            }),
+        _ => false,
+    }
+}
+
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+const SQLITE_IOERR: i32 = 10;
+const SQLITE_CANTOPEN: i32 = 14;
+
+impl<E: Into<anyhow::Error>> From<E> for AppError {
+    fn from(error: E) -> Self {
+        let error = error.into();
+        if let Some(pinned) = error.downcast_ref::<pins::Pinned>() {
+            return pinned.to_error();
+        }
+        if let Some(decode) = error.downcast_ref::<processor::DecodeError>() {
+            return decode.to_error();
+        }
+        if let Some(strip) = error.downcast_ref::<metadata::StripError>() {
+            return strip.to_error();
+        }
+        if error.downcast_ref().is_some_and(unavailable) {
+            eprintln!("Database unavailable: {error:#}");
+            remember("database_unavailable", format!("{error:#}"));
+            return Self::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "database_unavailable",
+                "The database is unavailable, try again later",
+            );
+        }
+        eprintln!("Internal error: {error:#}");
+        remember("internal_error", format!("{error:#}"));
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            "Internal server error",
+        )
+    }
+}
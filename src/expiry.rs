@@ -0,0 +1,170 @@
+//! Uploads that delete themselves.
+//!
+//! An upload with an `expires_in` field (seconds) gets an `expires_at` time. A reaper checks
+//! every minute for images past it, deletes their rows and files and leaves a row in
+//! `image_tombstones`, so requests for the id get 410 Gone instead of 404. Pinned images
+//! (see `pins`) don't expire while they're pinned.
+
+use std::time::Duration;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use sqlx::SqlitePool;
+
+use crate::{
+    audit, blobs, burst, cdn,
+    error::AppError,
+    ids::{self, ImageKey},
+    image_locks, pins, response_cache,
+};
+
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Tables with per-image rows, emptied along with the image.
+const IMAGE_TABLES: [&str; 15] = [
+    "image_tags",
+    "image_metadata",
+    "image_versions",
+    "image_placeholders",
+    "image_palettes",
+    "image_fingerprints",
+    "jobs",
+    "comments",
+    "corruption_reports",
+    "burst_frames",
+    "served_files",
+    "decode_failures",
+    "saved_search_matches",
+    "annotations",
+    "album_images",
+];
+
+/// Errors with 410 Gone if image `id` existed and has expired.
+pub async fn gone(pool: &SqlitePool, id: i64) -> Result<(), AppError> {
+    // The image's id as URLs had it, see `ids`.
+    let tombstone: Option<(i64, String)> = sqlx::query_as(
+        "SELECT expired_at, COALESCE(public_id, CAST(image_id AS TEXT)) \
+         FROM image_tombstones WHERE image_id = ?",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+
+    match tombstone {
+        Some((expired_at, key)) => Err(AppError::new(
+            StatusCode::GONE,
+            "image_expired",
+            format!("Image {key} expired and was deleted"),
+        )
+        .with_param("id", key)
+        .with_param("expired_at", expired_at.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// The response for an image id with no visible image: 410 if it expired, else 404.
+pub async fn not_found(pool: &SqlitePool, id: i64, key: &ImageKey) -> Response {
+    match gone(pool, id).await {
+        Ok(()) => ids::not_found(key).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn reap(pool: &SqlitePool) -> anyhow::Result<()> {
+    let expired: Vec<(i64, i64)> = sqlx::query_as(
+        "SELECT id, expires_at FROM images \
+         WHERE expires_at <= CAST(strftime('%s', 'now') AS INTEGER) AND NOT pinned",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for (id, expires_at) in expired {
+        purge(pool, id, Some(expires_at)).await?;
+        audit::record(pool, None, "image_expired", Some(id), "").await?;
+    }
+
+    Ok(())
+}
+
+/// Deletes image `id`: its row, its rows in every per-image table and its files. With
+/// `expired_at`, a tombstone is left so the id answers 410 Gone. Fails with
+/// [`pins::Pinned`] if the image is pinned.
+pub async fn purge(pool: &SqlitePool, id: i64, expired_at: Option<i64>) -> anyhow::Result<()> {
+    let _lock = image_locks::write(id).await;
+    burst::invalidate(pool, id).await?;
+    let mut tx = pool.begin().await?;
+    let pinned: Option<String> = sqlx::query_scalar(
+        "SELECT COALESCE(public_id, CAST(id AS TEXT)) FROM images WHERE id = ? AND pinned",
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some(key) = pinned {
+        return Err(pins::Pinned { key }.into());
+    }
+    for table in IMAGE_TABLES {
+        sqlx::query(&format!("DELETE FROM {table} WHERE image_id = ?"))
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    // A burst goes with its first image, see `burst`.
+    sqlx::query("DELETE FROM burst_frames WHERE burst_id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    if let Some(expired_at) = expired_at {
+        sqlx::query(
+            "INSERT OR REPLACE INTO image_tombstones (image_id, public_id, expired_at) \
+             VALUES (?1, (SELECT public_id FROM images WHERE id = ?1), ?2)",
+        )
+        .bind(id)
+        .bind(expired_at)
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query("DELETE FROM images WHERE id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    response_cache::invalidate();
+    cdn::forget(id);
+
+    delete_files(pool, id).await
+}
+
+/// Deletes the original and everything derived from it: `images/{id}.jpg` and
+/// `images/{id}_*` (thumbnails, conversions, old versions).
+async fn delete_files(pool: &SqlitePool, id: i64) -> anyhow::Result<()> {
+    let original = format!("{id}.");
+    let derived = format!("{id}_");
+    let mut entries = tokio::fs::read_dir("images").await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(&original) || name.starts_with(&derived) {
+            let path = format!("images/{name}");
+            if let Err(e) = blobs::remove(pool, &path).await {
+                eprintln!("Failed to delete {name} of image {id}: {e:#}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes expired images now and then every minute.
+pub fn spawn_reaper(pool: SqlitePool) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = reap(&pool).await {
+                eprintln!("Expiring images failed: {e:#}");
+            }
+        }
+    });
+}
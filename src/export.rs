@@ -0,0 +1,285 @@
+//! `GET /api/v1/search/export?q=&format=csv|jsonl`: every image a search matches, as a file.
+//!
+//! Takes the text query as `q`, the exact tag terms as `filter` and the filters of
+//! `GET /images` (see `filters`), like `POST /search`, and writes one row per image, oldest
+//! first. As `format` names the file's format here, the image format filter is taken as
+//! `image_format`. Rows are streamed as the query yields them, so large result sets are
+//! never held in memory. A database error partway, or an export taking longer than
+//! [`MAX_EXPORT_TIME`] (the query holds a database snapshot while a slow client reads),
+//! aborts the response, so the client sees a failed download rather than a short file.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::RawQuery,
+    http::header,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tokio::sync::mpsc;
+
+use crate::{
+    api, auth::Viewer, config::SharedConfig, error::AppError, filters::SearchFilters,
+    ids::ImageKey, thumbnail::Crop, ImageRecord, IMAGE_COLUMNS,
+};
+
+/// Rows buffered ahead of a slow client.
+const BUFFERED_ROWS: usize = 64;
+/// How long an export may take, slow client included.
+const MAX_EXPORT_TIME: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Copy)]
+enum Format {
+    Csv,
+    Jsonl,
+}
+
+impl Format {
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Jsonl => "application/jsonl",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Jsonl => "jsonl",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    q: String,
+    /// Exact tag terms, see `tag_match` in `sql_functions`.
+    filter: Option<String>,
+    format: Option<String>,
+}
+
+/// One exported image, named like the JSON API's fields.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Row {
+    id: ImageKey,
+    tags: String,
+    title: String,
+    original_url: String,
+    thumbnail_url: String,
+    width: Option<i64>,
+    height: Option<i64>,
+    byte_size: Option<i64>,
+    format: Option<String>,
+    created_at: String,
+    updated_at: String,
+    taken_at: Option<String>,
+    license: String,
+    attribution: String,
+}
+
+const CSV_HEADER: &str = "id,tags,title,originalUrl,thumbnailUrl,width,height,byteSize,format,\
+                          createdAt,updatedAt,takenAt,license,attribution\n";
+
+impl Row {
+    fn new(image: ImageRecord, strip_metadata: bool) -> Self {
+        Row {
+            original_url: image.original_url(strip_metadata),
+            thumbnail_url: image.thumbnail_url(Crop::Fit),
+            id: image.key,
+            tags: image.tags,
+            title: image.title,
+            width: image.width,
+            height: image.height,
+            byte_size: image.byte_size,
+            format: image.format,
+            created_at: api::rfc3339(image.created_at),
+            updated_at: api::rfc3339(image.updated_at),
+            taken_at: image.taken_at.map(api::rfc3339),
+            license: image.license,
+            attribution: image.attribution,
+        }
+    }
+
+    fn write(&self, format: Format) -> String {
+        match format {
+            Format::Jsonl => serde_json::to_string(self).unwrap_or_default() + "\n",
+            Format::Csv => {
+                let number = |value: Option<i64>| value.map(|n| n.to_string()).unwrap_or_default();
+                let fields = [
+                    self.id.to_string(),
+                    self.tags.clone(),
+                    self.title.clone(),
+                    self.original_url.clone(),
+                    self.thumbnail_url.clone(),
+                    number(self.width),
+                    number(self.height),
+                    number(self.byte_size),
+                    self.format.clone().unwrap_or_default(),
+                    self.created_at.clone(),
+                    self.updated_at.clone(),
+                    self.taken_at.clone().unwrap_or_default(),
+                    self.license.clone(),
+                    self.attribution.clone(),
+                ];
+                let mut line = fields.map(|field| csv_field(&field)).join(",");
+                line.push('\n');
+                line
+            }
+        }
+    }
+}
+
+/// Quotes a CSV field if it needs it (RFC 4180).
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Reads the query string as the export's own parameters and the search filters.
+fn parse_query(raw: &str) -> Result<(ExportQuery, SearchFilters), AppError> {
+    let invalid = |e: serde_urlencoded::de::Error| {
+        AppError::bad_request("invalid_query", format!("Invalid query: {e}"))
+            .with_param("error", e.to_string())
+    };
+    let query = serde_urlencoded::from_str(raw).map_err(invalid)?;
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_str(raw).map_err(invalid)?;
+    let filters = pairs
+        .into_iter()
+        .filter(|(name, _)| name != "format")
+        .map(|(name, value)| match name.as_str() {
+            "image_format" => ("format".to_string(), value),
+            _ => (name, value),
+        })
+        .collect::<Vec<_>>();
+    let filters = serde_urlencoded::to_string(filters).unwrap_or_default();
+    let filters = serde_urlencoded::from_str(&filters).map_err(invalid)?;
+
+    Ok((query, filters))
+}
+
+pub async fn export(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(viewer): Extension<Viewer>,
+    RawQuery(raw): RawQuery,
+) -> Result<Response, AppError> {
+    let (query, filters) = parse_query(raw.as_deref().unwrap_or_default())?;
+    let format = match query.format.as_deref().unwrap_or("csv") {
+        "csv" => Format::Csv,
+        "jsonl" => Format::Jsonl,
+        other => {
+            return Err(AppError::bad_request(
+                "invalid_export_format",
+                "format must be csv or jsonl",
+            )
+            .with_param("format", other))
+        }
+    };
+    let filters = filters.compile(4)?;
+    let sql = format!(
+        "SELECT {IMAGE_COLUMNS} FROM images \
+         WHERE (tags LIKE ?1 OR title LIKE ?1 OR description LIKE ?1) \
+             AND (?2 IS NULL OR tag_match(tags, ?2, ?3)) AND {}{} \
+         ORDER BY created_at, id",
+        viewer.visible(),
+        filters.sql
+    );
+    let pattern = format!("%{}%", query.q);
+    let strip_metadata = config.load().strip_metadata;
+    let descendants = config.load().tag_search_descendants;
+
+    // The query runs in a task of its own, feeding the response through a channel, as the
+    // rows borrow from the pool and the SQL. The channel closing before the task finished
+    // means it failed.
+    let (rows, received) = mpsc::channel::<Bytes>(BUFFERED_ROWS);
+    let finished = Arc::new(AtomicBool::new(false));
+    let finishing = finished.clone();
+    tokio::spawn(async move {
+        let export = async {
+            if let Format::Csv = format {
+                if rows
+                    .send(Bytes::from_static(CSV_HEADER.as_bytes()))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            let mut images = filters
+                .bind(
+                    sqlx::query_as::<_, ImageRecord>(&sql)
+                        .bind(pattern)
+                        .bind(query.filter)
+                        .bind(descendants),
+                )
+                .fetch(&pool);
+            loop {
+                match images.try_next().await {
+                    Ok(Some(image)) => {
+                        let line = Row::new(image, strip_metadata).write(format);
+                        // The client went away.
+                        if rows.send(Bytes::from(line)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => {
+                        finishing.store(true, Ordering::SeqCst);
+                        return;
+                    }
+                    Err(e) => {
+                        eprintln!("Export failed partway: {e:#}");
+                        return;
+                    }
+                }
+            }
+        };
+        if tokio::time::timeout(MAX_EXPORT_TIME, export).await.is_err() {
+            eprintln!(
+                "Export ended after {} seconds, unfinished",
+                MAX_EXPORT_TIME.as_secs()
+            );
+        }
+    });
+    let body = futures::stream::unfold(Some(received), move |received| {
+        let finished = finished.clone();
+        async move {
+            let mut received = received?;
+            match received.recv().await {
+                Some(chunk) => Some((Ok(chunk), Some(received))),
+                None if finished.load(Ordering::SeqCst) => None,
+                // Failing the body aborts the response instead of ending it cleanly.
+                None => Some((
+                    Err(std::io::Error::other("the export failed partway")),
+                    None,
+                )),
+            }
+        }
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"search.{}\"", format.extension()),
+            ),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response())
+}
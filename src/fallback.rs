@@ -0,0 +1,120 @@
+//! What requests for images that aren't there get.
+//!
+//! Unknown, deleted and hidden ids get a `404` problem document, and expired ones a `410`,
+//! which is what API clients want. An `<img>` tag can't show either, so with
+//! `?fallback=placeholder` (or `MISSING_IMAGE_FALLBACK=placeholder`, for every request) they
+//! get a generated "not found" PNG instead, still with the error status. It's `w` by `h`
+//! pixels when given (one of them makes a square), else thumbnail sized.
+//! `?fallback=problem` asks for the problem document whatever the setting.
+
+use std::io::Cursor;
+
+use axum::{
+    extract::Request,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use image::{DynamicImage, GrayImage, ImageFormat, Luma};
+use serde::Deserialize;
+
+use crate::{config::SharedConfig, thumbnail::THUMBNAIL_SIZE};
+
+/// Largest placeholder side, in pixels.
+const MAX_SIZE: u32 = 2000;
+const BACKGROUND: u8 = 238;
+const FOREGROUND: u8 = 170;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fallback {
+    Problem,
+    Placeholder,
+}
+
+impl Fallback {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "problem" => Ok(Fallback::Problem),
+            "placeholder" => Ok(Fallback::Placeholder),
+            _ => anyhow::bail!(
+                "MISSING_IMAGE_FALLBACK must be problem or placeholder, got {value:?}"
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct FallbackQuery {
+    fallback: Option<Fallback>,
+    w: Option<u32>,
+    h: Option<u32>,
+}
+
+/// Middleware for the routes serving image files, swapping their 404s and 410s for a
+/// placeholder when asked to.
+pub async fn missing_image(
+    Extension(config): Extension<SharedConfig>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // A query the handler refuses gets its 400 as is, so anything unreadable will do here.
+    let query: FallbackQuery = request
+        .uri()
+        .query()
+        .and_then(|query| serde_urlencoded::from_str(query).ok())
+        .unwrap_or_default();
+    let response = next.run(request).await;
+
+    let status = response.status();
+    if !matches!(status, StatusCode::NOT_FOUND | StatusCode::GONE) {
+        return response;
+    }
+    let fallback = query
+        .fallback
+        .unwrap_or(config.load().missing_image_fallback);
+    if fallback != Fallback::Placeholder {
+        return response;
+    }
+    let width = query.w.or(query.h).unwrap_or(THUMBNAIL_SIZE);
+    let height = query.h.or(query.w).unwrap_or(THUMBNAIL_SIZE);
+    match placeholder(width.clamp(1, MAX_SIZE), height.clamp(1, MAX_SIZE)) {
+        Ok(png) => (
+            status,
+            [
+                (header::CONTENT_TYPE, "image/png"),
+                // The id may yet be uploaded to, or the image restored.
+                (header::CACHE_CONTROL, "no-cache"),
+            ],
+            png,
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("Failed to draw a placeholder image: {e:#}");
+            response
+        }
+    }
+}
+
+/// A `width` by `height` PNG of a crossed-out frame, grey on light grey.
+fn placeholder(width: u32, height: u32) -> anyhow::Result<Vec<u8>> {
+    let side = (width.min(height) / 2).max(1) as i64;
+    let stroke = (side / 12).max(1);
+    let (left, top) = ((width as i64 - side) / 2, (height as i64 - side) / 2);
+    let pixels = GrayImage::from_fn(width, height, |x, y| {
+        let (x, y) = (x as i64 - left, y as i64 - top);
+        let inside = (0..side).contains(&x) && (0..side).contains(&y);
+        let frame = x < stroke || y < stroke || x >= side - stroke || y >= side - stroke;
+        let cross = (x - y).abs() < stroke || (x + y - side + 1).abs() < stroke;
+        Luma([if inside && (frame || cross) {
+            FOREGROUND
+        } else {
+            BACKGROUND
+        }])
+    });
+
+    let mut png = Vec::new();
+    DynamicImage::ImageLuma8(pixels).write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    Ok(png)
+}
@@ -0,0 +1,170 @@
+//! Site icons made from an image, for when the service hosts a site's assets:
+//! `GET /image/:id/favicon.ico`, an ICO holding the image at 16, 32 and 48 pixels, and
+//! `GET /image/:id/apple-touch-icon.png?size=`, a PNG for iOS home screens.
+//!
+//! Icons are square, so they show the middle square of the image. Each is made on first
+//! request and kept as `images/<id>_favicon.ico` and `images/<id>_apple_touch_<size>.png`,
+//! and made again after the image is replaced.
+
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use image::{codecs::ico::IcoFrame, ExtendedColorType};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use crate::{
+    auth::Viewer, decode_failures, error::AppError, expiry, ids::ImageId, image_locks, processor,
+    served_files,
+};
+
+/// Sizes in the ICO.
+const FAVICON_SIZES: [u32; 3] = [16, 32, 48];
+/// Sizes Apple devices ask for: iPhone, iPad, iPad Pro, and the default for the rest.
+const APPLE_TOUCH_SIZES: [u32; 4] = [120, 152, 167, 180];
+const DEFAULT_APPLE_TOUCH_SIZE: u32 = 180;
+
+fn favicon_path(id: i64) -> String {
+    format!("images/{id}_favicon.ico")
+}
+
+fn apple_touch_path(id: i64, size: u32) -> String {
+    format!("images/{id}_apple_touch_{size}.png")
+}
+
+/// Every icon file image `id` may have, for deleting them when its original changes.
+pub fn paths(id: i64) -> Vec<String> {
+    let mut paths = vec![favicon_path(id)];
+    paths.extend(APPLE_TOUCH_SIZES.map(|size| apple_touch_path(id, size)));
+    paths
+}
+
+fn encode_ico(icons: &[image::RgbaImage]) -> anyhow::Result<Vec<u8>> {
+    let frames = icons
+        .iter()
+        .map(|icon| {
+            IcoFrame::as_png(
+                icon.as_raw(),
+                icon.width(),
+                icon.height(),
+                ExtendedColorType::Rgba8,
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut ico = Vec::new();
+    image::codecs::ico::IcoEncoder::new(&mut ico).encode_images(&frames)?;
+
+    Ok(ico)
+}
+
+fn encode_png(icon: &image::RgbaImage) -> anyhow::Result<Vec<u8>> {
+    let mut png = Vec::new();
+    icon.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)?;
+
+    Ok(png)
+}
+
+/// The file at `path`, made from image `id` by `make` if it isn't there yet.
+async fn cached(
+    pool: &SqlitePool,
+    id: i64,
+    path: String,
+    make: impl FnOnce(&std::path::Path) -> anyhow::Result<Vec<u8>> + Send + 'static,
+) -> anyhow::Result<Vec<u8>> {
+    if let Ok(bytes) = tokio::fs::read(&path).await {
+        return Ok(bytes);
+    }
+
+    let _lock = image_locks::read(id).await;
+    let made =
+        processor::watched(move || make(std::path::Path::new(&processor::raster_source(id)?)))
+            .await;
+    if let Err(e) = &made {
+        decode_failures::record(pool, id, e).await;
+    }
+    let bytes = made?;
+    let partial = format!("{path}.{}.tmp", rand::random::<u32>());
+    tokio::fs::write(&partial, &bytes).await?;
+    tokio::fs::rename(&partial, &path).await?;
+    served_files::record(pool, id, &path).await?;
+
+    Ok(bytes)
+}
+
+async fn serve(
+    pool: &SqlitePool,
+    id: i64,
+    path: &str,
+    content_type: &'static str,
+    bytes: Vec<u8>,
+) -> Result<Response, AppError> {
+    let info = served_files::lookup(pool, id, path).await?;
+    let mut response = ([(header::CONTENT_TYPE, content_type)], bytes).into_response();
+    info.apply(response.headers_mut());
+
+    Ok(response)
+}
+
+/// `GET /image/:id/favicon.ico`
+pub async fn favicon(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    ImageId { id, key }: ImageId,
+) -> Result<Response, AppError> {
+    if crate::fetch_visible_image(&pool, viewer, id)
+        .await?
+        .is_none()
+    {
+        return Ok(expiry::not_found(&pool, id, &key).await);
+    }
+
+    let path = favicon_path(id);
+    let ico = cached(&pool, id, path.clone(), |source| {
+        encode_ico(&processor::get().icons(source, &FAVICON_SIZES)?)
+    })
+    .await?;
+
+    serve(&pool, id, &path, "image/x-icon", ico).await
+}
+
+#[derive(Deserialize)]
+pub struct AppleTouchQuery {
+    size: Option<u32>,
+}
+
+/// `GET /image/:id/apple-touch-icon.png?size=`
+pub async fn apple_touch_icon(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    ImageId { id, key }: ImageId,
+    Query(query): Query<AppleTouchQuery>,
+) -> Result<Response, AppError> {
+    let size = query.size.unwrap_or(DEFAULT_APPLE_TOUCH_SIZE);
+    if !APPLE_TOUCH_SIZES.contains(&size) {
+        let sizes = APPLE_TOUCH_SIZES.map(|size| size.to_string()).join(", ");
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_icon_size",
+            format!("size must be one of {sizes}"),
+        )
+        .with_param("sizes", sizes));
+    }
+    if crate::fetch_visible_image(&pool, viewer, id)
+        .await?
+        .is_none()
+    {
+        return Ok(expiry::not_found(&pool, id, &key).await);
+    }
+
+    let path = apple_touch_path(id, size);
+    let png = cached(&pool, id, path.clone(), move |source| {
+        let icons = processor::get().icons(source, &[size])?;
+        encode_png(&icons[0])
+    })
+    .await?;
+
+    serve(&pool, id, &path, "image/png", png).await
+}
@@ -0,0 +1,266 @@
+//! Structured search filters: upload and change date ranges, dimensions, orientation, file
+//! size, format, stars and pins.
+//!
+//! They're optional form fields next to the text query of `POST /search` and the HTML
+//! search, and query parameters of `GET /images`. They compile to plain comparisons on indexed `images` columns. Blank fields (an
+//! HTML form sends those for inputs left empty) are ignored.
+
+use std::fmt::Write;
+
+use axum::{
+    async_trait,
+    extract::{FromRequest, RawForm, Request},
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use sqlx::{query::QueryAs, sqlite::SqliteArguments, Sqlite};
+
+use crate::{api, error::AppError, metadata};
+
+#[derive(Deserialize, Default)]
+pub struct SearchFilters {
+    /// Date (`2024-05-01`, from the start of that day) or Unix timestamp, inclusive.
+    uploaded_after: Option<String>,
+    /// Date or Unix timestamp, exclusive.
+    uploaded_before: Option<String>,
+    /// Last changed at or after this date or timestamp, see `updated_at`.
+    since: Option<String>,
+    /// Last changed before this date or timestamp.
+    until: Option<String>,
+    min_width: Option<String>,
+    max_width: Option<String>,
+    min_height: Option<String>,
+    max_height: Option<String>,
+    min_bytes: Option<String>,
+    max_bytes: Option<String>,
+    /// File extension of the format, like `png` or `jpg`.
+    format: Option<String>,
+    /// `true` for starred images only, `false` for the others.
+    starred: Option<String>,
+    /// `true` for pinned images only, `false` for the others.
+    pinned: Option<String>,
+    /// `landscape`, `portrait` or `square`, see the `orientation` column.
+    orientation: Option<String>,
+}
+
+/// The values of the `orientation` column.
+const ORIENTATIONS: [&str; 3] = ["landscape", "portrait", "square"];
+
+#[derive(Clone, Copy)]
+enum Value {
+    Int(i64),
+    Text(&'static str),
+}
+
+/// Filters compiled to SQL.
+pub struct Compiled {
+    /// Conditions on `images`, each starting with ` AND `.
+    pub sql: String,
+    first_param: usize,
+    values: Vec<Value>,
+}
+
+impl Compiled {
+    fn push(&mut self, condition: &str, value: Value) {
+        let n = self.first_param + self.values.len();
+        let _ = write!(self.sql, " AND {condition} ?{n}");
+        self.values.push(value);
+    }
+
+    /// Binds the filters' values, which must come after every other parameter of `query`.
+    pub fn bind<'q, O>(
+        &self,
+        mut query: QueryAs<'q, Sqlite, O, SqliteArguments<'q>>,
+    ) -> QueryAs<'q, Sqlite, O, SqliteArguments<'q>> {
+        for &value in &self.values {
+            query = match value {
+                Value::Int(value) => query.bind(value),
+                Value::Text(value) => query.bind(value),
+            };
+        }
+        query
+    }
+}
+
+fn present(value: &Option<String>) -> Option<&str> {
+    value
+        .as_deref()
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+fn number(value: &Option<String>, name: &'static str) -> Result<Option<i64>, AppError> {
+    present(value)
+        .map(|value| {
+            value.parse().map_err(|_| {
+                AppError::bad_request("invalid_number", format!("{name} must be a number"))
+                    .with_param("name", name)
+            })
+        })
+        .transpose()
+}
+
+/// `true`/`false`, also taking `on`, which a checked HTML checkbox sends.
+fn boolean(value: &Option<String>, name: &'static str) -> Result<Option<bool>, AppError> {
+    present(value)
+        .map(|value| match value.to_ascii_lowercase().as_str() {
+            "true" | "1" | "on" => Ok(true),
+            "false" | "0" => Ok(false),
+            _ => Err(AppError::bad_request(
+                "invalid_boolean",
+                format!("{name} must be true or false"),
+            )
+            .with_param("name", name)),
+        })
+        .transpose()
+}
+
+/// Days from 1970-01-01 to the given date of the proleptic Gregorian calendar.
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// A `YYYY-MM-DD` date (as midnight UTC), RFC 3339 timestamp (as the JSON API formats
+/// them) or Unix timestamp, in seconds.
+pub fn timestamp(value: &Option<String>, name: &'static str) -> Result<Option<i64>, AppError> {
+    let Some(value) = present(value) else {
+        return Ok(None);
+    };
+    if let Ok(secs) = value.parse() {
+        return Ok(Some(secs));
+    }
+    if let Some(secs) = api::parse_rfc3339(value) {
+        return Ok(Some(secs));
+    }
+
+    let date = value
+        .splitn(3, '-')
+        .map(|part| part.parse::<i64>().ok())
+        .collect::<Option<Vec<_>>>();
+    match date.as_deref() {
+        Some(&[year, month, day]) if (1..=12).contains(&month) && (1..=31).contains(&day) => {
+            Ok(Some(days_from_civil(year, month, day) * 86_400))
+        }
+        _ => Err(AppError::bad_request(
+            "invalid_date",
+            format!("{name} must be a date like 2024-05-01, a time like 2024-05-01T12:30:00Z or a Unix timestamp"),
+        )
+        .with_param("name", name)),
+    }
+}
+
+impl SearchFilters {
+    pub fn is_empty(&self) -> bool {
+        [
+            &self.uploaded_after,
+            &self.uploaded_before,
+            &self.since,
+            &self.until,
+            &self.min_width,
+            &self.max_width,
+            &self.min_height,
+            &self.max_height,
+            &self.min_bytes,
+            &self.max_bytes,
+            &self.format,
+            &self.starred,
+            &self.pinned,
+            &self.orientation,
+        ]
+        .into_iter()
+        .all(|value| present(value).is_none())
+    }
+
+    /// Compiles the filters into conditions using parameters `?{first_param}` onwards.
+    pub fn compile(&self, first_param: usize) -> Result<Compiled, AppError> {
+        let mut compiled = Compiled {
+            sql: String::new(),
+            first_param,
+            values: Vec::new(),
+        };
+
+        if let Some(at) = timestamp(&self.uploaded_after, "uploaded_after")? {
+            compiled.push("created_at >=", Value::Int(at));
+        }
+        if let Some(at) = timestamp(&self.uploaded_before, "uploaded_before")? {
+            compiled.push("created_at <", Value::Int(at));
+        }
+        if let Some(at) = timestamp(&self.since, "since")? {
+            compiled.push("updated_at >=", Value::Int(at));
+        }
+        if let Some(at) = timestamp(&self.until, "until")? {
+            compiled.push("updated_at <", Value::Int(at));
+        }
+        let bounds = [
+            (&self.min_width, "min_width", "width >="),
+            (&self.max_width, "max_width", "width <="),
+            (&self.min_height, "min_height", "height >="),
+            (&self.max_height, "max_height", "height <="),
+            (&self.min_bytes, "min_bytes", "byte_size >="),
+            (&self.max_bytes, "max_bytes", "byte_size <="),
+        ];
+        for (value, name, condition) in bounds {
+            if let Some(bound) = number(value, name)? {
+                compiled.push(condition, Value::Int(bound));
+            }
+        }
+        if let Some(format) = present(&self.format) {
+            let name = metadata::parse_format(format).ok_or_else(|| {
+                AppError::bad_request("invalid_format", format!("Unknown image format {format}"))
+                    .with_param("format", format)
+            })?;
+            compiled.push("format =", Value::Text(name));
+        }
+        if let Some(starred) = boolean(&self.starred, "starred")? {
+            compiled.push("starred =", Value::Int(starred.into()));
+        }
+        if let Some(pinned) = boolean(&self.pinned, "pinned")? {
+            compiled.push("pinned =", Value::Int(pinned.into()));
+        }
+        if let Some(orientation) = present(&self.orientation) {
+            let orientation = orientation.to_ascii_lowercase();
+            let orientation = ORIENTATIONS
+                .into_iter()
+                .find(|known| *known == orientation)
+                .ok_or_else(|| {
+                    AppError::bad_request(
+                        "invalid_orientation",
+                        "orientation must be landscape, portrait or square",
+                    )
+                })?;
+            compiled.push("orientation =", Value::Text(orientation));
+        }
+
+        Ok(compiled)
+    }
+}
+
+/// A urlencoded form read both as `T` and as the [`SearchFilters`] among its fields.
+/// (`#[serde(flatten)]` would do, but it breaks parsing the numbers in `T`.)
+pub struct FilteredForm<T>(pub T, pub SearchFilters);
+
+#[async_trait]
+impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S> for FilteredForm<T> {
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let RawForm(body) = RawForm::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let invalid = |e: serde_urlencoded::de::Error| {
+            AppError::bad_request("invalid_form", format!("Invalid form: {e}"))
+                .with_param("error", e.to_string())
+                .into_response()
+        };
+
+        Ok(Self(
+            serde_urlencoded::from_bytes(&body).map_err(invalid)?,
+            serde_urlencoded::from_bytes(&body).map_err(invalid)?,
+        ))
+    }
+}
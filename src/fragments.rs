@@ -0,0 +1,206 @@
+//! HTML fragments for htmx partial updates. Each handler returns only the markup for the
+//! element it targets so the page can be updated without a full reload.
+
+use axum::{
+    extract::{Multipart, Query},
+    http::HeaderMap,
+    response::Html,
+    Extension,
+};
+use serde::Deserialize;
+
+use crate::{
+    auth::Viewer, checksum::ExpectedChecksums, config::SharedConfig, error::AppError,
+    filters::FilteredForm, jobs::JobQueue, pipeline::SharedPipeline, quarantine::SharedQuarantine,
+    ranking::Sort, receipts::Receipt, thumbnail::Crop, ImageRecord, Ingested, UploadOptions,
+    IMAGE_COLUMNS,
+};
+
+const GALLERY_PAGE_SIZE: i64 = 24;
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A template from `src/pages`, translated into the request's locale.
+pub async fn read_template(name: &str) -> String {
+    let template = crate::templates::get(name).await;
+
+    crate::i18n::localize(&template).replace("{csp_nonce}", &crate::security::nonce())
+}
+
+/// Renders one `thumbnail.html` card per image.
+pub async fn render_thumbnails(images: &[ImageRecord]) -> String {
+    let template = read_template("thumbnail.html").await;
+
+    let mut image_html = String::new();
+    for image in images {
+        let mut _tmp = template.clone();
+        _tmp = _tmp.replace("{tags}", &escape_html(&image.tags));
+        _tmp = _tmp.replace("{title}", &escape_html(&image.title));
+        _tmp = _tmp.replace("{alt}", &escape_html(image.alt()));
+        // The first image of a burst shows the burst, see `burst`.
+        let thumbnail_url = match image.burst_size {
+            Some(_) => format!("/image/{}/burst.webp", image.key),
+            None => image.thumbnail_url(Crop::Fit),
+        };
+        _tmp = _tmp.replace("{thumbnail_url}", &escape_html(&thumbnail_url));
+        _tmp = _tmp.replace("{star}", &crate::stars::button(&image.key, image.starred));
+        _tmp = _tmp.replace("{id}", &image.key.to_string());
+
+        image_html.push_str(&_tmp);
+    }
+
+    image_html
+}
+
+#[derive(Deserialize)]
+pub struct GalleryQuery {
+    page: Option<i64>,
+}
+
+/// `GET /fragments/gallery?page=` — one page of thumbnails, followed by a sentinel that
+/// loads the next page when scrolled into view.
+pub async fn gallery(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    Query(query): Query<GalleryQuery>,
+) -> Html<String> {
+    Html(render_gallery_page(&pool, viewer, query.page.unwrap_or(1).max(1)).await)
+}
+
+async fn render_gallery_page(pool: &sqlx::SqlitePool, viewer: Viewer, page: i64) -> String {
+    // Fetch one extra row to find out whether there's a next page.
+    let mut images = sqlx::query_as::<_, ImageRecord>(&format!(
+        "SELECT {IMAGE_COLUMNS} FROM images WHERE {} ORDER BY id LIMIT ? OFFSET ?",
+        viewer.visible()
+    ))
+    .bind(GALLERY_PAGE_SIZE + 1)
+    .bind((page - 1) * GALLERY_PAGE_SIZE)
+    .fetch_all(pool)
+    .await
+    .unwrap();
+
+    let has_more = images.len() as i64 > GALLERY_PAGE_SIZE;
+    images.truncate(GALLERY_PAGE_SIZE as usize);
+
+    let more = if has_more {
+        read_template("gallery_more.html")
+            .await
+            .replace("{next_page}", &(page + 1).to_string())
+    } else {
+        String::new()
+    };
+
+    read_template("gallery_page.html")
+        .await
+        .replace("{page}", &page.to_string())
+        .replace("{thumbnails}", &render_thumbnails(&images).await)
+        .replace("{more}", &more)
+}
+
+#[derive(Deserialize)]
+pub struct SearchForm {
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    sort: Sort,
+}
+
+/// `POST /fragments/search-results` — the contents of `#thumbnails` for a tag search,
+/// narrowed by the advanced search filters. An empty query without filters falls back to
+/// the first gallery page.
+pub async fn search_results(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(viewer): Extension<Viewer>,
+    FilteredForm(form, filters): FilteredForm<SearchForm>,
+) -> Result<Html<String>, AppError> {
+    if form.tags.trim().is_empty() && filters.is_empty() {
+        return Ok(Html(render_gallery_page(&pool, viewer, 1).await));
+    }
+
+    let images = crate::search_by_tags(
+        &pool,
+        viewer,
+        &form.tags,
+        &filters,
+        form.sort.order_by(&config.load(), 1),
+    )
+    .await?;
+    let thumbnails = render_thumbnails(&images).await;
+
+    let html = read_template("search_results.html")
+        .await
+        .replace("{count}", &images.len().to_string())
+        .replace("{query}", &escape_html(&form.tags))
+        .replace("{thumbnails}", &thumbnails);
+
+    Ok(Html(html))
+}
+
+/// `POST /fragments/upload-result` — ingests a multipart upload and returns the new card,
+/// plus an out-of-band swap of `#upload-status`. If the file is already stored, only
+/// `#upload-status` changes, to show the existing image and offer to upload it anyway.
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_result(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(pipeline): Extension<SharedPipeline>,
+    Extension(jobs): Extension<JobQueue>,
+    Extension(quarantine): Extension<Option<SharedQuarantine>>,
+    options: UploadOptions,
+    headers: HeaderMap,
+    multipart: Multipart,
+) -> Result<Html<String>, AppError> {
+    let checksums = ExpectedChecksums::from_form_headers(&headers)?;
+    let ingested = crate::ingest_upload(
+        &pool,
+        &pipeline,
+        &jobs,
+        quarantine.as_deref(),
+        checksums,
+        options,
+        multipart,
+    )
+    .await?;
+    let image = match ingested {
+        Ingested::Stored(image) => image,
+        Ingested::Duplicate(existing) => {
+            let html = read_template("upload_duplicate.html")
+                .await
+                .replace("{tags}", &escape_html(&existing.tags))
+                .replace("{alt}", &escape_html(existing.alt()))
+                .replace(
+                    "{thumbnail_url}",
+                    &escape_html(&existing.thumbnail_url(Crop::Fit)),
+                )
+                .replace("{id}", &existing.key.to_string());
+            return Ok(Html(html));
+        }
+    };
+
+    let receipt = Receipt::new(&config.load(), &headers, &image)
+        .render()
+        .await;
+    let mut html = render_thumbnails(std::slice::from_ref(&image)).await;
+    html.push_str(
+        &read_template("upload_status.html")
+            .await
+            .replace("{id}", &image.key.to_string())
+            .replace("{receipt}", &receipt),
+    );
+
+    Ok(Html(html))
+}
@@ -0,0 +1,166 @@
+//! Translations for the HTML pages and error messages.
+//!
+//! Catalogs live in `src/locales/<lang>.txt` as `key = value` lines. Templates reference
+//! them as `{t:key}`, and values may contain `{param}` placeholders. The locale for a request
+//! is negotiated from `Accept-Language` by [`negotiate`] and kept in a task-local, so code
+//! that renders text doesn't need it passed down.
+
+use std::{collections::HashMap, path::Path, sync::OnceLock};
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+static CATALOGS: OnceLock<Catalogs> = OnceLock::new();
+
+tokio::task_local! {
+    static LOCALE: String;
+}
+
+struct Catalogs {
+    default_locale: String,
+    languages: HashMap<String, HashMap<String, String>>,
+}
+
+/// Reads every catalog in `dir`. `default_locale` is used when nothing in `Accept-Language`
+/// matches, and must have a catalog of its own.
+pub fn load(dir: impl AsRef<Path>, default_locale: &str) -> anyhow::Result<()> {
+    let mut languages = HashMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("txt") {
+            continue;
+        }
+        let Some(lang) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+
+        let mut messages = HashMap::new();
+        for line in std::fs::read_to_string(&path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                anyhow::bail!("{}: expected `key = value`, got {line:?}", path.display());
+            };
+            messages.insert(key.trim().to_string(), value.trim().to_string());
+        }
+        languages.insert(lang.to_ascii_lowercase(), messages);
+    }
+
+    let default_locale = default_locale.to_ascii_lowercase();
+    if !languages.contains_key(&default_locale) {
+        anyhow::bail!("No catalog for the default locale {default_locale:?}");
+    }
+
+    CATALOGS
+        .set(Catalogs {
+            default_locale,
+            languages,
+        })
+        .map_err(|_| anyhow::anyhow!("Translations were already loaded"))
+}
+
+fn catalogs() -> &'static Catalogs {
+    CATALOGS
+        .get()
+        .expect("i18n::load must run before serving requests")
+}
+
+/// Middleware choosing the request's locale from `Accept-Language`.
+pub async fn negotiate(request: Request, next: Next) -> Response {
+    let locale = request
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(best_match)
+        .unwrap_or_else(|| catalogs().default_locale.clone());
+
+    let mut response = LOCALE.scope(locale.clone(), next.run(request)).await;
+    if let Ok(value) = HeaderValue::from_str(&locale) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LANGUAGE, value);
+    }
+
+    response
+}
+
+/// Picks the highest-weighted language we have a catalog for, trying `es-mx` then `es`.
+fn best_match(accept_language: &str) -> Option<String> {
+    let mut ranges: Vec<(&str, f32)> = accept_language
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let lang = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            (!lang.is_empty() && quality > 0.0).then_some((lang, quality))
+        })
+        .collect();
+    // Stable sort keeps the client's order between equal weights.
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let languages = &catalogs().languages;
+    ranges.into_iter().find_map(|(lang, _)| {
+        let lang = lang.to_ascii_lowercase();
+        if languages.contains_key(&lang) {
+            return Some(lang);
+        }
+        let primary = lang.split('-').next()?;
+        languages.contains_key(primary).then(|| primary.to_string())
+    })
+}
+
+/// The current request's locale, or the default outside of a request.
+pub fn current_locale() -> String {
+    LOCALE
+        .try_with(|locale| locale.clone())
+        .unwrap_or_else(|_| catalogs().default_locale.clone())
+}
+
+/// Looks up `key` in the current locale (falling back to the default locale) and fills in
+/// its `{param}` placeholders.
+pub fn translate(key: &str, params: &[(&str, &str)]) -> Option<String> {
+    let catalogs = CATALOGS.get()?;
+    let message = catalogs
+        .languages
+        .get(&current_locale())
+        .and_then(|messages| messages.get(key))
+        .or_else(|| catalogs.languages[&catalogs.default_locale].get(key))?;
+
+    let mut message = message.clone();
+    for (name, value) in params {
+        message = message.replace(&format!("{{{name}}}"), value);
+    }
+
+    Some(message)
+}
+
+/// Like [`translate`], but falls back to the key itself so missing entries are visible.
+pub fn t(key: &str, params: &[(&str, &str)]) -> String {
+    translate(key, params).unwrap_or_else(|| key.to_string())
+}
+
+/// Replaces every `{t:key}` in a template with its translation.
+pub fn localize(template: &str) -> String {
+    let mut localized = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{t:") {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        localized.push_str(&rest[..start]);
+        localized.push_str(&t(&rest[start + 3..start + len], &[]));
+        rest = &rest[start + len + 1..];
+    }
+    localized.push_str(rest);
+
+    localized
+}
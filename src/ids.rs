@@ -0,0 +1,234 @@
+//! Image ids as seen from outside.
+//!
+//! Images are numbered in the database, and those numbers are what tables join on and files
+//! are named after. Sequential numbers in URLs give away how many images there are and let
+//! anyone walk through them, so with `IMAGE_ID_SCHEME=uuid` or `ulid` each image also gets a
+//! random `public_id` that URLs, pages and responses use instead. Numeric ids are then no
+//! longer accepted in URLs. Images from before the switch get one at startup.
+
+use std::{collections::HashMap, fmt};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use serde::{Deserialize, Serialize, Serializer};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{config::SharedConfig, error::AppError};
+
+/// Crockford's base32, which ULIDs are written in.
+const ULID_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IdScheme {
+    /// The database's numbers.
+    Sequential,
+    /// Random (version 4) UUIDs.
+    Uuid,
+    /// ULIDs, which sort by creation time.
+    Ulid,
+}
+
+impl IdScheme {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "sequential" => Ok(IdScheme::Sequential),
+            "uuid" => Ok(IdScheme::Uuid),
+            "ulid" => Ok(IdScheme::Ulid),
+            _ => anyhow::bail!("IMAGE_ID_SCHEME must be sequential, uuid or ulid, got {value:?}"),
+        }
+    }
+
+    /// A new public id, `None` for the sequential scheme.
+    pub fn generate(self) -> Option<String> {
+        match self {
+            IdScheme::Sequential => None,
+            IdScheme::Uuid => Some(uuid()),
+            IdScheme::Ulid => Some(ulid()),
+        }
+    }
+}
+
+fn uuid() -> String {
+    let mut bits = rand::random::<u128>();
+    bits = bits & !(0xf << 76) | 0x4 << 76;
+    bits = bits & !(0x3 << 62) | 0x2 << 62;
+    let hex = format!("{bits:032x}");
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+fn ulid() -> String {
+    let millis = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let bits = (millis & ((1 << 48) - 1)) << 80 | rand::random::<u128>() & ((1 << 80) - 1);
+
+    (0..26)
+        .map(|i| ULID_ALPHABET[(bits >> (125 - 5 * i)) as usize & 31] as char)
+        .collect()
+}
+
+/// Gives every image without a public id one, after switching from the sequential scheme.
+pub async fn assign_missing(pool: &SqlitePool, scheme: IdScheme) -> anyhow::Result<()> {
+    if scheme == IdScheme::Sequential {
+        return Ok(());
+    }
+    let missing: Vec<i64> = sqlx::query_scalar("SELECT id FROM images WHERE public_id IS NULL")
+        .fetch_all(pool)
+        .await?;
+    for id in &missing {
+        sqlx::query("UPDATE images SET public_id = ? WHERE id = ?")
+            .bind(scheme.generate())
+            .bind(id)
+            .execute(pool)
+            .await?;
+    }
+    if !missing.is_empty() {
+        println!("Assigned public ids to {} images", missing.len());
+    }
+
+    Ok(())
+}
+
+/// An image's id and public id, read from the `id` and `public_id` columns. It is written
+/// out as the public id where there is one, as the number otherwise.
+#[derive(FromRow, Clone, Debug, Default)]
+pub struct ImageKey {
+    id: i64,
+    public_id: Option<String>,
+}
+
+impl ImageKey {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+impl fmt::Display for ImageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.public_id {
+            Some(public_id) => f.write_str(public_id),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+impl Serialize for ImageKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.public_id {
+            Some(public_id) => serializer.serialize_str(public_id),
+            None => serializer.serialize_i64(self.id),
+        }
+    }
+}
+
+pub fn not_found(key: impl fmt::Display) -> AppError {
+    AppError::new(
+        StatusCode::NOT_FOUND,
+        "image_not_found",
+        format!("No image with id {key}"),
+    )
+    .with_param("id", key.to_string())
+}
+
+/// The image an id from a URL names, or `None` if there's no such image. Expired images
+/// are found too, for their 410.
+pub async fn resolve(
+    pool: &SqlitePool,
+    scheme: IdScheme,
+    key: &str,
+) -> Result<Option<ImageKey>, AppError> {
+    let found = sqlx::query_as(
+        "SELECT id, public_id FROM images WHERE public_id = ?1 \
+         UNION ALL SELECT image_id, public_id FROM image_tombstones WHERE public_id = ?1 \
+         LIMIT 1",
+    )
+    .bind(key)
+    .fetch_optional(pool)
+    .await?;
+    if found.is_some() {
+        return Ok(found);
+    }
+
+    Ok(match scheme {
+        IdScheme::Sequential => key.parse().ok().map(|id| ImageKey {
+            id,
+            public_id: None,
+        }),
+        IdScheme::Uuid | IdScheme::Ulid => None,
+    })
+}
+
+/// An image id in a request body: a number, or a public id.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum RequestedId {
+    Number(i64),
+    Public(String),
+}
+
+impl fmt::Display for RequestedId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestedId::Number(id) => write!(f, "{id}"),
+            RequestedId::Public(public_id) => f.write_str(public_id),
+        }
+    }
+}
+
+impl RequestedId {
+    /// The database id, `None` if there's no such image.
+    pub async fn resolve(
+        &self,
+        pool: &SqlitePool,
+        scheme: IdScheme,
+    ) -> Result<Option<i64>, AppError> {
+        Ok(resolve(pool, scheme, &self.to_string())
+            .await?
+            .map(|key| key.id))
+    }
+}
+
+/// The image named by a route's `:id`, taking the place of `Path<i64>`: its database `id`,
+/// and its `key` for links back and responses.
+pub struct ImageId {
+    pub id: i64,
+    pub key: ImageKey,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ImageId {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path(params) = Path::<HashMap<String, String>>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let Extension(pool) = Extension::<SqlitePool>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let Extension(config) = Extension::<SharedConfig>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        let key = params.get("id").map(String::as_str).unwrap_or_default();
+        match resolve(&pool, config.load().image_id_scheme, key).await {
+            Ok(Some(key)) => Ok(ImageId { id: key.id, key }),
+            Ok(None) => Err(not_found(key).into_response()),
+            Err(e) => Err(e.into_response()),
+        }
+    }
+}
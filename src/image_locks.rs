@@ -0,0 +1,105 @@
+//! Per-image locks, so operations on one image don't interleave.
+//!
+//! Replacing an image swaps its original and deletes the files made from the old one; a
+//! thumbnail rendered from the old original at the same time would be written back after the
+//! delete and served for the new one, and an image deleted halfway through a replace would
+//! leave files behind. Whatever changes an image's original or its files (replacing,
+//! deleting, editing, moderating, restoring a corrupted original, evicting derived files)
+//! takes the image's write lock ([`write`]); whatever makes files from the original takes its
+//! read lock ([`read`]), so those still run side by side. Waiting writers go first. Changes to
+//! an image's rows alone, made in one transaction (tagging, pinning, starring), take no lock:
+//! SQLite keeps those whole.
+//!
+//! The locks are per process. SQLite has no row locks: a write transaction locks the whole
+//! database, which keeps each statement's rows consistent but not an operation spanning
+//! several transactions and files, hence these. Run one service per database.
+//!
+//! Locks aren't reentrant: code holding one must not wait for another on the same image,
+//! e.g. by making a thumbnail itself rather than queueing a job.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+/// Locks of the images some task holds or waits for.
+static LOCKS: OnceLock<Mutex<HashMap<i64, Arc<RwLock<()>>>>> = OnceLock::new();
+
+/// Times a task had to wait for another's lock on the same image, since startup.
+pub static CONTENDED: AtomicU64 = AtomicU64::new(0);
+
+fn locks() -> &'static Mutex<HashMap<i64, Arc<RwLock<()>>>> {
+    LOCKS.get_or_init(Default::default)
+}
+
+/// A held lock on image `id`, released when dropped.
+pub struct ImageLock {
+    id: i64,
+    read: Option<OwnedRwLockReadGuard<()>>,
+    write: Option<OwnedRwLockWriteGuard<()>>,
+}
+
+impl Drop for ImageLock {
+    fn drop(&mut self) {
+        self.read.take();
+        self.write.take();
+        let mut locks = locks().lock().unwrap();
+        // Only the registry's own reference left: nobody holds or waits for it.
+        if locks
+            .get(&self.id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.id);
+        }
+    }
+}
+
+fn lock_of(id: i64) -> Arc<RwLock<()>> {
+    locks().lock().unwrap().entry(id).or_default().clone()
+}
+
+/// Waits for image `id` to be free of writers, for reading its original.
+pub async fn read(id: i64) -> ImageLock {
+    // Declared first so it's dropped last, clearing up after a caller that gave up waiting.
+    let mut held = ImageLock {
+        id,
+        read: None,
+        write: None,
+    };
+    let lock = lock_of(id);
+    let guard = match lock.clone().try_read_owned() {
+        Ok(guard) => guard,
+        Err(_) => {
+            CONTENDED.fetch_add(1, Ordering::Relaxed);
+            lock.read_owned().await
+        }
+    };
+    held.read = Some(guard);
+
+    held
+}
+
+/// Waits until nobody else uses image `id`, for changing it.
+pub async fn write(id: i64) -> ImageLock {
+    let mut held = ImageLock {
+        id,
+        read: None,
+        write: None,
+    };
+    let lock = lock_of(id);
+    let guard = match lock.clone().try_write_owned() {
+        Ok(guard) => guard,
+        Err(_) => {
+            CONTENDED.fetch_add(1, Ordering::Relaxed);
+            lock.write_owned().await
+        }
+    };
+    held.write = Some(guard);
+
+    held
+}
@@ -0,0 +1,211 @@
+//! Catching originals that rot on disk.
+//!
+//! Every `INTEGRITY_CHECK_SECS` the originals verified longest ago (`INTEGRITY_CHECK_BATCH`
+//! of them) are hashed again and compared with their `content_hash`, so the whole library
+//! is covered in turn. A file that doesn't match, or is missing, is recorded in
+//! `corruption_reports` and the audit log, and listed by `GET /api/v1/admin/corruption`.
+//! With `BACKUP_DIR` set (a copy of the data directory, e.g. made with rsync), a good copy is
+//! looked for there as `blobs/<hash>` or `images/{id}.jpg` and put back in place.
+
+use std::path::Path;
+
+use axum::{extract::Query, Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{audit, blobs, cdn, config::SharedConfig, error::AppError, ids::ImageKey, image_locks};
+
+/// Images uploaded more recently aren't verified yet, as their original may still be being
+/// stored.
+const SETTLE_SECS: i64 = 60;
+
+#[derive(FromRow, Serialize)]
+pub struct Report {
+    #[sqlx(rename = "report_id")]
+    id: i64,
+    /// As URLs show it, see `ids`.
+    #[sqlx(flatten)]
+    #[serde(rename = "image_id")]
+    image: ImageKey,
+    path: String,
+    expected_hash: String,
+    /// `None` if the file was missing.
+    actual_hash: Option<String>,
+    detected_at: i64,
+    /// When a good copy was restored from `BACKUP_DIR`.
+    restored_at: Option<i64>,
+}
+
+/// SHA-256 of the file at `path`, or `None` if there's no file.
+async fn hash_file(path: &str) -> anyhow::Result<Option<String>> {
+    let bytes = match tokio::fs::read(path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(Some(
+        tokio::task::spawn_blocking(move || cdn::content_hash(&bytes)).await?,
+    ))
+}
+
+/// A copy of image `id`'s original in `backup` that has the expected hash.
+async fn find_backup(backup: &Path, id: i64, expected: &str) -> Option<Vec<u8>> {
+    let candidates = [
+        backup.join("blobs").join(expected),
+        backup.join("images").join(format!("{id}.jpg")),
+    ];
+    for candidate in candidates {
+        let Ok(bytes) = tokio::fs::read(&candidate).await else {
+            continue;
+        };
+        if cdn::content_hash(&bytes) == expected {
+            return Some(bytes);
+        }
+        eprintln!("Backup {} doesn't match either", candidate.display());
+    }
+    None
+}
+
+/// Records that image `id`'s original at `path` doesn't match, unless that's already known,
+/// and restores it from `backup` if it can.
+async fn report(
+    pool: &SqlitePool,
+    backup: Option<&Path>,
+    id: i64,
+    path: &str,
+    expected: &str,
+    actual: Option<&str>,
+) -> anyhow::Result<()> {
+    let recorded = sqlx::query(
+        "INSERT INTO corruption_reports (image_id, path, expected_hash, actual_hash, detected_at) \
+         SELECT ?1, ?2, ?3, ?4, CAST(strftime('%s', 'now') AS INTEGER) \
+         WHERE NOT EXISTS ( \
+             SELECT 1 FROM corruption_reports \
+             WHERE image_id = ?1 AND restored_at IS NULL AND actual_hash IS ?4 \
+         )",
+    )
+    .bind(id)
+    .bind(path)
+    .bind(expected)
+    .bind(actual)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if recorded > 0 {
+        let found = actual.unwrap_or("no file");
+        eprintln!("{path} is corrupted: expected SHA-256 {expected}, found {found}");
+        audit::record(
+            pool,
+            None,
+            "image_corrupted",
+            Some(id),
+            &format!("{path}: {found}"),
+        )
+        .await?;
+    }
+
+    let Some(backup) = backup else {
+        return Ok(());
+    };
+    let Some(bytes) = find_backup(backup, id, expected).await else {
+        eprintln!("No good copy of {path} in {}", backup.display());
+        return Ok(());
+    };
+    blobs::restore(pool, path, &bytes).await?;
+    sqlx::query(
+        "UPDATE corruption_reports SET restored_at = CAST(strftime('%s', 'now') AS INTEGER) \
+         WHERE image_id = ? AND restored_at IS NULL",
+    )
+    .bind(id)
+    .execute(pool)
+    .await?;
+    println!("Restored {path} from {}", backup.display());
+    audit::record(pool, None, "image_restored", Some(id), path).await?;
+
+    Ok(())
+}
+
+/// Verifies the `batch` originals checked longest ago.
+async fn verify(pool: &SqlitePool, batch: u32, backup: Option<&Path>) -> anyhow::Result<()> {
+    let images: Vec<(i64, String)> = sqlx::query_as(
+        "SELECT id, content_hash FROM images \
+         WHERE content_hash IS NOT NULL \
+             AND created_at < CAST(strftime('%s', 'now') AS INTEGER) - ? \
+         ORDER BY verified_at IS NOT NULL, verified_at, id LIMIT ?",
+    )
+    .bind(SETTLE_SECS)
+    .bind(batch)
+    .fetch_all(pool)
+    .await?;
+
+    for (id, expected) in images {
+        // So a replace or a deletion doesn't change the file between the check and a restore.
+        let _lock = image_locks::write(id).await;
+        let path = format!("images/{id}.jpg");
+        let actual = hash_file(&path).await?;
+        if actual.as_deref() != Some(expected.as_str()) {
+            // Replaced or deleted since the batch was picked; that's no corruption.
+            let current: Option<String> =
+                sqlx::query_scalar("SELECT content_hash FROM images WHERE id = ?")
+                    .bind(id)
+                    .fetch_optional(pool)
+                    .await?
+                    .flatten();
+            if current.as_deref() == Some(expected.as_str()) {
+                report(pool, backup, id, &path, &expected, actual.as_deref()).await?;
+            }
+        }
+        sqlx::query(
+            "UPDATE images SET verified_at = CAST(strftime('%s', 'now') AS INTEGER) WHERE id = ?",
+        )
+        .bind(id)
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Verifies a batch of originals every `INTEGRITY_CHECK_SECS`.
+pub fn spawn_verifier(pool: SqlitePool, config: SharedConfig) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(config.load().integrity_check_interval);
+        loop {
+            ticker.tick().await;
+            let config = config.load();
+            if config.integrity_check_batch == 0 {
+                continue;
+            }
+            let backup = config.backup_dir.as_deref();
+            if let Err(e) = verify(&pool, config.integrity_check_batch, backup).await {
+                eprintln!("Verifying stored files failed: {e:#}");
+            }
+        }
+    });
+}
+
+#[derive(Deserialize)]
+pub struct ReportQuery {
+    /// Only reports not restored yet.
+    #[serde(default)]
+    open: bool,
+}
+
+/// `GET /api/v1/admin/corruption?open=`: corrupted originals found, newest first.
+pub async fn list(
+    Extension(pool): Extension<SqlitePool>,
+    Query(query): Query<ReportQuery>,
+) -> Result<Json<Vec<Report>>, AppError> {
+    Ok(Json(
+        sqlx::query_as(
+            "SELECT corruption_reports.id AS report_id, image_id AS id, public_id, path, \
+                 expected_hash, actual_hash, detected_at, restored_at \
+             FROM corruption_reports LEFT JOIN images ON images.id = image_id \
+             WHERE NOT ? OR restored_at IS NULL ORDER BY corruption_reports.id DESC",
+        )
+        .bind(query.open)
+        .fetch_all(&pool)
+        .await?,
+    ))
+}
@@ -0,0 +1,213 @@
+//! Background jobs persisted in the `jobs` table, so queued work survives restarts.
+//!
+//! Jobs move `queued` -> `running` -> `done` (or back to `queued` on failure until they run
+//! out of attempts, then `failed`). A single worker claims them one at a time.
+
+use std::{sync::Arc, time::Duration};
+
+use sqlx::{FromRow, SqlitePool};
+use tokio::sync::Notify;
+use tracing::Instrument;
+
+use crate::{
+    burst,
+    config::SharedConfig,
+    decode_failures,
+    processor::DecodeError,
+    saved_searches, served_files,
+    thumbnail::{self, Crop, Priority},
+};
+
+const MAX_ATTEMPTS: i64 = 3;
+/// How often the worker looks for jobs when nobody has woken it up.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobKind {
+    Thumbnail,
+    BurstPreview,
+    /// Matching a new image against the saved searches, see `saved_searches`.
+    SavedSearches,
+}
+
+impl JobKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            JobKind::Thumbnail => "thumbnail",
+            JobKind::BurstPreview => "burst_preview",
+            JobKind::SavedSearches => "saved_searches",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "thumbnail" => Some(JobKind::Thumbnail),
+            "burst_preview" => Some(JobKind::BurstPreview),
+            "saved_searches" => Some(JobKind::SavedSearches),
+            _ => None,
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct ClaimedJob {
+    id: i64,
+    kind: String,
+    image_id: i64,
+    attempts: i64,
+}
+
+/// Handle for enqueueing jobs and waking the worker.
+#[derive(Clone)]
+pub struct JobQueue {
+    pool: SqlitePool,
+    config: SharedConfig,
+    wake: Arc<Notify>,
+}
+
+impl JobQueue {
+    /// Requeues jobs left `running` by a previous process and starts the worker.
+    pub async fn start(pool: SqlitePool, config: SharedConfig) -> anyhow::Result<Self> {
+        let resumed = sqlx::query("UPDATE jobs SET state = 'queued' WHERE state = 'running'")
+            .execute(&pool)
+            .await?
+            .rows_affected();
+        if resumed > 0 {
+            println!("Resuming {resumed} interrupted jobs");
+        }
+
+        let queue = Self {
+            pool,
+            config,
+            wake: Arc::new(Notify::new()),
+        };
+        tokio::spawn(queue.clone().run());
+
+        Ok(queue)
+    }
+
+    /// Queues a job unless an identical one is already waiting or running.
+    #[tracing::instrument(
+        name = "db.enqueue_job",
+        skip(self),
+        fields(db.system = "sqlite", job.kind = kind.as_str(), image.id = image_id)
+    )]
+    pub async fn enqueue(&self, kind: JobKind, image_id: i64) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO jobs (kind, image_id, state, created_at, updated_at) \
+             SELECT ?1, ?2, 'queued', CAST(strftime('%s', 'now') AS INTEGER), \
+                    CAST(strftime('%s', 'now') AS INTEGER) \
+             WHERE NOT EXISTS (SELECT 1 FROM jobs \
+                               WHERE kind = ?1 AND image_id = ?2 AND state IN ('queued', 'running'))",
+        )
+        .bind(kind.as_str())
+        .bind(image_id)
+        .execute(&self.pool)
+        .await?;
+
+        self.wake.notify_one();
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "db.claim_job", skip_all, fields(db.system = "sqlite"))]
+    async fn claim(&self) -> anyhow::Result<Option<ClaimedJob>> {
+        let job = sqlx::query_as::<_, ClaimedJob>(
+            "UPDATE jobs \
+             SET state = 'running', attempts = attempts + 1, \
+                 updated_at = CAST(strftime('%s', 'now') AS INTEGER) \
+             WHERE id = (SELECT id FROM jobs WHERE state = 'queued' ORDER BY id LIMIT 1) \
+             RETURNING id, kind, image_id, attempts",
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(job)
+    }
+
+    #[tracing::instrument(name = "db.finish_job", skip_all, fields(db.system = "sqlite", job.id = job.id))]
+    async fn finish(&self, job: &ClaimedJob, result: anyhow::Result<()>) -> anyhow::Result<()> {
+        if let Err(e) = &result {
+            decode_failures::record(&self.pool, job.image_id, e).await;
+        }
+        let (state, error) = match result {
+            Ok(()) => ("done", None),
+            // An image too large or too slow to decode will be again.
+            Err(e) if job.attempts < MAX_ATTEMPTS && e.downcast_ref::<DecodeError>().is_none() => {
+                ("queued", Some(format!("{e:#}")))
+            }
+            Err(e) => ("failed", Some(format!("{e:#}"))),
+        };
+
+        sqlx::query(
+            "UPDATE jobs SET state = ?, error = ?, \
+                 updated_at = CAST(strftime('%s', 'now') AS INTEGER) \
+             WHERE id = ?",
+        )
+        .bind(state)
+        .bind(error)
+        .bind(job.id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn run(self) {
+        loop {
+            match self.claim().await {
+                Ok(Some(job)) => {
+                    let span = tracing::info_span!(
+                        "job",
+                        job.id = job.id,
+                        job.kind = job.kind,
+                        job.attempt = job.attempts,
+                        image.id = job.image_id,
+                    );
+                    async {
+                        let result = match JobKind::parse(&job.kind) {
+                            Some(JobKind::Thumbnail) => {
+                                let made = thumbnail::make_thumbnail(
+                                    job.image_id,
+                                    Crop::Fit,
+                                    Priority::Background,
+                                )
+                                .await;
+                                match made {
+                                    Ok(()) => {
+                                        served_files::record(
+                                            &self.pool,
+                                            job.image_id,
+                                            &thumbnail::thumbnail_path(job.image_id, Crop::Fit),
+                                        )
+                                        .await
+                                    }
+                                    Err(e) => Err(e),
+                                }
+                            }
+                            Some(JobKind::BurstPreview) => {
+                                burst::generate(&self.pool, job.image_id).await.map(drop)
+                            }
+                            Some(JobKind::SavedSearches) => {
+                                saved_searches::check(&self.pool, &self.config, job.image_id).await
+                            }
+                            None => Err(anyhow::anyhow!("Unknown job kind {:?}", job.kind)),
+                        };
+                        if let Err(e) = self.finish(&job, result).await {
+                            eprintln!("Failed to record result of job {}: {e:#}", job.id);
+                        }
+                    }
+                    .instrument(span)
+                    .await
+                }
+                Ok(None) => {
+                    let _ = tokio::time::timeout(POLL_INTERVAL, self.wake.notified()).await;
+                }
+                Err(e) => {
+                    eprintln!("Failed to claim job: {e:#}");
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+}
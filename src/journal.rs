@@ -0,0 +1,134 @@
+//! Write-ahead journal of uploads being stored.
+//!
+//! Storing an upload takes several steps: the `images` row, the original, metadata and
+//! derived data, tag rows, jobs. Each upload gets an `upload_journal` row before the first,
+//! updated with the step it's on and the image it created, and deleted once it's done. An
+//! upload failing partway is undone on the spot; one interrupted by a crash is undone at the
+//! next startup, before anything else runs. Undoing deletes the image and everything stored
+//! for it: the client never heard the upload succeed, so it's as if it hadn't arrived.
+//! Partial files (`*.tmp`) left in `images/` and `blobs/` are deleted at startup too.
+
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::expiry;
+
+/// Directories whose `*.tmp` files are partial writes, renamed into place once complete.
+const TEMP_DIRS: [&str; 2] = ["images", "blobs"];
+
+/// An upload's journal row.
+pub struct Entry {
+    id: i64,
+    image_id: Option<i64>,
+}
+
+/// Journals an upload about to be stored.
+pub async fn begin(pool: &SqlitePool) -> anyhow::Result<Entry> {
+    let id = sqlx::query_scalar(
+        "INSERT INTO upload_journal (step, started_at, updated_at) \
+         VALUES ('record', CAST(strftime('%s', 'now') AS INTEGER), \
+                 CAST(strftime('%s', 'now') AS INTEGER)) \
+         RETURNING id",
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(Entry { id, image_id: None })
+}
+
+impl Entry {
+    /// Records image `image_id` as the upload's, on the connection whose transaction
+    /// inserted it.
+    pub async fn created(
+        &mut self,
+        conn: &mut SqliteConnection,
+        image_id: i64,
+    ) -> anyhow::Result<()> {
+        sqlx::query("UPDATE upload_journal SET image_id = ? WHERE id = ?")
+            .bind(image_id)
+            .bind(self.id)
+            .execute(conn)
+            .await?;
+        self.image_id = Some(image_id);
+
+        Ok(())
+    }
+
+    /// Records that the upload has got to `step`.
+    pub async fn step(&self, pool: &SqlitePool, step: &str) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE upload_journal SET step = ?, \
+                 updated_at = CAST(strftime('%s', 'now') AS INTEGER) \
+             WHERE id = ?",
+        )
+        .bind(step)
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// The upload is stored completely.
+    pub async fn finish(self, pool: &SqlitePool) -> anyhow::Result<()> {
+        remove(pool, self.id).await
+    }
+
+    /// The upload failed partway: deletes what was stored of it.
+    pub async fn abandon(self, pool: &SqlitePool) {
+        if let Err(e) = undo(pool, self.id, self.image_id).await {
+            eprintln!("Undoing upload {} failed: {e:#}", self.id);
+        }
+    }
+}
+
+async fn remove(pool: &SqlitePool, id: i64) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM upload_journal WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+async fn undo(pool: &SqlitePool, id: i64, image_id: Option<i64>) -> anyhow::Result<()> {
+    if let Some(image_id) = image_id {
+        expiry::purge(pool, image_id, None).await?;
+    }
+    remove(pool, id).await
+}
+
+/// Undoes the uploads a crash interrupted and deletes partial files. Runs at startup, while
+/// nothing else is writing.
+pub async fn recover(pool: &SqlitePool) -> anyhow::Result<()> {
+    let interrupted: Vec<(i64, Option<i64>, String)> =
+        sqlx::query_as("SELECT id, image_id, step FROM upload_journal ORDER BY id")
+            .fetch_all(pool)
+            .await?;
+    for (id, image_id, step) in interrupted {
+        undo(pool, id, image_id).await?;
+        match image_id {
+            Some(image_id) => println!("Undid upload of image {image_id}, interrupted at {step}"),
+            None => println!("Dropped an upload interrupted before it was stored"),
+        }
+    }
+
+    for dir in TEMP_DIRS {
+        let mut entries = match tokio::fs::read_dir(dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            if entry
+                .path()
+                .extension()
+                .is_some_and(|extension| extension == "tmp")
+            {
+                tokio::fs::remove_file(entry.path()).await?;
+                println!("Deleted partial file {}", entry.path().display());
+            }
+        }
+    }
+
+    Ok(())
+}
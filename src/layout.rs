@@ -0,0 +1,87 @@
+//! The chrome shared by every full page: `layout.html` (header, navigation to the gallery,
+//! upload, search and timeline, and a flash message area) and its stylesheet.
+//!
+//! Page templates hold only their content and are wrapped with [`page`]. Fragments can
+//! show a message by swapping `#flash` out of band, as `upload_status.html` does.
+
+use axum::{
+    extract::Query,
+    http::{header, HeaderMap},
+    response::{Html, IntoResponse, Response},
+};
+use serde::Deserialize;
+
+use crate::{csrf, fragments, i18n, templates};
+
+/// Answers with `content`, a page template already filled in, wrapped in the layout. The
+/// page is titled `title` (escaped here), followed by the service's name.
+pub async fn page(headers: &HeaderMap, title: Option<&str>, content: &str) -> Response {
+    let app_title = i18n::t("app.title", &[]);
+    let title = match title {
+        Some(title) => format!("{} - {app_title}", fragments::escape_html(title)),
+        None => app_title,
+    };
+
+    let (token, cookie) = csrf::issue(headers);
+    let html = fragments::read_template("layout.html")
+        .await
+        .replace("{csrf_token}", &token)
+        .replace("{title}", &title)
+        .replace("{css_version}", templates::APP_CSS_VERSION)
+        // Last, so nothing in the content is taken for a placeholder.
+        .replace("{content}", content);
+
+    ([(header::SET_COOKIE, cookie)], Html(html)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct StylesheetQuery {
+    /// [`templates::APP_CSS_VERSION`], as the layout links it.
+    v: Option<String>,
+}
+
+/// Whether `Accept-Encoding` allows Brotli.
+fn accepts_brotli(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            name.eq_ignore_ascii_case("br") && quality > 0.0
+        })
+}
+
+/// `GET /static/app.css?v=`: Brotli-compressed at build time for clients that accept it.
+/// Cached for good at the versioned URL the layout links, as a new stylesheet gets a new one,
+/// and for an hour otherwise; not at all while templates are read from disk.
+pub async fn stylesheet(Query(query): Query<StylesheetQuery>, headers: HeaderMap) -> Response {
+    let cache_control = if templates::dev() {
+        "no-cache"
+    } else if query.v.as_deref() == Some(templates::APP_CSS_VERSION) {
+        "public, max-age=31536000, immutable"
+    } else {
+        "public, max-age=3600"
+    };
+    let response_headers = [
+        (header::CONTENT_TYPE, "text/css; charset=utf-8"),
+        (header::CACHE_CONTROL, cache_control),
+        (header::VARY, "accept-encoding"),
+    ];
+
+    match templates::stylesheet_br() {
+        Some(compressed) if accepts_brotli(&headers) => (
+            response_headers,
+            [(header::CONTENT_ENCODING, "br")],
+            compressed,
+        )
+            .into_response(),
+        _ => (response_headers, templates::get("app.css").await).into_response(),
+    }
+}
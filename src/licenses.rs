@@ -0,0 +1,114 @@
+//! Licenses images are published under, and who to credit for them.
+//!
+//! An image's `license` is one of the SPDX identifiers in [`LICENSES`] (matched without regard
+//! to case, stored as listed), or empty when none was given; anything else is refused with 400
+//! `unknown_license`. The Creative Commons licenses other than CC0 require crediting the
+//! author, so images under them are refused without an `attribution` (422
+//! `missing_attribution`). That way every image the API and exports show under such a
+//! license carries its credit along.
+
+use axum::http::StatusCode;
+
+use crate::{error::AppError, fragments, i18n};
+
+/// Known licenses and their deeds.
+const LICENSES: [(&str, &str); 7] = [
+    (
+        "CC0-1.0",
+        "https://creativecommons.org/publicdomain/zero/1.0/",
+    ),
+    ("CC-BY-4.0", "https://creativecommons.org/licenses/by/4.0/"),
+    (
+        "CC-BY-SA-4.0",
+        "https://creativecommons.org/licenses/by-sa/4.0/",
+    ),
+    (
+        "CC-BY-ND-4.0",
+        "https://creativecommons.org/licenses/by-nd/4.0/",
+    ),
+    (
+        "CC-BY-NC-4.0",
+        "https://creativecommons.org/licenses/by-nc/4.0/",
+    ),
+    (
+        "CC-BY-NC-SA-4.0",
+        "https://creativecommons.org/licenses/by-nc-sa/4.0/",
+    ),
+    (
+        "CC-BY-NC-ND-4.0",
+        "https://creativecommons.org/licenses/by-nc-nd/4.0/",
+    ),
+];
+
+/// The listed spelling of `license`, or `""` for none.
+pub fn normalize(license: &str) -> Result<String, AppError> {
+    let license = license.trim();
+    if license.is_empty() {
+        return Ok(String::new());
+    }
+    match LICENSES
+        .iter()
+        .find(|(id, _)| id.eq_ignore_ascii_case(license))
+    {
+        Some((id, _)) => Ok(id.to_string()),
+        None => {
+            let known = LICENSES.map(|(id, _)| id).join(", ");
+            Err(AppError::bad_request(
+                "unknown_license",
+                format!("Unknown license {license:?}, use one of {known}"),
+            )
+            .with_param("license", license)
+            .with_param("licenses", known))
+        }
+    }
+}
+
+/// Whether images under `license` must credit their author.
+pub fn requires_attribution(license: &str) -> bool {
+    license.starts_with("CC-BY-")
+}
+
+/// Refuses a (normalized) license that requires an attribution without one.
+pub fn check(license: &str, attribution: &str) -> Result<(), AppError> {
+    if requires_attribution(license) && attribution.trim().is_empty() {
+        return Err(AppError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "missing_attribution",
+            format!("Images licensed under {license} need an `attribution`"),
+        )
+        .with_param("license", license));
+    }
+
+    Ok(())
+}
+
+pub fn url(license: &str) -> Option<&'static str> {
+    LICENSES
+        .iter()
+        .find(|(id, _)| *id == license)
+        .map(|(_, url)| *url)
+}
+
+/// The license and credit line of a details page, or nothing for an image with neither.
+pub fn html(license: &str, attribution: &str) -> String {
+    let mut parts = Vec::new();
+    if !license.is_empty() {
+        let name = fragments::escape_html(license);
+        let link = match url(license) {
+            Some(url) => format!("<a href=\"{url}\" rel=\"license\">{name}</a>"),
+            None => name,
+        };
+        parts.push(format!("{} {link}", i18n::t("details.license", &[])));
+    }
+    if !attribution.trim().is_empty() {
+        parts.push(format!(
+            "{} {}",
+            i18n::t("details.attribution", &[]),
+            fragments::escape_html(attribution.trim())
+        ));
+    }
+    if parts.is_empty() {
+        return String::new();
+    }
+    format!("<p class=\"license\">{}</p>", parts.join(" · "))
+}
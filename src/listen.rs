@@ -0,0 +1,281 @@
+//! Where the service accepts connections.
+//!
+//! `LISTEN` lists TCP addresses (`0.0.0.0:3000`, `[::]:3000`) and Unix socket paths
+//! (`unix:/run/thumbnail_service.sock`) to serve on at once. When systemd starts the service
+//! through socket activation (`LISTEN_PID`/`LISTEN_FDS`), the sockets it passes are used
+//! instead. With TLS configured (see `tls`), the TCP listeners serve HTTPS.
+//!
+//! On SIGTERM or Ctrl-C every listener stops accepting connections and [`serve`] returns once
+//! the requests in flight are answered, for at most `SHUTDOWN_GRACE`.
+
+use std::{
+    net::SocketAddr,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::fs::FileTypeExt,
+    },
+    time::Duration,
+};
+
+use axum::{extract::ConnectInfo, Extension, Router};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    signal::unix::{signal, SignalKind},
+    task::JoinSet,
+};
+
+use crate::{client_ip, config::Config, tls};
+
+/// The first descriptor systemd passes, see `sd_listen_fds(3)`.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Peer address given to requests over a Unix socket, which have none. Unless `unix` is in
+/// `TRUSTED_PROXIES` they all share one anonymous rate limit, see [`client_ip::UNIX_PEER`].
+const UNIX_PEER: SocketAddr = SocketAddr::new(client_ip::UNIX_PEER, 0);
+
+/// How long requests in flight get to finish once shutting down.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+/// Resolves once the process is asked to stop.
+pub type Shutdown = Shared<BoxFuture<'static, ()>>;
+
+/// Waits for SIGTERM or Ctrl-C.
+pub fn shutdown_signal() -> Shutdown {
+    async {
+        let terminate = async {
+            match signal(SignalKind::terminate()) {
+                Ok(mut terminate) => {
+                    terminate.recv().await;
+                }
+                Err(_) => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            () = terminate => {}
+        }
+        println!("Shutting down, finishing requests in flight");
+    }
+    .boxed()
+    .shared()
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Binds everything in `LISTEN`, or takes over the sockets from systemd.
+pub async fn bind(config: &Config) -> anyhow::Result<Vec<Listener>> {
+    let inherited = from_systemd()?;
+    if !inherited.is_empty() {
+        return Ok(inherited);
+    }
+
+    let mut listeners = Vec::new();
+    for address in &config.listen {
+        let listener = bind_one(address)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to listen on {address}: {e}"))?;
+        listeners.push(listener);
+    }
+    if listeners.is_empty() {
+        anyhow::bail!("LISTEN must name at least one address");
+    }
+
+    Ok(listeners)
+}
+
+/// Binds `TLS_REDIRECT_LISTEN`, if set.
+pub async fn bind_redirect(config: &Config) -> anyhow::Result<Option<TcpListener>> {
+    let Some(address) = &config.tls_redirect_listen else {
+        return Ok(None);
+    };
+    match bind_one(address).await {
+        Ok(Listener::Tcp(listener)) => Ok(Some(listener)),
+        Ok(Listener::Unix(_)) => anyhow::bail!("TLS_REDIRECT_LISTEN must be a TCP address"),
+        Err(e) => anyhow::bail!("Failed to listen on {address}: {e}"),
+    }
+}
+
+async fn bind_one(address: &str) -> anyhow::Result<Listener> {
+    if let Some(path) = address.strip_prefix("unix:") {
+        // A socket left behind by a previous run would make the bind fail.
+        if let Ok(metadata) = tokio::fs::metadata(path).await {
+            if metadata.file_type().is_socket() {
+                tokio::fs::remove_file(path).await?;
+            }
+        }
+        return Ok(Listener::Unix(UnixListener::bind(path)?));
+    }
+
+    let address: SocketAddr = address
+        .parse()
+        .map_err(|_| anyhow::anyhow!("not an address like 0.0.0.0:3000 or unix:/path"))?;
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(address),
+        socket2::Type::STREAM,
+        None,
+    )?;
+    // Without this, `[::]:3000` would also claim the IPv4 port and `0.0.0.0:3000` next to it
+    // would fail.
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+
+    Ok(Listener::Tcp(TcpListener::from_std(socket.into())?))
+}
+
+/// The sockets passed by systemd socket activation, if it started us.
+fn from_systemd() -> anyhow::Result<Vec<Listener>> {
+    let Ok(pid) = std::env::var("LISTEN_PID") else {
+        return Ok(Vec::new());
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = std::env::var("LISTEN_FDS")?
+        .parse()
+        .map_err(|_| anyhow::anyhow!("LISTEN_FDS must be a number"))?;
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd hands these descriptors to this process, and nothing else in it
+            // uses them.
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            let unix = std::os::unix::net::UnixListener::from(fd);
+            // Only succeeds for `AF_UNIX` sockets.
+            if unix.local_addr().is_ok() {
+                unix.set_nonblocking(true)?;
+                return Ok(Listener::Unix(UnixListener::from_std(unix)?));
+            }
+            let tcp = std::net::TcpListener::from(OwnedFd::from(unix));
+            tcp.set_nonblocking(true)?;
+            Ok(Listener::Tcp(TcpListener::from_std(tcp)?))
+        })
+        .collect()
+}
+
+/// Serves `app` on all listeners, over HTTPS on TCP when `tls` is set, until one of them
+/// fails. `redirect` serves redirects to the first TCP listener's port.
+pub async fn serve(
+    listeners: Vec<Listener>,
+    app: Router,
+    tls: Option<RustlsConfig>,
+    redirect: Option<TcpListener>,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let https_port = listeners
+        .iter()
+        .find_map(|listener| match listener {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            Listener::Unix(_) => None,
+        })
+        .map_or(443, |address| address.port());
+
+    let mut servers: Vec<futures::future::BoxFuture<anyhow::Result<()>>> = Vec::new();
+    for listener in listeners {
+        let app = app.clone();
+        let tls = tls.clone();
+        let shutdown = shutdown.clone();
+        servers.push(Box::pin(async move {
+            match (listener, tls) {
+                (Listener::Tcp(listener), Some(tls)) => {
+                    let handle = Handle::new();
+                    tokio::spawn({
+                        let handle = handle.clone();
+                        async move {
+                            shutdown.await;
+                            handle.graceful_shutdown(Some(SHUTDOWN_GRACE));
+                        }
+                    });
+                    axum_server::from_tcp_rustls(listener.into_std()?, tls)
+                        .handle(handle)
+                        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                        .await?
+                }
+                (Listener::Tcp(listener), None) => {
+                    let serving = axum::serve(
+                        listener,
+                        app.into_make_service_with_connect_info::<SocketAddr>(),
+                    )
+                    .with_graceful_shutdown(shutdown.clone());
+                    let grace = async {
+                        shutdown.await;
+                        tokio::time::sleep(SHUTDOWN_GRACE).await;
+                    };
+                    tokio::select! {
+                        served = serving => served?,
+                        () = grace => {}
+                    }
+                }
+                (Listener::Unix(listener), _) => serve_unix(listener, app, shutdown).await,
+            }
+            Ok(())
+        }));
+    }
+    if let Some(redirect) = redirect {
+        servers.push(Box::pin(async move {
+            axum::serve(redirect, tls::redirect_app(https_port).into_make_service())
+                .with_graceful_shutdown(shutdown)
+                .await?;
+            Ok(())
+        }));
+    }
+    futures::future::try_join_all(servers).await?;
+
+    Ok(())
+}
+
+async fn serve_unix(listener: UnixListener, app: Router, shutdown: Shutdown) {
+    let app = app.layer(Extension(ConnectInfo(UNIX_PEER)));
+    let mut connections = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = shutdown.clone() => break,
+        };
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                // Usually out of file descriptors; give connections a moment to close.
+                eprintln!("Failed to accept a connection on the Unix socket: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let service = TowerToHyperService::new(app.clone());
+        let shutdown = shutdown.clone();
+        connections.spawn(async move {
+            let builder = Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+            // Errors here are clients going away mid-request.
+            tokio::select! {
+                _ = connection.as_mut() => {}
+                () = shutdown => {
+                    connection.as_mut().graceful_shutdown();
+                    let _ = connection.await;
+                }
+            }
+        });
+        // Reap the connections that are done, so the set doesn't grow.
+        while connections.try_join_next().is_some() {}
+    }
+
+    let _ = tokio::time::timeout(SHUTDOWN_GRACE, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+}
@@ -0,0 +1,96 @@
+//! `GET /api/v1/search/live?q=`, search as you type: the tags starting with what's been
+//! typed so far and a few images tagged with them, small enough to answer on every keystroke.
+//!
+//! Tags are matched by prefix as a range over the `image_tags_tag` index, so the query stays
+//! fast however many images there are; its statements are prepared once per connection and
+//! answers are kept by `response_cache` like other searches. Clients should still wait for a
+//! pause in typing (100-200 ms) before asking.
+
+use axum::{extract::Query, Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{
+    auth::Viewer, error::AppError, ids::ImageKey, tags, thumbnail::Crop, ImageRecord, IMAGE_COLUMNS,
+};
+
+const DEFAULT_LIMIT: i64 = 8;
+const MAX_LIMIT: i64 = 20;
+/// Tags suggested at most.
+const TAG_LIMIT: i64 = 5;
+
+#[derive(Deserialize)]
+pub struct LiveQuery {
+    #[serde(default)]
+    q: String,
+    /// Images returned at most.
+    limit: Option<i64>,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct TagSuggestion {
+    tag: String,
+    /// Images the viewer can see with it.
+    images: i64,
+}
+
+#[derive(Serialize)]
+pub struct LiveImage {
+    id: ImageKey,
+    title: String,
+    thumbnail: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct LiveResults {
+    tags: Vec<TagSuggestion>,
+    images: Vec<LiveImage>,
+}
+
+/// `GET /api/v1/search/live?q=&limit=`
+pub async fn live(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    Query(query): Query<LiveQuery>,
+) -> Result<Json<LiveResults>, AppError> {
+    let prefix = tags::normalize_tag(&query.q);
+    if prefix.is_empty() {
+        return Ok(Json(LiveResults::default()));
+    }
+    // Every tag starting with `prefix` sorts from it up to this.
+    let end = format!("{prefix}\u{10FFFF}");
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let tags = sqlx::query_as(&format!(
+        "SELECT tag, COUNT(*) AS images FROM image_tags JOIN images ON images.id = image_id \
+         WHERE tag >= ?1 AND tag < ?2 AND {} \
+         GROUP BY tag ORDER BY images DESC, tag LIMIT ?3",
+        viewer.visible()
+    ))
+    .bind(&prefix)
+    .bind(&end)
+    .bind(TAG_LIMIT)
+    .fetch_all(&pool)
+    .await?;
+
+    let images = sqlx::query_as::<_, ImageRecord>(&format!(
+        "SELECT {IMAGE_COLUMNS} FROM images \
+         WHERE id IN (SELECT image_id FROM image_tags WHERE tag >= ?1 AND tag < ?2) AND {} \
+         ORDER BY id DESC LIMIT ?3",
+        viewer.visible()
+    ))
+    .bind(&prefix)
+    .bind(&end)
+    .bind(limit)
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|image| LiveImage {
+        thumbnail: image.thumbnail_url(Crop::Fit),
+        id: image.key,
+        title: image.title,
+    })
+    .collect();
+
+    Ok(Json(LiveResults { tags, images }))
+}
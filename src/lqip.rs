@@ -0,0 +1,96 @@
+//! Low-quality image placeholders: a `PLACEHOLDER_WIDTH` pixel wide, heavily compressed JPEG
+//! of each image, made at upload and kept in `image_placeholders`. At a few hundred bytes
+//! they're small enough to inline into HTML as a blurred preview while the real image loads.
+
+use axum::{
+    extract::Query,
+    http::header,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use crate::{
+    auth::Viewer,
+    decode_failures,
+    error::AppError,
+    expiry,
+    ids::{self, ImageId},
+    processor,
+};
+
+pub const PLACEHOLDER_WIDTH: u32 = 24;
+pub const PLACEHOLDER_QUALITY: u8 = 30;
+
+/// Makes and stores the placeholder for image `id`, returning the JPEG.
+pub async fn generate(pool: &SqlitePool, id: i64) -> anyhow::Result<Vec<u8>> {
+    let jpeg = processor::watched(move || {
+        processor::get().placeholder(std::path::Path::new(&processor::raster_source(id)?))
+    })
+    .await;
+    if let Err(e) = &jpeg {
+        decode_failures::record(pool, id, e).await;
+    }
+    let jpeg = jpeg?;
+
+    sqlx::query("INSERT OR REPLACE INTO image_placeholders (image_id, jpeg) VALUES (?, ?)")
+        .bind(id)
+        .bind(&jpeg)
+        .execute(pool)
+        .await?;
+
+    Ok(jpeg)
+}
+
+/// Like [`generate`], for callers that carry on without a placeholder: the endpoint makes
+/// missing ones on demand.
+pub async fn generate_or_log(pool: &SqlitePool, id: i64) {
+    if let Err(e) = generate(pool, id).await {
+        eprintln!("Failed to make placeholder for image {id}: {e:#}");
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PlaceholderQuery {
+    /// `base64` returns a `data:` URI as text instead of the JPEG itself.
+    encoding: Option<String>,
+}
+
+/// `GET /image/:id/lqip`
+pub async fn placeholder(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    ImageId { id, key }: ImageId,
+    Query(query): Query<PlaceholderQuery>,
+) -> Result<Response, AppError> {
+    if crate::fetch_visible_image(&pool, viewer, id)
+        .await?
+        .is_none()
+    {
+        return Ok(expiry::not_found(&pool, id, &key).await);
+    }
+
+    let stored: Option<Vec<u8>> =
+        sqlx::query_scalar("SELECT jpeg FROM image_placeholders WHERE image_id = ?")
+            .bind(id)
+            .fetch_optional(&pool)
+            .await?;
+    let jpeg = match stored {
+        Some(jpeg) => jpeg,
+        // A missing original is as good as no image; a file that can't be decoded is a 422.
+        None => generate(&pool, id)
+            .await
+            .map_err(|e| crate::missing_file(e, || ids::not_found(&key)))?,
+    };
+
+    Ok(match query.encoding.as_deref() {
+        Some("base64") => (
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            format!("data:image/jpeg;base64,{}", STANDARD.encode(jpeg)),
+        )
+            .into_response(),
+        _ => ([(header::CONTENT_TYPE, "image/jpeg")], jpeg).into_response(),
+    })
+}
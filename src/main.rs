@@ -1,280 +1,1861 @@
+mod access_log;
+mod albums;
+mod allowlist;
+mod annotations;
+mod api;
+mod audit;
+mod auth;
+mod blobs;
+mod bucket_ingest;
+mod burst;
+mod cdn;
+mod checksum;
+mod client_ip;
+mod comments;
+mod config;
+mod csrf;
+mod dashboard;
+mod decode_failures;
+mod disk;
+mod doctor;
+mod error;
+mod expiry;
+mod export;
+mod fallback;
+mod favicon;
+mod filters;
+mod fragments;
+mod i18n;
+mod ids;
+mod image_locks;
+mod integrity;
+mod jobs;
+mod journal;
+mod layout;
+mod licenses;
+mod listen;
+mod live_search;
+mod lqip;
+mod maintenance;
+mod markdown;
+mod metadata;
+mod migrations;
+mod moderation;
+mod pagination;
+mod palette;
+mod pdf;
+mod pins;
+mod pipeline;
+mod presigned_upload;
+mod processor;
+mod proxy;
+mod quality_report;
+mod quarantine;
+mod ranking;
+mod raw_upload;
+mod receipts;
+mod replication;
+mod response_cache;
+mod retention;
+mod s3;
+mod saved_searches;
+mod scanner;
+mod security;
+mod served_files;
+mod settings;
+mod similarity;
+mod slow_log;
+mod sql_functions;
+mod stars;
+mod stats;
+mod storage_migration;
+mod svg;
+mod tags;
+mod telemetry;
+mod templates;
+mod throttle;
+mod thumbnail;
+mod timeline;
+mod tls;
+mod upload_policy;
+mod upload_sessions;
+mod versions;
+
+use std::{net::IpAddr, time::Duration};
+
 use axum::{
-    extract::{Multipart, Path},
-    http::{header, HeaderMap},
-    response::{Html, IntoResponse},
-    routing::{get, post},
-    Extension, Form, Json, Router,
+    extract::{Multipart, Query},
+    http::{header, HeaderMap, Method, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{delete, get, patch, post, put},
+    Extension, Json, Router,
 };
 use futures::TryStreamExt;
+use image::ImageFormat;
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, Pool, Row, Sqlite};
+use tokio::io::AsyncReadExt;
 use tokio_util::io::ReaderStream;
+use tower_http::compression::CompressionLayer;
+use tracing::Instrument;
+
+use crate::{
+    auth::{RateLimiter, Viewer},
+    checksum::ExpectedChecksums,
+    client_ip::ClientIp,
+    comments::CommentLimiter,
+    config::{Config, SharedConfig},
+    error::AppError,
+    filters::{FilteredForm, SearchFilters},
+    ids::{IdScheme, ImageId, ImageKey},
+    jobs::{JobKind, JobQueue},
+    pagination::{Page, PageQuery, AFTER_CURSOR, BEFORE_CURSOR},
+    pipeline::{Pipeline, SharedPipeline, Upload},
+    quarantine::{Quarantine, SharedQuarantine},
+    ranking::Sort,
+    scanner::ScanVerdict,
+    served_files::FileInfo,
+    thumbnail::{Crop, Priority},
+};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let pool = setup().await?;
+    dotenv::dotenv()?;
+    if std::env::args().any(|arg| arg == "--doctor") {
+        std::process::exit(if doctor::run_cli().await { 0 } else { 1 });
+    }
+    if std::env::args().any(|arg| arg == "--backfill-albums") {
+        let pool = connect(&Config::from_env()?).await?;
+        let (added, removed) = albums::backfill(&pool).await?;
+        println!("Added {added} images to albums and removed {removed}");
+        return Ok(());
+    }
+    let config = Config::from_env()?;
+    telemetry::init(&config)?;
+    i18n::load("src/locales", &config.default_locale)?;
+    cdn::init(config.public_base_url.as_deref());
+    client_ip::init(&config.trusted_proxies);
+    csrf::init(config.csrf_secret.as_deref());
+    thumbnail::set_max_parallel(config.max_parallel_thumbnails);
+    thumbnail::set_sharpen(config.sharpen);
+    thumbnail::set_quality(config.thumbnail_quality);
+    processor::init(&config.image_processor)?;
+    processor::set_limits(processor::DecodeLimits {
+        max_pixels: config.max_decode_pixels,
+        timeout: config.decode_timeout,
+    });
+    if config.dev_templates {
+        templates::enable_dev();
+    }
+    let pool = setup(&config).await?;
+    journal::recover(&pool).await?;
+    ids::assign_missing(&pool, config.image_id_scheme).await?;
+    expiry::spawn_reaper(pool.clone());
+    if config.blocking_backfills {
+        migrations::run_backfills(&pool).await?;
+    } else {
+        migrations::spawn_backfills(pool.clone());
+    }
+    let scanner = scanner::from_config(&config);
+    let quarantine = quarantine::from_config(&config);
+    let pipeline = pipeline::from_config(&config, scanner.clone())?;
+    if let Some(quarantine) = &quarantine {
+        quarantine.clone().spawn_expiry(pool.clone());
+    }
+    let listeners = listen::bind(&config).await?;
+    let redirect = listen::bind_redirect(&config).await?;
+    let tls = tls::from_config(&config).await?;
+    let access_log = access_log::AccessLog::open(&config)?;
+    let config = settings::load(&pool, config).await?;
+    settings::spawn_watcher(pool.clone(), config.clone());
+    let jobs = JobQueue::start(pool.clone(), config.clone()).await?;
+    // Under a cap, missing thumbnails were most likely evicted, and making them all again
+    // would undo that; they're made when asked for.
+    if config.load().derived_files_max_bytes == 0 {
+        fill_missing_thumbnails(&pool, &jobs).await?;
+    }
+    proxy::spawn_sweeper(config.clone());
+    disk::spawn_monitor(config.clone());
+    upload_sessions::spawn_reaper(pool.clone());
+    integrity::spawn_verifier(pool.clone(), config.clone());
+    served_files::spawn_evictor(pool.clone(), config.clone());
+    retention::spawn_enforcer(pool.clone(), config.clone());
+    storage_migration::resume(pool.clone(), config.clone()).await?;
+
+    // Everything that changes data, and the admin routes, need the API token; reads may be
+    // public. The JSON routes are versioned, see `api`.
+    let api_writes = Router::new()
+        .route(
+            "/image/:id",
+            patch(update_image).merge(
+                put(versions::replace_image)
+                    .route_layer(axum::middleware::from_fn(disk::require_space)),
+            ),
+        )
+        .route(
+            "/image/:id/versions/:n/restore",
+            post(versions::restore_version),
+        )
+        .route("/images/tags", post(tags::edit_tags))
+        .route("/images/auto-tags/confirm", post(tags::confirm_auto_tags))
+        .route("/images/auto-tags/remove", post(tags::remove_auto_tags))
+        .route("/admin/tags/rename", post(tags::rename_tag))
+        .route("/admin/tags/merge", post(tags::merge_tags))
+        .route("/admin/doctor", get(doctor::doctor))
+        .route("/admin/migrations", get(migrations::status))
+        .route("/admin/settings", get(settings::list))
+        .route(
+            "/admin/settings/:name",
+            put(settings::set).delete(settings::remove),
+        )
+        .route("/admin/quarantine", get(quarantine::list))
+        .route("/admin/quarantine/:id", get(quarantine::download))
+        .route_layer(axum::middleware::from_fn(response_cache::invalidate_writes))
+        .route_layer(axum::middleware::from_fn(auth::require_token));
+    let api_reads = Router::new()
+        .route(
+            "/images",
+            get(list_images).route_layer(axum::middleware::from_fn(response_cache::cached)),
+        )
+        .route("/image/:id/tags", get(tags::image_tags))
+        .route("/image/:id/palette", get(palette::palette))
+        .route("/image/:id/versions", get(versions::list_versions))
+        .route(
+            "/search",
+            post(search_images)
+                .route_layer(axum::middleware::from_fn(response_cache::cached))
+                .route_layer(axum::middleware::from_fn(csrf::verify)),
+        )
+        .route("/search/by-image", post(similarity::search_by_image))
+        .route_layer(axum::middleware::from_fn(auth::allow_public));
+    // Routes added since `/api/v1` have no unversioned alias.
+    let legacy_api = api_reads.merge(api_writes);
+    let api = legacy_api
+        .clone()
+        .merge(
+            Router::new()
+                .route("/stats", get(stats::stats))
+                .route("/admin/comments/:id", delete(comments::delete_comment))
+                .route("/image/:id/star", post(stars::toggle_star))
+                .route("/image/:id/pin", post(pins::pin).delete(pins::unpin))
+                .route("/admin/moderation", get(moderation::list))
+                .route("/admin/moderation/:id", put(moderation::decide))
+                .route("/admin/corruption", get(integrity::list))
+                .route("/admin/decode-failures", get(decode_failures::list))
+                .route("/admin/thumbnail-quality", get(quality_report::report))
+                .route("/admin/retention", get(retention::schedule))
+                .route(
+                    "/admin/storage/migrate",
+                    get(storage_migration::progress).post(storage_migration::migrate),
+                )
+                .route(
+                    "/searches",
+                    get(saved_searches::list).post(saved_searches::create),
+                )
+                .route("/searches/:id", delete(saved_searches::delete))
+                .route("/searches/:id/matches", get(saved_searches::matches))
+                .route(
+                    "/album-rules",
+                    get(albums::list_rules).post(albums::create_rule),
+                )
+                .route(
+                    "/upload/raw",
+                    post(raw_upload::upload)
+                        .route_layer(axum::middleware::from_fn(csrf::verify))
+                        .route_layer(axum::middleware::from_fn(disk::require_space)),
+                )
+                .route("/album-rules/:id", delete(albums::delete_rule))
+                .route("/albums/:id", delete(albums::delete))
+                .route(
+                    "/image/:id/annotations",
+                    post(annotations::create_annotation),
+                )
+                .route(
+                    "/image/:id/annotations/:annotation",
+                    patch(annotations::update_annotation).delete(annotations::delete_annotation),
+                )
+                .route_layer(axum::middleware::from_fn(response_cache::invalidate_writes))
+                .route_layer(axum::middleware::from_fn(auth::require_token)),
+        )
+        .merge(
+            Router::new()
+                .route(
+                    "/image/:id/comments",
+                    get(comments::list_comments).post(comments::post_comment),
+                )
+                .route("/image/:id/annotations", get(annotations::list_annotations))
+                .route("/annotations/coco", get(annotations::coco))
+                .route("/image/:id/receipt", get(receipts::receipt))
+                .route("/albums", get(albums::list))
+                .route("/albums/:id/images", get(albums::images))
+                .route("/timeline", get(timeline::timeline))
+                .route("/tags/tree", get(tags::tree))
+                .route(
+                    "/tags/:tag/related",
+                    get(tags::related)
+                        .route_layer(axum::middleware::from_fn(response_cache::cached)),
+                )
+                .route("/search/export", get(export::export))
+                .route(
+                    "/search/live",
+                    get(live_search::live)
+                        .route_layer(axum::middleware::from_fn(response_cache::cached)),
+                )
+                .route_layer(axum::middleware::from_fn(response_cache::invalidate_writes))
+                .route_layer(axum::middleware::from_fn(auth::allow_public)),
+        );
 
-    let app = Router::new()
+    let writes = Router::new()
+        .route(
+            "/upload",
+            post(uploader)
+                .route_layer(axum::middleware::from_fn(csrf::verify))
+                .route_layer(axum::middleware::from_fn(disk::require_space)),
+        )
+        .route("/upload/policy", post(upload_policy::issue))
+        .route("/upload/presign", post(presigned_upload::presign))
+        .route(
+            "/upload/complete",
+            post(presigned_upload::complete)
+                .route_layer(axum::middleware::from_fn(disk::require_space)),
+        )
+        .route(
+            "/ingest/s3",
+            post(bucket_ingest::notify).route_layer(axum::middleware::from_fn(disk::require_space)),
+        )
+        .route(
+            "/upload/session",
+            post(upload_sessions::open).route_layer(axum::middleware::from_fn(csrf::verify)),
+        )
+        .route("/upload/session/:id", get(upload_sessions::status))
+        .route(
+            "/upload/session/:id/commit",
+            post(upload_sessions::commit).route_layer(axum::middleware::from_fn(csrf::verify)),
+        )
+        .route(
+            "/upload/session/:id/abort",
+            post(upload_sessions::abort).route_layer(axum::middleware::from_fn(csrf::verify)),
+        )
+        .route(
+            "/fragments/upload-result",
+            post(fragments::upload_result)
+                .route_layer(axum::middleware::from_fn(csrf::verify))
+                .route_layer(axum::middleware::from_fn(disk::require_space)),
+        )
+        .route(
+            "/fragments/star/:id",
+            post(stars::toggle_star_button).route_layer(axum::middleware::from_fn(csrf::verify)),
+        )
+        .route("/metrics", get(stats::metrics))
+        .route("/admin/moderation", get(moderation::queue_page))
+        .route("/admin/dashboard", get(dashboard::page))
+        .route(
+            "/fragments/moderation/:id/approve",
+            post(moderation::approve_button).route_layer(axum::middleware::from_fn(csrf::verify)),
+        )
+        .route(
+            "/fragments/moderation/:id/reject",
+            post(moderation::reject_button).route_layer(axum::middleware::from_fn(csrf::verify)),
+        )
+        .route_layer(axum::middleware::from_fn(response_cache::invalidate_writes))
+        .route_layer(axum::middleware::from_fn(auth::require_token));
+    let reads = Router::new()
         .route("/", get(home_page))
-        .route("/upload", post(uploader))
-        .route("/image/:id", get(get_image))
-        .route("/thumb/:id", get(get_thumbnail))
-        .route("/images", get(list_images))
+        .route("/static/app.css", get(layout::stylesheet))
+        .route(
+            "/image/:id",
+            get(get_image).route_layer(axum::middleware::from_fn(fallback::missing_image)),
+        )
+        .route("/image/:id/details", get(image_details_page))
+        .route("/timeline", get(timeline::timeline_page))
+        .route("/image/:id/lqip", get(lqip::placeholder))
+        .route("/image/:id/burst.webp", get(burst::preview))
+        .route("/image/:id/favicon.ico", get(favicon::favicon))
+        .route("/image/:id/receipt.png", get(receipts::qr_code))
+        .route(
+            "/image/:id/apple-touch-icon.png",
+            get(favicon::apple_touch_icon),
+        )
+        .route(
+            "/thumb/:id",
+            get(get_thumbnail).route_layer(axum::middleware::from_fn(fallback::missing_image)),
+        )
+        .route(
+            "/i/:file",
+            get(cdn::original).route_layer(axum::middleware::from_fn(fallback::missing_image)),
+        )
+        .route(
+            "/t/:file",
+            get(cdn::thumbnail).route_layer(axum::middleware::from_fn(fallback::missing_image)),
+        )
+        .route("/proxy", get(proxy::proxy))
         .route("/images-html", get(render_images))
         .route("/image-count", get(image_count_page))
-        .route("/search", post(search_images))
-        .layer(Extension(pool));
+        .route("/fragments/gallery", get(fragments::gallery))
+        .route("/fragments/timeline", get(timeline::sections))
+        .route(
+            "/fragments/search-results",
+            post(fragments::search_results).route_layer(axum::middleware::from_fn(csrf::verify)),
+        )
+        .route(
+            "/fragments/comments/:id",
+            post(comments::comment_form)
+                .route_layer(axum::middleware::from_fn(csrf::verify))
+                .route_layer(axum::middleware::from_fn(response_cache::invalidate_writes)),
+        )
+        .route_layer(axum::middleware::from_fn(auth::allow_public));
+
+    // Event streams pages open, which can't send the API token; see `auth::stream_token`.
+    let streams = Router::new()
+        .route("/admin/dashboard/events", get(dashboard::events))
+        .route_layer(axum::middleware::from_fn(auth::require_stream_token));
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await?;
+    // Uploads from browsers on other sites, with a policy instead of the API token.
+    let signed = Router::new()
+        .route(
+            "/upload/signed",
+            post(upload_policy::upload).route_layer(axum::middleware::from_fn(disk::require_space)),
+        )
+        .route_layer(axum::middleware::from_fn(response_cache::invalidate_writes))
+        .route_layer(axum::middleware::from_fn(upload_policy::require_policy))
+        .route_layer(axum::middleware::map_response(
+            upload_policy::allow_any_origin,
+        ));
+
+    let app = reads
+        .merge(writes)
+        .merge(streams)
+        .merge(signed)
+        .nest("/api/v1", api.layer(axum::middleware::from_fn(api::v1)))
+        .merge(legacy_api.layer(axum::middleware::from_fn(api::deprecated)))
+        .layer(axum::middleware::from_fn(security::headers))
+        .layer(axum::middleware::from_fn(allowlist::admin_only))
+        .layer(Extension(pool.clone()))
+        .layer(Extension(scanner))
+        .layer(Extension(pipeline))
+        .layer(Extension(quarantine))
+        .layer(Extension(jobs))
+        .layer(Extension(config))
+        .layer(Extension(RateLimiter::default()))
+        .layer(Extension(CommentLimiter::default()))
+        // The default predicate leaves images (already compressed) and tiny bodies alone, so
+        // this only ever compresses the JSON, HTML and other text responses.
+        .layer(CompressionLayer::new())
+        .layer(axum::middleware::from_fn(i18n::negotiate))
+        .layer(axum::middleware::from_fn(telemetry::trace));
+    // Outermost, so the log has the bytes as sent and the whole time taken.
+    let app = match access_log {
+        Some(log) => app
+            .layer(axum::middleware::from_fn(access_log::record))
+            .layer(Extension(log)),
+        None => app,
+    };
+
+    listen::serve(listeners, app, tls, redirect, listen::shutdown_signal()).await?;
+    maintenance::close(pool).await;
 
     Ok(())
 }
 
-async fn setup() -> anyhow::Result<sqlx::SqlitePool, anyhow::Error> {
-    dotenv::dotenv()?;
+/// Wait before the second attempt at opening the database, doubled after each failure.
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_CONNECT_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// How long a query waits for a connection before failing with 503, rather than hanging
+/// while the database is unavailable.
+const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(5);
 
-    let db_url = std::env::var("DATABASE_URL")?;
-    let db_pool = sqlx::SqlitePool::connect(&db_url).await?;
+async fn setup(config: &Config) -> anyhow::Result<sqlx::SqlitePool, anyhow::Error> {
+    replication::restore_if_missing(config).await?;
 
-    sqlx::migrate!("./migrations").run(&db_pool).await?;
+    // The database may be locked by another process for a moment, or its volume not yet
+    // mounted, so opening it is retried with backoff before giving up.
+    let mut delay = CONNECT_RETRY_DELAY;
+    let mut attempt = 1;
+    let db_pool = loop {
+        match connect(config).await {
+            Ok(pool) => break pool,
+            Err(e) if attempt < config.database_connect_attempts => {
+                eprintln!(
+                    "Opening the database failed (attempt {attempt} of {}), retrying in {}s: {e:#}",
+                    config.database_connect_attempts,
+                    delay.as_secs()
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_CONNECT_RETRY_DELAY);
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(e.context(format!("Database unavailable after {attempt} attempts")))
+            }
+        }
+    };
 
-    fill_missing_thumbnails(&db_pool).await?;
+    if let Some(interval) = config.wal_checkpoint_interval {
+        replication::spawn_checkpointer(db_pool.clone(), interval);
+    }
+    if let Some(interval) = config.analyze_interval {
+        maintenance::spawn_analyzer(db_pool.clone(), interval);
+    }
 
     Ok(db_pool)
 }
 
-async fn image_count_page(Extension(pool): Extension<sqlx::SqlitePool>) -> String {
-    let result = sqlx::query("SELECT COUNT(id) FROM images")
-        .fetch_one(&pool)
-        .await
-        .unwrap();
+/// Opens the pool and brings the schema up to date.
+async fn connect(config: &Config) -> anyhow::Result<sqlx::SqlitePool> {
+    let db_pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .acquire_timeout(ACQUIRE_TIMEOUT)
+        .after_connect(|conn, _| Box::pin(sql_functions::register(conn)))
+        .connect(&config.database_url)
+        .await?;
+    migrations::apply(&db_pool).await?;
+
+    Ok(db_pool)
+}
+
+async fn image_count_page(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+) -> Result<String, AppError> {
+    let result = sqlx::query(&format!(
+        "SELECT COUNT(id) FROM images WHERE {}",
+        viewer.visible()
+    ))
+    .fetch_one(&pool)
+    .await?;
 
     let count = result.get::<i64, _>(0);
-    format!("{count} images in the database")
+    Ok(i18n::t("image_count", &[("count", &count.to_string())]))
 }
 
-async fn home_page() -> Html<String> {
-    let path = std::path::Path::new("src/pages/index.html");
-    let content = tokio::fs::read_to_string(path).await.unwrap();
+async fn home_page(headers: HeaderMap) -> Response {
+    let content = fragments::read_template("index.html").await;
 
-    Html(content)
+    layout::page(&headers, None, &content).await
 }
 
-async fn store_image_to_database(pool: &sqlx::SqlitePool, tags: &str) -> anyhow::Result<i64> {
-    let row = sqlx::query("INSERT INTO images (tags) VALUES (?) RETURNING id")
-        .bind(tags)
-        .fetch_one(pool)
-        .await?;
+/// Details submitted alongside an uploaded image.
+#[derive(Default)]
+struct NewImage {
+    tags: String,
+    title: String,
+    description: String,
+    content_hash: String,
+    private: bool,
+    alt_text: String,
+    /// See `licenses`.
+    license: String,
+    attribution: String,
+    /// Seconds until the image is deleted, if it should be.
+    expires_in: Option<i64>,
+    /// Upload session the image is held back in until it's committed.
+    session_id: Option<String>,
+    /// Under `IMAGE_ID_SCHEME=uuid` or `ulid`, see `ids`.
+    public_id: Option<String>,
+    /// Pending under `MODERATE_UPLOADS`, see `moderation`.
+    status: moderation::Status,
+}
+
+async fn image_details_page(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(viewer): Extension<Viewer>,
+    image_id: ImageId,
+    headers: HeaderMap,
+) -> Response {
+    let config = config.load();
+    let id = image_id.id;
+    let image = match fetch_visible_image(&pool, viewer, id).await {
+        Ok(Some(image)) => image,
+        Ok(None) => return expiry::not_found(&pool, id, &image_id.key).await,
+        Err(e) => return AppError::from(e).into_response(),
+    };
 
-    Ok(row.get(0))
+    let title = if image.title.is_empty() {
+        i18n::t("details.untitled", &[("id", &image.key.to_string())])
+    } else {
+        image.title.clone()
+    };
+
+    let swatches: String = palette::colors(&pool, id)
+        .await
+        .unwrap_or_default()
+        .iter()
+        .map(|color| {
+            format!(
+                "<span class=\"swatch\" title=\"{color}\" style=\"background: {color}\"></span>"
+            )
+        })
+        .collect();
+
+    let comments = match comments::render(&pool, &image_id).await {
+        Ok(comments) => comments,
+        Err(e) => return e.into_response(),
+    };
+
+    let annotations = match annotations::render(&pool, &image).await {
+        Ok(annotations) => annotations,
+        Err(e) => return e.into_response(),
+    };
+
+    let content = fragments::read_template("details.html")
+        .await
+        .replace("{title}", &fragments::escape_html(&title))
+        .replace("{alt}", &fragments::escape_html(image.alt()))
+        .replace("{palette}", &swatches)
+        .replace("{tags}", &fragments::escape_html(&image.tags))
+        .replace("{description}", &markdown::render(&image.description))
+        .replace(
+            "{license}",
+            &licenses::html(&image.license, &image.attribution),
+        )
+        .replace(
+            "{image_url}",
+            &fragments::escape_html(&image.original_url(config.strip_metadata)),
+        )
+        .replace("{annotations}", &annotations)
+        .replace("{comments}", &comments);
+
+    layout::page(&headers, Some(&title), &content).await
 }
 
-async fn save_image(id: i64, bytes: &[u8]) -> anyhow::Result<()> {
+#[tracing::instrument(name = "db.insert_image", skip_all, fields(db.system = "sqlite", image.id))]
+async fn store_image_to_database(
+    conn: &mut sqlx::SqliteConnection,
+    image: &NewImage,
+) -> anyhow::Result<i64> {
+    // Ids are picked past any expired image's too, so an old link never shows a new image.
+    let row = sqlx::query(
+        "INSERT INTO images \
+             (id, tags, title, description, content_hash, private, alt_text, license, \
+              attribution, expires_at, session_id, public_id, status, created_at, updated_at) \
+         VALUES ( \
+             COALESCE((SELECT MAX(id) FROM (SELECT MAX(id) AS id FROM images \
+                 UNION ALL SELECT MAX(image_id) FROM image_tombstones)), 0) + 1, \
+             ?, ?, ?, ?, ?, ?, ?, ?, CAST(strftime('%s', 'now') AS INTEGER) + ?, ?, ?, ?, \
+             CAST(strftime('%s', 'now') AS INTEGER), CAST(strftime('%s', 'now') AS INTEGER)) \
+         RETURNING id",
+    )
+    .bind(&image.tags)
+    .bind(&image.title)
+    .bind(&image.description)
+    .bind(&image.content_hash)
+    .bind(image.private)
+    .bind(&image.alt_text)
+    .bind(&image.license)
+    .bind(&image.attribution)
+    .bind(image.expires_in)
+    .bind(&image.session_id)
+    .bind(&image.public_id)
+    .bind(image.status)
+    .fetch_one(conn)
+    .await?;
+    let id: i64 = row.get(0);
+    tracing::Span::current().record("image.id", id);
+
+    Ok(id)
+}
+
+#[tracing::instrument(name = "storage.save_image", skip(pool, bytes), fields(image.id = id, size = bytes.len()))]
+async fn save_image(
+    pool: &sqlx::SqlitePool,
+    client: Option<IpAddr>,
+    id: i64,
+    bytes: &[u8],
+) -> anyhow::Result<()> {
     let base_path = std::path::Path::new("images");
     if !base_path.exists() || !base_path.is_dir() {
         tokio::fs::create_dir_all(base_path).await?;
     }
 
+    // A file already there was left by an image whose record is gone, e.g. after a crash
+    // or a database restored from backup, as this id was just given out. The same content is
+    // simply linked again; anything else is set aside rather than served as this image's.
     let image_path = base_path.join(format!("{id}.jpg"));
     if image_path.exists() {
-        anyhow::bail!("File already exists");
+        let existing = tokio::fs::read(&image_path).await?;
+        if cdn::content_hash(&existing) != cdn::content_hash(bytes) {
+            set_aside_stale_files(pool, client, id).await?;
+        }
     }
 
-    tokio::fs::write(image_path, bytes).await?;
+    blobs::store(pool, bytes, &format!("images/{id}.jpg")).await
+}
+
+/// Moves image `id`'s files (`images/{id}.*` and `images/{id}_*`) left from before its record
+/// into `images/orphaned/{id}-<time>/`, forgetting what was recorded about them, and notes it
+/// in the audit log.
+async fn set_aside_stale_files(
+    pool: &sqlx::SqlitePool,
+    client: Option<IpAddr>,
+    id: i64,
+) -> anyhow::Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let dir = format!("images/orphaned/{id}-{now}");
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let original = format!("{id}.");
+    let derived = format!("{id}_");
+    let mut entries = tokio::fs::read_dir("images").await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(&original) || name.starts_with(&derived) {
+            let path = format!("images/{name}");
+            tokio::fs::rename(&path, format!("{dir}/{name}")).await?;
+            // The file's gone, so this only drops its link, and the blob if it was the last.
+            blobs::remove(pool, &path).await?;
+        }
+    }
+    served_files::forget(pool, id).await?;
+    audit::record(pool, client, "stale_files_set_aside", Some(id), &dir).await?;
+    eprintln!("Set aside stale files of image {id} in {dir}");
 
     Ok(())
 }
 
-async fn get_image(Path(id): Path<i64>) -> impl IntoResponse {
-    let filename = format!("images/{id}.jpg");
-    let attachment = format!("filename={filename}");
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        header::CONTENT_TYPE,
-        header::HeaderValue::from_static("image/jpeg"),
-    );
-    headers.insert(
-        header::CONTENT_DISPOSITION,
-        header::HeaderValue::from_str(&attachment).unwrap(),
-    );
+#[derive(Deserialize)]
+struct ImageQuery {
+    strip_metadata: Option<bool>,
+}
+
+/// Path of a copy of the original with EXIF/XMP removed, created on first request.
+async fn stripped_image_path(pool: &sqlx::SqlitePool, id: i64) -> anyhow::Result<String> {
+    let stripped_path = format!("images/{id}_stripped.jpg");
+    if !std::path::Path::new(&stripped_path).exists() {
+        let _lock = image_locks::read(id).await;
+        let bytes = tokio::fs::read(format!("images/{id}.jpg")).await?;
+        let partial_path = format!("{stripped_path}.tmp");
+        tokio::fs::write(&partial_path, metadata::strip(&bytes)?).await?;
+        tokio::fs::rename(&partial_path, &stripped_path).await?;
+        served_files::record(pool, id, &stripped_path).await?;
+    }
+
+    Ok(stripped_path)
+}
+
+/// Path of a PNG copy of the original's first page, created on first request.
+async fn converted_image_path(pool: &sqlx::SqlitePool, id: i64) -> anyhow::Result<String> {
+    let converted_path = format!("images/{id}_converted.png");
+    if !std::path::Path::new(&converted_path).exists() {
+        let _lock = image_locks::read(id).await;
+        let partial_path = format!("{converted_path}.tmp");
+        let source = format!("images/{id}.jpg");
+        let partial = partial_path.clone();
+        processor::watched(move || {
+            processor::get().to_png(
+                std::path::Path::new(&source),
+                std::path::Path::new(&partial),
+            )
+        })
+        .await?;
+        tokio::fs::rename(partial_path, &converted_path).await?;
+        served_files::record(pool, id, &converted_path).await?;
+    }
+
+    Ok(converted_path)
+}
 
-    let file = tokio::fs::File::open(&filename).await.unwrap();
+/// Path and content type of the original as served. Browsers can't display TIFF or BMP, so
+/// those are served as a PNG conversion, which also leaves their metadata behind. SVGs are
+/// served as stored, having been sanitized on upload, and so are PDFs. Also gives the
+/// served file's size and hash, see `served_files`.
+async fn served_original(
+    pool: &sqlx::SqlitePool,
+    id: i64,
+    strip_metadata: bool,
+) -> anyhow::Result<(String, &'static str, Option<FileInfo>)> {
+    let path = format!("images/{id}.jpg");
+    let mut head = [0u8; 64];
+    let read = tokio::fs::File::open(&path).await?.read(&mut head).await?;
+    let format = image::guess_format(&head[..read]).ok();
+    let content_type = format.map_or("image/jpeg", |format| format.to_mime_type());
 
-    axum::response::Response::builder()
+    let (path, content_type) = match format {
+        Some(ImageFormat::Tiff | ImageFormat::Bmp) => {
+            (converted_image_path(pool, id).await?, "image/png")
+        }
+        None if svg::is_svg(&head[..read]) => (path, svg::CONTENT_TYPE),
+        None if pdf::is_pdf(&head[..read]) => (path, pdf::CONTENT_TYPE),
+        _ if strip_metadata => (stripped_image_path(pool, id).await?, content_type),
+        _ => (path, content_type),
+    };
+    let info = if path == format!("images/{id}.jpg") {
+        fetch_image_record(pool, id)
+            .await?
+            .as_ref()
+            .and_then(FileInfo::original)
+    } else {
+        Some(served_files::lookup(pool, id, &path).await?)
+    };
+
+    Ok((path, content_type, info))
+}
+
+/// [`served_original`] for a `HEAD` request: worked out from the database without opening
+/// the file, unless the format wasn't recorded (for images from before it was) or the
+/// conversion to serve hasn't been made yet.
+async fn served_original_head(
+    pool: &sqlx::SqlitePool,
+    image: &ImageRecord,
+    strip_metadata: bool,
+) -> anyhow::Result<(String, &'static str, Option<FileInfo>)> {
+    let id = image.id;
+    let original = format!("images/{id}.jpg");
+    let Some(format) = image.format.as_deref() else {
+        return served_original(pool, id, strip_metadata).await;
+    };
+    let (path, content_type) = match format {
+        metadata::SVG_FORMAT => (original, svg::CONTENT_TYPE),
+        metadata::PDF_FORMAT => (original, pdf::CONTENT_TYPE),
+        _ => match ImageFormat::from_extension(format) {
+            Some(ImageFormat::Tiff | ImageFormat::Bmp) => {
+                (format!("images/{id}_converted.png"), "image/png")
+            }
+            Some(format) if strip_metadata => {
+                (format!("images/{id}_stripped.jpg"), format.to_mime_type())
+            }
+            Some(format) => (original, format.to_mime_type()),
+            None => return served_original(pool, id, strip_metadata).await,
+        },
+    };
+    let info = if path == format!("images/{id}.jpg") {
+        FileInfo::original(image)
+    } else {
+        match served_files::known(pool, &path).await? {
+            Some(info) => Some(info),
+            None => return served_original(pool, id, strip_metadata).await,
+        }
+    };
+
+    Ok((path, content_type, info))
+}
+
+/// The response for an image file, with `body`. See [`stream_image`].
+fn file_response(
+    name: &str,
+    content_type: &'static str,
+    info: Option<&FileInfo>,
+    body: axum::body::Body,
+) -> Response {
+    let extension = match content_type {
+        svg::CONTENT_TYPE => metadata::SVG_FORMAT,
+        pdf::CONTENT_TYPE => metadata::PDF_FORMAT,
+        _ => ImageFormat::from_mime_type(content_type).map_or("jpg", metadata::format_name),
+    };
+    let attachment = format!("filename={name}.{extension}");
+
+    let mut response = axum::response::Response::builder()
         .header(
             header::CONTENT_TYPE,
-            header::HeaderValue::from_static("image/jpeg"),
+            header::HeaderValue::from_static(content_type),
         )
         .header(
             header::CONTENT_DISPOSITION,
             header::HeaderValue::from_str(&attachment).unwrap(),
         )
-        .body(axum::body::Body::from_stream(ReaderStream::new(file)))
-        .unwrap()
+        .body(body)
+        .unwrap();
+    if let Some(info) = info {
+        info.apply(response.headers_mut());
+    }
+    response
+}
+
+/// The response to a `HEAD` request for an image file: the headers [`stream_image`] would
+/// send, without opening it.
+async fn head_image(
+    filename: &str,
+    name: &str,
+    content_type: &'static str,
+    info: Option<&FileInfo>,
+) -> std::io::Result<Response> {
+    let mut response = file_response(name, content_type, info, axum::body::Body::empty());
+    if info.is_none() {
+        let length = tokio::fs::metadata(filename).await?.len();
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, header::HeaderValue::from(length));
+    }
+
+    Ok(response)
 }
 
-// TODO: Make generic with get_image
-async fn get_thumbnail(Path(id): Path<i64>) -> impl IntoResponse {
-    let filename = format!("images/{id}_thumb.jpg");
-    let attachment = format!("filename={filename}");
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        header::CONTENT_TYPE,
-        header::HeaderValue::from_static("image/jpeg"),
-    );
-    headers.insert(
-        header::CONTENT_DISPOSITION,
-        header::HeaderValue::from_str(&attachment).unwrap(),
-    );
+/// Streams an image from disk at up to `bytes_per_sec` if given. It's named `name` with the
+/// extension for `content_type` in `Content-Disposition`; files are named by database id,
+/// which with `IMAGE_ID_SCHEME` set mustn't show, and all end in `.jpg`. `info` adds the
+/// file's integrity headers, see `served_files`.
+async fn stream_image(
+    filename: &str,
+    name: &str,
+    content_type: &'static str,
+    info: Option<&FileInfo>,
+    bytes_per_sec: Option<u64>,
+) -> std::io::Result<Response> {
+    let file = tokio::fs::File::open(filename).await?;
 
-    let file = tokio::fs::File::open(&filename).await.unwrap();
+    Ok(file_response(
+        name,
+        content_type,
+        info,
+        axum::body::Body::from_stream(throttle::throttle(ReaderStream::new(file), bytes_per_sec)),
+    ))
+}
 
-    axum::response::Response::builder()
-        .header(
-            header::CONTENT_TYPE,
-            header::HeaderValue::from_static("image/jpeg"),
-        )
-        .header(
-            header::CONTENT_DISPOSITION,
-            header::HeaderValue::from_str(&attachment).unwrap(),
+/// `not_found()` if `error` is about a file that isn't there, as when the image was deleted
+/// while it was being served; an internal error otherwise.
+fn missing_file(error: impl Into<anyhow::Error>, not_found: impl FnOnce() -> AppError) -> AppError {
+    let error = error.into();
+    match error.downcast_ref::<std::io::Error>() {
+        Some(e) if e.kind() == std::io::ErrorKind::NotFound => not_found(),
+        _ => error.into(),
+    }
+}
+
+async fn get_image(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(viewer): Extension<Viewer>,
+    ImageId { id, key }: ImageId,
+    Query(query): Query<ImageQuery>,
+    method: Method,
+) -> Response {
+    let config = config.load();
+    let image = match fetch_visible_image(&pool, viewer, id).await {
+        Ok(Some(image)) => image,
+        Ok(None) => return expiry::not_found(&pool, id, &key).await,
+        Err(e) => return AppError::from(e).into_response(),
+    };
+    let strip_metadata = query.strip_metadata.unwrap_or(config.strip_metadata);
+    // Not permanent: replacing or restoring the image changes its hash.
+    if let Some(hash) = &image.content_hash {
+        return Redirect::temporary(&cdn::original_url(hash, strip_metadata)).into_response();
+    }
+
+    let served = if method == Method::HEAD {
+        served_original_head(&pool, &image, strip_metadata).await
+    } else {
+        served_original(&pool, id, strip_metadata).await
+    };
+    let (path, content_type, info) = match served {
+        Ok(served) => served,
+        Err(e) => return missing_file(e, || ids::not_found(&key)).into_response(),
+    };
+    let response = if method == Method::HEAD {
+        head_image(&path, &key.to_string(), content_type, info.as_ref()).await
+    } else {
+        stream_image(
+            &path,
+            &key.to_string(),
+            content_type,
+            info.as_ref(),
+            throttle::rate(&config, viewer),
         )
-        .body(axum::body::Body::from_stream(ReaderStream::new(file)))
-        .unwrap()
+        .await
+    };
+    match response {
+        Ok(response) => {
+            if method != Method::HEAD {
+                ranking::count_download(&pool, id).await;
+            }
+            response
+        }
+        Err(e) => missing_file(e, || ids::not_found(&key)).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct ThumbnailQuery {
+    #[serde(default)]
+    crop: Crop,
+}
+
+async fn get_thumbnail(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    ImageId { id, key }: ImageId,
+    Query(query): Query<ThumbnailQuery>,
+    method: Method,
+) -> Response {
+    let image = match fetch_visible_image(&pool, viewer, id).await {
+        Ok(Some(image)) => image,
+        Ok(None) => return expiry::not_found(&pool, id, &key).await,
+        Err(e) => return AppError::from(e).into_response(),
+    };
+    if let Some(hash) = &image.content_hash {
+        return Redirect::temporary(&cdn::thumbnail_url(hash, query.crop)).into_response();
+    }
+
+    let filename = thumbnail::thumbnail_path(id, query.crop);
+    // Cropped variants are made on first request, and the job queue may not have got to the
+    // default one yet.
+    if !std::path::Path::new(&filename).exists() {
+        if let Err(e) = thumbnail::make_thumbnail(id, query.crop, Priority::Interactive).await {
+            decode_failures::record(&pool, id, &e).await;
+            return AppError::from(e).into_response();
+        }
+        if let Err(e) = served_files::record(&pool, id, &filename).await {
+            return AppError::from(e).into_response();
+        }
+    }
+    let info = match served_files::lookup(&pool, id, &filename).await {
+        Ok(info) => info,
+        Err(e) => return missing_file(e, || ids::not_found(&key)).into_response(),
+    };
+    let response = if method == Method::HEAD {
+        head_image(&filename, &key.to_string(), "image/jpeg", Some(&info)).await
+    } else {
+        stream_image(&filename, &key.to_string(), "image/jpeg", Some(&info), None).await
+    };
+    response.unwrap_or_else(|e| missing_file(e, || ids::not_found(&key)).into_response())
+}
+
+#[derive(Deserialize)]
+struct UploadQuery {
+    /// Store the upload even if the same file is already stored.
+    #[serde(default)]
+    force: bool,
+    /// Upload session to hold the image back in, see `upload_sessions`.
+    session: Option<String>,
+}
+
+/// How an upload request is handled: its `?force=` and `?session=`, the live
+/// `REQUIRE_ALT_TEXT` and `IMAGE_ID_SCHEME`.
+struct UploadOptions {
+    force: bool,
+    session: Option<String>,
+    /// The address of the client uploading, for the audit log.
+    client: Option<IpAddr>,
+    require_alt_text: bool,
+    id_scheme: IdScheme,
+    moderate: bool,
+    tag_limits: tags::TagLimits,
+    /// The upload policy of a `POST /upload/signed`, see `upload_policy`.
+    policy: Option<upload_policy::Policy>,
+}
+
+#[async_trait::async_trait]
+impl<S: Send + Sync> axum::extract::FromRequestParts<S> for UploadOptions {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<UploadQuery>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let Extension(config) = Extension::<SharedConfig>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let ClientIp(client) = ClientIp::from_request_parts(parts, state).await?;
+
+        let policy = parts.extensions.get::<upload_policy::Policy>().cloned();
+
+        Ok(Self {
+            // Refusing a duplicate would tell whoever holds a policy about the image already
+            // stored, which they may not be allowed to see.
+            force: query.force || policy.is_some(),
+            session: query.session,
+            client: Some(client),
+            policy,
+            ..Self::from_config(&config.load())
+        })
+    }
+}
+
+impl UploadOptions {
+    /// The options of an upload without `?force=` or `?session=`, from no client in particular.
+    fn from_config(config: &Config) -> Self {
+        Self {
+            force: false,
+            session: None,
+            client: None,
+            require_alt_text: config.require_alt_text,
+            id_scheme: config.image_id_scheme,
+            moderate: config.moderate_uploads,
+            tag_limits: config.tag_limits,
+            policy: None,
+        }
+    }
+}
+
+/// What `ingest_upload` did with an upload.
+enum Ingested {
+    Stored(ImageRecord),
+    /// The same file is already stored as this image, and the upload wasn't forced.
+    Duplicate(ImageRecord),
+}
+
+/// The 409 for an upload of a file already stored as `existing`.
+fn duplicate_error(existing: &ImageRecord) -> AppError {
+    AppError::new(
+        StatusCode::CONFLICT,
+        "duplicate_image",
+        format!(
+            "This file was already uploaded as image {}, add ?force=true to store it again",
+            existing.key
+        ),
+    )
+    .with_param("id", existing.key.to_string())
+    .with_extension(
+        "existing",
+        serde_json::json!({
+            "id": existing.key,
+            "thumbnail_url": existing.thumbnail_url(Crop::Fit),
+            "tags": tags::split(&existing.tags),
+        }),
+    )
 }
 
 async fn uploader(
     Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(pipeline): Extension<SharedPipeline>,
+    Extension(jobs): Extension<JobQueue>,
+    Extension(quarantine): Extension<Option<SharedQuarantine>>,
+    options: UploadOptions,
+    headers: HeaderMap,
+    multipart: Multipart,
+) -> Result<Html<String>, AppError> {
+    let checksums = ExpectedChecksums::from_form_headers(&headers)?;
+    let ingested = ingest_upload(
+        &pool,
+        &pipeline,
+        &jobs,
+        quarantine.as_deref(),
+        checksums,
+        options,
+        multipart,
+    )
+    .await?;
+    let image = match ingested {
+        Ingested::Stored(image) => image,
+        Ingested::Duplicate(existing) => return Err(duplicate_error(&existing)),
+    };
+
+    Ok(Html(
+        fragments::render_thumbnails(std::slice::from_ref(&image)).await,
+    ))
+}
+
+/// The text of upload form field `name`.
+fn field_text(name: &str, data: &[u8]) -> Result<String, AppError> {
+    String::from_utf8(data.to_vec()).map_err(|_| {
+        AppError::bad_request("invalid_field", format!("Field {name} isn't valid UTF-8"))
+            .with_param("name", name)
+    })
+}
+
+/// Reads the `tags`, `title`, `description`, `alt_text`, `license`, `attribution` and `image`
+/// fields of an upload form, checks the image against any checksums the client sent and
+/// [`ingest`]s it. Uploads failing the checksum are quarantined.
+async fn ingest_upload(
+    pool: &sqlx::SqlitePool,
+    pipeline: &Pipeline,
+    jobs: &JobQueue,
+    quarantine: Option<&Quarantine>,
+    mut checksums: ExpectedChecksums,
+    mut options: UploadOptions,
     mut multipart: Multipart,
-) -> Html<String> {
+) -> Result<Ingested, AppError> {
     let mut tags = None;
+    let mut details = NewImage::default();
     let mut image = None;
+    let mut file_name = None;
 
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let name = field.name().unwrap().to_string();
-        let data = field.bytes().await.unwrap();
+    while let Some(field) = multipart.next_field().await? {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "image" {
+            file_name = field.file_name().map(str::to_string);
+        }
+        let data = field.bytes().await?;
 
         match name.as_str() {
-            "tags" => tags = Some(String::from_utf8(data.to_vec()).unwrap()),
+            "tags" => tags = Some(field_text(&name, &data)?),
+            "title" => details.title = field_text(&name, &data)?,
+            "description" => details.description = field_text(&name, &data)?,
+            "private" => details.private = matches!(&data[..], b"1" | b"true" | b"on"),
+            "alt_text" => details.alt_text = field_text(&name, &data)?,
+            "license" => details.license = field_text(&name, &data)?,
+            "attribution" => details.attribution = field_text(&name, &data)?,
+            "force" => options.force |= matches!(&data[..], b"1" | b"true" | b"on"),
+            "session" => options.session = Some(field_text(&name, &data)?),
+            "expires_in" => {
+                let seconds = std::str::from_utf8(&data)
+                    .ok()
+                    .and_then(|value| value.trim().parse::<i64>().ok())
+                    .filter(|&seconds| seconds > 0);
+                details.expires_in = Some(seconds.ok_or_else(|| {
+                    AppError::bad_request(
+                        "invalid_expiry",
+                        "`expires_in` must be a positive number of seconds",
+                    )
+                })?);
+            }
             "image" => image = Some(data.to_vec()),
-            _ => panic!("Unknown field: {name}"), // TODO: Handle Error.
+            "content_md5" | "checksum_sha256" => {
+                checksums.set_field(&name, field_text(&name, &data)?);
+            }
+            _ => {
+                return Err(AppError::bad_request(
+                    "unknown_field",
+                    format!("Unknown field: {name}"),
+                )
+                .with_param("name", name))
+            }
         }
     }
 
-    let p = std::path::Path::new("src/pages/thumbnail.html");
-    let mut template = tokio::fs::read_to_string(p).await.unwrap();
+    if let Some(policy) = &options.policy {
+        if options.session.is_some() {
+            return Err(AppError::new(
+                StatusCode::FORBIDDEN,
+                "session_not_allowed",
+                "Uploads with a policy can't go into upload sessions",
+            ));
+        }
+        if let Some(image) = &image {
+            policy.check(image)?;
+        }
+        tags = Some(policy.tag(tags));
+    }
+    let (Some(tags), Some(image)) = (tags, image) else {
+        return Err(AppError::bad_request(
+            "missing_field",
+            "Uploads need both `tags` and `image` fields",
+        ));
+    };
+    if let Err(e) = checksums.verify(&image) {
+        if let Some(quarantine) = quarantine {
+            quarantine
+                .store(pool, &image, file_name.as_deref(), &e)
+                .await;
+        }
+        return Err(e);
+    }
 
-    if let (Some(tags), Some(image)) = (tags, image) {
-        // TODO: Return response header instead on failure rather than erroring out.
-        let image_id = store_image_to_database(&pool, &tags).await.unwrap();
-        save_image(image_id, &image).await.unwrap();
-        make_thumbnail(image_id).await.unwrap();
+    details.tags = tags;
+    let upload = Upload {
+        bytes: image,
+        file_name,
+        details,
+        metadata: Vec::new(),
+        auto_tags: false,
+        follow_ups: Vec::new(),
+        client: options.client,
+    };
+    ingest(pool, pipeline, jobs, quarantine, &options, upload).await
+}
 
-        template = template.replace("{tags}", &tags);
-        template = template.replace("{id}", &image_id.to_string());
-    } else {
-        panic!("Missing field"); // TODO: Handle Error. -> Return 400 Bad Request
+/// Runs a new image through the upload pipeline, then stores the record, the original and
+/// its metadata and queues its thumbnail. `upload.details.tags` are normalized here, and the
+/// metadata extracted. Images rejected by stages that ask for it are quarantined.
+///
+/// Files that are already stored are turned away unless `options.force` is set. Images put
+/// into an upload session (`options.session`) stay hidden until it's committed.
+async fn ingest(
+    pool: &sqlx::SqlitePool,
+    pipeline: &Pipeline,
+    jobs: &JobQueue,
+    quarantine: Option<&Quarantine>,
+    options: &UploadOptions,
+    mut upload: Upload,
+) -> Result<Ingested, AppError> {
+    upload.details.license = licenses::normalize(&upload.details.license)?;
+    let details = &upload.details;
+    check_alt_text(options.require_alt_text, details.private, &details.alt_text)?;
+    licenses::check(&details.license, &details.attribution)?;
+    upload.bytes = svg::sanitize(std::mem::take(&mut upload.bytes))?;
+    upload.details.tags = tags::normalize(&upload.details.tags, options.tag_limits)?.join(", ");
+    upload.metadata = metadata::extract(&upload.bytes);
+    pipeline.run(pool, quarantine, &mut upload).await?;
+    upload.details.content_hash = cdn::content_hash(&upload.bytes);
+    if !options.force {
+        if let Some(existing) = find_duplicate(pool, &upload.details.content_hash).await? {
+            return Ok(Ingested::Duplicate(existing));
+        }
     }
 
-    Html(template.to_string())
+    if let Some(session) = &options.session {
+        upload_sessions::check_open(pool, session).await?;
+        upload.details.session_id = Some(session.clone());
+    }
+    upload.details.public_id = options.id_scheme.generate();
+    if options.moderate {
+        upload.details.status = moderation::Status::Pending;
+    }
+    let mut entry = journal::begin(pool).await?;
+    let image_id = match store_upload(pool, jobs, upload, &mut entry).await {
+        Ok(image_id) => image_id,
+        Err(e) => {
+            entry.abandon(pool).await;
+            return Err(e.into());
+        }
+    };
+    entry.finish(pool).await?;
+    if let Some(session) = &options.session {
+        upload_sessions::settle(pool, session, image_id).await?;
+    }
+
+    Ok(Ingested::Stored(
+        fetch_image_record(pool, image_id)
+            .await?
+            .expect("image was just inserted"),
+    ))
+}
+
+/// Stores an upload that passed the pipeline: its record, original, metadata, derived data,
+/// tags and jobs, journaling each step in `entry`.
+async fn store_upload(
+    pool: &sqlx::SqlitePool,
+    jobs: &JobQueue,
+    upload: Upload,
+    entry: &mut journal::Entry,
+) -> anyhow::Result<i64> {
+    let mut tx = pool.begin().await?;
+    let image_id = store_image_to_database(&mut tx, &upload.details).await?;
+    entry.created(&mut tx, image_id).await?;
+    tx.commit().await?;
+
+    entry.step(pool, "original").await?;
+    save_image(pool, upload.client, image_id, &upload.bytes).await?;
+    entry.step(pool, "metadata").await?;
+    metadata::store(pool, image_id, &upload.metadata).await?;
+    metadata::store_file_info(pool, image_id, &upload.bytes).await?;
+    lqip::generate_or_log(pool, image_id).await;
+    palette::generate_or_log(pool, image_id).await;
+    similarity::fingerprint_or_log(pool, image_id).await;
+    entry.step(pool, "tags").await?;
+    let mut tx = pool.begin().await?;
+    tags::insert(
+        &mut tx,
+        image_id,
+        &tags::split(&upload.details.tags),
+        upload.auto_tags,
+    )
+    .await?;
+    tx.commit().await?;
+    entry.step(pool, "jobs").await?;
+    jobs.enqueue(JobKind::Thumbnail, image_id).await?;
+    jobs.enqueue(JobKind::SavedSearches, image_id).await?;
+    for kind in upload.follow_ups {
+        jobs.enqueue(kind, image_id).await?;
+    }
+
+    Ok(image_id)
+}
+
+/// The oldest image whose original hashes to `hash`.
+async fn find_duplicate(
+    pool: &sqlx::SqlitePool,
+    hash: &str,
+) -> anyhow::Result<Option<ImageRecord>> {
+    let record = sqlx::query_as::<_, ImageRecord>(&format!(
+        "SELECT {IMAGE_COLUMNS} FROM images WHERE content_hash = ? ORDER BY id LIMIT 1"
+    ))
+    .bind(hash)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(record)
+}
+
+/// Rejects public images without alt text when `REQUIRE_ALT_TEXT` is set.
+fn check_alt_text(required: bool, private: bool, alt_text: &str) -> Result<(), AppError> {
+    if required && !private && alt_text.trim().is_empty() {
+        return Err(AppError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "missing_alt_text",
+            "Public images need an `alt_text` describing them",
+        ));
+    }
+
+    Ok(())
 }
 
-async fn fill_missing_thumbnails(pool: &Pool<Sqlite>) -> anyhow::Result<()> {
+/// Rejects uploads whose format isn't recognised or whose header can't be decoded. This only
+/// reads the dimensions; the full decode happens when the thumbnail is made.
+fn check_readable(image: &[u8]) -> Result<(), AppError> {
+    if svg::is_svg(image) && svg::size(image).is_some() || pdf::is_readable(image) {
+        return Ok(());
+    }
+    let (width, height) = image::io::Reader::new(std::io::Cursor::new(image))
+        .with_guessed_format()
+        .ok()
+        .filter(|reader| reader.format().is_some())
+        .and_then(|reader| reader.into_dimensions().ok())
+        .ok_or_else(|| {
+            AppError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "unreadable_image",
+                "The upload is not an image we can read",
+            )
+        })?;
+    processor::check_size(width, height).map_err(|e| e.to_error())
+}
+
+/// Rejects `image` if the configured scanner finds malware in it, recording the attempt in
+/// the audit log along with `detail`.
+async fn scan_upload(
+    pool: &sqlx::SqlitePool,
+    client: Option<IpAddr>,
+    scanner: Option<&dyn scanner::Scanner>,
+    image: &[u8],
+    image_id: Option<i64>,
+    detail: &str,
+) -> Result<(), AppError> {
+    let Some(scanner) = scanner else {
+        return Ok(());
+    };
+
+    let verdict = scanner.scan(image).await.map_err(|e| {
+        eprintln!("Malware scan failed: {e:#}");
+        AppError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "scanner_unavailable",
+            "The upload could not be scanned for malware",
+        )
+    })?;
+    if let ScanVerdict::Infected(signature) = verdict {
+        audit::record(
+            pool,
+            client,
+            "upload_rejected_malware",
+            image_id,
+            &format!("signature={signature} {detail}"),
+        )
+        .await?;
+        return Err(AppError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "malware_detected",
+            format!("Upload rejected: {signature}"),
+        )
+        .with_param("signature", signature));
+    }
+
+    Ok(())
+}
+
+async fn fill_missing_thumbnails(pool: &Pool<Sqlite>, jobs: &JobQueue) -> anyhow::Result<()> {
     let mut rows = sqlx::query("SELECT id FROM images").fetch(pool);
 
     while let Some(row) = rows.try_next().await? {
         let id = row.get::<i64, _>(0);
-        let thumbnail_path = format!("images/{id}_thumb.jpg");
+        let thumbnail_path = thumbnail::thumbnail_path(id, Crop::Fit);
         if !std::path::Path::new(&thumbnail_path).exists() {
-            make_thumbnail(id).await?;
+            jobs.enqueue(JobKind::Thumbnail, id).await?;
         }
     }
 
     Ok(())
 }
 
-async fn make_thumbnail(id: i64) -> anyhow::Result<()> {
-    let image_path = format!("images/{id}.jpg");
-    let thumbnail_path = format!("images/{id}_thumb.jpg");
-    let image_bytes: Vec<u8> = std::fs::read(image_path)?;
+/// Columns selected into an [`ImageRecord`].
+const IMAGE_COLUMNS: &str = "id, tags, title, description, version, created_at, updated_at, \
+     content_hash, private, expires_at, alt_text, width, height, byte_size, format, starred, \
+     pinned, session_id, taken_at, public_id, status, orientation, burst_size, license, \
+     attribution";
 
-    let image = if let Ok(format) = image::guess_format(&image_bytes) {
-        image::load_from_memory_with_format(&image_bytes, format)?
-    } else {
-        image::load_from_memory(&image_bytes)?
-    };
+#[derive(Serialize, FromRow, Debug)]
+struct ImageRecord {
+    #[serde(skip_serializing)]
+    id: i64,
+    /// How the image is named in URLs and responses, see `ids`.
+    #[sqlx(flatten)]
+    #[serde(rename = "id")]
+    key: ImageKey,
+    tags: String,
+    title: String,
+    description: String,
+    version: i64,
+    created_at: i64,
+    updated_at: i64,
+    /// SHA-256 of the original, `None` until hashed for images from before it was recorded.
+    content_hash: Option<String>,
+    /// Hidden from anonymous visitors of a public gallery.
+    private: bool,
+    /// When the image will be deleted, for uploads with `expires_in`.
+    expires_at: Option<i64>,
+    /// Text alternative for screen readers; empty when none was given.
+    alt_text: String,
+    /// Dimensions, size in bytes and format (`jpg`, `png`, ...) of the original; `None`
+    /// until recorded for images from before they were, and for unreadable files.
+    width: Option<i64>,
+    height: Option<i64>,
+    byte_size: Option<i64>,
+    format: Option<String>,
+    #[serde(default)]
+    starred: bool,
+    /// Kept from deletion and expiry, see `pins`.
+    #[serde(default)]
+    pinned: bool,
+    /// The open upload session holding the image back, see `upload_sessions`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session_id: Option<String>,
+    /// When the photo was taken according to its EXIF, see `metadata::taken_at`.
+    #[serde(default)]
+    taken_at: Option<i64>,
+    /// Whether anonymous visitors may see it yet, see `moderation`.
+    status: moderation::Status,
+    /// `landscape`, `portrait` or `square`, from the dimensions; `None` without them.
+    orientation: Option<String>,
+    /// Images in the burst this one starts, which has an animated preview; see `burst`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    burst_size: Option<i64>,
+    /// SPDX identifier of the license, empty when none was given; see `licenses`.
+    #[serde(default)]
+    license: String,
+    /// Who to credit for the image.
+    #[serde(default)]
+    attribution: String,
+}
 
-    let thumbnail = image.thumbnail(100, 100);
-    thumbnail.save(thumbnail_path)?;
+impl ImageRecord {
+    /// The `alt` attribute for the image: its alt text, or failing that its title.
+    fn alt(&self) -> &str {
+        if self.alt_text.trim().is_empty() {
+            &self.title
+        } else {
+            &self.alt_text
+        }
+    }
 
-    Ok(())
+    fn etag(&self) -> String {
+        format!("\"{}\"", self.version)
+    }
+
+    fn original_url(&self, strip_metadata: bool) -> String {
+        match &self.content_hash {
+            Some(hash) => cdn::original_url(hash, strip_metadata),
+            None => format!("/image/{}", self.key),
+        }
+    }
+
+    fn thumbnail_url(&self, crop: Crop) -> String {
+        match &self.content_hash {
+            Some(hash) => cdn::thumbnail_url(hash, crop),
+            None => format!("/thumb/{}", self.key),
+        }
+    }
 }
 
-#[derive(Deserialize, Serialize, FromRow, Debug)]
-struct ImageRecord {
+#[tracing::instrument(name = "db.fetch_image", skip(pool), fields(db.system = "sqlite", image.id = id))]
+async fn fetch_image_record(
+    pool: &sqlx::SqlitePool,
     id: i64,
-    tags: String,
+) -> anyhow::Result<Option<ImageRecord>> {
+    let record = sqlx::query_as::<_, ImageRecord>(&format!(
+        "SELECT {IMAGE_COLUMNS} FROM images WHERE id = ?"
+    ))
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(record)
 }
 
-async fn list_images(Extension(pool): Extension<sqlx::SqlitePool>) -> Json<Vec<ImageRecord>> {
-    sqlx::query_as::<_, ImageRecord>("SELECT id, tags FROM images ORDER BY id")
-        .fetch_all(&pool)
-        .await
-        .unwrap()
-        .into()
+/// Like [`fetch_image_record`], but `None` for private images the viewer may not see.
+async fn fetch_visible_image(
+    pool: &sqlx::SqlitePool,
+    viewer: Viewer,
+    id: i64,
+) -> anyhow::Result<Option<ImageRecord>> {
+    let record = fetch_image_record(pool, id).await?;
+
+    Ok(record
+        .filter(|image| image.session_id.is_none() && viewer.can_see(image.private, image.status)))
 }
 
-async fn render_images(Extension(pool): Extension<sqlx::SqlitePool>) -> Html<String> {
-    let images = sqlx::query_as::<_, ImageRecord>("SELECT id, tags FROM images ORDER BY id")
-        .fetch_all(&pool)
-        .await
-        .unwrap();
+/// Fields of `PATCH /image/:id`; anything left out keeps its current value.
+#[derive(Deserialize)]
+struct ImageUpdate {
+    tags: Option<String>,
+    title: Option<String>,
+    description: Option<String>,
+    private: Option<bool>,
+    alt_text: Option<String>,
+    license: Option<String>,
+    attribution: Option<String>,
+}
+
+enum Precondition {
+    Any,
+    Version(i64),
+    UnmodifiedSince(i64),
+}
+
+impl Precondition {
+    /// Reads `If-Match` (preferred) or `If-Unmodified-Since` from the request.
+    fn from_headers(headers: &HeaderMap) -> Result<Self, (StatusCode, &'static str)> {
+        if let Some(if_match) = headers.get(header::IF_MATCH) {
+            let etag = if_match.to_str().unwrap_or_default().trim();
+            if etag == "*" {
+                return Ok(Precondition::Any);
+            }
+            // An ETag we can't parse can never match the current record.
+            return etag
+                .trim_start_matches("W/")
+                .trim_matches('"')
+                .parse()
+                .map(Precondition::Version)
+                .map_err(|_| (StatusCode::PRECONDITION_FAILED, "Malformed If-Match header"));
+        }
+
+        if let Some(since) = headers.get(header::IF_UNMODIFIED_SINCE) {
+            let since = since
+                .to_str()
+                .ok()
+                .and_then(|date| httpdate::parse_http_date(date).ok())
+                .ok_or((
+                    StatusCode::BAD_REQUEST,
+                    "Malformed If-Unmodified-Since header",
+                ))?;
+            let secs = since
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            return Ok(Precondition::UnmodifiedSince(secs as i64));
+        }
+
+        Err((
+            StatusCode::PRECONDITION_REQUIRED,
+            "PATCH requires an If-Match or If-Unmodified-Since header",
+        ))
+    }
+}
+
+const UPDATE_IMAGE: &str = "UPDATE images \
+    SET tags = COALESCE(?, tags), title = COALESCE(?, title), description = COALESCE(?, description), \
+    private = COALESCE(?, private), alt_text = COALESCE(?, alt_text), \
+    license = COALESCE(?, license), attribution = COALESCE(?, attribution), \
+    version = version + 1, updated_at = CAST(strftime('%s', 'now') AS INTEGER) \
+    WHERE id = ?";
+
+/// Updates an image's tags, title, description, privacy, alt text, license and attribution.
+/// The client must send either `If-Match` (the record's ETag) or `If-Unmodified-Since`; if
+/// the record changed in the meantime we answer 412 with the current record so the client
+/// can merge and retry.
+async fn update_image(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    ImageId { id, key }: ImageId,
+    headers: HeaderMap,
+    Json(mut update): Json<ImageUpdate>,
+) -> Result<Response, AppError> {
+    let config = config.load();
+    if let Some(tags) = &update.tags {
+        update.tags = Some(tags::normalize(tags, config.tag_limits)?.join(", "));
+    }
+    if let Some(license) = &update.license {
+        update.license = Some(licenses::normalize(license)?);
+    }
+    let precondition = match Precondition::from_headers(&headers) {
+        Ok(precondition) => precondition,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+    // Held until the tag rows are synced too.
+    let _lock = image_locks::write(id).await;
+    let licensing = update.license.is_some() || update.attribution.is_some();
+    if config.require_alt_text || licensing {
+        if let Some(current) = fetch_image_record(&pool, id).await? {
+            let private = update.private.unwrap_or(current.private);
+            let alt_text = update.alt_text.as_deref().unwrap_or(&current.alt_text);
+            check_alt_text(config.require_alt_text, private, alt_text)?;
+            let license = update.license.as_deref().unwrap_or(&current.license);
+            let attribution = update
+                .attribution
+                .as_deref()
+                .unwrap_or(&current.attribution);
+            licenses::check(license, attribution)?;
+        }
+    }
 
-    let p = std::path::Path::new("src/pages/thumbnail.html");
-    let template = tokio::fs::read_to_string(p).await.unwrap();
+    let (sql, expected) = match precondition {
+        Precondition::Any => (format!("{UPDATE_IMAGE} AND ? IS NULL"), None),
+        Precondition::Version(version) => {
+            (format!("{UPDATE_IMAGE} AND version = ?"), Some(version))
+        }
+        Precondition::UnmodifiedSince(secs) => {
+            (format!("{UPDATE_IMAGE} AND updated_at <= ?"), Some(secs))
+        }
+    };
 
-    let mut image_html = String::new();
-    for image in images {
-        let mut _tmp = template.clone();
-        _tmp = _tmp.replace("{tags}", &image.tags);
-        _tmp = _tmp.replace("{id}", &image.id.to_string());
+    // The row and its tag rows change together or not at all.
+    let mut tx = pool.begin().await?;
+    let updated = sqlx::query_as::<_, ImageRecord>(&format!("{sql} RETURNING {IMAGE_COLUMNS}"))
+        .bind(&update.tags)
+        .bind(&update.title)
+        .bind(&update.description)
+        .bind(update.private)
+        .bind(&update.alt_text)
+        .bind(&update.license)
+        .bind(&update.attribution)
+        .bind(id)
+        .bind(expected)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if let (Some(_), Some(tags)) = (&updated, &update.tags) {
+        tags::sync(&mut tx, id, tags).await?;
+    }
+    tx.commit().await?;
 
-        image_html.push_str(&_tmp);
+    if let Some(record) = updated {
+        if update.private.is_some() {
+            burst::invalidate(&pool, id).await?;
+        }
+        let etag = record.etag();
+        return Ok(([(header::ETAG, etag)], Json(record)).into_response());
     }
 
-    Html(image_html.to_string())
+    Ok(match fetch_image_record(&pool, id).await? {
+        Some(current) => {
+            let etag = current.etag();
+            (
+                StatusCode::PRECONDITION_FAILED,
+                [(header::ETAG, etag)],
+                Json(current),
+            )
+                .into_response()
+        }
+        None => expiry::not_found(&pool, id, &key).await,
+    })
+}
+
+async fn list_images(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    Query(query): Query<PageQuery>,
+    Query(filters): Query<SearchFilters>,
+) -> Result<Json<Page>, AppError> {
+    let (cursor, limit) = query.parse()?;
+    let filters = filters.compile(4)?;
+
+    let images = filters
+        .bind(
+            sqlx::query_as::<_, ImageRecord>(&format!(
+                "SELECT {IMAGE_COLUMNS} FROM images WHERE {} AND {AFTER_CURSOR}{} \
+                 ORDER BY created_at, id LIMIT ?3",
+                viewer.visible(),
+                filters.sql
+            ))
+            .bind(cursor.map(|cursor| cursor.created_at))
+            .bind(cursor.map(|cursor| cursor.id))
+            .bind(limit + 1),
+        )
+        .fetch_all(&pool)
+        .instrument(tracing::info_span!("db.list_images", db.system = "sqlite"))
+        .await?;
+
+    Ok(Json(Page::from_rows(images, limit)))
+}
+
+async fn render_images(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+) -> Result<Html<String>, AppError> {
+    let images = sqlx::query_as::<_, ImageRecord>(&format!(
+        "SELECT {IMAGE_COLUMNS} FROM images WHERE {} ORDER BY id",
+        viewer.visible()
+    ))
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Html(fragments::render_thumbnails(&images).await))
 }
 
 #[derive(Deserialize)]
 struct Search {
     tags: String,
+    /// Exact tag terms, see `tag_match` in [`sql_functions`].
+    filter: Option<String>,
+    #[serde(default)]
+    sort: Sort,
+    cursor: Option<String>,
+    limit: Option<i64>,
 }
 
-async fn search_images(
-    Extension(pool): Extension<sqlx::SqlitePool>,
-    Form(form): Form<Search>,
-) -> Html<String> {
-    let tag = format!("%{}%", form.tags);
+/// Matches the query against tags, title and description, in the order of `order_by` (see
+/// [`Sort::order_by`], with the pattern as `?1`).
+#[tracing::instrument(name = "db.search", skip_all, fields(db.system = "sqlite"))]
+async fn search_by_tags(
+    pool: &sqlx::SqlitePool,
+    viewer: Viewer,
+    tags: &str,
+    filters: &SearchFilters,
+    order_by: String,
+) -> Result<Vec<ImageRecord>, AppError> {
+    let tag = format!("%{tags}%");
+    let filters = filters.compile(2)?;
 
-    let images = sqlx::query_as::<_, ImageRecord>(
-        "SELECT id, tags FROM images WHERE tags LIKE ? ORDER BY id",
-    )
-    .bind(tag)
-    .fetch_all(&pool)
-    .await
-    .unwrap();
+    let images = filters
+        .bind(
+            sqlx::query_as::<_, ImageRecord>(&format!(
+                "SELECT {IMAGE_COLUMNS} FROM images \
+                 WHERE (tags LIKE ?1 OR title LIKE ?1 OR description LIKE ?1) AND {}{} \
+                 ORDER BY {order_by}",
+                viewer.visible(),
+                filters.sql
+            ))
+            .bind(tag),
+        )
+        .fetch_all(pool)
+        .await?;
 
-    let p = std::path::Path::new("src/pages/thumbnail.html");
-    let template = tokio::fs::read_to_string(p).await.unwrap();
+    Ok(images)
+}
 
-    let mut image_html = String::new();
-    for image in images {
-        let mut _tmp = template.clone();
-        _tmp = _tmp.replace("{tags}", &image.tags);
-        _tmp = _tmp.replace("{id}", &image.id.to_string());
+async fn search_images(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(viewer): Extension<Viewer>,
+    FilteredForm(form, filters): FilteredForm<Search>,
+) -> Result<Json<Page>, AppError> {
+    let page = PageQuery {
+        cursor: form.cursor,
+        limit: form.limit,
+    };
+    // Ranked orders page by offset (`?7`), the others after the last row (`?1`, `?2`).
+    let (cursor, offset, limit) = if form.sort.ranked() {
+        let (offset, limit) = page.parse_offset()?;
+        (None, offset, limit)
+    } else {
+        let (cursor, limit) = page.parse()?;
+        (cursor, 0, limit)
+    };
+    let after = match form.sort {
+        Sort::Newest => BEFORE_CURSOR,
+        _ => AFTER_CURSOR,
+    };
+    let config = config.load();
+    let filters = filters.compile(8)?;
 
-        image_html.push_str(&_tmp);
-    }
+    let images = filters
+        .bind(
+            sqlx::query_as::<_, ImageRecord>(&format!(
+                "SELECT {IMAGE_COLUMNS} FROM images \
+                 WHERE (tags LIKE ?3 OR title LIKE ?3 OR description LIKE ?3) \
+                     AND (?5 IS NULL OR tag_match(tags, ?5, ?6)) AND {} AND {after}{} \
+                 ORDER BY {} LIMIT ?4 OFFSET ?7",
+                viewer.visible(),
+                filters.sql,
+                form.sort.order_by(&config, 3)
+            ))
+            .bind(cursor.map(|cursor| cursor.created_at))
+            .bind(cursor.map(|cursor| cursor.id))
+            .bind(format!("%{}%", form.tags))
+            .bind(limit + 1)
+            .bind(form.filter)
+            .bind(config.tag_search_descendants)
+            .bind(offset),
+        )
+        .fetch_all(&pool)
+        .instrument(tracing::info_span!("db.search", db.system = "sqlite"))
+        .await?;
 
-    Html(image_html.to_string())
+    Ok(Json(if form.sort.ranked() {
+        Page::from_ranked_rows(images, limit, offset)
+    } else {
+        Page::from_rows(images, limit)
+    }))
 }
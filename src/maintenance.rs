@@ -0,0 +1,43 @@
+//! Keeping the database file compact and its query plans good as the tables grow.
+//!
+//! Every `ANALYZE_SECS` (a day by default) `ANALYZE` refreshes the statistics the query
+//! planner picks indexes by. On a graceful shutdown, once the last request is answered, the
+//! WAL is checkpointed into the main file and truncated, `PRAGMA optimize` runs, and the pool
+//! is closed, so the next start (or a copy of the file) finds one consistent database file.
+
+use std::time::Duration;
+
+use sqlx::SqlitePool;
+
+/// Runs `ANALYZE` every `interval`.
+pub fn spawn_analyzer(pool: SqlitePool, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            if let Err(e) = sqlx::query("ANALYZE").execute(&pool).await {
+                eprintln!("ANALYZE failed: {e}");
+            }
+        }
+    });
+}
+
+/// Checkpoints and optimizes the database, then closes `pool`. Failures are logged: the
+/// process is exiting either way, and SQLite recovers from the WAL on the next start.
+pub async fn close(pool: SqlitePool) {
+    match sqlx::query_as::<_, (i64, i64, i64)>("PRAGMA wal_checkpoint(TRUNCATE)")
+        .fetch_one(&pool)
+        .await
+    {
+        // Readers or writers still busy kept part of the WAL from being copied back.
+        Ok((busy, _, _)) if busy != 0 => eprintln!("WAL checkpoint at shutdown was incomplete"),
+        Ok(_) => {}
+        Err(e) => eprintln!("WAL checkpoint at shutdown failed: {e}"),
+    }
+    if let Err(e) = sqlx::query("PRAGMA optimize").execute(&pool).await {
+        eprintln!("PRAGMA optimize at shutdown failed: {e}");
+    }
+    pool.close().await;
+}
@@ -0,0 +1,12 @@
+use pulldown_cmark::{html, Options, Parser};
+
+/// Renders user-supplied markdown to HTML, stripping anything that isn't on ammonia's
+/// allowlist (scripts, event handlers, `javascript:` links, ...).
+pub fn render(text: &str) -> String {
+    let parser = Parser::new_ext(text, Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES);
+
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, parser);
+
+    ammonia::clean(&unsafe_html)
+}
@@ -0,0 +1,339 @@
+use axum::http::StatusCode;
+use image::ImageFormat;
+use sqlx::SqlitePool;
+
+use crate::{error::AppError, filters, pdf, svg};
+
+const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+const JPEG_APP1: u8 = 0xE1;
+const JPEG_SOS: u8 = 0xDA;
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const XMP_JPEG_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_PNG_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+
+/// Reads the EXIF fields (and raw XMP packet, if any) out of an image as `(tag, value)` pairs.
+pub fn extract(bytes: &[u8]) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+
+    if let Ok(exif) = exif::Reader::new().read_from_container(&mut std::io::Cursor::new(bytes)) {
+        for field in exif.fields() {
+            fields.push((
+                field.tag.to_string(),
+                field.display_value().with_unit(&exif).to_string(),
+            ));
+        }
+    }
+
+    if let Some(xmp) = find_jpeg_xmp(bytes) {
+        fields.push(("XMP".to_string(), String::from_utf8_lossy(xmp).into_owned()));
+    }
+
+    fields
+}
+
+/// Records the fields for image `id`, and when it was taken according to them.
+pub async fn store(pool: &SqlitePool, id: i64, fields: &[(String, String)]) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    for (tag, value) in fields {
+        sqlx::query("INSERT INTO image_metadata (image_id, tag, value) VALUES (?, ?, ?)")
+            .bind(id)
+            .bind(tag)
+            .bind(value)
+            .execute(&mut *tx)
+            .await?;
+    }
+    sqlx::query("UPDATE images SET taken_at = ? WHERE id = ?")
+        .bind(taken_at(fields))
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// When the photo was taken, from EXIF `DateTimeOriginal` or `DateTime`, in seconds since
+/// the epoch. Cameras record local time without a zone, so it's taken as UTC: the date
+/// stays the one on the camera.
+pub fn taken_at(fields: &[(String, String)]) -> Option<i64> {
+    let date = ["DateTimeOriginal", "DateTime"].iter().find_map(|name| {
+        fields
+            .iter()
+            .find(|(tag, _)| tag == name)
+            .map(|(_, value)| value.trim_matches('"').trim())
+    })?;
+    // Shown as `2023-11-05 14:03:22`, stored as `2023:11:05 14:03:22`.
+    let parts = date
+        .split(['-', ':', ' '])
+        .map(|part| part.parse::<i64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let &[year, month, day, hour, minute, second] = parts.as_slice() else {
+        return None;
+    };
+    // Cameras without a clock set write zeros.
+    if year == 0 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    Some(filters::days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Sets image `id`'s `taken_at` from its stored fields, for images from before it existed.
+pub async fn store_taken_at(pool: &SqlitePool, id: i64) -> anyhow::Result<()> {
+    let fields: Vec<(String, String)> =
+        sqlx::query_as("SELECT tag, value FROM image_metadata WHERE image_id = ?")
+            .bind(id)
+            .fetch_all(pool)
+            .await?;
+    sqlx::query("UPDATE images SET taken_at = ? WHERE id = ?")
+        .bind(taken_at(&fields))
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Dates image `id` by its original's modification time, for images stored before upload
+/// times were recorded. Their `created_at` (and `updated_at`, unless edited since) is when
+/// that was added, so it's only ever moved earlier: a file rewritten later, say when
+/// identical files were deduplicated, leaves it as is.
+pub async fn store_upload_date(pool: &SqlitePool, id: i64) -> anyhow::Result<()> {
+    let modified = tokio::fs::metadata(format!("images/{id}.jpg"))
+        .await?
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs() as i64;
+    sqlx::query(
+        "UPDATE images SET \
+             updated_at = CASE WHEN updated_at = created_at THEN MIN(updated_at, ?1) \
+                 ELSE updated_at END, \
+             created_at = MIN(created_at, ?1), \
+             dated_by_upload = 1 \
+         WHERE id = ?2",
+    )
+    .bind(modified)
+    .bind(id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Format names of SVGs and PDFs, which the `image` crate doesn't know.
+pub const SVG_FORMAT: &str = "svg";
+pub const PDF_FORMAT: &str = "pdf";
+
+/// Name searches use for a format: its usual file extension, e.g. `jpg` or `png`.
+pub fn format_name(format: ImageFormat) -> &'static str {
+    format
+        .extensions_str()
+        .first()
+        .copied()
+        .unwrap_or("unknown")
+}
+
+/// The format called `name` (a file extension like `png` or `jpeg`), as [`format_name`]
+/// names it.
+pub fn parse_format(name: &str) -> Option<&'static str> {
+    let name = name.to_ascii_lowercase();
+    if name == SVG_FORMAT {
+        Some(SVG_FORMAT)
+    } else if name == PDF_FORMAT {
+        Some(PDF_FORMAT)
+    } else {
+        ImageFormat::from_extension(&name).map(format_name)
+    }
+}
+
+/// The name of the format of `bytes`, if it's one we know.
+pub fn format_of(bytes: &[u8]) -> Option<&'static str> {
+    if svg::is_svg(bytes) {
+        Some(SVG_FORMAT)
+    } else if pdf::is_pdf(bytes) {
+        Some(PDF_FORMAT)
+    } else {
+        image::guess_format(bytes).ok().map(format_name)
+    }
+}
+
+/// Records the dimensions, size and format of image `id`'s original, which searches filter
+/// on. Dimensions and format stay NULL for files we can't read, and dimensions for PDFs.
+pub async fn store_file_info(pool: &SqlitePool, id: i64, bytes: &[u8]) -> anyhow::Result<()> {
+    let (format, (width, height)) = if svg::is_svg(bytes) {
+        (Some(SVG_FORMAT), svg::size(bytes).unzip())
+    } else if pdf::is_pdf(bytes) {
+        (Some(PDF_FORMAT), (None, None))
+    } else {
+        let reader = image::io::Reader::new(std::io::Cursor::new(bytes)).with_guessed_format()?;
+        let format = reader.format().map(format_name);
+        (format, reader.into_dimensions().ok().unzip())
+    };
+
+    sqlx::query("UPDATE images SET width = ?, height = ?, byte_size = ?, format = ? WHERE id = ?")
+        .bind(width)
+        .bind(height)
+        .bind(bytes.len() as i64)
+        .bind(format)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Formats with nowhere to keep EXIF or XMP, which [`strip`] returns unchanged.
+const FORMATS_WITHOUT_METADATA: [&str; 6] = ["bmp", "ico", "qoi", "pbm", "tga", "ff"];
+
+/// An image [`strip`] can't remove metadata from: a damaged JPEG, PNG or WebP, or a format
+/// it can't rewrite (GIF, TIFF, HEIC, SVG, PDF...).
+#[derive(Debug)]
+pub struct StripError {
+    pub format: &'static str,
+}
+
+impl std::fmt::Display for StripError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Metadata can't be removed from this {} file",
+            self.format
+        )
+    }
+}
+
+impl std::error::Error for StripError {}
+
+impl StripError {
+    pub fn to_error(&self) -> AppError {
+        AppError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "metadata_not_strippable",
+            self.to_string(),
+        )
+        .with_param("format", self.format)
+    }
+}
+
+/// Returns a copy of the image with its EXIF and XMP blocks removed. Fails rather than
+/// return the image as it is when that can't be done.
+pub fn strip(bytes: &[u8]) -> Result<Vec<u8>, StripError> {
+    let format = format_of(bytes).unwrap_or("unknown");
+    let stripped = match format {
+        "jpg" => strip_jpeg(bytes),
+        "png" => strip_png(bytes),
+        "webp" => strip_webp(bytes),
+        _ if FORMATS_WITHOUT_METADATA.contains(&format) => Some(bytes.to_vec()),
+        _ => None,
+    };
+    stripped.ok_or(StripError { format })
+}
+
+/// Iterates the JPEG marker segments before the scan data, yielding `(marker, segment)`
+/// where `segment` includes the marker and length bytes.
+fn jpeg_segments(bytes: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut pos = JPEG_SOI.len();
+    std::iter::from_fn(move || {
+        if pos + 4 > bytes.len() || bytes[pos] != 0xFF || bytes[pos + 1] == JPEG_SOS {
+            return None;
+        }
+        let marker = bytes[pos + 1];
+        let len = u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+        let end = pos + 2 + len;
+        if len < 2 || end > bytes.len() {
+            return None;
+        }
+        let segment = &bytes[pos..end];
+        pos = end;
+        Some((marker, segment))
+    })
+}
+
+fn find_jpeg_xmp(bytes: &[u8]) -> Option<&[u8]> {
+    if !bytes.starts_with(&JPEG_SOI) {
+        return None;
+    }
+    jpeg_segments(bytes)
+        .find(|(marker, segment)| *marker == JPEG_APP1 && segment[4..].starts_with(XMP_JPEG_HEADER))
+        .map(|(_, segment)| &segment[4 + XMP_JPEG_HEADER.len()..])
+}
+
+fn strip_jpeg(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(&JPEG_SOI);
+
+    let mut consumed = JPEG_SOI.len();
+    for (marker, segment) in jpeg_segments(bytes) {
+        consumed += segment.len();
+        // Both EXIF and XMP live in APP1 segments.
+        if marker != JPEG_APP1 {
+            out.extend_from_slice(segment);
+        }
+    }
+
+    // Anything left should be the start-of-scan marker followed by the entropy-coded data.
+    if bytes.get(consumed..consumed + 2) != Some(&[0xFF, JPEG_SOS]) {
+        return None;
+    }
+    out.extend_from_slice(&bytes[consumed..]);
+
+    Some(out)
+}
+
+fn strip_png(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(&PNG_SIGNATURE);
+
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < bytes.len() {
+        let len = u32::from_be_bytes(bytes.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let kind = bytes.get(pos + 4..pos + 8)?;
+        // length + type + data + crc
+        let end = pos + 12 + len;
+        let chunk = bytes.get(pos..end)?;
+        let data = &chunk[8..8 + len];
+
+        let is_exif = kind == b"eXIf";
+        let is_xmp = kind == b"iTXt" && data.starts_with(XMP_PNG_KEYWORD);
+        if !is_exif && !is_xmp {
+            out.extend_from_slice(chunk);
+        }
+
+        pos = end;
+    }
+
+    Some(out)
+}
+
+fn strip_webp(bytes: &[u8]) -> Option<Vec<u8>> {
+    if bytes.get(..4)? != b"RIFF" || bytes.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let mut out = bytes[..12].to_vec();
+
+    let mut pos = 12;
+    while pos < bytes.len() {
+        let kind = bytes.get(pos..pos + 4)?;
+        let len = u32::from_le_bytes(bytes.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        // type + length + data, padded to an even length
+        let end = pos + 8 + len + len % 2;
+        let chunk = bytes.get(pos..end)?;
+
+        if kind != b"EXIF" && kind != b"XMP " {
+            let start = out.len();
+            out.extend_from_slice(chunk);
+            // The extended header flags which chunks follow.
+            if kind == b"VP8X" {
+                *out.get_mut(start + 8)? &= !(WEBP_EXIF_FLAG | WEBP_XMP_FLAG);
+            }
+        }
+
+        pos = end;
+    }
+    let size = u32::try_from(out.len() - 8).ok()?;
+    out[4..8].copy_from_slice(&size.to_le_bytes());
+
+    Some(out)
+}
@@ -0,0 +1,325 @@
+//! Schema migrations and the backfills that fill in data for existing images.
+//!
+//! Schema changes (`migrations/`) are quick and applied at startup. Filling in data for
+//! images stored before a feature existed (content hashes, file info, fingerprints,
+//! placeholders, palettes, capture dates, upload dates) can take a long time on a big library, so that runs as
+//! backfills: in the background by default, or before serving with `BLOCKING_BACKFILLS`.
+//! Each backfill records its progress in `backfills` after every batch and carries on from
+//! there after a restart.
+//! `GET /admin/migrations` shows both.
+
+use std::collections::{HashMap, HashSet};
+
+use axum::{Extension, Json};
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{cdn, error::AppError, lqip, metadata, palette, similarity};
+
+/// Images handled between progress updates.
+const BATCH_SIZE: i64 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Backfill {
+    ContentHash,
+    FileInfo,
+    Fingerprints,
+    Placeholders,
+    Palettes,
+    TakenAt,
+    UploadDates,
+}
+
+/// Every backfill, in the order they run.
+const BACKFILLS: [Backfill; 7] = [
+    Backfill::ContentHash,
+    Backfill::FileInfo,
+    Backfill::Fingerprints,
+    Backfill::Placeholders,
+    Backfill::Palettes,
+    Backfill::TakenAt,
+    Backfill::UploadDates,
+];
+
+impl Backfill {
+    fn name(self) -> &'static str {
+        match self {
+            Backfill::ContentHash => "content_hash",
+            Backfill::FileInfo => "file_info",
+            Backfill::Fingerprints => "fingerprints",
+            Backfill::Placeholders => "placeholders",
+            Backfill::Palettes => "palettes",
+            Backfill::TakenAt => "taken_at",
+            Backfill::UploadDates => "upload_dates",
+        }
+    }
+
+    /// Condition on `images` selecting the images this backfill still has to do.
+    fn pending(self) -> &'static str {
+        match self {
+            Backfill::ContentHash => "content_hash IS NULL",
+            Backfill::FileInfo => "byte_size IS NULL",
+            Backfill::Fingerprints => "id NOT IN (SELECT image_id FROM image_fingerprints)",
+            Backfill::Placeholders => "id NOT IN (SELECT image_id FROM image_placeholders)",
+            Backfill::Palettes => "id NOT IN (SELECT image_id FROM image_palettes)",
+            Backfill::TakenAt => {
+                "taken_at IS NULL AND id IN (SELECT image_id FROM image_metadata \
+                 WHERE tag IN ('DateTimeOriginal', 'DateTime'))"
+            }
+            Backfill::UploadDates => "NOT dated_by_upload",
+        }
+    }
+
+    async fn apply(self, pool: &SqlitePool, id: i64) -> anyhow::Result<()> {
+        match self {
+            Backfill::ContentHash => cdn::hash_original(pool, id).await,
+            Backfill::FileInfo => {
+                let bytes = tokio::fs::read(format!("images/{id}.jpg")).await?;
+                metadata::store_file_info(pool, id, &bytes).await
+            }
+            Backfill::Fingerprints => similarity::fingerprint(pool, id).await,
+            Backfill::Placeholders => lqip::generate(pool, id).await.map(drop),
+            Backfill::Palettes => palette::generate(pool, id).await.map(drop),
+            Backfill::TakenAt => metadata::store_taken_at(pool, id).await,
+            Backfill::UploadDates => metadata::store_upload_date(pool, id).await,
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct AppliedMigration {
+    version: i64,
+    installed_on: String,
+    execution_time: i64,
+}
+
+async fn applied(pool: &SqlitePool) -> anyhow::Result<Vec<AppliedMigration>> {
+    let has_table: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')",
+    )
+    .fetch_one(pool)
+    .await?;
+    if !has_table {
+        return Ok(Vec::new());
+    }
+
+    let applied = sqlx::query_as::<_, AppliedMigration>(
+        "SELECT version, CAST(installed_on AS TEXT) AS installed_on, execution_time \
+         FROM _sqlx_migrations WHERE success = 1",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(applied)
+}
+
+/// Applies the schema migrations the database doesn't have yet, naming each.
+pub async fn apply(pool: &SqlitePool) -> anyhow::Result<()> {
+    let migrator = sqlx::migrate!("./migrations");
+    let applied: HashSet<i64> = applied(pool)
+        .await?
+        .into_iter()
+        .map(|migration| migration.version)
+        .collect();
+    for migration in migrator.iter() {
+        if !applied.contains(&migration.version) {
+            println!(
+                "Applying migration {} ({})",
+                migration.version, migration.description
+            );
+        }
+    }
+    migrator.run(pool).await?;
+
+    Ok(())
+}
+
+/// Runs every unfinished backfill in turn.
+pub async fn run_backfills(pool: &SqlitePool) -> anyhow::Result<()> {
+    for backfill in BACKFILLS {
+        if let Err(e) = run_backfill(pool, backfill).await {
+            sqlx::query(
+                "UPDATE backfills SET state = 'failed', error = ?, \
+                     updated_at = CAST(strftime('%s', 'now') AS INTEGER) \
+                 WHERE name = ?",
+            )
+            .bind(format!("{e:#}"))
+            .bind(backfill.name())
+            .execute(pool)
+            .await?;
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Like [`run_backfills`], without holding up the caller. A failed backfill is retried at
+/// the next start.
+pub fn spawn_backfills(pool: SqlitePool) {
+    tokio::spawn(async move {
+        if let Err(e) = run_backfills(&pool).await {
+            eprintln!("Backfills stopped: {e:#}");
+        }
+    });
+}
+
+async fn run_backfill(pool: &SqlitePool, backfill: Backfill) -> anyhow::Result<()> {
+    let name = backfill.name();
+    sqlx::query(
+        "INSERT OR IGNORE INTO backfills (name, updated_at) \
+         VALUES (?, CAST(strftime('%s', 'now') AS INTEGER))",
+    )
+    .bind(name)
+    .execute(pool)
+    .await?;
+    let (state, mut last_id): (String, i64) =
+        sqlx::query_as("SELECT state, last_id FROM backfills WHERE name = ?")
+            .bind(name)
+            .fetch_one(pool)
+            .await?;
+    if state == "done" {
+        return Ok(());
+    }
+
+    let remaining: i64 = sqlx::query_scalar(&format!(
+        "SELECT COUNT(*) FROM images WHERE id > ? AND {}",
+        backfill.pending()
+    ))
+    .bind(last_id)
+    .fetch_one(pool)
+    .await?;
+    sqlx::query(
+        "UPDATE backfills SET state = 'running', total = done + ?, error = NULL, \
+             updated_at = CAST(strftime('%s', 'now') AS INTEGER) \
+         WHERE name = ?",
+    )
+    .bind(remaining)
+    .bind(name)
+    .execute(pool)
+    .await?;
+    if remaining > 0 {
+        println!("Backfilling {name} for {remaining} images");
+    }
+
+    loop {
+        let ids: Vec<i64> = sqlx::query_scalar(&format!(
+            "SELECT id FROM images WHERE id > ? AND {} ORDER BY id LIMIT ?",
+            backfill.pending()
+        ))
+        .bind(last_id)
+        .bind(BATCH_SIZE)
+        .fetch_all(pool)
+        .await?;
+        let Some(&last) = ids.last() else {
+            break;
+        };
+
+        for &id in &ids {
+            // Usually a missing or unreadable original, which trying again won't fix.
+            if let Err(e) = backfill.apply(pool, id).await {
+                eprintln!("Backfill {name} skipped image {id}: {e:#}");
+            }
+        }
+        last_id = last;
+        sqlx::query(
+            "UPDATE backfills SET last_id = ?, done = done + ?, \
+                 updated_at = CAST(strftime('%s', 'now') AS INTEGER) \
+             WHERE name = ?",
+        )
+        .bind(last_id)
+        .bind(ids.len() as i64)
+        .bind(name)
+        .execute(pool)
+        .await?;
+    }
+
+    sqlx::query(
+        "UPDATE backfills SET state = 'done', \
+             updated_at = CAST(strftime('%s', 'now') AS INTEGER) \
+         WHERE name = ?",
+    )
+    .bind(name)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct MigrationStatus {
+    version: i64,
+    description: String,
+    applied: bool,
+    applied_at: Option<String>,
+    execution_ms: Option<i64>,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct BackfillStatus {
+    name: String,
+    /// `pending`, `running`, `done` or `failed`.
+    state: String,
+    done: i64,
+    total: i64,
+    error: Option<String>,
+    updated_at: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct Status {
+    migrations: Vec<MigrationStatus>,
+    backfills: Vec<BackfillStatus>,
+}
+
+/// `GET /admin/migrations`: the schema migrations this build knows, whether each is applied,
+/// and the progress of every backfill.
+pub async fn status(Extension(pool): Extension<SqlitePool>) -> Result<Json<Status>, AppError> {
+    let applied: HashMap<i64, AppliedMigration> = applied(&pool)
+        .await?
+        .into_iter()
+        .map(|migration| (migration.version, migration))
+        .collect();
+    let migrations = sqlx::migrate!("./migrations")
+        .iter()
+        .map(|migration| {
+            let applied = applied.get(&migration.version);
+            MigrationStatus {
+                version: migration.version,
+                description: migration.description.to_string(),
+                applied: applied.is_some(),
+                applied_at: applied.map(|applied| applied.installed_on.clone()),
+                execution_ms: applied.map(|applied| applied.execution_time / 1_000_000),
+            }
+        })
+        .collect();
+
+    let mut recorded: HashMap<String, BackfillStatus> = sqlx::query_as::<_, BackfillStatus>(
+        "SELECT name, state, done, total, error, updated_at FROM backfills",
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|backfill| (backfill.name.clone(), backfill))
+    .collect();
+    let backfills = BACKFILLS
+        .iter()
+        .map(|backfill| {
+            recorded
+                .remove(backfill.name())
+                .unwrap_or_else(|| BackfillStatus {
+                    name: backfill.name().to_string(),
+                    state: "pending".to_string(),
+                    done: 0,
+                    total: 0,
+                    error: None,
+                    updated_at: None,
+                })
+        })
+        .collect();
+
+    Ok(Json(Status {
+        migrations,
+        backfills,
+    }))
+}
@@ -0,0 +1,192 @@
+//! Holding uploads back until a moderator approves them, for public galleries.
+//!
+//! With `MODERATE_UPLOADS=true` new uploads are `pending`: anonymous visitors don't see
+//! them in listings, searches, the timeline or on their own pages until they're `approved`.
+//! A `rejected` image stays hidden from them. API token holders see every image, with its
+//! status. The queue is at `GET /admin/moderation` as a page and
+//! `GET /api/v1/admin/moderation` as JSON.
+
+use std::net::IpAddr;
+
+use axum::{
+    extract::Query,
+    http::HeaderMap,
+    response::{Html, IntoResponse, Response},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::{
+    audit, burst,
+    client_ip::ClientIp,
+    error::AppError,
+    fragments, i18n,
+    ids::{self, ImageId},
+    image_locks, layout,
+    thumbnail::Crop,
+    ImageRecord, IMAGE_COLUMNS,
+};
+
+#[derive(sqlx::Type, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pending,
+    #[default]
+    Approved,
+    Rejected,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Pending => "pending",
+            Status::Approved => "approved",
+            Status::Rejected => "rejected",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct QueueQuery {
+    #[serde(default = "pending")]
+    status: Status,
+}
+
+fn pending() -> Status {
+    Status::Pending
+}
+
+/// Images with `status`, oldest first: the order they're moderated in.
+async fn queue(pool: &SqlitePool, status: Status) -> Result<Vec<ImageRecord>, AppError> {
+    Ok(sqlx::query_as::<_, ImageRecord>(&format!(
+        "SELECT {IMAGE_COLUMNS} FROM images \
+         WHERE status = ? AND session_id IS NULL ORDER BY id"
+    ))
+    .bind(status)
+    .fetch_all(pool)
+    .await?)
+}
+
+async fn set_status(
+    pool: &SqlitePool,
+    client: IpAddr,
+    image: &ImageId,
+    status: Status,
+) -> Result<(), AppError> {
+    let _lock = image_locks::write(image.id).await;
+    sqlx::query("UPDATE images SET status = ? WHERE id = ? RETURNING id")
+        .bind(status)
+        .bind(image.id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| ids::not_found(&image.key))?;
+    burst::invalidate(pool, image.id).await?;
+    audit::record(
+        pool,
+        Some(client),
+        &format!("image_{}", status.as_str()),
+        Some(image.id),
+        "",
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// `GET /api/v1/admin/moderation?status=`: images awaiting moderation, or with another
+/// status.
+pub async fn list(
+    Extension(pool): Extension<SqlitePool>,
+    Query(query): Query<QueueQuery>,
+) -> Result<Json<Vec<ImageRecord>>, AppError> {
+    Ok(Json(queue(&pool, query.status).await?))
+}
+
+#[derive(Deserialize)]
+pub struct Decision {
+    status: Status,
+}
+
+/// `PUT /api/v1/admin/moderation/:id` with `{"status": "approved"}` (or `rejected`, or
+/// `pending` to put it back in the queue).
+pub async fn decide(
+    Extension(pool): Extension<SqlitePool>,
+    ClientIp(client): ClientIp,
+    image: ImageId,
+    Json(decision): Json<Decision>,
+) -> Result<Response, AppError> {
+    set_status(&pool, client, &image, decision.status).await?;
+    match crate::fetch_image_record(&pool, image.id).await? {
+        Some(record) => Ok(Json(record).into_response()),
+        None => Err(ids::not_found(&image.key)),
+    }
+}
+
+/// One queued image with its approve and reject buttons.
+fn render_item(image: &ImageRecord) -> String {
+    let title = if image.title.is_empty() {
+        i18n::t("details.untitled", &[("id", &image.key.to_string())])
+    } else {
+        image.title.clone()
+    };
+    format!(
+        "<li class=\"moderation-item\" id=\"moderation-{id}\">\n\
+         <a href=\"/image/{id}/details\"><img src=\"{thumbnail}\" alt=\"{alt}\"/></a>\n\
+         <div>{title}</div>\n<div>{tags}</div>\n\
+         <button type=\"button\" hx-post=\"/fragments/moderation/{id}/approve\" \
+         hx-target=\"closest li\" hx-swap=\"outerHTML\">{approve}</button>\n\
+         <button type=\"button\" hx-post=\"/fragments/moderation/{id}/reject\" \
+         hx-target=\"closest li\" hx-swap=\"outerHTML\">{reject}</button>\n\
+         </li>\n",
+        id = image.key,
+        thumbnail = fragments::escape_html(&image.thumbnail_url(Crop::Fit)),
+        alt = fragments::escape_html(image.alt()),
+        title = fragments::escape_html(&title),
+        tags = fragments::escape_html(&image.tags),
+        approve = i18n::t("moderation.approve", &[]),
+        reject = i18n::t("moderation.reject", &[]),
+    )
+}
+
+/// `GET /admin/moderation`: the queue as a page.
+pub async fn queue_page(
+    Extension(pool): Extension<SqlitePool>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let images = queue(&pool, Status::Pending).await?;
+    let items = if images.is_empty() {
+        format!("<li>{}</li>", i18n::t("moderation.empty", &[]))
+    } else {
+        images.iter().map(render_item).collect()
+    };
+
+    let content = fragments::read_template("moderation.html")
+        .await
+        .replace("{items}", &items);
+
+    Ok(layout::page(&headers, Some(&i18n::t("moderation.title", &[])), &content).await)
+}
+
+/// `POST /fragments/moderation/:id/approve`, which takes the image off the page.
+pub async fn approve_button(
+    Extension(pool): Extension<SqlitePool>,
+    ClientIp(client): ClientIp,
+    image: ImageId,
+) -> Result<Html<String>, AppError> {
+    set_status(&pool, client, &image, Status::Approved).await?;
+
+    Ok(Html(String::new()))
+}
+
+/// `POST /fragments/moderation/:id/reject`, likewise.
+pub async fn reject_button(
+    Extension(pool): Extension<SqlitePool>,
+    ClientIp(client): ClientIp,
+    image: ImageId,
+) -> Result<Html<String>, AppError> {
+    set_status(&pool, client, &image, Status::Rejected).await?;
+
+    Ok(Html(String::new()))
+}
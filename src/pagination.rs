@@ -0,0 +1,128 @@
+//! Keyset pagination over `images`, ordered by `(created_at, id)`.
+//!
+//! Clients get an opaque `next_cursor` with each page and pass it back as `cursor` to get
+//! the next one. Unlike offsets, cursors don't skip or repeat rows when images are added
+//! while paging. Orders without such a key, like search `relevance`, page by offset behind
+//! the same opaque cursor instead.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::AppError, ImageRecord};
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+/// Condition selecting rows after the cursor bound as `?1` (created_at) and `?2` (id), both
+/// NULL for the first page.
+pub const AFTER_CURSOR: &str = "(?1 IS NULL OR (created_at, id) > (?1, ?2))";
+
+/// Like [`AFTER_CURSOR`], for rows ordered newest first.
+pub const BEFORE_CURSOR: &str = "(?1 IS NULL OR (created_at, id) < (?1, ?2))";
+
+#[derive(Deserialize, Default)]
+pub struct PageQuery {
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+/// Position just after a row, as `(created_at, id)`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    pub created_at: i64,
+    pub id: i64,
+}
+
+impl Cursor {
+    pub fn encode(self) -> String {
+        URL_SAFE_NO_PAD.encode(format!("{}:{}", self.created_at, self.id))
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, AppError> {
+        let invalid = || AppError::bad_request("invalid_cursor", "Invalid pagination cursor");
+
+        let decoded = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+        let (created_at, id) = decoded.split_once(':').ok_or_else(invalid)?;
+
+        Ok(Self {
+            created_at: created_at.parse().map_err(|_| invalid())?,
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl PageQuery {
+    /// The decoded cursor, if any, and the page size clamped to `1..=MAX_LIMIT`.
+    pub fn parse(&self) -> Result<(Option<Cursor>, i64), AppError> {
+        let cursor = self.cursor.as_deref().map(Cursor::decode).transpose()?;
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+        Ok((cursor, limit))
+    }
+
+    /// Like [`PageQuery::parse`], for a cursor made by [`Page::from_ranked_rows`]: the rows
+    /// to skip.
+    pub fn parse_offset(&self) -> Result<(i64, i64), AppError> {
+        let offset = self.cursor.as_deref().map(decode_offset).transpose()?;
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+        Ok((offset.unwrap_or(0), limit))
+    }
+}
+
+fn encode_offset(offset: i64) -> String {
+    URL_SAFE_NO_PAD.encode(format!("offset:{offset}"))
+}
+
+fn decode_offset(cursor: &str) -> Result<i64, AppError> {
+    let invalid = || AppError::bad_request("invalid_cursor", "Invalid pagination cursor");
+
+    let decoded = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+    let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+    let offset = decoded.strip_prefix("offset:").ok_or_else(invalid)?;
+
+    offset
+        .parse()
+        .ok()
+        .filter(|offset| *offset >= 0)
+        .ok_or_else(invalid)
+}
+
+/// JSON envelope of a page of images.
+#[derive(Serialize)]
+pub struct Page {
+    pub images: Vec<ImageRecord>,
+    pub next_cursor: Option<String>,
+}
+
+impl Page {
+    /// Builds a page from up to `limit + 1` rows; the extra row only tells us there's more.
+    pub fn from_rows(mut images: Vec<ImageRecord>, limit: i64) -> Self {
+        let has_more = images.len() as i64 > limit;
+        images.truncate(limit as usize);
+        let next_cursor = images.last().filter(|_| has_more).map(|last| {
+            Cursor {
+                created_at: last.created_at,
+                id: last.id,
+            }
+            .encode()
+        });
+
+        Self {
+            images,
+            next_cursor,
+        }
+    }
+
+    /// Like [`Page::from_rows`], for rows from `offset` on in an order without a key.
+    pub fn from_ranked_rows(mut images: Vec<ImageRecord>, limit: i64, offset: i64) -> Self {
+        let has_more = images.len() as i64 > limit;
+        images.truncate(limit as usize);
+
+        Self {
+            images,
+            next_cursor: has_more.then(|| encode_offset(offset + limit)),
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! The dominant colors of each image, as `#rrggbb` strings, most common first.
+//!
+//! Palettes are worked out at upload (median cut, see [`crate::processor`]) and kept in
+//! `image_palettes` as a comma-separated list; images from before then get theirs on first
+//! request.
+
+use axum::{response::IntoResponse, response::Response, Extension, Json};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::{
+    auth::Viewer,
+    decode_failures,
+    error::AppError,
+    expiry,
+    ids::{self, ImageId},
+    processor,
+};
+
+pub const PALETTE_SIZE: usize = 5;
+
+#[derive(Serialize)]
+pub struct Palette {
+    colors: Vec<String>,
+}
+
+/// Works out and stores the palette of image `id`.
+pub async fn generate(pool: &SqlitePool, id: i64) -> anyhow::Result<Vec<String>> {
+    let colors = processor::watched(move || {
+        processor::get().palette(std::path::Path::new(&processor::raster_source(id)?))
+    })
+    .await;
+    if let Err(e) = &colors {
+        decode_failures::record(pool, id, e).await;
+    }
+    let colors = colors?;
+
+    sqlx::query("INSERT OR REPLACE INTO image_palettes (image_id, colors) VALUES (?, ?)")
+        .bind(id)
+        .bind(colors.join(","))
+        .execute(pool)
+        .await?;
+
+    Ok(colors)
+}
+
+/// Like [`generate`], for callers that carry on without a palette.
+pub async fn generate_or_log(pool: &SqlitePool, id: i64) {
+    if let Err(e) = generate(pool, id).await {
+        eprintln!("Failed to work out the palette of image {id}: {e:#}");
+    }
+}
+
+/// The stored palette, worked out now if there isn't one yet.
+pub async fn colors(pool: &SqlitePool, id: i64) -> anyhow::Result<Vec<String>> {
+    let stored: Option<String> =
+        sqlx::query_scalar("SELECT colors FROM image_palettes WHERE image_id = ?")
+            .bind(id)
+            .fetch_optional(pool)
+            .await?;
+
+    match stored {
+        Some(colors) => Ok(colors.split(',').map(str::to_string).collect()),
+        None => generate(pool, id).await,
+    }
+}
+
+/// `GET /image/:id/palette`
+pub async fn palette(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    ImageId { id, key }: ImageId,
+) -> Result<Response, AppError> {
+    if crate::fetch_visible_image(&pool, viewer, id)
+        .await?
+        .is_none()
+    {
+        return Ok(expiry::not_found(&pool, id, &key).await);
+    }
+
+    let colors = colors(&pool, id)
+        .await
+        .map_err(|e| crate::missing_file(e, || ids::not_found(&key)))?;
+    Ok(Json(Palette { colors }).into_response())
+}
@@ -0,0 +1,59 @@
+//! PDF uploads, for scanned documents. Builds with the `pdf` feature accept them; others
+//! refuse them as unreadable.
+//!
+//! The PDF is stored and served (`application/pdf`) as uploaded. Thumbnails, placeholders,
+//! palettes and fingerprints are made from its first page, rendered to PNG by poppler's
+//! `pdftoppm`, which must be on `PATH`, with the longer side `RASTER_SIZE` pixels.
+
+pub const CONTENT_TYPE: &str = "application/pdf";
+/// Longer side of the rendering of the first page, like [`crate::svg`]'s.
+#[cfg(feature = "pdf")]
+const RASTER_SIZE: u32 = 2048;
+
+/// Whether `bytes` start like a PDF document.
+pub fn is_pdf(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"%PDF-")
+}
+
+/// Renders the first page of a PDF to PNG.
+#[cfg(feature = "pdf")]
+pub fn rasterize(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    use std::{
+        io::Write,
+        process::{Command, Stdio},
+    };
+
+    let mut child = Command::new("pdftoppm")
+        .args(["-png", "-singlefile", "-f", "1", "-l", "1", "-scale-to"])
+        .arg(RASTER_SIZE.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Written from another thread, as pdftoppm may fill its output pipe before it's read
+    // all of its input.
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = bytes.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output()?;
+    // pdftoppm stops reading at an error, which the status reports better.
+    let _ = writer.join();
+    if !output.status.success() || output.stdout.is_empty() {
+        anyhow::bail!(
+            "pdftoppm failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(output.stdout)
+}
+
+#[cfg(not(feature = "pdf"))]
+pub fn rasterize(_bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    anyhow::bail!("PDFs need a build with the `pdf` feature")
+}
+
+/// Whether `bytes` are a PDF whose first page can be rendered.
+pub fn is_readable(bytes: &[u8]) -> bool {
+    is_pdf(bytes) && rasterize(bytes).is_ok()
+}
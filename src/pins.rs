@@ -0,0 +1,90 @@
+//! Pinning images so nothing deletes them.
+//!
+//! A pinned image doesn't expire, and every path that deletes images (see `expiry::purge`)
+//! refuses it with 423 Locked, until it's unpinned. Listings and searches take
+//! `pinned=true` (or `false`) to filter on it, see `filters`.
+
+use std::net::IpAddr;
+
+use axum::{http::StatusCode, Extension, Json};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::{
+    audit,
+    client_ip::ClientIp,
+    error::AppError,
+    ids::{self, ImageId, ImageKey},
+};
+
+/// Why an image wasn't deleted: it's pinned. Surfaces as 423 Locked, see `error`.
+#[derive(Debug)]
+pub struct Pinned {
+    /// The image's id as URLs have it.
+    pub key: String,
+}
+
+impl std::fmt::Display for Pinned {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Image {} is pinned and can't be deleted", self.key)
+    }
+}
+
+impl std::error::Error for Pinned {}
+
+impl Pinned {
+    pub fn to_error(&self) -> AppError {
+        AppError::new(StatusCode::LOCKED, "image_pinned", self.to_string())
+            .with_param("id", self.key.clone())
+    }
+}
+
+#[derive(Serialize)]
+pub struct Pin {
+    #[serde(rename = "id")]
+    key: ImageKey,
+    pinned: bool,
+}
+
+async fn set(
+    pool: &SqlitePool,
+    client: IpAddr,
+    image: ImageId,
+    pinned: bool,
+) -> Result<Json<Pin>, AppError> {
+    sqlx::query("UPDATE images SET pinned = ? WHERE id = ? RETURNING id")
+        .bind(pinned)
+        .bind(image.id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| ids::not_found(&image.key))?;
+    let action = if pinned {
+        "image_pinned"
+    } else {
+        "image_unpinned"
+    };
+    audit::record(pool, Some(client), action, Some(image.id), "").await?;
+
+    Ok(Json(Pin {
+        key: image.key,
+        pinned,
+    }))
+}
+
+/// `POST /api/v1/image/:id/pin`: pins the image.
+pub async fn pin(
+    Extension(pool): Extension<SqlitePool>,
+    ClientIp(client): ClientIp,
+    image: ImageId,
+) -> Result<Json<Pin>, AppError> {
+    set(&pool, client, image, true).await
+}
+
+/// `DELETE /api/v1/image/:id/pin`: unpins the image, so it can be deleted again.
+pub async fn unpin(
+    Extension(pool): Extension<SqlitePool>,
+    ClientIp(client): ClientIp,
+    image: ImageId,
+) -> Result<Json<Pin>, AppError> {
+    set(&pool, client, image, false).await
+}
@@ -0,0 +1,156 @@
+//! The checks and changes an upload goes through between being received and being stored.
+//!
+//! `UPLOAD_STAGES` lists the stages to run, in order:
+//!
+//! - `auto_tag`: suggests tags from the filename and EXIF when none were given.
+//! - `scan`: checks the file with the malware scanner, if `CLAMD_ADDRESS` configures one.
+//! - `validate`: rejects files that aren't images we can decode. Rejections are quarantined.
+//! - `strip_exif`: removes EXIF/XMP from the stored original and doesn't record it.
+//!
+//! Each stage may change the upload, reject it, or ask for jobs to run once it's stored.
+
+use std::{net::IpAddr, sync::Arc};
+
+use async_trait::async_trait;
+use sqlx::SqlitePool;
+
+use crate::{
+    config::Config, error::AppError, jobs::JobKind, metadata, quarantine::Quarantine,
+    scanner::SharedScanner, tags, NewImage,
+};
+
+/// An upload on its way through the pipeline.
+pub struct Upload {
+    pub bytes: Vec<u8>,
+    pub file_name: Option<String>,
+    pub details: NewImage,
+    /// EXIF/XMP fields to record for the image.
+    pub metadata: Vec<(String, String)>,
+    /// Whether `details.tags` were suggested rather than typed by the uploader.
+    pub auto_tags: bool,
+    /// Jobs to queue for the image once it's stored, besides its thumbnail.
+    pub follow_ups: Vec<JobKind>,
+    /// The address of the client uploading it, for the audit log.
+    pub client: Option<IpAddr>,
+}
+
+#[async_trait]
+pub trait UploadStage: Send + Sync {
+    /// Whether uploads this stage rejects are kept in quarantine.
+    fn quarantines_rejects(&self) -> bool {
+        false
+    }
+
+    async fn process(&self, pool: &SqlitePool, upload: &mut Upload) -> Result<(), AppError>;
+}
+
+pub struct Pipeline {
+    stages: Vec<Box<dyn UploadStage>>,
+}
+
+pub type SharedPipeline = Arc<Pipeline>;
+
+/// Assembles the stages named by `UPLOAD_STAGES`.
+pub fn from_config(
+    config: &Config,
+    scanner: Option<SharedScanner>,
+) -> anyhow::Result<SharedPipeline> {
+    let mut stages: Vec<Box<dyn UploadStage>> = Vec::new();
+    for name in &config.upload_stages {
+        stages.push(match name.as_str() {
+            "auto_tag" => Box::new(AutoTag),
+            "scan" => Box::new(Scan {
+                scanner: scanner.clone(),
+            }),
+            "validate" => Box::new(Validate),
+            "strip_exif" => Box::new(StripExif),
+            _ => anyhow::bail!("Unknown upload stage {name:?} in UPLOAD_STAGES"),
+        });
+    }
+
+    Ok(Arc::new(Pipeline { stages }))
+}
+
+impl Pipeline {
+    /// Runs every stage in turn, stopping at the first rejection.
+    pub async fn run(
+        &self,
+        pool: &SqlitePool,
+        quarantine: Option<&Quarantine>,
+        upload: &mut Upload,
+    ) -> Result<(), AppError> {
+        for stage in &self.stages {
+            if let Err(e) = stage.process(pool, upload).await {
+                if let Some(quarantine) = quarantine.filter(|_| stage.quarantines_rejects()) {
+                    quarantine
+                        .store(pool, &upload.bytes, upload.file_name.as_deref(), &e)
+                        .await;
+                }
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Falls back to tags guessed from the filename and EXIF when none were given, flagged so
+/// they can be confirmed or dropped later.
+struct AutoTag;
+
+#[async_trait]
+impl UploadStage for AutoTag {
+    async fn process(&self, _pool: &SqlitePool, upload: &mut Upload) -> Result<(), AppError> {
+        if upload.details.tags.trim().is_empty() {
+            upload.details.tags =
+                tags::suggest(upload.file_name.as_deref(), &upload.metadata).join(", ");
+            upload.auto_tags = true;
+        }
+
+        Ok(())
+    }
+}
+
+struct Scan {
+    scanner: Option<SharedScanner>,
+}
+
+#[async_trait]
+impl UploadStage for Scan {
+    async fn process(&self, pool: &SqlitePool, upload: &mut Upload) -> Result<(), AppError> {
+        crate::scan_upload(
+            pool,
+            upload.client,
+            self.scanner.as_deref(),
+            &upload.bytes,
+            None,
+            &format!("tags={}", upload.details.tags),
+        )
+        .await
+    }
+}
+
+struct Validate;
+
+#[async_trait]
+impl UploadStage for Validate {
+    fn quarantines_rejects(&self) -> bool {
+        true
+    }
+
+    async fn process(&self, _pool: &SqlitePool, upload: &mut Upload) -> Result<(), AppError> {
+        crate::check_readable(&upload.bytes)
+    }
+}
+
+struct StripExif;
+
+#[async_trait]
+impl UploadStage for StripExif {
+    async fn process(&self, _pool: &SqlitePool, upload: &mut Upload) -> Result<(), AppError> {
+        upload.bytes = metadata::strip(&upload.bytes).map_err(|e| e.to_error())?;
+        upload.metadata.clear();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+//! Presigned uploads, for clients putting large images straight into the bucket rather than
+//! through the service.
+//!
+//! `POST /upload/presign` with the API token, and optionally the `tags` the image gets,
+//! answers an `id` and a `url` presigned for a `PUT` of the image to
+//! `uploads/<id>` in `UPLOAD_S3_BUCKET` at `S3_ENDPOINT` (see `s3`), good for
+//! `PRESIGNED_UPLOAD_TTL_SECS`. Once the `PUT` is done, `POST /upload/complete` with the
+//! `id` fetches the object and ingests it like an upload, answering the new image as
+//! `POST /upload/signed` does, and deletes the object from the bucket.
+//!
+//! Completing may take as long again as the URL's lifetime, so an upload started just before
+//! the URL expires can still be completed. Uploads not completed by then are forgotten, and
+//! their objects deleted, the next time one is presigned. When the bucket can't be reached
+//! or the upload fails on the service's side, the upload can be completed again. An upload
+//! is taken off the pending ones while it's being completed, so completing it twice at once
+//! ingests it once; the other request answers 404 unless the first fails that way.
+
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{
+    bucket_ingest,
+    config::{Config, SharedConfig},
+    error::AppError,
+    jobs::JobQueue,
+    pipeline::{SharedPipeline, Upload},
+    quarantine::SharedQuarantine,
+    receipts::Receipt,
+    s3, tags,
+    upload_policy::Uploaded,
+    Ingested, NewImage, UploadOptions,
+};
+
+fn disabled() -> AppError {
+    AppError::new(
+        StatusCode::NOT_FOUND,
+        "presigned_uploads_disabled",
+        "Presigned uploads are disabled",
+    )
+}
+
+fn not_found(id: &str) -> AppError {
+    AppError::new(
+        StatusCode::NOT_FOUND,
+        "pending_upload_not_found",
+        format!("No pending upload {id}"),
+    )
+    .with_param("id", id)
+}
+
+/// `S3_ENDPOINT` and `UPLOAD_S3_BUCKET`, when presigned uploads are on; they also need
+/// credentials to sign with.
+fn bucket(config: &Config) -> Result<(&str, &str), AppError> {
+    match (
+        &config.s3_endpoint,
+        &config.upload_s3_bucket,
+        &config.s3_access_key,
+        &config.s3_secret_key,
+    ) {
+        (Some(endpoint), Some(bucket), Some(_), Some(_)) => Ok((endpoint, bucket)),
+        _ => Err(disabled()),
+    }
+}
+
+/// Deletes object `key` of `bucket`. Failing to is only logged: the object is left behind.
+async fn delete_object(config: &Config, endpoint: &str, bucket: &str, key: &str) {
+    let Some(url) = s3::object_url(endpoint, bucket, key) else {
+        return;
+    };
+    let deleted = s3::request(
+        &reqwest::Client::new(),
+        config,
+        Method::DELETE,
+        &url,
+        s3::UNSIGNED_PAYLOAD,
+    )
+    .send()
+    .await;
+    match deleted {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => eprintln!(
+            "Deleting {key} from the bucket failed: the bucket answered {}",
+            response.status()
+        ),
+        Err(e) => eprintln!("Deleting {key} from the bucket failed: {e}"),
+    }
+}
+
+/// Forgets the uploads that can't be completed anymore, deleting their objects.
+async fn reap(
+    pool: &SqlitePool,
+    config: &Config,
+    endpoint: &str,
+    bucket: &str,
+) -> anyhow::Result<()> {
+    let expired: Vec<(String, String)> = sqlx::query_as(
+        "DELETE FROM pending_uploads \
+         WHERE expires_at + ? < CAST(strftime('%s', 'now') AS INTEGER) \
+         RETURNING id, object_key",
+    )
+    .bind(config.presigned_upload_ttl.as_secs() as i64)
+    .fetch_all(pool)
+    .await?;
+    for (_, key) in expired {
+        delete_object(config, endpoint, bucket, &key).await;
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct PresignRequest {
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct Presigned {
+    id: String,
+    url: String,
+    method: &'static str,
+    /// Unix time the URL stops working at.
+    expires_at: i64,
+}
+
+/// `POST /upload/presign`
+pub async fn presign(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Json(request): Json<PresignRequest>,
+) -> Result<(StatusCode, Json<Presigned>), AppError> {
+    let config = config.load();
+    let (endpoint, bucket) = bucket(&config)?;
+    let tags = tags::normalize(&request.tags.join(","), config.tag_limits)?;
+    reap(&pool, &config, endpoint, bucket).await?;
+
+    let id = format!("{:032x}", rand::random::<u128>());
+    let key = format!("uploads/{id}");
+    let ttl = config.presigned_upload_ttl.as_secs();
+    let url = s3::object_url(endpoint, bucket, &key)
+        .and_then(|url| s3::presign(&config, &Method::PUT, &url, ttl))
+        .ok_or_else(disabled)?;
+    let (expires_at,): (i64,) = sqlx::query_as(
+        "INSERT INTO pending_uploads (id, object_key, tags, created_at, expires_at) \
+         VALUES (?, ?, ?, CAST(strftime('%s', 'now') AS INTEGER), \
+                 CAST(strftime('%s', 'now') AS INTEGER) + ?) \
+         RETURNING expires_at",
+    )
+    .bind(&id)
+    .bind(&key)
+    .bind(tags.join(", "))
+    .bind(ttl as i64)
+    .fetch_one(&pool)
+    .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(Presigned {
+            id,
+            url: url.to_string(),
+            method: "PUT",
+            expires_at,
+        }),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct CompleteRequest {
+    id: String,
+}
+
+/// A `pending_uploads` row.
+#[derive(FromRow)]
+struct PendingUpload {
+    id: String,
+    object_key: String,
+    tags: String,
+    created_at: i64,
+    expires_at: i64,
+}
+
+/// Takes upload `id` off `pending_uploads` for this request to complete, so no other request
+/// completes it too; `None` if it isn't pending, or can't be completed anymore.
+async fn claim(
+    pool: &SqlitePool,
+    config: &Config,
+    id: &str,
+) -> anyhow::Result<Option<PendingUpload>> {
+    Ok(sqlx::query_as(
+        "DELETE FROM pending_uploads \
+         WHERE id = ? AND expires_at + ? >= CAST(strftime('%s', 'now') AS INTEGER) \
+         RETURNING id, object_key, tags, created_at, expires_at",
+    )
+    .bind(id)
+    .bind(config.presigned_upload_ttl.as_secs() as i64)
+    .fetch_optional(pool)
+    .await?)
+}
+
+/// Puts a claimed upload back, as completing it failed in a way that may pass.
+async fn release(pool: &SqlitePool, pending: &PendingUpload) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT INTO pending_uploads (id, object_key, tags, created_at, expires_at) \
+         VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(&pending.id)
+    .bind(&pending.object_key)
+    .bind(&pending.tags)
+    .bind(pending.created_at)
+    .bind(pending.expires_at)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// `POST /upload/complete`
+#[allow(clippy::too_many_arguments)]
+pub async fn complete(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(pipeline): Extension<SharedPipeline>,
+    Extension(jobs): Extension<JobQueue>,
+    Extension(quarantine): Extension<Option<SharedQuarantine>>,
+    options: UploadOptions,
+    headers: HeaderMap,
+    Json(request): Json<CompleteRequest>,
+) -> Result<Response, AppError> {
+    let config = config.load();
+    let (endpoint, bucket) = bucket(&config)?;
+    let pending = claim(&pool, &config, &request.id)
+        .await?
+        .ok_or_else(|| not_found(&request.id))?;
+
+    // Not put yet, or the bucket failing, can pass: the upload stays pending.
+    let bytes = match bucket_ingest::fetch(&config, endpoint, bucket, &pending.object_key).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            release(&pool, &pending).await?;
+            return Err(e);
+        }
+    };
+    let upload = Upload {
+        bytes,
+        file_name: None,
+        details: NewImage {
+            tags: pending.tags.clone(),
+            ..NewImage::default()
+        },
+        metadata: Vec::new(),
+        auto_tags: false,
+        follow_ups: Vec::new(),
+        client: options.client,
+    };
+    let ingested = crate::ingest(
+        &pool,
+        &pipeline,
+        &jobs,
+        quarantine.as_deref(),
+        &options,
+        upload,
+    )
+    .await;
+    match &ingested {
+        Err(e) if e.status().is_server_error() => release(&pool, &pending).await?,
+        _ => delete_object(&config, endpoint, bucket, &pending.object_key).await,
+    }
+    let image = match ingested? {
+        Ingested::Stored(image) => image,
+        Ingested::Duplicate(existing) => return Err(crate::duplicate_error(&existing)),
+    };
+    let receipt = Receipt::new(&config, &headers, &image);
+
+    Ok((StatusCode::CREATED, Json(Uploaded { image, receipt })).into_response())
+}
@@ -0,0 +1,564 @@
+//! Image decoding, resizing and conversion backends, chosen with `IMAGE_PROCESSOR`.
+//!
+//! `image` (the default) uses the pure-Rust `image` crate. Building with the `vips` feature
+//! adds `vips`, which runs libvips' `vipsthumbnail` and `vips` tools and is much faster on
+//! large photos because it decodes JPEGs at reduced size.
+//!
+//! A crafted image can make a decoder spin for minutes or allocate gigabytes, so images are
+//! refused before decoding if their header declares more than `MAX_DECODE_PIXELS`, and image
+//! work runs under [`watched`], which gives up on it after `DECODE_TIMEOUT_SECS`. Both fail
+//! with a [`DecodeError`], which `decode_failures` records against the image.
+
+use std::{
+    io::{Cursor, Read},
+    path::Path,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use image::{DynamicImage, GenericImageView, RgbaImage};
+
+use axum::http::StatusCode;
+
+use crate::{
+    error::AppError,
+    lqip::{PLACEHOLDER_QUALITY, PLACEHOLDER_WIDTH},
+    palette::PALETTE_SIZE,
+    pdf, svg,
+    thumbnail::{self, Cancel, Crop, Sharpen, THUMBNAIL_SIZE},
+};
+
+static PROCESSOR: OnceLock<Box<dyn Processor>> = OnceLock::new();
+static LIMITS: OnceLock<DecodeLimits> = OnceLock::new();
+
+/// How much decoding an image may take, from `MAX_DECODE_PIXELS` and `DECODE_TIMEOUT_SECS`.
+pub struct DecodeLimits {
+    pub max_pixels: u64,
+    pub timeout: Duration,
+}
+
+/// Sets the decoding limits. Must run before the first image is processed.
+pub fn set_limits(limits: DecodeLimits) {
+    let _ = LIMITS.set(limits);
+}
+
+fn limits() -> &'static DecodeLimits {
+    LIMITS.get_or_init(|| DecodeLimits {
+        max_pixels: 100_000_000,
+        timeout: Duration::from_secs(30),
+    })
+}
+
+/// Why an image couldn't be decoded, other than being broken. Surfaces as 422, see `error`.
+#[derive(Clone, Debug)]
+pub enum DecodeError {
+    /// Its header declares more pixels than `MAX_DECODE_PIXELS`.
+    TooManyPixels { width: u32, height: u32, max: u64 },
+    /// The work ran longer than `DECODE_TIMEOUT_SECS`.
+    TimedOut { after: Duration },
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooManyPixels { width, height, max } => write!(
+                f,
+                "The image is {width}x{height}, more than the {max} pixels allowed"
+            ),
+            Self::TimedOut { after } => write!(
+                f,
+                "Processing the image took longer than {} seconds",
+                after.as_secs()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl DecodeError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::TooManyPixels { .. } => "image_too_large",
+            Self::TimedOut { .. } => "decode_timeout",
+        }
+    }
+
+    pub fn to_error(&self) -> AppError {
+        let error = AppError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            self.code(),
+            self.to_string(),
+        );
+        match self {
+            Self::TooManyPixels { width, height, max } => error
+                .with_param("width", width.to_string())
+                .with_param("height", height.to_string())
+                .with_param("max", max.to_string()),
+            Self::TimedOut { after } => error.with_param("seconds", after.as_secs().to_string()),
+        }
+    }
+}
+
+/// Checks an image of `width` x `height` is within the pixel budget.
+pub fn check_size(width: u32, height: u32) -> Result<(), DecodeError> {
+    let max = limits().max_pixels;
+    if width as u64 * height as u64 > max {
+        return Err(DecodeError::TooManyPixels { width, height, max });
+    }
+    Ok(())
+}
+
+/// Decodes `bytes`, after checking the size its header declares.
+pub fn decode(bytes: &[u8]) -> anyhow::Result<DynamicImage> {
+    let reader = || image::io::Reader::new(Cursor::new(bytes)).with_guessed_format();
+    let (width, height) = reader()?.into_dimensions()?;
+    check_size(width, height)?;
+
+    Ok(reader()?.decode()?)
+}
+
+fn decode_file(path: &Path) -> anyhow::Result<DynamicImage> {
+    decode(&std::fs::read(path)?)
+}
+
+/// Checks the size the header of the file at `path` declares, for backends that decode it
+/// themselves. Formats the `image` crate can't read are left to them.
+#[cfg_attr(not(feature = "vips"), allow(dead_code))]
+fn check_file(path: &Path) -> anyhow::Result<()> {
+    let dimensions = image::io::Reader::open(path)?
+        .with_guessed_format()?
+        .into_dimensions();
+    if let Ok((width, height)) = dimensions {
+        check_size(width, height)?;
+    }
+    Ok(())
+}
+
+/// Runs image work on the blocking pool, giving up on it after `DECODE_TIMEOUT_SECS`. A
+/// thread can't be stopped from outside, so a decode in this process runs on to its end once
+/// given up on, with nobody waiting for it. The vips backend's tools are killed at the
+/// timeout instead, see `vips::run`.
+pub async fn watched<T: Send + 'static>(
+    work: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    let timeout = limits().timeout;
+    match tokio::time::timeout(timeout, tokio::task::spawn_blocking(work)).await {
+        Ok(result) => result?,
+        Err(_) => Err(DecodeError::TimedOut { after: timeout }.into()),
+    }
+}
+
+pub trait Processor: Send + Sync {
+    /// JPEG quality of thumbnails without `THUMBNAIL_QUALITY`.
+    fn default_quality(&self) -> u8 {
+        75
+    }
+
+    /// Writes a `THUMBNAIL_SIZE` JPEG thumbnail of `source` to `dest`, sharpened afterwards
+    /// if `sharpen` is given, at `thumbnail::quality`. Stops between steps once `cancel` is
+    /// set.
+    fn thumbnail(
+        &self,
+        source: &Path,
+        dest: &Path,
+        crop: Crop,
+        sharpen: Option<Sharpen>,
+        cancel: &Cancel,
+    ) -> anyhow::Result<()>;
+
+    /// Writes a JPEG of `source` scaled down to fit within `width` x `height` to `dest`.
+    /// Smaller images keep their size.
+    fn resize(&self, source: &Path, dest: &Path, width: u32, height: u32) -> anyhow::Result<()>;
+
+    /// Converts `source` to PNG at `dest`, taking the first page of multi-page files.
+    fn to_png(&self, source: &Path, dest: &Path) -> anyhow::Result<()>;
+
+    /// Encodes a `PLACEHOLDER_WIDTH` wide, `PLACEHOLDER_QUALITY` JPEG of `source`.
+    fn placeholder(&self, source: &Path) -> anyhow::Result<Vec<u8>>;
+
+    /// Up to `PALETTE_SIZE` dominant colors of `source` as `#rrggbb`, most common first.
+    fn palette(&self, source: &Path) -> anyhow::Result<Vec<String>> {
+        let image = decode_file(source)?;
+        let small = image.thumbnail(PALETTE_SAMPLE_SIZE, PALETTE_SAMPLE_SIZE);
+
+        Ok(median_cut(
+            small.to_rgb8().pixels().map(|pixel| pixel.0).collect(),
+            PALETTE_SIZE,
+        ))
+    }
+
+    /// `source` scaled down to fit within `size` x `size`, as a frame of a burst preview.
+    fn frame(&self, source: &Path, size: u32) -> anyhow::Result<RgbaImage> {
+        let image = decode_file(source)?;
+
+        Ok(image.thumbnail(size, size).to_rgba8())
+    }
+
+    /// The middle square of `source` at each of `sizes`, as icons.
+    fn icons(&self, source: &Path, sizes: &[u32]) -> anyhow::Result<Vec<RgbaImage>> {
+        let image = decode_file(source)?;
+        let side = image.width().min(image.height());
+        let square = image.crop_imm(
+            (image.width() - side) / 2,
+            (image.height() - side) / 2,
+            side,
+            side,
+        );
+
+        Ok(sizes
+            .iter()
+            .map(|&size| {
+                square
+                    .resize_exact(size, size, image::imageops::FilterType::Lanczos3)
+                    .to_rgba8()
+            })
+            .collect())
+    }
+}
+
+/// Palettes are worked out from a copy scaled down to at most this size.
+const PALETTE_SAMPLE_SIZE: u32 = 64;
+
+/// Splits the colors into `count` boxes, each time cutting the box with the widest channel
+/// range at that channel's median, and returns the average color of each box, largest box
+/// first.
+fn median_cut(pixels: Vec<[u8; 3]>, count: usize) -> Vec<String> {
+    fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+        (0..3)
+            .map(|channel| {
+                let values = pixels.iter().map(|pixel| pixel[channel]);
+                let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+                (channel, range)
+            })
+            .max_by_key(|&(_, range)| range)
+            .unwrap_or((0, 0))
+    }
+
+    let mut boxes = vec![pixels];
+    while boxes.len() < count {
+        let Some((index, channel)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, pixels)| pixels.len() > 1)
+            .map(|(index, pixels)| (index, widest_channel(pixels)))
+            .filter(|(_, (_, range))| *range > 0)
+            .max_by_key(|(_, (_, range))| *range)
+            .map(|(index, (channel, _))| (index, channel))
+        else {
+            break;
+        };
+
+        let mut pixels = boxes.swap_remove(index);
+        pixels.sort_unstable_by_key(|pixel| pixel[channel]);
+        let upper = pixels.split_off(pixels.len() / 2);
+        boxes.push(pixels);
+        boxes.push(upper);
+    }
+
+    boxes.retain(|pixels| !pixels.is_empty());
+    boxes.sort_by_key(|pixels| std::cmp::Reverse(pixels.len()));
+    boxes
+        .iter()
+        .map(|pixels| {
+            let mut sums = [0u64; 3];
+            for pixel in pixels {
+                for channel in 0..3 {
+                    sums[channel] += pixel[channel] as u64;
+                }
+            }
+            let n = pixels.len() as u64;
+            format!("#{:02x}{:02x}{:02x}", sums[0] / n, sums[1] / n, sums[2] / n)
+        })
+        .collect()
+}
+
+/// Selects the backend named by `IMAGE_PROCESSOR`. Must run before the first image is
+/// processed.
+pub fn init(name: &str) -> anyhow::Result<()> {
+    let processor: Box<dyn Processor> = match name {
+        "image" => Box::new(ImageCrate),
+        #[cfg(feature = "vips")]
+        "vips" => Box::new(vips::Vips),
+        #[cfg(not(feature = "vips"))]
+        "vips" => anyhow::bail!("IMAGE_PROCESSOR=vips needs a build with the `vips` feature"),
+        _ => anyhow::bail!("Unknown IMAGE_PROCESSOR {name:?}, expected `image` or `vips`"),
+    };
+    PROCESSOR
+        .set(processor)
+        .map_err(|_| anyhow::anyhow!("The image processor was already chosen"))
+}
+
+/// Path of a file with image `id`'s pixels for the processor: the original, or for an SVG or
+/// PDF a PNG rendering (`images/{id}_converted.png`), made on first use.
+pub fn raster_source(id: i64) -> anyhow::Result<String> {
+    let original = format!("images/{id}.jpg");
+    let mut head = [0u8; 64];
+    let read = std::fs::File::open(&original)?.read(&mut head)?;
+    let rasterize = if svg::is_svg(&head[..read]) {
+        svg::rasterize
+    } else if pdf::is_pdf(&head[..read]) {
+        pdf::rasterize
+    } else {
+        return Ok(original);
+    };
+
+    let converted = format!("images/{id}_converted.png");
+    if !Path::new(&converted).exists() {
+        let bytes = std::fs::read(&original)?;
+        let partial = format!("{converted}.{}.tmp", rand::random::<u32>());
+        std::fs::write(&partial, rasterize(&bytes)?)?;
+        std::fs::rename(&partial, &converted)?;
+    }
+
+    Ok(converted)
+}
+
+pub fn get() -> &'static dyn Processor {
+    PROCESSOR.get_or_init(|| Box::new(ImageCrate)).as_ref()
+}
+
+/// The pure-Rust `image` crate.
+pub struct ImageCrate;
+
+impl Processor for ImageCrate {
+    fn thumbnail(
+        &self,
+        source: &Path,
+        dest: &Path,
+        crop: Crop,
+        sharpen: Option<Sharpen>,
+        cancel: &Cancel,
+    ) -> anyhow::Result<()> {
+        let image = decode_file(source)?;
+        cancel.check()?;
+
+        let image = match crop {
+            Crop::Fit => image,
+            Crop::Center => {
+                let (width, height) = image.dimensions();
+                let side = width.min(height);
+                image.crop_imm((width - side) / 2, (height - side) / 2, side, side)
+            }
+            Crop::Smart => {
+                let (x, y, side) = thumbnail::smart_square(&image);
+                image.crop_imm(x, y, side, side)
+            }
+        };
+
+        cancel.check()?;
+        let mut thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
+        if let Some(sharpen) = sharpen {
+            cancel.check()?;
+            thumbnail = thumbnail::unsharp_mask(&thumbnail, sharpen);
+        }
+        cancel.check()?;
+        let file = std::io::BufWriter::new(std::fs::File::create(dest)?);
+        image::codecs::jpeg::JpegEncoder::new_with_quality(
+            file,
+            thumbnail::quality(self.default_quality()),
+        )
+        .encode_image(&thumbnail)?;
+
+        Ok(())
+    }
+
+    fn resize(&self, source: &Path, dest: &Path, width: u32, height: u32) -> anyhow::Result<()> {
+        let image = decode_file(source)?;
+        let image = if image.width() > width || image.height() > height {
+            image.resize(width, height, image::imageops::FilterType::Lanczos3)
+        } else {
+            image
+        };
+        DynamicImage::ImageRgb8(image.to_rgb8())
+            .save_with_format(dest, image::ImageFormat::Jpeg)?;
+
+        Ok(())
+    }
+
+    fn to_png(&self, source: &Path, dest: &Path) -> anyhow::Result<()> {
+        decode_file(source)?.save_with_format(dest, image::ImageFormat::Png)?;
+
+        Ok(())
+    }
+
+    fn placeholder(&self, source: &Path) -> anyhow::Result<Vec<u8>> {
+        let image = decode_file(source)?;
+        let small = image.thumbnail(PLACEHOLDER_WIDTH, u32::MAX).to_rgb8();
+
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, PLACEHOLDER_QUALITY)
+            .encode_image(&small)?;
+
+        Ok(jpeg)
+    }
+}
+
+#[cfg(feature = "vips")]
+mod vips {
+    use std::{
+        io::Read,
+        path::Path,
+        process::{Command, Stdio},
+        time::{Duration, Instant},
+    };
+
+    use super::{check_file, limits, DecodeError, Processor};
+    use crate::{
+        lqip::{PLACEHOLDER_QUALITY, PLACEHOLDER_WIDTH},
+        thumbnail::{self, Cancel, Crop, Sharpen, THUMBNAIL_SIZE},
+    };
+
+    /// libvips through its command-line tools, which must be on `PATH`.
+    pub struct Vips;
+
+    /// How often a running tool is checked on.
+    const POLL: Duration = Duration::from_millis(20);
+
+    /// Runs a tool, killing it if it's still going after `DECODE_TIMEOUT_SECS`, so that work
+    /// [`super::watched`] gave up on doesn't keep a process busy.
+    fn run(command: &mut Command) -> anyhow::Result<()> {
+        let timeout = limits().timeout;
+        let started = Instant::now();
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // Read on another thread so a chatty tool can't fill the pipe and stall.
+        let mut stderr = child.stderr.take();
+        let reader = std::thread::spawn(move || {
+            let mut text = String::new();
+            if let Some(stderr) = stderr.as_mut() {
+                let _ = stderr.read_to_string(&mut text);
+            }
+            text
+        });
+
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if started.elapsed() >= timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(DecodeError::TimedOut { after: timeout }.into());
+            }
+            std::thread::sleep(POLL);
+        };
+        let stderr = reader.join().unwrap_or_default();
+        if !status.success() {
+            anyhow::bail!("{:?} failed: {}", command.get_program(), stderr.trim());
+        }
+
+        Ok(())
+    }
+
+    /// `vipsthumbnail` resolves relative output paths against the input's directory.
+    fn absolute(path: &Path) -> anyhow::Result<std::path::PathBuf> {
+        Ok(std::env::current_dir()?.join(path))
+    }
+
+    impl Processor for Vips {
+        fn default_quality(&self) -> u8 {
+            85
+        }
+
+        fn thumbnail(
+            &self,
+            source: &Path,
+            dest: &Path,
+            crop: Crop,
+            sharpen: Option<Sharpen>,
+            cancel: &Cancel,
+        ) -> anyhow::Result<()> {
+            check_file(source)?;
+            let mut command = Command::new("vipsthumbnail");
+            command
+                .arg(source)
+                .arg("--size")
+                .arg(format!("{THUMBNAIL_SIZE}x{THUMBNAIL_SIZE}"));
+            match crop {
+                Crop::Fit => {}
+                Crop::Center => {
+                    command.args(["--smartcrop", "centre"]);
+                }
+                Crop::Smart => {
+                    command.args(["--smartcrop", "attention"]);
+                }
+            }
+            // Thumbnails are always JPEG whatever the temporary file is called.
+            let dest = absolute(dest)?;
+            let output = format!(
+                "{}[Q={},strip]",
+                dest.display(),
+                thumbnail::quality(self.default_quality())
+            );
+            let Some(sharpen) = sharpen else {
+                command.arg("-o").arg(&output);
+                return run(&mut command);
+            };
+
+            // `vips sharpen` works on lightness (0-100) and only sharpens differences above
+            // `x1`, by `m2`; `m1` is for the flat areas below it.
+            let scaled = dest.with_extension("unsharp.v");
+            command.arg("-o").arg(&scaled);
+            let result = run(&mut command).and_then(|()| {
+                cancel.check()?;
+                run(Command::new("vips")
+                    .arg("sharpen")
+                    .arg(&scaled)
+                    .arg(&output)
+                    .arg(format!("--sigma={}", sharpen.radius))
+                    .arg(format!("--x1={}", sharpen.threshold as f32 * 100.0 / 255.0))
+                    .arg("--m1=0")
+                    .arg(format!("--m2={}", sharpen.amount)))
+            });
+            let _ = std::fs::remove_file(&scaled);
+
+            result
+        }
+
+        fn resize(
+            &self,
+            source: &Path,
+            dest: &Path,
+            width: u32,
+            height: u32,
+        ) -> anyhow::Result<()> {
+            check_file(source)?;
+            // `>` only ever shrinks.
+            let dest = absolute(dest)?;
+            run(Command::new("vipsthumbnail")
+                .arg(source)
+                .arg("--size")
+                .arg(format!("{width}x{height}>"))
+                .arg("-o")
+                .arg(format!("{}[Q=85,strip]", dest.display())))
+        }
+
+        fn to_png(&self, source: &Path, dest: &Path) -> anyhow::Result<()> {
+            check_file(source)?;
+            run(Command::new("vips").arg("pngsave").arg(source).arg(dest))
+        }
+
+        fn placeholder(&self, source: &Path) -> anyhow::Result<Vec<u8>> {
+            check_file(source)?;
+            let nanos = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .subsec_nanos();
+            let dest =
+                std::env::temp_dir().join(format!("lqip-{}-{nanos}.jpg", std::process::id()));
+            run(Command::new("vipsthumbnail")
+                .arg(source)
+                .arg("--size")
+                .arg(format!("{PLACEHOLDER_WIDTH}x"))
+                .arg("-o")
+                .arg(format!("{}[Q={PLACEHOLDER_QUALITY},strip]", dest.display())))?;
+            let jpeg = std::fs::read(&dest);
+            let _ = std::fs::remove_file(&dest);
+
+            Ok(jpeg?)
+        }
+    }
+}
@@ -0,0 +1,359 @@
+//! `GET /proxy?url=...&w=...&h=...`: resized copies of images hosted elsewhere, for embedding
+//! them in the gallery.
+//!
+//! Links must be signed with `PROXY_SECRET`, or anyone could use the service as an open
+//! proxy: `sig` is the URL-safe base64 (unpadded) HMAC-SHA256 of `{w}:{h}:{url}`, with an
+//! empty string for a size left out. Only public addresses are fetched. Hosts resolving to
+//! loopback, private, link-local and other internal ranges, or to IPv6 ranges embedding an
+//! IPv4 address (NAT64, 6to4, Teredo), are refused, after redirects too,
+//! and the connection goes to the address that was checked, so DNS can't change its answer
+//! in between. Results are cached under `proxy_cache/` for `PROXY_CACHE_TTL_SECS`.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use reqwest::{redirect, Url};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::{
+    cdn,
+    config::SharedConfig,
+    error::AppError,
+    processor,
+    thumbnail::{self, Priority},
+};
+
+const CACHE_DIR: &str = "proxy_cache";
+/// Largest `w` and `h`; a missing one is taken as this.
+const MAX_DIMENSION: u32 = 4096;
+const MAX_REDIRECTS: usize = 3;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Deserialize)]
+pub struct ProxyQuery {
+    url: String,
+    w: Option<u32>,
+    h: Option<u32>,
+    sig: Option<String>,
+}
+
+impl ProxyQuery {
+    /// What `sig` signs.
+    fn signed_part(&self) -> String {
+        let size = |size: Option<u32>| size.map(|size| size.to_string()).unwrap_or_default();
+        format!("{}:{}:{}", size(self.w), size(self.h), self.url)
+    }
+}
+
+fn verify(secret: &str, query: &ProxyQuery) -> Result<(), AppError> {
+    let invalid = || {
+        AppError::new(
+            StatusCode::FORBIDDEN,
+            "invalid_signature",
+            "The proxy URL's signature is missing or wrong",
+        )
+    };
+    let given = query
+        .sig
+        .as_deref()
+        .and_then(|sig| URL_SAFE_NO_PAD.decode(sig).ok())
+        .ok_or_else(invalid)?;
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(query.signed_part().as_bytes());
+
+    mac.verify_slice(&given).map_err(|_| invalid())
+}
+
+/// Whether `ip` is reachable on the internet, as opposed to this host or its networks.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // "This network" and the reserved 240.0.0.0/4.
+                || a == 0
+                || a >= 240
+                // Carrier-grade NAT, protocol assignments and benchmarking.
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 192 && b == 0 && c == 0)
+                || (a == 198 && (b == 18 || b == 19)))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_public(mapped.into());
+            }
+            let [first, second, ..] = ip.segments();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                // Site-local, deprecated but still routed by some networks.
+                || (first & 0xffc0) == 0xfec0
+                // Documentation.
+                || (first == 0x2001 && second == 0x0db8)
+                // Ranges embedding an IPv4 address (NAT64 with its local-use /48, 6to4,
+                // Teredo, IPv4-compatible), which can reach IPv4 ranges refused above and are
+                // refused whatever the address.
+                || (first == 0x0064 && second == 0xff9b)
+                || first == 0x2002
+                || (first == 0x2001 && second == 0)
+                || ip.segments()[..6] == [0; 6])
+        }
+    }
+}
+
+fn fetch_failed(url: &Url, reason: impl Into<String>) -> AppError {
+    let reason = reason.into();
+    AppError::new(
+        StatusCode::BAD_GATEWAY,
+        "proxy_fetch_failed",
+        format!("Fetching {url} failed: {reason}"),
+    )
+    .with_param("url", url.as_str())
+    .with_param("reason", reason)
+}
+
+/// Checks `url` may be fetched and returns the address to connect to.
+async fn check_target(url: &Url) -> Result<SocketAddr, AppError> {
+    let refused = |reason: &str| {
+        AppError::new(
+            StatusCode::FORBIDDEN,
+            "proxy_refused",
+            format!("Refusing to fetch {url}: {reason}"),
+        )
+        .with_param("url", url.as_str())
+        .with_param("reason", reason)
+    };
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(refused("only http and https URLs are proxied"));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| refused("the URL has no host"))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| refused("the URL has no port"))?;
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addresses: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| fetch_failed(url, e.to_string()))?
+            .collect(),
+    };
+    // Every answer must be public, or a name could mix an internal address in.
+    if addresses.iter().any(|address| !is_public(address.ip())) {
+        return Err(refused("it isn't a public address"));
+    }
+
+    addresses
+        .first()
+        .copied()
+        .ok_or_else(|| fetch_failed(url, "the host has no addresses"))
+}
+
+/// Downloads `url`, following redirects to public addresses.
+async fn fetch(url: &str, max_bytes: u32) -> Result<Vec<u8>, AppError> {
+    let mut url = Url::parse(url).map_err(|_| {
+        AppError::bad_request("invalid_url", format!("Invalid URL {url}")).with_param("url", url)
+    })?;
+
+    for _ in 0..=MAX_REDIRECTS {
+        let address = check_target(&url).await?;
+        let client = reqwest::Client::builder()
+            .redirect(redirect::Policy::none())
+            .timeout(FETCH_TIMEOUT)
+            .no_proxy()
+            .resolve(url.host_str().unwrap_or_default(), address)
+            .build()?;
+        let mut response = client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| fetch_failed(&url, e.to_string()))?;
+
+        let status = response.status();
+        if status.is_redirection() {
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or_else(|| fetch_failed(&url, "redirect without a Location"))?;
+            url = url
+                .join(location)
+                .map_err(|_| fetch_failed(&url, "redirect to an invalid URL"))?;
+            continue;
+        }
+        if !status.is_success() {
+            return Err(fetch_failed(&url, format!("the server answered {status}")));
+        }
+
+        let too_large = || fetch_failed(&url, format!("larger than {max_bytes} bytes"));
+        if response
+            .content_length()
+            .is_some_and(|length| length > max_bytes as u64)
+        {
+            return Err(too_large());
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| fetch_failed(&url, e.to_string()))?
+        {
+            body.extend_from_slice(&chunk);
+            if body.len() > max_bytes as usize {
+                return Err(too_large());
+            }
+        }
+
+        return Ok(body);
+    }
+
+    Err(fetch_failed(&url, "too many redirects"))
+}
+
+/// Whether the cached file at `path` exists and is younger than `ttl`.
+async fn is_fresh(path: &Path, ttl: Duration) -> bool {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age < ttl))
+}
+
+/// Resizes the downloaded `bytes` into the cache at `path`.
+async fn render(bytes: Vec<u8>, path: &Path, width: u32, height: u32) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(CACHE_DIR).await?;
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .subsec_nanos();
+    let source = path.with_extension(format!("{nanos}.download"));
+    let partial = path.with_extension(format!("{nanos}.tmp"));
+    tokio::fs::write(&source, bytes).await?;
+
+    let path = path.to_path_buf();
+    let result = thumbnail::limited(Priority::Interactive, {
+        let source = source.clone();
+        move || {
+            processor::get().resize(&source, &partial, width, height)?;
+            std::fs::rename(&partial, &path)?;
+            Ok(())
+        }
+    })
+    .await;
+    let _ = tokio::fs::remove_file(&source).await;
+
+    result
+}
+
+/// `GET /proxy`
+pub async fn proxy(
+    Extension(config): Extension<SharedConfig>,
+    Query(query): Query<ProxyQuery>,
+) -> Result<Response, AppError> {
+    let config = config.load();
+    let Some(secret) = &config.proxy_secret else {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            "proxy_disabled",
+            "The image proxy is disabled",
+        ));
+    };
+    if query.w.is_none() && query.h.is_none() {
+        return Err(AppError::bad_request(
+            "missing_size",
+            "Give the size to resize to as w, h or both",
+        ));
+    }
+    let width = query.w.unwrap_or(MAX_DIMENSION);
+    let height = query.h.unwrap_or(MAX_DIMENSION);
+    if !(1..=MAX_DIMENSION).contains(&width) || !(1..=MAX_DIMENSION).contains(&height) {
+        return Err(AppError::bad_request(
+            "invalid_size",
+            format!("w and h must be between 1 and {MAX_DIMENSION}"),
+        )
+        .with_param("max", MAX_DIMENSION.to_string()));
+    }
+    verify(secret, &query)?;
+
+    let key = cdn::content_hash(query.signed_part().as_bytes());
+    let path = PathBuf::from(CACHE_DIR).join(format!("{key}.jpg"));
+    if !is_fresh(&path, config.proxy_cache_ttl).await {
+        let bytes = fetch(&query.url, config.proxy_max_bytes).await?;
+        render(bytes, &path, width, height).await.map_err(|e| {
+            AppError::new(
+                StatusCode::BAD_GATEWAY,
+                "proxy_unreadable",
+                format!("{} is not an image we can read", query.url),
+            )
+            .with_param("url", query.url.clone())
+            .with_extension("reason", format!("{e:#}"))
+        })?;
+    }
+
+    let jpeg = tokio::fs::read(&path).await?;
+    let cache_control = format!("public, max-age={}", config.proxy_cache_ttl.as_secs());
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/jpeg".to_string()),
+            (header::CACHE_CONTROL, cache_control),
+        ],
+        jpeg,
+    )
+        .into_response())
+}
+
+async fn sweep(ttl: Duration) -> anyhow::Result<()> {
+    let Ok(mut entries) = tokio::fs::read_dir(CACHE_DIR).await else {
+        return Ok(());
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let expired = entry
+            .metadata()
+            .await?
+            .modified()?
+            .elapsed()
+            .is_ok_and(|age| age >= ttl);
+        if expired {
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes cached results once they're older than `PROXY_CACHE_TTL_SECS`, every hour.
+pub fn spawn_sweeper(config: SharedConfig) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = sweep(config.load().proxy_cache_ttl).await {
+                eprintln!("Proxy cache sweep failed: {e:#}");
+            }
+        }
+    });
+}
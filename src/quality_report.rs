@@ -0,0 +1,318 @@
+//! `GET /api/v1/admin/thumbnail-quality`: how thumbnails would come out at other JPEG
+//! qualities, to choose `THUMBNAIL_QUALITY` by.
+//!
+//! A random sample of images (`sample`, 8 by default, at most 50) is scaled down as for a
+//! thumbnail, and the pixels encoded as JPEG at each of `qualities` (50 to 95 in steps of 5
+//! by default, plus the current quality) and losslessly as PNG and WebP for comparison. Each
+//! encoding is decoded again and compared with the pixels it was made from: PSNR over the RGB
+//! channels, and SSIM averaged over 8x8 blocks of luma. The report gives each encoding's
+//! average size and scores, and recommends the lowest quality whose average SSIM reaches
+//! `target_ssim` (0.95 by default), with how much smaller its thumbnails are than now.
+//!
+//! The work takes background thumbnail slots, one image at a time, so it doesn't hold up
+//! thumbnails anyone is waiting for.
+
+use std::{collections::BTreeMap, io::Cursor};
+
+use axum::{extract::Query, Extension, Json};
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageFormat, RgbImage};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::{
+    error::AppError,
+    processor,
+    thumbnail::{self, Priority, THUMBNAIL_SIZE},
+};
+
+const DEFAULT_SAMPLE: u32 = 8;
+const MAX_SAMPLE: u32 = 50;
+const DEFAULT_QUALITIES: [u8; 10] = [50, 55, 60, 65, 70, 75, 80, 85, 90, 95];
+const DEFAULT_TARGET_SSIM: f64 = 0.95;
+/// Side of the blocks SSIM is computed over.
+const SSIM_BLOCK: u32 = 8;
+
+#[derive(Deserialize)]
+pub struct ReportQuery {
+    sample: Option<u32>,
+    /// Comma separated, like `60,70,80`.
+    qualities: Option<String>,
+    target_ssim: Option<f64>,
+}
+
+#[derive(Clone, Copy)]
+enum Encoding {
+    Jpeg(u8),
+    Png,
+    WebP,
+}
+
+impl Encoding {
+    fn format(self) -> &'static str {
+        match self {
+            Encoding::Jpeg(_) => "jpeg",
+            Encoding::Png => "png",
+            Encoding::WebP => "webp",
+        }
+    }
+
+    fn encode(self, pixels: &RgbImage) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        match self {
+            Encoding::Jpeg(quality) => {
+                JpegEncoder::new_with_quality(&mut bytes, quality).encode_image(pixels)?
+            }
+            Encoding::Png => DynamicImage::ImageRgb8(pixels.clone())
+                .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?,
+            Encoding::WebP => DynamicImage::ImageRgb8(pixels.clone())
+                .write_to(&mut Cursor::new(&mut bytes), ImageFormat::WebP)?,
+        }
+        Ok(bytes)
+    }
+}
+
+/// One encoding of one image.
+struct Measurement {
+    bytes: usize,
+    /// `None` for an exact copy.
+    psnr: Option<f64>,
+    ssim: f64,
+}
+
+/// One encoding over the whole sample.
+#[derive(Serialize)]
+struct Candidate {
+    format: &'static str,
+    /// JPEG quality; `None` for the lossless formats.
+    quality: Option<u8>,
+    mean_bytes: u64,
+    /// `None` when every image came out exactly.
+    mean_psnr: Option<f64>,
+    mean_ssim: f64,
+    min_ssim: f64,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    sampled: usize,
+    /// Images that couldn't be read, or took too long.
+    skipped: usize,
+    thumbnail_size: u32,
+    current_quality: u8,
+    target_ssim: f64,
+    candidates: Vec<Candidate>,
+    /// Settings to change, by name; empty when no quality reaches the target.
+    recommended: BTreeMap<&'static str, String>,
+    /// Fraction of bytes the recommended quality saves on thumbnails, negative if they'd
+    /// grow.
+    savings: Option<f64>,
+}
+
+pub async fn report(
+    Extension(pool): Extension<SqlitePool>,
+    Query(query): Query<ReportQuery>,
+) -> Result<Json<Report>, AppError> {
+    let invalid = |error: &str| {
+        AppError::bad_request("invalid_query", format!("Invalid query: {error}"))
+            .with_param("error", error)
+    };
+    let sample = query.sample.unwrap_or(DEFAULT_SAMPLE).clamp(1, MAX_SAMPLE);
+    let target_ssim = query.target_ssim.unwrap_or(DEFAULT_TARGET_SSIM);
+    if !(target_ssim > 0.0 && target_ssim <= 1.0) {
+        return Err(invalid("target_ssim must be above 0 and at most 1"));
+    }
+    let mut qualities = match &query.qualities {
+        Some(list) => list
+            .split(',')
+            .map(|quality| {
+                quality
+                    .trim()
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|quality| (1..=100).contains(quality))
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| invalid("qualities must be numbers from 1 to 100"))?,
+        None => DEFAULT_QUALITIES.to_vec(),
+    };
+    let current_quality = thumbnail::quality(processor::get().default_quality());
+    qualities.push(current_quality);
+    qualities.sort_unstable();
+    qualities.dedup();
+
+    let mut encodings: Vec<Encoding> = qualities.iter().map(|&q| Encoding::Jpeg(q)).collect();
+    encodings.extend([Encoding::Png, Encoding::WebP]);
+
+    let ids: Vec<i64> = sqlx::query_scalar(
+        "SELECT id FROM images WHERE session_id IS NULL ORDER BY random() LIMIT ?",
+    )
+    .bind(sample)
+    .fetch_all(&pool)
+    .await?;
+
+    let mut results = Vec::new();
+    let mut skipped = 0;
+    for id in ids {
+        let encodings = encodings.clone();
+        let measured = thumbnail::limited(Priority::Background, move || {
+            let source = processor::raster_source(id)?;
+            let pixels = DynamicImage::ImageRgba8(
+                processor::get().frame(std::path::Path::new(&source), THUMBNAIL_SIZE)?,
+            )
+            .to_rgb8();
+            encodings
+                .iter()
+                .map(|encoding| measure(*encoding, &pixels))
+                .collect::<anyhow::Result<Vec<_>>>()
+        })
+        .await;
+        match measured {
+            Ok(measurements) => results.push(measurements),
+            Err(_) => skipped += 1,
+        }
+    }
+
+    let candidates: Vec<Candidate> = encodings
+        .iter()
+        .enumerate()
+        .map(|(i, encoding)| summarize(*encoding, results.iter().map(|image| &image[i])))
+        .collect();
+
+    let mut recommended = BTreeMap::new();
+    let mut savings = None;
+    let jpeg = |quality| {
+        candidates
+            .iter()
+            .find(|candidate| candidate.format == "jpeg" && candidate.quality == Some(quality))
+    };
+    let best = candidates
+        .iter()
+        .filter(|candidate| candidate.format == "jpeg" && !results.is_empty())
+        .find(|candidate| candidate.mean_ssim >= target_ssim);
+    if let (Some(best), Some(current)) = (best, jpeg(current_quality)) {
+        let quality = best.quality.unwrap_or(current_quality);
+        if quality != current_quality {
+            recommended.insert("THUMBNAIL_QUALITY", quality.to_string());
+        }
+        if current.mean_bytes > 0 {
+            let saved = 1.0 - best.mean_bytes as f64 / current.mean_bytes as f64;
+            savings = Some(round(saved, 3));
+        }
+    }
+
+    Ok(Json(Report {
+        sampled: results.len(),
+        skipped,
+        thumbnail_size: THUMBNAIL_SIZE,
+        current_quality,
+        target_ssim,
+        candidates,
+        recommended,
+        savings,
+    }))
+}
+
+fn measure(encoding: Encoding, pixels: &RgbImage) -> anyhow::Result<Measurement> {
+    let bytes = encoding.encode(pixels)?;
+    let decoded = image::load_from_memory(&bytes)?.to_rgb8();
+
+    Ok(Measurement {
+        bytes: bytes.len(),
+        psnr: psnr(pixels, &decoded),
+        ssim: ssim(pixels, &decoded),
+    })
+}
+
+fn summarize<'a>(
+    encoding: Encoding,
+    measurements: impl Iterator<Item = &'a Measurement>,
+) -> Candidate {
+    let measurements: Vec<&Measurement> = measurements.collect();
+    let count = measurements.len().max(1) as f64;
+    let psnrs: Vec<f64> = measurements.iter().filter_map(|m| m.psnr).collect();
+
+    Candidate {
+        format: encoding.format(),
+        quality: match encoding {
+            Encoding::Jpeg(quality) => Some(quality),
+            _ => None,
+        },
+        mean_bytes: (measurements.iter().map(|m| m.bytes).sum::<usize>() as f64 / count).round()
+            as u64,
+        mean_psnr: (!psnrs.is_empty())
+            .then(|| round(psnrs.iter().sum::<f64>() / psnrs.len() as f64, 2)),
+        mean_ssim: round(measurements.iter().map(|m| m.ssim).sum::<f64>() / count, 4),
+        min_ssim: round(
+            measurements
+                .iter()
+                .map(|m| m.ssim)
+                .reduce(f64::min)
+                .unwrap_or_default(),
+            4,
+        ),
+    }
+}
+
+fn round(value: f64, places: i32) -> f64 {
+    let scale = 10f64.powi(places);
+    (value * scale).round() / scale
+}
+
+/// Peak signal-to-noise ratio in decibels over all channels; `None` for identical images.
+fn psnr(original: &RgbImage, encoded: &RgbImage) -> Option<f64> {
+    let samples = original.as_raw().len().max(1) as f64;
+    let squared_error: f64 = original
+        .as_raw()
+        .iter()
+        .zip(encoded.as_raw())
+        .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+        .sum();
+    let mse = squared_error / samples;
+    (mse > 0.0).then(|| 10.0 * (255.0 * 255.0 / mse).log10())
+}
+
+/// Structural similarity of the luma, averaged over `SSIM_BLOCK` squares (smaller at the
+/// edges).
+fn ssim(original: &RgbImage, encoded: &RgbImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let luma = |pixel: &image::Rgb<u8>| {
+        let [r, g, b] = pixel.0.map(f64::from);
+        0.299 * r + 0.587 * g + 0.114 * b
+    };
+    let (width, height) = original.dimensions();
+
+    let mut total = 0.0;
+    let mut blocks = 0;
+    for top in (0..height).step_by(SSIM_BLOCK as usize) {
+        for left in (0..width).step_by(SSIM_BLOCK as usize) {
+            let mut pairs = Vec::new();
+            for y in top..(top + SSIM_BLOCK).min(height) {
+                for x in left..(left + SSIM_BLOCK).min(width) {
+                    pairs.push((
+                        luma(original.get_pixel(x, y)),
+                        luma(encoded.get_pixel(x, y)),
+                    ));
+                }
+            }
+            let n = pairs.len() as f64;
+            let mean_a = pairs.iter().map(|(a, _)| a).sum::<f64>() / n;
+            let mean_b = pairs.iter().map(|(_, b)| b).sum::<f64>() / n;
+            let (mut var_a, mut var_b, mut covariance) = (0.0, 0.0, 0.0);
+            for (a, b) in &pairs {
+                var_a += (a - mean_a).powi(2) / n;
+                var_b += (b - mean_b).powi(2) / n;
+                covariance += (a - mean_a) * (b - mean_b) / n;
+            }
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            blocks += 1;
+        }
+    }
+
+    if blocks == 0 {
+        1.0
+    } else {
+        total / blocks as f64
+    }
+}
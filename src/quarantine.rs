@@ -0,0 +1,165 @@
+//! Keeps the bytes of rejected uploads for a while so failures can be reproduced.
+//!
+//! Payloads go to `quarantine/{id}.bin`, cut off at `QUARANTINE_MAX_BYTES`, with the reason
+//! in the `quarantine` table. Entries older than `QUARANTINE_TTL_SECS` are deleted.
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use axum::{
+    extract::Path,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{config::Config, error::AppError};
+
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+pub type SharedQuarantine = Arc<Quarantine>;
+
+pub struct Quarantine {
+    dir: PathBuf,
+    max_bytes: usize,
+    ttl: Duration,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct QuarantineEntry {
+    id: i64,
+    at: i64,
+    reason: String,
+    detail: String,
+    file_name: Option<String>,
+    size: i64,
+    stored_bytes: i64,
+}
+
+/// Builds the quarantine if `QUARANTINE_TTL_SECS` enables it.
+pub fn from_config(config: &Config) -> Option<SharedQuarantine> {
+    Some(Arc::new(Quarantine {
+        dir: PathBuf::from("quarantine"),
+        max_bytes: config.quarantine_max_bytes as usize,
+        ttl: config.quarantine_ttl?,
+    }))
+}
+
+impl Quarantine {
+    fn path(&self, id: i64) -> PathBuf {
+        self.dir.join(format!("{id}.bin"))
+    }
+
+    /// Keeps `bytes` along with the error the upload was rejected with. Failures are only
+    /// logged: the client should get the original error, not one from the quarantine.
+    pub async fn store(
+        &self,
+        pool: &SqlitePool,
+        bytes: &[u8],
+        file_name: Option<&str>,
+        error: &AppError,
+    ) {
+        if let Err(e) = self.try_store(pool, bytes, file_name, error).await {
+            eprintln!("Failed to quarantine rejected upload: {e:#}");
+        }
+    }
+
+    async fn try_store(
+        &self,
+        pool: &SqlitePool,
+        bytes: &[u8],
+        file_name: Option<&str>,
+        error: &AppError,
+    ) -> anyhow::Result<()> {
+        let kept = &bytes[..bytes.len().min(self.max_bytes)];
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO quarantine (at, reason, detail, file_name, size, stored_bytes) \
+             VALUES (CAST(strftime('%s', 'now') AS INTEGER), ?, ?, ?, ?, ?) RETURNING id",
+        )
+        .bind(error.code())
+        .bind(error.message())
+        .bind(file_name)
+        .bind(bytes.len() as i64)
+        .bind(kept.len() as i64)
+        .fetch_one(pool)
+        .await?;
+
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::write(self.path(id), kept).await?;
+
+        Ok(())
+    }
+
+    async fn expire(&self, pool: &SqlitePool) -> anyhow::Result<()> {
+        let expired: Vec<i64> = sqlx::query_scalar(
+            "DELETE FROM quarantine \
+             WHERE at < CAST(strftime('%s', 'now') AS INTEGER) - ? RETURNING id",
+        )
+        .bind(self.ttl.as_secs() as i64)
+        .fetch_all(pool)
+        .await?;
+
+        for id in expired {
+            match tokio::fs::remove_file(self.path(id)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    eprintln!("Failed to delete quarantined upload {id}: {e:#}");
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes expired entries now and then every hour.
+    pub fn spawn_expiry(self: Arc<Self>, pool: SqlitePool) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(EXPIRY_INTERVAL);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.expire(&pool).await {
+                    eprintln!("Quarantine expiry failed: {e:#}");
+                }
+            }
+        });
+    }
+}
+
+/// `GET /admin/quarantine`, newest first.
+pub async fn list(
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<Json<Vec<QuarantineEntry>>, AppError> {
+    let entries = sqlx::query_as::<_, QuarantineEntry>(
+        "SELECT id, at, reason, detail, file_name, size, stored_bytes \
+         FROM quarantine ORDER BY id DESC",
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(entries))
+}
+
+/// `GET /admin/quarantine/:id`: the kept bytes of a rejected upload.
+pub async fn download(
+    Extension(quarantine): Extension<Option<SharedQuarantine>>,
+    Path(id): Path<i64>,
+) -> Response {
+    let Some(quarantine) = quarantine else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match tokio::fs::read(quarantine.path(id)).await {
+        Ok(bytes) => (
+            [
+                (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=quarantine-{id}.bin"),
+                ),
+            ],
+            bytes,
+        )
+            .into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
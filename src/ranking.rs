@@ -0,0 +1,85 @@
+//! Orders of search results, picked with `sort`.
+//!
+//! `oldest` (the default) and `newest` go by upload time. `popular` puts the most downloaded
+//! originals first. `relevance` ranks by a score mixing how well the text matched (in the
+//! title counts most, then tags, then description), how recent the upload is and how often
+//! the original was downloaded, weighted by `SEARCH_TEXT_WEIGHT`, `SEARCH_RECENCY_WEIGHT`
+//! and `SEARCH_POPULARITY_WEIGHT`. Recency halves every `SEARCH_RECENCY_HALF_LIFE_DAYS`, and
+//! popularity reaches half at [`POPULAR_DOWNLOADS`], so neither grows without bound.
+
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use crate::config::Config;
+
+/// Downloads that make an image half as popular as it can get.
+const POPULAR_DOWNLOADS: u32 = 10;
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    #[default]
+    Oldest,
+    Newest,
+    Relevance,
+    Popular,
+}
+
+impl Sort {
+    /// Whether results are paged by position rather than by upload time, as their order
+    /// has no stable key.
+    pub fn ranked(self) -> bool {
+        matches!(self, Sort::Relevance | Sort::Popular)
+    }
+
+    /// `ORDER BY` terms for `images`, for a query matching the `LIKE` pattern bound as
+    /// `?{pattern}`.
+    pub fn order_by(self, config: &Config, pattern: usize) -> String {
+        match self {
+            Sort::Oldest => "created_at, id".to_string(),
+            Sort::Newest => "created_at DESC, id DESC".to_string(),
+            Sort::Popular => "downloads DESC, created_at DESC, id DESC".to_string(),
+            Sort::Relevance => format!(
+                "{} DESC, created_at DESC, id DESC",
+                relevance(config.relevance_weights, pattern)
+            ),
+        }
+    }
+}
+
+/// How much each part of the `relevance` score counts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RelevanceWeights {
+    pub text: f32,
+    pub recency: f32,
+    pub popularity: f32,
+    pub recency_half_life_days: u32,
+}
+
+/// The `relevance` score of an `images` row, each part from 0 to 1 times its weight.
+fn relevance(weights: RelevanceWeights, pattern: usize) -> String {
+    let half_life = weights.recency_half_life_days.max(1) as f64 * 86_400.0;
+    format!(
+        "({:?} * ((title LIKE ?{pattern}) * 3 + (tags LIKE ?{pattern}) * 2 \
+             + (description LIKE ?{pattern})) / 6.0 \
+         + {:?} * (1.0 / (1.0 + MAX(CAST(strftime('%s', 'now') AS INTEGER) - created_at, 0) \
+             / {half_life:?})) \
+         + {:?} * (downloads / (downloads + {:?})))",
+        weights.text as f64,
+        weights.recency as f64,
+        weights.popularity as f64,
+        POPULAR_DOWNLOADS as f64,
+    )
+}
+
+/// Counts a download of image `id`'s original, for `popular` and `relevance`. Failing to is
+/// only logged: the download goes ahead.
+pub async fn count_download(pool: &SqlitePool, id: i64) {
+    let counted = sqlx::query("UPDATE images SET downloads = downloads + 1 WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await;
+    if let Err(e) = counted {
+        eprintln!("Failed to count a download of image {id}: {e}");
+    }
+}
@@ -0,0 +1,95 @@
+//! `POST /api/v1/upload/raw`, uploads of a bare file, such as an image pasted from the
+//! clipboard in a browser: the request body is the image itself, with no form around it and
+//! no file name.
+//!
+//! The format is read from the file's first bytes, whatever `Content-Type` says, as pasted
+//! images often come as `application/octet-stream`. Tags come from the `X-Upload-Tags`
+//! header, comma-separated like the `tags` field of `POST /upload`, and checksums from the
+//! usual headers. The answer is the new image as JSON with 201, as for signed uploads.
+
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+
+use crate::{
+    checksum::ExpectedChecksums,
+    config::SharedConfig,
+    error::AppError,
+    jobs::JobQueue,
+    metadata,
+    pipeline::{SharedPipeline, Upload},
+    quarantine::SharedQuarantine,
+    receipts::Receipt,
+    upload_policy::Uploaded,
+    Ingested, NewImage, UploadOptions,
+};
+
+/// Header with the tags of the upload.
+const TAGS_HEADER: &str = "x-upload-tags";
+
+#[allow(clippy::too_many_arguments)]
+pub async fn upload(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(pipeline): Extension<SharedPipeline>,
+    Extension(jobs): Extension<JobQueue>,
+    Extension(quarantine): Extension<Option<SharedQuarantine>>,
+    options: UploadOptions,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    let image = body.to_vec();
+    if metadata::format_of(&image).is_none() {
+        return Err(AppError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unrecognized_format",
+            "The upload's format can't be told from its contents",
+        ));
+    }
+    let tags = match headers.get(TAGS_HEADER) {
+        Some(value) => String::from_utf8(value.as_bytes().to_vec()).map_err(|_| {
+            AppError::bad_request("invalid_header", "X-Upload-Tags isn't valid UTF-8")
+                .with_param("name", "X-Upload-Tags")
+        })?,
+        None => String::new(),
+    };
+
+    if let Err(e) = ExpectedChecksums::from_headers(&headers).verify(&image) {
+        if let Some(quarantine) = quarantine.as_deref() {
+            quarantine.store(&pool, &image, None, &e).await;
+        }
+        return Err(e);
+    }
+
+    let upload = Upload {
+        bytes: image,
+        file_name: None,
+        details: NewImage {
+            tags,
+            ..NewImage::default()
+        },
+        metadata: Vec::new(),
+        auto_tags: false,
+        follow_ups: Vec::new(),
+        client: options.client,
+    };
+    let ingested = crate::ingest(
+        &pool,
+        &pipeline,
+        &jobs,
+        quarantine.as_deref(),
+        &options,
+        upload,
+    )
+    .await?;
+    let image = match ingested {
+        Ingested::Stored(image) => image,
+        Ingested::Duplicate(existing) => return Err(crate::duplicate_error(&existing)),
+    };
+    let receipt = Receipt::new(&config.load(), &headers, &image);
+
+    Ok((StatusCode::CREATED, Json(Uploaded { image, receipt })).into_response())
+}
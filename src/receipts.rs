@@ -0,0 +1,155 @@
+//! Upload receipts, for tracking digitized documents back to their images.
+//!
+//! A receipt names the stored image's id and the SHA-256 of the file as uploaded, with the
+//! absolute URL of its details page and a QR code linking there, to print and attach to the
+//! paper original. The upload form shows one after each upload, uploads with a policy return
+//! one as `receipt`, and `GET /api/v1/image/:id/receipt` returns one for any image. URLs start
+//! with `SITE_URL`, or the request's `Host` when it's unset.
+
+use std::io::Cursor;
+
+use axum::{
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use image::{DynamicImage, GrayImage, ImageFormat, Luma};
+use qrcode::{Color, QrCode};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::{
+    auth::Viewer,
+    config::{Config, SharedConfig},
+    error::AppError,
+    expiry,
+    fragments::{escape_html, read_template},
+    ids::{self, ImageId, ImageKey},
+    ImageRecord,
+};
+
+/// Pixels per QR code module.
+const MODULE_PIXELS: u32 = 8;
+/// Blank modules around the code, as scanners need.
+const QUIET_ZONE: u32 = 4;
+
+#[derive(Serialize)]
+pub struct Receipt {
+    id: ImageKey,
+    /// Hex SHA-256 of the original upload; `None` for images stored before hashes were.
+    content_hash: Option<String>,
+    byte_size: Option<i64>,
+    uploaded_at: i64,
+    /// The image's details page.
+    url: String,
+    /// A PNG QR code of `url`.
+    qr_code: String,
+}
+
+/// The origin links in receipts start with.
+fn origin(config: &Config, headers: &HeaderMap) -> String {
+    if let Some(site_url) = &config.site_url {
+        return site_url.trim_end_matches('/').to_string();
+    }
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("localhost");
+    format!("http://{host}")
+}
+
+impl Receipt {
+    pub fn new(config: &Config, headers: &HeaderMap, image: &ImageRecord) -> Self {
+        let origin = origin(config, headers);
+        Receipt {
+            id: image.key.clone(),
+            content_hash: image.content_hash.clone(),
+            byte_size: image.byte_size,
+            uploaded_at: image.created_at,
+            url: format!("{origin}/image/{}/details", image.key),
+            qr_code: format!("{origin}/image/{}/receipt.png", image.key),
+        }
+    }
+
+    /// The receipt as shown under the upload form.
+    pub async fn render(&self) -> String {
+        read_template("upload_receipt.html")
+            .await
+            .replace("{qr_code}", &escape_html(&self.qr_code))
+            .replace("{url}", &escape_html(&self.url))
+            .replace(
+                "{content_hash}",
+                self.content_hash.as_deref().unwrap_or_default(),
+            )
+            .replace("{id}", &self.id.to_string())
+    }
+}
+
+/// `image`'s record, unless it doesn't exist or `viewer` may not see it.
+async fn fetch_image(
+    pool: &SqlitePool,
+    viewer: Viewer,
+    image: &ImageId,
+) -> Result<ImageRecord, AppError> {
+    match crate::fetch_visible_image(pool, viewer, image.id).await? {
+        Some(record) => Ok(record),
+        None => {
+            expiry::gone(pool, image.id).await?;
+            Err(ids::not_found(&image.key))
+        }
+    }
+}
+
+/// `GET /api/v1/image/:id/receipt`
+pub async fn receipt(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(viewer): Extension<Viewer>,
+    image: ImageId,
+    headers: HeaderMap,
+) -> Result<Json<Receipt>, AppError> {
+    let record = fetch_image(&pool, viewer, &image).await?;
+
+    Ok(Json(Receipt::new(&config.load(), &headers, &record)))
+}
+
+/// `GET /image/:id/receipt.png`: the QR code linking to the image's details page.
+pub async fn qr_code(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(viewer): Extension<Viewer>,
+    image: ImageId,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let record = fetch_image(&pool, viewer, &image).await?;
+    let receipt = Receipt::new(&config.load(), &headers, &record);
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            (header::CACHE_CONTROL, "public, max-age=86400"),
+        ],
+        encode_qr(&receipt.url)?,
+    )
+        .into_response())
+}
+
+/// A PNG QR code of `text`, black on white.
+fn encode_qr(text: &str) -> anyhow::Result<Vec<u8>> {
+    let code = QrCode::new(text)?;
+    let modules = code.width() as u32;
+    let colors = code.to_colors();
+    let side = (modules + 2 * QUIET_ZONE) * MODULE_PIXELS;
+    let pixels = GrayImage::from_fn(side, side, |x, y| {
+        let (x, y) = (x / MODULE_PIXELS, y / MODULE_PIXELS);
+        let inside = QUIET_ZONE..QUIET_ZONE + modules;
+        let dark = inside.contains(&x)
+            && inside.contains(&y)
+            && colors[((y - QUIET_ZONE) * modules + x - QUIET_ZONE) as usize] == Color::Dark;
+        Luma([if dark { 0 } else { 255 }])
+    });
+
+    let mut png = Vec::new();
+    DynamicImage::ImageLuma8(pixels).write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    Ok(png)
+}
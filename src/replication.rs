@@ -0,0 +1,72 @@
+//! Hooks for running alongside external SQLite replication (litestream and friends):
+//! restoring a missing database at startup and checkpointing the WAL on a schedule.
+
+use std::{path::Path, str::FromStr, time::Duration};
+
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+
+use crate::config::Config;
+
+/// If the database file doesn't exist yet, restore it from the configured replica path,
+/// or failing that by running the configured restore command.
+pub async fn restore_if_missing(config: &Config) -> anyhow::Result<()> {
+    let db_path = SqliteConnectOptions::from_str(&config.database_url)?.get_filename();
+    if db_path.as_os_str() == ":memory:" || db_path.exists() {
+        return Ok(());
+    }
+
+    if let Some(replica) = &config.database_replica {
+        if replica.exists() {
+            restore_from_file(replica, &db_path).await?;
+            println!("Restored database from replica {}", replica.display());
+            return Ok(());
+        }
+    }
+
+    if let Some(command) = &config.database_restore_command {
+        let command = command.replace("{path}", &db_path.to_string_lossy());
+        let status = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .status()
+            .await?;
+        if !status.success() {
+            anyhow::bail!("Database restore command failed ({status}): {command}");
+        }
+        println!("Restored database with `{command}`");
+    }
+
+    Ok(())
+}
+
+async fn restore_from_file(replica: &Path, db_path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = db_path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    // Copy next to the target and rename so a crash never leaves a half-written database.
+    let partial = db_path.with_extension("restoring");
+    tokio::fs::copy(replica, &partial).await?;
+    tokio::fs::rename(&partial, db_path).await?;
+
+    Ok(())
+}
+
+/// Runs `PRAGMA wal_checkpoint` every `interval`, so the main database file is a consistent
+/// snapshot that replication tools can pick up.
+pub fn spawn_checkpointer(pool: SqlitePool, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            if let Err(e) = sqlx::query("PRAGMA wal_checkpoint(PASSIVE)")
+                .execute(&pool)
+                .await
+            {
+                eprintln!("WAL checkpoint failed: {e}");
+            }
+        }
+    });
+}
@@ -0,0 +1,143 @@
+//! Caches the responses of `/images`, `/search` and `/search/live` for `RESPONSE_CACHE_MS`.
+//!
+//! Listing and searching run the same queries over and over while nothing changes. A
+//! response is kept under the viewer, the URL and the form it was asked with, and served
+//! again until it's older than `RESPONSE_CACHE_MS` or anything is written: every write
+//! request, image deletion or settings reload bumps a generation that older entries are
+//! ignored under. Hits and misses are counted in `/metrics`.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Instant,
+};
+
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::Request,
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{auth::Viewer, config::SharedConfig, error::AppError};
+
+/// Responses kept at most; when full, stale ones are dropped, and all of them if none is.
+const MAX_ENTRIES: usize = 256;
+/// Largest form a cached search may have.
+const MAX_FORM_BYTES: usize = 64 * 1024;
+
+/// Bumped by every write; entries from an older generation are stale.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+static ENTRIES: OnceLock<Mutex<HashMap<String, Entry>>> = OnceLock::new();
+
+/// Requests answered from the cache since startup.
+pub static HITS: AtomicU64 = AtomicU64::new(0);
+/// Requests the cache had no fresh response for since startup.
+pub static MISSES: AtomicU64 = AtomicU64::new(0);
+
+struct Entry {
+    generation: u64,
+    stored_at: Instant,
+    content_type: Option<HeaderValue>,
+    body: Bytes,
+}
+
+fn entries() -> &'static Mutex<HashMap<String, Entry>> {
+    ENTRIES.get_or_init(Default::default)
+}
+
+/// Makes every cached response stale.
+pub fn invalidate() {
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Middleware for the routes whose responses are cached.
+pub async fn cached(request: Request, next: Next) -> Response {
+    let Some(ttl) = request
+        .extensions()
+        .get::<SharedConfig>()
+        .and_then(|config| config.load().response_cache)
+    else {
+        return next.run(request).await;
+    };
+    let viewer = request.extensions().get::<Viewer>().copied();
+    let (parts, body) = request.into_parts();
+    let Ok(form) = to_bytes(body, MAX_FORM_BYTES).await else {
+        return AppError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "body_too_large",
+            format!("Forms may be at most {MAX_FORM_BYTES} bytes"),
+        )
+        .with_param("max", MAX_FORM_BYTES.to_string())
+        .into_response();
+    };
+    let key = format!(
+        "{viewer:?} {} {} {}",
+        parts.method,
+        parts.uri,
+        String::from_utf8_lossy(&form)
+    );
+
+    let generation = GENERATION.load(Ordering::Relaxed);
+    if let Some(entry) = entries().lock().unwrap().get(&key) {
+        if entry.generation == generation && entry.stored_at.elapsed() < ttl {
+            HITS.fetch_add(1, Ordering::Relaxed);
+            let mut response = entry.body.clone().into_response();
+            if let Some(content_type) = &entry.content_type {
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_TYPE, content_type.clone());
+            }
+            return response;
+        }
+    }
+    MISSES.fetch_add(1, Ordering::Relaxed);
+
+    let response = next.run(Request::from_parts(parts, Body::from(form))).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => return AppError::from(e).into_response(),
+    };
+
+    // Stored under the generation the response was made in, so a write while it was being
+    // made leaves it stale.
+    let mut entries = entries().lock().unwrap();
+    if entries.len() >= MAX_ENTRIES {
+        let current = GENERATION.load(Ordering::Relaxed);
+        entries.retain(|_, entry| entry.generation == current && entry.stored_at.elapsed() < ttl);
+        if entries.len() >= MAX_ENTRIES {
+            entries.clear();
+        }
+    }
+    entries.insert(
+        key,
+        Entry {
+            generation,
+            stored_at: Instant::now(),
+            content_type: parts.headers.get(header::CONTENT_TYPE).cloned(),
+            body: body.clone(),
+        },
+    );
+    drop(entries);
+
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Middleware for routes that write: once a request other than a `GET` or `HEAD` has been
+/// handled, cached responses are stale.
+pub async fn invalidate_writes(request: Request, next: Next) -> Response {
+    let writes = !matches!(*request.method(), Method::GET | Method::HEAD);
+    let response = next.run(request).await;
+    if writes {
+        invalidate();
+    }
+    response
+}
@@ -0,0 +1,256 @@
+//! Retention rules: images deleted once they're old enough.
+//!
+//! `RETENTION_RULES` lists rules separated by `;`, each a selector and an age:
+//! `untagged after 90d; tagged temp after 7d; all after 365d`. `untagged` is images without
+//! tags, `tagged <tag>` images with the tag or one below it and `all` every image; ages are in
+//! days (`d`) or hours (`h`) since upload. Every `RETENTION_CHECK_SECS` the images past any
+//! rule's age are deleted like expired ones, leaving a tombstone so their ids answer 410 Gone,
+//! and each deletion is recorded in the audit log with the rule. With `RETENTION_DRY_RUN` the
+//! check only logs how many images each rule would delete. Pinned images (see `pins`) and
+//! images in open upload sessions are never due.
+
+use std::{collections::HashMap, fmt, time::Duration};
+
+use axum::{extract::Query, Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::{
+    audit,
+    config::{Config, SharedConfig},
+    error::AppError,
+    expiry, fetch_image_record, tags, ImageRecord,
+};
+
+const DEFAULT_DUE: i64 = 50;
+const MAX_DUE: i64 = 500;
+
+/// Which images a rule applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Selector {
+    All,
+    Untagged,
+    /// Images with the tag or one below it.
+    Tagged(String),
+}
+
+/// Images of `selector` are deleted `max_age` after they were uploaded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub selector: Selector,
+    pub max_age: Duration,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.selector {
+            Selector::All => write!(f, "all")?,
+            Selector::Untagged => write!(f, "untagged")?,
+            Selector::Tagged(tag) => write!(f, "tagged {tag}")?,
+        }
+        let secs = self.max_age.as_secs();
+        if secs.is_multiple_of(86_400) {
+            write!(f, " after {}d", secs / 86_400)
+        } else {
+            write!(f, " after {}h", secs / 3_600)
+        }
+    }
+}
+
+impl Rule {
+    fn parse(rule: &str) -> anyhow::Result<Self> {
+        let invalid = || {
+            anyhow::anyhow!(
+                "RETENTION_RULES must be rules like \"untagged after 90d\" or \
+                 \"tagged temp after 7d\" separated by `;`, got {rule:?}"
+            )
+        };
+
+        let (selector, age) = rule.rsplit_once(" after ").ok_or_else(invalid)?;
+        let (kind, tag) = selector
+            .trim()
+            .split_once(' ')
+            .unwrap_or((selector.trim(), ""));
+        let tag = tags::normalize_tag(tag);
+        let selector = match (kind.to_ascii_lowercase().as_str(), tag.is_empty()) {
+            ("all", true) => Selector::All,
+            ("untagged", true) => Selector::Untagged,
+            ("tagged", false) => Selector::Tagged(tag),
+            _ => return Err(invalid()),
+        };
+        let age = age.trim();
+        let (count, unit) = age.split_at(age.len().saturating_sub(1));
+        let unit_secs = match unit {
+            "d" => 86_400,
+            "h" => 3_600,
+            _ => return Err(invalid()),
+        };
+        let count: u64 = count
+            .parse()
+            .ok()
+            .filter(|&count| count > 0)
+            .ok_or_else(invalid)?;
+
+        Ok(Self {
+            selector,
+            max_age: Duration::from_secs(count * unit_secs),
+        })
+    }
+
+    /// Condition on `images` selecting the rule's images, with its tag bound as `?1`.
+    fn condition(&self) -> &'static str {
+        match self.selector {
+            Selector::All => "1",
+            Selector::Untagged => {
+                "NOT EXISTS (SELECT 1 FROM image_tags WHERE image_id = images.id)"
+            }
+            Selector::Tagged(_) => {
+                "EXISTS (SELECT 1 FROM image_tags WHERE image_id = images.id \
+                     AND (tag = ?1 OR substr(tag, 1, length(?1) + 1) = ?1 || '/'))"
+            }
+        }
+    }
+
+    /// Ids of the rule's images with the Unix time each is due at, soonest first, at most
+    /// `limit` (all when negative); only those due already when `only_due` is set.
+    async fn due(
+        &self,
+        pool: &SqlitePool,
+        only_due: bool,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(i64, i64)>> {
+        let tag = match &self.selector {
+            Selector::Tagged(tag) => Some(tag.as_str()),
+            _ => None,
+        };
+        Ok(sqlx::query_as(&format!(
+            "SELECT id, created_at + ?2 AS due_at FROM images \
+             WHERE {} AND NOT pinned AND session_id IS NULL \
+                 AND (NOT ?3 OR created_at + ?2 <= CAST(strftime('%s', 'now') AS INTEGER)) \
+             ORDER BY due_at, id LIMIT ?4",
+            self.condition()
+        ))
+        .bind(tag)
+        .bind(self.max_age.as_secs() as i64)
+        .bind(only_due)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?)
+    }
+}
+
+/// Parses the rules of `RETENTION_RULES`.
+pub fn parse(value: &str) -> anyhow::Result<Vec<Rule>> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(Rule::parse)
+        .collect()
+}
+
+/// Deletes the images due under `config`'s rules, or only counts them in a dry run.
+async fn enforce(pool: &SqlitePool, config: &Config) -> anyhow::Result<()> {
+    for rule in &config.retention_rules {
+        let due = rule.due(pool, true, -1).await?;
+        if config.retention_dry_run {
+            if !due.is_empty() {
+                eprintln!(
+                    "Retention dry run: {} images due under \"{rule}\"",
+                    due.len()
+                );
+            }
+            continue;
+        }
+        for (id, due_at) in due {
+            // Pinned since it was found, or deleted by someone else.
+            if let Err(e) = expiry::purge(pool, id, Some(due_at)).await {
+                eprintln!("Deleting image {id} under \"{rule}\" failed: {e:#}");
+                continue;
+            }
+            audit::record(pool, None, "retention_deleted", Some(id), &rule.to_string()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies the retention rules now and then every `RETENTION_CHECK_SECS`.
+pub fn spawn_enforcer(pool: SqlitePool, config: SharedConfig) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(config.load().retention_check_interval);
+        loop {
+            ticker.tick().await;
+            let config = config.load();
+            if config.retention_rules.is_empty() {
+                continue;
+            }
+            if let Err(e) = enforce(&pool, &config).await {
+                eprintln!("Applying retention rules failed: {e:#}");
+            }
+        }
+    });
+}
+
+#[derive(Deserialize)]
+pub struct DueQuery {
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct Due {
+    /// The rule the image is due under first.
+    rule: String,
+    due_at: i64,
+    image: ImageRecord,
+}
+
+#[derive(Serialize)]
+pub struct Schedule {
+    rules: Vec<String>,
+    dry_run: bool,
+    due: Vec<Due>,
+}
+
+/// `GET /api/v1/admin/retention?limit=`: the rules and the images they'll delete next,
+/// soonest first. Images already due are listed too, until the next check (or for good, in
+/// a dry run).
+pub async fn schedule(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Query(query): Query<DueQuery>,
+) -> Result<Json<Schedule>, AppError> {
+    let config = config.load();
+    let limit = query.limit.unwrap_or(DEFAULT_DUE).clamp(1, MAX_DUE);
+
+    // Each image under the rule it's due under first.
+    let mut soonest: HashMap<i64, (i64, &Rule)> = HashMap::new();
+    for rule in &config.retention_rules {
+        for (id, due_at) in rule.due(&pool, false, limit).await? {
+            let entry = soonest.entry(id).or_insert((due_at, rule));
+            if due_at < entry.0 {
+                *entry = (due_at, rule);
+            }
+        }
+    }
+    let mut soonest: Vec<_> = soonest.into_iter().collect();
+    soonest.sort_by_key(|&(id, (due_at, _))| (due_at, id));
+    soonest.truncate(limit as usize);
+
+    let mut due = Vec::with_capacity(soonest.len());
+    for (id, (due_at, rule)) in soonest {
+        if let Some(image) = fetch_image_record(&pool, id).await? {
+            due.push(Due {
+                rule: rule.to_string(),
+                due_at,
+                image,
+            });
+        }
+    }
+
+    Ok(Json(Schedule {
+        rules: config.retention_rules.iter().map(Rule::to_string).collect(),
+        dry_run: config.retention_dry_run,
+        due,
+    }))
+}
@@ -0,0 +1,186 @@
+//! Requests to the S3 or MinIO endpoint at `S3_ENDPOINT`, shared by bucket ingestion,
+//! storage migration and presigned uploads.
+//!
+//! Objects are addressed path-style (`<endpoint>/<bucket>/<key>`), and requests are signed
+//! with Signature Version 4 when `S3_ACCESS_KEY` and `S3_SECRET_KEY` are set, anonymous
+//! otherwise. [`presign`] signs a URL instead, for a client to make the request itself.
+
+use hmac::{Hmac, Mac};
+use reqwest::{Method, RequestBuilder, Url};
+use sha2::{Digest, Sha256};
+
+use crate::{api, config::Config};
+
+/// Payload hash of requests whose body isn't signed.
+pub const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Percent-encodes `value` the way Signature Version 4 expects, keeping the `/`s if
+/// `keep_slashes`.
+fn encode(value: &str, keep_slashes: bool) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            b'/' if keep_slashes => "/".to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+fn encode_path(path: &str) -> String {
+    encode(path, true)
+}
+
+/// The URL of object `key` of `bucket`; `None` if `endpoint` isn't a URL.
+pub fn object_url(endpoint: &str, bucket: &str, key: &str) -> Option<Url> {
+    Url::parse(&format!(
+        "{}/{}/{}",
+        endpoint.trim_end_matches('/'),
+        encode_path(bucket),
+        encode_path(key)
+    ))
+    .ok()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// `url`'s `Host` header.
+fn host(url: &Url) -> String {
+    match url.port() {
+        Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+        None => url.host_str().unwrap_or_default().to_string(),
+    }
+}
+
+/// The current time as Signature Version 4 writes it, `20240501T123000Z`.
+fn amz_date() -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default();
+    api::rfc3339(now).replace(['-', ':'], "")
+}
+
+/// The credential scope of a request made on `date` (`20240501`).
+fn scope(config: &Config, date: &str) -> String {
+    format!("{date}/{}/s3/aws4_request", config.s3_region)
+}
+
+/// The signature of `canonical_request`, made at `amz_date`.
+fn signature(config: &Config, secret_key: &str, amz_date: &str, canonical_request: &str) -> String {
+    let date = &amz_date[..8];
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{}\n{}",
+        scope(config, date),
+        to_hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = [date, config.s3_region.as_str(), "s3", "aws4_request"]
+        .iter()
+        .fold(format!("AWS4{secret_key}").into_bytes(), |key, part| {
+            hmac(&key, part)
+        });
+
+    to_hex(&hmac(&key, &string_to_sign))
+}
+
+/// The `Authorization` header of a Signature Version 4 request for `url`, signing the `Host`,
+/// `X-Amz-Content-Sha256` (`payload_hash`) and `X-Amz-Date` (`amz_date`) headers.
+fn authorization(
+    config: &Config,
+    access_key: &str,
+    secret_key: &str,
+    method: &Method,
+    url: &Url,
+    payload_hash: &str,
+    amz_date: &str,
+) -> String {
+    let host = host(url);
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+    let canonical_request = format!(
+        "{method}\n{}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\n\
+         {signed_headers}\n{payload_hash}",
+        url.path()
+    );
+    let scope = scope(config, &amz_date[..8]);
+    let signature = signature(config, secret_key, amz_date, &canonical_request);
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={access_key}/{scope}, SignedHeaders={signed_headers}, \
+         Signature={signature}"
+    )
+}
+
+/// `url` signed in its query string, so anyone holding it can make a `method` request for it
+/// within `expires_in` seconds, with any body; `None` without credentials, as anonymous
+/// access needs no URL signed.
+pub fn presign(config: &Config, method: &Method, url: &Url, expires_in: u64) -> Option<Url> {
+    let (access_key, secret_key) = (
+        config.s3_access_key.as_ref()?,
+        config.s3_secret_key.as_ref()?,
+    );
+    let amz_date = amz_date();
+    let credential = format!("{access_key}/{}", scope(config, &amz_date[..8]));
+    // Sorted by name, as the canonical request needs them.
+    let query = [
+        ("X-Amz-Algorithm", "AWS4-HMAC-SHA256"),
+        ("X-Amz-Credential", credential.as_str()),
+        ("X-Amz-Date", amz_date.as_str()),
+        ("X-Amz-Expires", &expires_in.to_string()),
+        ("X-Amz-SignedHeaders", "host"),
+    ]
+    .iter()
+    .map(|(name, value)| format!("{name}={}", encode(value, false)))
+    .collect::<Vec<_>>()
+    .join("&");
+    let canonical_request = format!(
+        "{method}\n{}\n{query}\nhost:{}\n\nhost\n{UNSIGNED_PAYLOAD}",
+        url.path(),
+        host(url)
+    );
+    let signature = signature(config, secret_key, &amz_date, &canonical_request);
+
+    let mut presigned = url.clone();
+    presigned.set_query(Some(&format!("{query}&X-Amz-Signature={signature}")));
+    Some(presigned)
+}
+
+/// A `method` request for `url`, signed if there are credentials. `payload_hash` is the hex
+/// SHA-256 of the body the caller adds, or [`UNSIGNED_PAYLOAD`].
+pub fn request(
+    client: &reqwest::Client,
+    config: &Config,
+    method: Method,
+    url: &Url,
+    payload_hash: &str,
+) -> RequestBuilder {
+    let mut request = client.request(method.clone(), url.clone());
+    if let (Some(access_key), Some(secret_key)) = (&config.s3_access_key, &config.s3_secret_key) {
+        let amz_date = amz_date();
+        request = request
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", &amz_date)
+            .header(
+                "authorization",
+                authorization(
+                    config,
+                    access_key,
+                    secret_key,
+                    &method,
+                    url,
+                    payload_hash,
+                    &amz_date,
+                ),
+            );
+    }
+    request
+}
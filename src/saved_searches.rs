@@ -0,0 +1,336 @@
+//! Saved searches, checked against every new image.
+//!
+//! `POST /api/v1/searches` saves a search as the query string `GET /search/export` takes
+//! (`q`, `filter` and the filters of `GET /images`), with a name and optionally a webhook.
+//! Once an image is stored (or its upload session committed), a `saved_searches` job checks
+//! it against each saved search. Matches are recorded, and listed oldest first by
+//! `GET /api/v1/searches/:id/matches?after=`, a feed to poll with the `next_after` of the
+//! previous page. Searches with a webhook also get each match POSTed to it as JSON in the
+//! v1 format: `{"event": "saved_search_match", "search": {"id", "name"}, "image": {...}}`. A
+//! delivery that fails is retried with the job, and given up on when the job is.
+//!
+//! Only images uploaded from then on are matched, never the ones already stored.
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    Extension, Json,
+};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{
+    api, config::SharedConfig, error::AppError, fetch_image_record, filters::SearchFilters,
+    ImageRecord,
+};
+
+/// How long a webhook may take to answer.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MATCHES: i64 = 50;
+const MAX_MATCHES: i64 = 200;
+
+/// The search's own parameters; the rest of the query string are [`SearchFilters`].
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+    /// Exact tag terms, see `tag_match` in `sql_functions`.
+    filter: Option<String>,
+}
+
+fn parse_query(raw: &str) -> Result<(SearchQuery, SearchFilters), AppError> {
+    let invalid = |e: serde_urlencoded::de::Error| {
+        AppError::bad_request("invalid_query", format!("Invalid query: {e}"))
+            .with_param("error", e.to_string())
+    };
+    let query = serde_urlencoded::from_str(raw).map_err(invalid)?;
+    let filters: SearchFilters = serde_urlencoded::from_str(raw).map_err(invalid)?;
+    // Compiled now so a bad filter is refused when saving, not skipped on every match.
+    filters.compile(1)?;
+
+    Ok((query, filters))
+}
+
+#[derive(Deserialize)]
+pub struct NewSearch {
+    /// Defaults to the query.
+    name: Option<String>,
+    query: String,
+    webhook: Option<String>,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct SavedSearch {
+    id: i64,
+    name: String,
+    query: String,
+    webhook: Option<String>,
+    created_at: i64,
+    /// Images matched so far.
+    matches: i64,
+}
+
+const SEARCH_COLUMNS: &str = "id, name, query, webhook, created_at, \
+     (SELECT COUNT(*) FROM saved_search_matches WHERE search_id = saved_searches.id) AS matches";
+
+fn not_found(id: i64) -> AppError {
+    AppError::new(
+        StatusCode::NOT_FOUND,
+        "saved_search_not_found",
+        format!("No saved search {id}"),
+    )
+    .with_param("id", id.to_string())
+}
+
+/// `POST /api/v1/searches`
+pub async fn create(
+    Extension(pool): Extension<SqlitePool>,
+    Json(search): Json<NewSearch>,
+) -> Result<(StatusCode, Json<SavedSearch>), AppError> {
+    let query = search.query.trim().trim_start_matches('?').to_string();
+    parse_query(&query)?;
+    let webhook = search
+        .webhook
+        .as_deref()
+        .map(str::trim)
+        .filter(|webhook| !webhook.is_empty());
+    if let Some(webhook) = webhook {
+        let valid = Url::parse(webhook)
+            .map(|url| matches!(url.scheme(), "http" | "https"))
+            .unwrap_or(false);
+        if !valid {
+            return Err(AppError::bad_request(
+                "invalid_webhook",
+                "webhook must be an http or https URL",
+            ));
+        }
+    }
+    let name = search
+        .name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .unwrap_or(&query)
+        .to_string();
+
+    let saved = sqlx::query_as::<_, SavedSearch>(&format!(
+        "INSERT INTO saved_searches (name, query, webhook, created_at) \
+         VALUES (?, ?, ?, CAST(strftime('%s', 'now') AS INTEGER)) \
+         RETURNING {SEARCH_COLUMNS}"
+    ))
+    .bind(name)
+    .bind(&query)
+    .bind(webhook)
+    .fetch_one(&pool)
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(saved)))
+}
+
+/// `GET /api/v1/searches`, oldest first.
+pub async fn list(
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<Json<Vec<SavedSearch>>, AppError> {
+    Ok(Json(
+        sqlx::query_as(&format!(
+            "SELECT {SEARCH_COLUMNS} FROM saved_searches ORDER BY id"
+        ))
+        .fetch_all(&pool)
+        .await?,
+    ))
+}
+
+/// `DELETE /api/v1/searches/:id`, with its matches.
+pub async fn delete(
+    Extension(pool): Extension<SqlitePool>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM saved_search_matches WHERE search_id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    let deleted = sqlx::query("DELETE FROM saved_searches WHERE id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(not_found(id));
+    }
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+pub struct MatchesQuery {
+    /// `next_after` of the previous page.
+    after: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct Match {
+    id: i64,
+    matched_at: i64,
+    image: ImageRecord,
+}
+
+#[derive(Serialize)]
+pub struct Matches {
+    matches: Vec<Match>,
+    /// Pass as `after` for the matches since; the same as given when there were none.
+    next_after: Option<i64>,
+}
+
+/// `GET /api/v1/searches/:id/matches`, oldest first.
+pub async fn matches(
+    Extension(pool): Extension<SqlitePool>,
+    Path(id): Path<i64>,
+    Query(query): Query<MatchesQuery>,
+) -> Result<Json<Matches>, AppError> {
+    let exists: Option<i64> = sqlx::query_scalar("SELECT id FROM saved_searches WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&pool)
+        .await?;
+    if exists.is_none() {
+        return Err(not_found(id));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_MATCHES).clamp(1, MAX_MATCHES);
+    let rows: Vec<(i64, i64, i64)> = sqlx::query_as(
+        "SELECT id, image_id, matched_at FROM saved_search_matches \
+         WHERE search_id = ? AND id > ? ORDER BY id LIMIT ?",
+    )
+    .bind(id)
+    .bind(query.after.unwrap_or(0))
+    .bind(limit)
+    .fetch_all(&pool)
+    .await?;
+
+    let next_after = rows.last().map(|(id, ..)| *id).or(query.after);
+    let mut matches = Vec::with_capacity(rows.len());
+    for (id, image_id, matched_at) in rows {
+        if let Some(image) = fetch_image_record(&pool, image_id).await? {
+            matches.push(Match {
+                id,
+                matched_at,
+                image,
+            });
+        }
+    }
+
+    Ok(Json(Matches {
+        matches,
+        next_after,
+    }))
+}
+
+/// Checks image `id` against every saved search, then delivers its matches to the searches'
+/// webhooks. Images still in an upload session are left for when it's committed.
+pub async fn check(pool: &SqlitePool, config: &SharedConfig, id: i64) -> anyhow::Result<()> {
+    let searches: Vec<(i64, String)> = sqlx::query_as("SELECT id, query FROM saved_searches")
+        .fetch_all(pool)
+        .await?;
+    let descendants = config.load().tag_search_descendants;
+
+    for (search_id, raw) in searches {
+        // Checked when saved.
+        let Ok((query, filters)) = parse_query(&raw) else {
+            continue;
+        };
+        let Ok(filters) = filters.compile(5) else {
+            continue;
+        };
+        let sql = format!(
+            "SELECT id FROM images \
+             WHERE id = ?1 AND session_id IS NULL \
+                 AND (tags LIKE ?2 OR title LIKE ?2 OR description LIKE ?2) \
+                 AND (?3 IS NULL OR tag_match(tags, ?3, ?4)){}",
+            filters.sql
+        );
+        let matched = filters
+            .bind(
+                sqlx::query_as::<_, (i64,)>(&sql)
+                    .bind(id)
+                    .bind(format!("%{}%", query.q))
+                    .bind(query.filter)
+                    .bind(descendants),
+            )
+            .fetch_optional(pool)
+            .await?;
+        if matched.is_some() {
+            sqlx::query(
+                "INSERT OR IGNORE INTO saved_search_matches (search_id, image_id, matched_at) \
+                 VALUES (?, ?, CAST(strftime('%s', 'now') AS INTEGER))",
+            )
+            .bind(search_id)
+            .bind(id)
+            .execute(pool)
+            .await?;
+        }
+    }
+
+    deliver(pool, id).await
+}
+
+/// POSTs image `id`'s undelivered matches to their searches' webhooks.
+async fn deliver(pool: &SqlitePool, id: i64) -> anyhow::Result<()> {
+    let pending: Vec<(i64, i64, String, String)> = sqlx::query_as(
+        "SELECT saved_search_matches.id, saved_searches.id, name, webhook \
+         FROM saved_search_matches JOIN saved_searches ON saved_searches.id = search_id \
+         WHERE image_id = ? AND delivered_at IS NULL AND webhook IS NOT NULL",
+    )
+    .bind(id)
+    .fetch_all(pool)
+    .await?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let Some(image) = fetch_image_record(pool, id).await? else {
+        return Ok(());
+    };
+
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()?;
+    let mut failed = None;
+    for (match_id, search_id, name, webhook) in pending {
+        let body = api::response_to_v1(serde_json::json!({
+            "event": "saved_search_match",
+            "search": { "id": search_id, "name": name },
+            "image": image,
+        }));
+        let sent = client
+            .post(&webhook)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        match sent {
+            Ok(_) => {
+                sqlx::query(
+                    "UPDATE saved_search_matches \
+                     SET delivered_at = CAST(strftime('%s', 'now') AS INTEGER) WHERE id = ?",
+                )
+                .bind(match_id)
+                .execute(pool)
+                .await?;
+            }
+            Err(e) => {
+                failed = Some(anyhow::anyhow!(
+                    "Webhook of saved search {search_id} failed: {e}"
+                ))
+            }
+        }
+    }
+
+    match failed {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,99 @@
+//! Malware scanning of uploaded files before they're stored.
+
+use std::{path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub enum ScanVerdict {
+    Clean,
+    Infected(String),
+}
+
+#[async_trait]
+pub trait Scanner: Send + Sync {
+    async fn scan(&self, bytes: &[u8]) -> anyhow::Result<ScanVerdict>;
+}
+
+pub type SharedScanner = Arc<dyn Scanner>;
+
+/// Builds the scanner configured by `CLAMD_ADDRESS`, if any.
+pub fn from_config(config: &crate::config::Config) -> Option<SharedScanner> {
+    let address = config.clamd_address.as_deref()?;
+    Some(Arc::new(Clamd::parse(address)))
+}
+
+/// Talks to a clamd daemon using its `INSTREAM` command.
+pub enum Clamd {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+/// clamd rejects streams chunked larger than its `StreamMaxLength`; stay well below it.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+impl Clamd {
+    /// Accepts `tcp://host:port`, `unix:///path/clamd.sock`, a bare `host:port` or a bare
+    /// absolute socket path.
+    pub fn parse(address: &str) -> Self {
+        if let Some(addr) = address.strip_prefix("tcp://") {
+            Clamd::Tcp(addr.to_string())
+        } else if let Some(path) = address.strip_prefix("unix://") {
+            Clamd::Unix(PathBuf::from(path))
+        } else if address.starts_with('/') {
+            Clamd::Unix(PathBuf::from(address))
+        } else {
+            Clamd::Tcp(address.to_string())
+        }
+    }
+
+    async fn instream<S>(mut stream: S, bytes: &[u8]) -> anyhow::Result<ScanVerdict>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        stream.write_all(b"zINSTREAM\0").await?;
+        for chunk in bytes.chunks(CHUNK_SIZE) {
+            stream
+                .write_all(&(chunk.len() as u32).to_be_bytes())
+                .await?;
+            stream.write_all(chunk).await?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).await?;
+        stream.flush().await?;
+
+        // With the `z` prefix clamd terminates its reply with a NUL byte.
+        let mut reply = Vec::new();
+        let mut byte = [0u8; 1];
+        while stream.read(&mut byte).await? == 1 && byte[0] != 0 {
+            reply.push(byte[0]);
+        }
+        let reply = String::from_utf8_lossy(&reply);
+        let reply = reply.trim_end_matches(['\0', '\n']);
+
+        // Replies look like `stream: OK` or `stream: Eicar-Test-Signature FOUND`.
+        let status = reply.strip_prefix("stream: ").unwrap_or(reply);
+        if status == "OK" {
+            Ok(ScanVerdict::Clean)
+        } else if let Some(signature) = status.strip_suffix(" FOUND") {
+            Ok(ScanVerdict::Infected(signature.to_string()))
+        } else {
+            anyhow::bail!("Unexpected clamd reply: {reply}")
+        }
+    }
+}
+
+#[async_trait]
+impl Scanner for Clamd {
+    async fn scan(&self, bytes: &[u8]) -> anyhow::Result<ScanVerdict> {
+        match self {
+            Clamd::Tcp(addr) => {
+                let stream = tokio::net::TcpStream::connect(addr).await?;
+                Self::instream(stream, bytes).await
+            }
+            Clamd::Unix(path) => {
+                let stream = tokio::net::UnixStream::connect(path).await?;
+                Self::instream(stream, bytes).await
+            }
+        }
+    }
+}
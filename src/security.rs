@@ -0,0 +1,96 @@
+//! Security headers for HTML responses, and for SVG images.
+//!
+//! Each HTML response gets a fresh CSP nonce. Templates put it on their `<script>` tags as
+//! `nonce="{csp_nonce}"` (filled in by [`crate::fragments::read_template`]), and only
+//! scripts carrying it may run. SVGs are sanitized on upload, and opened directly they're
+//! sandboxed too, so nothing left in one can run or load anything.
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::{
+    config::{Config, SharedConfig},
+    svg,
+};
+
+/// Policy for SVG images: their own inline styles and embedded images, nothing else.
+const SVG_POLICY: &str = "default-src 'none'; style-src 'unsafe-inline'; img-src data:; sandbox";
+
+tokio::task_local! {
+    static NONCE: String;
+}
+
+/// The current request's CSP nonce, empty outside of a request.
+pub fn nonce() -> String {
+    NONCE.try_with(|nonce| nonce.clone()).unwrap_or_default()
+}
+
+fn policy(config: &Config, nonce: &str) -> String {
+    if let Some(policy) = &config.content_security_policy {
+        return policy.replace("{nonce}", nonce);
+    }
+
+    // Images may also come from the CDN in front of us.
+    let mut img_src = "'self' data:".to_string();
+    if let Some(cdn) = &config.public_base_url {
+        img_src = format!("{img_src} {cdn}");
+    }
+    format!(
+        "default-src 'self'; script-src 'nonce-{nonce}'; style-src 'self' 'unsafe-inline'; \
+         img-src {img_src}; connect-src 'self'; base-uri 'self'; form-action 'self'; \
+         object-src 'none'; frame-ancestors {}",
+        config.frame_ancestors
+    )
+}
+
+/// Middleware adding `Content-Security-Policy`, `X-Content-Type-Options` and
+/// `Referrer-Policy` to HTML responses, and the first two to SVGs.
+pub async fn headers(
+    Extension(config): Extension<SharedConfig>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = config.load();
+    let nonce = STANDARD.encode(rand::random::<[u8; 16]>());
+    let mut response = NONCE.scope(nonce.clone(), next.run(request)).await;
+
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if content_type == svg::CONTENT_TYPE {
+        let headers = response.headers_mut();
+        headers.insert(
+            header::CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static(SVG_POLICY),
+        );
+        headers.insert(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        );
+        return response;
+    }
+    if !content_type.starts_with("text/html") {
+        return response;
+    }
+
+    let headers = response.headers_mut();
+    let mut set = |name: HeaderName, value: &str| match HeaderValue::from_str(value) {
+        Ok(value) => {
+            headers.insert(name, value);
+        }
+        Err(e) => eprintln!("Invalid {name} header {value:?}: {e:#}"),
+    };
+    set(header::CONTENT_SECURITY_POLICY, &policy(&config, &nonce));
+    set(header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+    set(header::REFERRER_POLICY, &config.referrer_policy);
+
+    response
+}
@@ -0,0 +1,215 @@
+//! Size, SHA-256 and modification time of the files images are served from, sent along as
+//! `Content-Length`, `X-Checksum-SHA256` and `Last-Modified` so clients can check what they
+//! downloaded and caches can revalidate.
+//!
+//! For originals they come from the `images` row. Files made from an original (thumbnails,
+//! stripped and converted copies, burst previews) are recorded in `served_files` whenever
+//! they're written, and found there when served, rather than by looking at the file.
+//!
+//! The table also accounts for the space these files take, per image and variant (the part
+//! of the file name after the image id: `thumb`, `thumb_center`, `stripped`...), and when
+//! each was last served. With `DERIVED_FILES_MAX_BYTES` set, the least recently served are
+//! deleted every minute until they fit again; they're made again when next asked for.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{cdn, config::SharedConfig, image_locks, ImageRecord};
+
+pub const CHECKSUM_HEADER: HeaderName = HeaderName::from_static("x-checksum-sha256");
+
+const EVICT_INTERVAL: Duration = Duration::from_secs(60);
+/// Serving a file again within this many seconds doesn't update when it was last served,
+/// sparing a write on every request.
+const SERVED_PRECISION_SECS: i64 = 60;
+
+/// Files evicted, and their bytes, since startup.
+pub static EVICTED_FILES: AtomicU64 = AtomicU64::new(0);
+pub static EVICTED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, FromRow)]
+pub struct FileInfo {
+    byte_size: i64,
+    sha256: String,
+    modified_at: i64,
+}
+
+impl FileInfo {
+    /// The original's, `None` for images stored before its size and hash were recorded.
+    pub fn original(image: &ImageRecord) -> Option<FileInfo> {
+        Some(FileInfo {
+            byte_size: image.byte_size?,
+            sha256: image.content_hash.clone()?,
+            modified_at: image.updated_at,
+        })
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        let modified = UNIX_EPOCH + Duration::from_secs(self.modified_at.max(0) as u64);
+        let values = [
+            (
+                axum::http::header::CONTENT_LENGTH,
+                HeaderValue::from(self.byte_size),
+            ),
+            (
+                CHECKSUM_HEADER,
+                HeaderValue::from_str(&self.sha256).unwrap(),
+            ),
+            (
+                axum::http::header::ETAG,
+                HeaderValue::from_str(&format!("\"{}\"", self.sha256)).unwrap(),
+            ),
+            (
+                axum::http::header::LAST_MODIFIED,
+                HeaderValue::from_str(&httpdate::fmt_http_date(modified)).unwrap(),
+            ),
+        ];
+        for (name, value) in values {
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// The variant of image `id` at `path`, `images/<id>_<variant>.<extension>`.
+fn variant(id: i64, path: &str) -> &str {
+    let name = path.strip_prefix(&format!("images/{id}_")).unwrap_or(path);
+    name.rsplit_once('.').map_or(name, |(variant, _)| variant)
+}
+
+/// Records `path`, just written from image `id`'s original.
+pub async fn record(pool: &SqlitePool, id: i64, path: &str) -> anyhow::Result<()> {
+    let bytes = tokio::fs::read(path).await?;
+    sqlx::query(
+        "INSERT OR REPLACE INTO served_files \
+             (path, image_id, variant, byte_size, sha256, modified_at, last_served_at) \
+         VALUES (?, ?, ?, ?, ?, CAST(strftime('%s', 'now') AS INTEGER), \
+                 CAST(strftime('%s', 'now') AS INTEGER))",
+    )
+    .bind(path)
+    .bind(id)
+    .bind(variant(id, path))
+    .bind(bytes.len() as i64)
+    .bind(cdn::content_hash(&bytes))
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// What was recorded about `path`, noting it's being served. Files made before recording
+/// began are recorded now, once.
+pub async fn lookup(pool: &SqlitePool, id: i64, path: &str) -> anyhow::Result<FileInfo> {
+    sqlx::query(
+        "UPDATE served_files SET last_served_at = CAST(strftime('%s', 'now') AS INTEGER) \
+         WHERE path = ? AND last_served_at < CAST(strftime('%s', 'now') AS INTEGER) - ?",
+    )
+    .bind(path)
+    .bind(SERVED_PRECISION_SECS)
+    .execute(pool)
+    .await?;
+
+    if let Some(info) = known(pool, path).await? {
+        return Ok(info);
+    }
+    record(pool, id, path).await?;
+
+    known(pool, path)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("{path} wasn't recorded"))
+}
+
+/// The recorded size and hash of `path`, `None` if it isn't recorded (yet).
+pub async fn known(pool: &SqlitePool, path: &str) -> anyhow::Result<Option<FileInfo>> {
+    Ok(
+        sqlx::query_as("SELECT byte_size, sha256, modified_at FROM served_files WHERE path = ?")
+            .bind(path)
+            .fetch_optional(pool)
+            .await?,
+    )
+}
+
+/// Forgets the files made from image `id`'s original, when they're deleted.
+pub async fn forget(pool: &SqlitePool, id: i64) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM served_files WHERE image_id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Space taken by derived files of one variant.
+#[derive(FromRow, serde::Serialize)]
+pub struct VariantUsage {
+    pub variant: String,
+    pub files: i64,
+    pub bytes: i64,
+}
+
+pub async fn usage(pool: &SqlitePool) -> anyhow::Result<Vec<VariantUsage>> {
+    Ok(sqlx::query_as(
+        "SELECT variant, COUNT(*) AS files, SUM(byte_size) AS bytes FROM served_files \
+         GROUP BY variant ORDER BY variant",
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Deletes the least recently served derived files until they take at most `max_bytes`.
+async fn evict(pool: &SqlitePool, max_bytes: u64) -> anyhow::Result<()> {
+    let total: i64 = sqlx::query_scalar("SELECT COALESCE(SUM(byte_size), 0) FROM served_files")
+        .fetch_one(pool)
+        .await?;
+    let mut excess = total - max_bytes as i64;
+    if excess <= 0 {
+        return Ok(());
+    }
+
+    let files: Vec<(i64, String, i64)> = sqlx::query_as(
+        "SELECT image_id, path, byte_size FROM served_files ORDER BY last_served_at, path",
+    )
+    .fetch_all(pool)
+    .await?;
+    for (id, path, byte_size) in files {
+        if excess <= 0 {
+            break;
+        }
+        let _lock = image_locks::write(id).await;
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        sqlx::query("DELETE FROM served_files WHERE path = ?")
+            .bind(&path)
+            .execute(pool)
+            .await?;
+        excess -= byte_size;
+        EVICTED_FILES.fetch_add(1, Ordering::Relaxed);
+        EVICTED_BYTES.fetch_add(byte_size as u64, Ordering::Relaxed);
+    }
+
+    Ok(())
+}
+
+/// Keeps derived files within `DERIVED_FILES_MAX_BYTES`, checking every minute.
+pub fn spawn_evictor(pool: SqlitePool, config: SharedConfig) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(EVICT_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let max_bytes = config.load().derived_files_max_bytes;
+            if max_bytes == 0 {
+                continue;
+            }
+            if let Err(e) = evict(&pool, max_bytes).await {
+                eprintln!("Evicting derived files failed: {e:#}");
+            }
+        }
+    });
+}
@@ -0,0 +1,217 @@
+//! Configuration overrides stored in the `settings` table, applied without a restart.
+//!
+//! Rows are named like the environment variables they override and take precedence over
+//! them. A watcher re-reads the table every `SETTINGS_POLL_SECS` and swaps in a new
+//! [`Config`] when it changed, so edits made directly in the database are picked up too.
+//! Only settings read per request can be overridden; the rest (database, storage, worker
+//! pools) are fixed at startup.
+
+use std::{collections::HashMap, sync::Arc};
+
+use arc_swap::ArcSwap;
+use axum::{extract::Path, http::StatusCode, Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{
+    audit,
+    client_ip::ClientIp,
+    config::{Config, SharedConfig},
+    error::AppError,
+    response_cache,
+};
+
+/// Settings that take effect on the next request when changed.
+pub const RELOADABLE: [&str; 22] = [
+    "STRIP_METADATA",
+    "PUBLIC_GALLERY",
+    "ANONYMOUS_RATE_LIMIT",
+    "IMAGE_VERSIONS_KEPT",
+    "CONTENT_SECURITY_POLICY",
+    "FRAME_ANCESTORS",
+    "REFERRER_POLICY",
+    "CSRF_PROTECTION",
+    "READ_ONLY",
+    "REQUIRE_ALT_TEXT",
+    "MIN_FREE_DISK_BYTES",
+    "COMMENT_RATE_LIMIT",
+    "DOWNLOAD_RATE_LIMIT",
+    "ANONYMOUS_DOWNLOAD_RATE_LIMIT",
+    "RESPONSE_CACHE_MS",
+    "MODERATE_UPLOADS",
+    "INTEGRITY_CHECK_BATCH",
+    "BURST_PREVIEWS",
+    "DERIVED_FILES_MAX_BYTES",
+    "TAG_SEARCH_DESCENDANTS",
+    "RETENTION_RULES",
+    "RETENTION_DRY_RUN",
+];
+
+#[derive(FromRow, Serialize)]
+pub struct Setting {
+    name: String,
+    value: String,
+    updated_at: i64,
+}
+
+#[derive(Deserialize)]
+pub struct SettingValue {
+    value: String,
+}
+
+async fn overrides(pool: &SqlitePool) -> anyhow::Result<HashMap<String, String>> {
+    let rows: Vec<(String, String)> = sqlx::query_as("SELECT name, value FROM settings")
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter(|(name, _)| RELOADABLE.contains(&name.as_str()))
+        .collect())
+}
+
+/// The configuration at startup: `base` (from the environment) with the stored overrides.
+/// Overrides that don't make a valid configuration are ignored with a warning.
+pub async fn load(pool: &SqlitePool, base: Config) -> anyhow::Result<SharedConfig> {
+    let config = match Config::with_overrides(&overrides(pool).await?) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Ignoring the settings table: {e:#}");
+            base
+        }
+    };
+
+    Ok(Arc::new(ArcSwap::from_pointee(config)))
+}
+
+/// Re-reads the overrides every `settings_poll_interval`, swapping in the new configuration
+/// when it differs from the live one.
+pub fn spawn_watcher(pool: SqlitePool, config: SharedConfig) {
+    tokio::spawn(async move {
+        let mut rejected = None;
+        loop {
+            tokio::time::sleep(config.load().settings_poll_interval).await;
+            let latest = match overrides(&pool).await {
+                Ok(latest) => latest,
+                Err(e) => {
+                    eprintln!("Failed to read settings: {e:#}");
+                    continue;
+                }
+            };
+            match Config::with_overrides(&latest) {
+                Ok(new) if **config.load() != new => {
+                    config.store(Arc::new(new));
+                    response_cache::invalidate();
+                    println!("Reloaded settings");
+                }
+                Ok(_) => {}
+                // Only complain once about the same bad overrides.
+                Err(e) if rejected.as_ref() != Some(&latest) => {
+                    eprintln!("Keeping the current configuration: {e:#}");
+                    rejected = Some(latest);
+                }
+                Err(_) => {}
+            }
+        }
+    });
+}
+
+/// Checks the overrides with `name` set to `value` (or removed) make a valid configuration,
+/// and applies them straight away.
+async fn apply(
+    pool: &SqlitePool,
+    config: &SharedConfig,
+    name: &str,
+    value: Option<&str>,
+) -> Result<(), AppError> {
+    let mut latest = overrides(pool).await?;
+    match value {
+        Some(value) => latest.insert(name.to_string(), value.to_string()),
+        None => latest.remove(name),
+    };
+    let new = Config::with_overrides(&latest).map_err(|e| {
+        AppError::bad_request("invalid_setting", format!("Invalid setting: {e:#}"))
+            .with_param("error", format!("{e:#}"))
+    })?;
+
+    match value {
+        Some(value) => {
+            sqlx::query(
+                "INSERT OR REPLACE INTO settings (name, value, updated_at) \
+                 VALUES (?, ?, CAST(strftime('%s', 'now') AS INTEGER))",
+            )
+            .bind(name)
+            .bind(value)
+            .execute(pool)
+            .await?;
+        }
+        None => {
+            sqlx::query("DELETE FROM settings WHERE name = ?")
+                .bind(name)
+                .execute(pool)
+                .await?;
+        }
+    }
+    config.store(Arc::new(new));
+
+    Ok(())
+}
+
+fn check_reloadable(name: &str) -> Result<(), AppError> {
+    if RELOADABLE.contains(&name) {
+        return Ok(());
+    }
+
+    Err(AppError::new(
+        StatusCode::NOT_FOUND,
+        "unknown_setting",
+        format!("{name} can't be changed at runtime"),
+    )
+    .with_param("name", name.to_string()))
+}
+
+/// `GET /admin/settings`: the stored overrides.
+pub async fn list(Extension(pool): Extension<SqlitePool>) -> Result<Json<Vec<Setting>>, AppError> {
+    let settings =
+        sqlx::query_as::<_, Setting>("SELECT name, value, updated_at FROM settings ORDER BY name")
+            .fetch_all(&pool)
+            .await?;
+
+    Ok(Json(settings))
+}
+
+/// `PUT /admin/settings/:name` with `{"value": "..."}`.
+pub async fn set(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    ClientIp(client): ClientIp,
+    Path(name): Path<String>,
+    Json(setting): Json<SettingValue>,
+) -> Result<StatusCode, AppError> {
+    check_reloadable(&name)?;
+    apply(&pool, &config, &name, Some(&setting.value)).await?;
+    audit::record(
+        &pool,
+        Some(client),
+        "setting_changed",
+        None,
+        &format!("{name}={}", setting.value),
+    )
+    .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `DELETE /admin/settings/:name`: goes back to the environment's value.
+pub async fn remove(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    ClientIp(client): ClientIp,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    check_reloadable(&name)?;
+    apply(&pool, &config, &name, None).await?;
+    audit::record(&pool, Some(client), "setting_removed", None, &name).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,144 @@
+//! Finding visually similar images.
+//!
+//! Every image gets a 64-bit difference hash (dHash): the picture is shrunk to 9x8 grey
+//! pixels and each bit records whether a pixel is brighter than its right neighbour.
+//! Resizing, recompression and small edits flip few bits, so the Hamming distance between
+//! two hashes (the `hamming` SQL function) measures how alike the images look.
+
+use axum::{extract::Multipart, Extension, Json};
+use image::imageops::FilterType;
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{
+    auth::Viewer, decode_failures, error::AppError, pdf, processor, svg, ImageRecord, IMAGE_COLUMNS,
+};
+
+/// Matches further apart than this many bits (of 64) are left out by default.
+const DEFAULT_MAX_DISTANCE: u32 = 10;
+const DEFAULT_LIMIT: u32 = 20;
+
+#[derive(FromRow, Serialize)]
+pub struct SimilarImage {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    image: ImageRecord,
+    /// Bits that differ from the query image's hash; 0 means practically identical.
+    distance: i64,
+}
+
+/// The dHash of an encoded image, as stored in `image_fingerprints`.
+pub fn dhash(bytes: &[u8]) -> anyhow::Result<i64> {
+    let grey = processor::decode(bytes)?
+        .resize_exact(9, 8, FilterType::Triangle)
+        .to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = grey.get_pixel(x, y)[0] > grey.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+
+    // SQLite integers are signed; keep the bits as they are.
+    Ok(hash as i64)
+}
+
+/// Hashes image `id`'s original and stores the result.
+pub async fn fingerprint(pool: &SqlitePool, id: i64) -> anyhow::Result<()> {
+    let hash =
+        processor::watched(move || dhash(&std::fs::read(processor::raster_source(id)?)?)).await;
+    if let Err(e) = &hash {
+        decode_failures::record(pool, id, e).await;
+    }
+    let hash = hash?;
+
+    sqlx::query("INSERT OR REPLACE INTO image_fingerprints (image_id, dhash) VALUES (?, ?)")
+        .bind(id)
+        .bind(hash)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Like [`fingerprint`], for callers that carry on without one.
+pub async fn fingerprint_or_log(pool: &SqlitePool, id: i64) {
+    if let Err(e) = fingerprint(pool, id).await {
+        eprintln!("Failed to fingerprint image {id}: {e:#}");
+    }
+}
+
+/// `POST /search/by-image`: multipart `image`, plus optional `max_distance` and `limit`.
+/// Returns stored images sorted by how close their hash is to the query image's.
+pub async fn search_by_image(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    mut multipart: Multipart,
+) -> Result<Json<Vec<SimilarImage>>, AppError> {
+    let mut image = None;
+    let mut max_distance = DEFAULT_MAX_DISTANCE;
+    let mut limit = DEFAULT_LIMIT;
+    while let Some(field) = multipart.next_field().await? {
+        let name = field.name().unwrap_or_default().to_string();
+        let data = field.bytes().await?;
+        let number = || {
+            std::str::from_utf8(&data)
+                .ok()
+                .and_then(|value| value.trim().parse::<u32>().ok())
+                .ok_or_else(|| {
+                    AppError::bad_request("invalid_number", format!("{name} must be a number"))
+                        .with_param("name", name.clone())
+                })
+        };
+        match name.as_str() {
+            "image" => image = Some(data.to_vec()),
+            "max_distance" => max_distance = number()?.min(64),
+            "limit" => limit = number()?.clamp(1, 100),
+            _ => {
+                return Err(AppError::bad_request(
+                    "unknown_field",
+                    format!("Unknown field: {name}"),
+                )
+                .with_param("name", name))
+            }
+        }
+    }
+    let Some(image) = image else {
+        return Err(AppError::bad_request(
+            "missing_image",
+            "Searching by image needs an `image` field",
+        ));
+    };
+
+    crate::check_readable(&image)?;
+    let image = if svg::is_svg(&image) {
+        svg::rasterize(&image)?
+    } else if pdf::is_pdf(&image) {
+        pdf::rasterize(&image)?
+    } else {
+        image
+    };
+    let hash = processor::watched(move || dhash(&image)).await?;
+
+    let columns = IMAGE_COLUMNS
+        .split(", ")
+        .map(|column| format!("images.{column}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let matches = sqlx::query_as::<_, SimilarImage>(&format!(
+        "SELECT {columns}, hamming(image_fingerprints.dhash, ?1) AS distance \
+         FROM images JOIN image_fingerprints ON image_fingerprints.image_id = images.id \
+         WHERE {} AND hamming(image_fingerprints.dhash, ?1) <= ?2 \
+         ORDER BY distance, images.id LIMIT ?3",
+        viewer.visible()
+    ))
+    .bind(hash)
+    .bind(max_distance)
+    .bind(limit)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(matches))
+}
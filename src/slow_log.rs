@@ -0,0 +1,288 @@
+//! Logs requests and database queries slower than `SLOW_REQUEST_MS` and `SLOW_QUERY_MS`.
+//!
+//! Built on the spans `telemetry` already opens, so it works with or without an OTLP
+//! collector. A slow request is logged with its route, the image it concerned, and how its
+//! time split between database queries, storage (`storage.*` spans) and encoding
+//! (`thumbnail.render`). A slow query is logged with its SQL and the request it ran for.
+//! Both are counted in `/metrics`.
+
+use std::{
+    fmt::Debug,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    subscriber::Interest,
+    Event, Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use crate::config::Config;
+
+/// Requests over `SLOW_REQUEST_MS` since startup.
+pub static SLOW_REQUESTS: AtomicU64 = AtomicU64::new(0);
+/// Queries over `SLOW_QUERY_MS` since startup.
+pub static SLOW_QUERIES: AtomicU64 = AtomicU64::new(0);
+
+/// Where sqlx reports each statement it ran, with its duration.
+const QUERY_TARGET: &str = "sqlx::query";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Category {
+    Storage,
+    Encode,
+}
+
+impl Category {
+    fn of(name: &str) -> Option<Category> {
+        if name.starts_with("storage.") {
+            Some(Category::Storage)
+        } else if name == "thumbnail.render" {
+            Some(Category::Encode)
+        } else {
+            None
+        }
+    }
+}
+
+/// Kept on every span while it's open.
+struct Started(Instant);
+
+/// Kept on request spans: what the request was, and where its time went so far.
+#[derive(Default)]
+struct Breakdown {
+    method: String,
+    route: String,
+    image_id: Option<i64>,
+    queries: u32,
+    db: Duration,
+    storage: Duration,
+    encode: Duration,
+}
+
+/// The span and event fields the log cares about.
+#[derive(Default)]
+struct Fields {
+    method: Option<String>,
+    route: Option<String>,
+    image_id: Option<i64>,
+    summary: Option<String>,
+    statement: Option<String>,
+    elapsed_secs: Option<f64>,
+}
+
+impl Visit for Fields {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if field.name() == "image.id" {
+            self.image_id = Some(value);
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "image.id" {
+            self.image_id = i64::try_from(value).ok();
+        }
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if field.name() == "elapsed_secs" {
+            self.elapsed_secs = Some(value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let slot = match field.name() {
+            "http.route" => &mut self.route,
+            "summary" => &mut self.summary,
+            "db.statement" => &mut self.statement,
+            _ => return,
+        };
+        *slot = Some(value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "http.request.method" {
+            self.method = Some(format!("{value:?}"));
+        }
+    }
+}
+
+pub struct SlowLog {
+    request_threshold: Option<Duration>,
+    query_threshold: Option<Duration>,
+}
+
+impl SlowLog {
+    pub fn new(config: &Config) -> Self {
+        SlowLog {
+            request_threshold: config.slow_request,
+            query_threshold: config.slow_query,
+        }
+    }
+
+    /// Remembers the first image a request's spans name, for its log line.
+    fn note_image<S>(&self, ctx: &Context<'_, S>, id: &Id, image_id: i64)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if let Some(request) = span.scope().find(|span| span.name() == "request") {
+            if let Some(breakdown) = request.extensions_mut().get_mut::<Breakdown>() {
+                breakdown.image_id.get_or_insert(image_id);
+            }
+        }
+    }
+}
+
+impl<S> Layer<S> for SlowLog
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if metadata.is_span() || metadata.target() == QUERY_TARGET {
+            Interest::always()
+        } else {
+            Interest::never()
+        }
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+
+        let mut extensions = span.extensions_mut();
+        extensions.insert(Started(Instant::now()));
+        if span.name() == "request" {
+            extensions.insert(Breakdown {
+                method: fields.method.unwrap_or_default(),
+                route: fields.route.unwrap_or_default(),
+                ..Breakdown::default()
+            });
+        } else if let Some(image_id) = fields.image_id {
+            drop(extensions);
+            self.note_image(&ctx, id, image_id);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        values.record(&mut fields);
+        if let Some(image_id) = fields.image_id {
+            self.note_image(&ctx, id, image_id);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if event.metadata().target() != QUERY_TARGET {
+            return;
+        }
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let Some(elapsed) = fields
+            .elapsed_secs
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        else {
+            return;
+        };
+
+        let request = ctx
+            .event_scope(event)
+            .and_then(|mut scope| scope.find(|span| span.name() == "request"));
+        let mut context = String::new();
+        if let Some(request) = &request {
+            if let Some(breakdown) = request.extensions_mut().get_mut::<Breakdown>() {
+                breakdown.queries += 1;
+                breakdown.db += elapsed;
+                context = format!(" in {} {}", breakdown.method, breakdown.route);
+                if let Some(image_id) = breakdown.image_id {
+                    context.push_str(&format!(" (image {image_id})"));
+                }
+            }
+        }
+
+        if self
+            .query_threshold
+            .is_some_and(|threshold| elapsed >= threshold)
+        {
+            SLOW_QUERIES.fetch_add(1, Ordering::Relaxed);
+            // sqlx leaves the statement empty when the summary is the whole of it.
+            let sql = fields
+                .statement
+                .filter(|statement| !statement.is_empty())
+                .or(fields.summary)
+                .unwrap_or_default();
+            // sqlx formats long statements over several lines.
+            let sql = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+            eprintln!("Slow query ({} ms){context}: {sql}", elapsed.as_millis());
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(elapsed) = span
+            .extensions()
+            .get::<Started>()
+            .map(|started| started.0.elapsed())
+        else {
+            return;
+        };
+
+        if span.name() == "request" {
+            let Some(threshold) = self.request_threshold else {
+                return;
+            };
+            if elapsed < threshold {
+                return;
+            }
+            let extensions = span.extensions();
+            let Some(breakdown) = extensions.get::<Breakdown>() else {
+                return;
+            };
+            SLOW_REQUESTS.fetch_add(1, Ordering::Relaxed);
+            let image = breakdown
+                .image_id
+                .map(|id| format!(" image {id},"))
+                .unwrap_or_default();
+            eprintln!(
+                "Slow request {} {} ({} ms,{image} db {} ms in {} queries, storage {} ms, encode {} ms)",
+                breakdown.method,
+                breakdown.route,
+                elapsed.as_millis(),
+                breakdown.db.as_millis(),
+                breakdown.queries,
+                breakdown.storage.as_millis(),
+                breakdown.encode.as_millis(),
+            );
+            return;
+        }
+
+        let Some(category) = Category::of(span.name()) else {
+            return;
+        };
+        // Counted once, at the outermost span of its kind, up to the request.
+        for ancestor in span.scope().skip(1) {
+            if ancestor.name() == "request" {
+                if let Some(breakdown) = ancestor.extensions_mut().get_mut::<Breakdown>() {
+                    match category {
+                        Category::Storage => breakdown.storage += elapsed,
+                        Category::Encode => breakdown.encode += elapsed,
+                    }
+                }
+                return;
+            }
+            if Category::of(ancestor.name()) == Some(category) {
+                return;
+            }
+        }
+    }
+}
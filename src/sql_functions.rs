@@ -0,0 +1,130 @@
+//! Application-defined SQLite functions, registered on every pooled connection so filters
+//! can run inside queries rather than over rows pulled into Rust.
+//!
+//! - `tag_match(tags, filter, descendants)`: 1 when the comma-separated `tags` satisfy every
+//!   term of the comma-separated `filter`, else 0. Terms match whole tags normalized like
+//!   stored ones (see `tags::normalize_tag`), so case and Unicode composition don't matter;
+//!   `sun*` matches any tag starting with `sun`, and `-cat` requires that no tag matches
+//!   `cat`. When `descendants` is 1, a term also matches the tags below it, so `animal`
+//!   matches `animal/cat` (`TAG_SEARCH_DESCENDANTS`).
+//! - `hamming(a, b)`: the number of bits that differ between two 64-bit integers, for
+//!   comparing perceptual hashes.
+
+use std::{
+    ffi::{c_int, CStr},
+    ptr,
+};
+
+use libsqlite3_sys as ffi;
+use sqlx::SqliteConnection;
+
+use crate::tags;
+
+type ScalarFunction =
+    unsafe extern "C" fn(*mut ffi::sqlite3_context, c_int, *mut *mut ffi::sqlite3_value);
+
+pub async fn register(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    let mut handle = conn.lock_handle().await?;
+    let db = handle.as_raw_handle().as_ptr();
+
+    let functions: [(&CStr, c_int, ScalarFunction); 2] =
+        [(c"tag_match", 3, tag_match), (c"hamming", 2, hamming)];
+    for (name, arguments, function) in functions {
+        // SAFETY: `db` is a live connection we hold the lock on, the name is NUL-terminated
+        // and `function` has the signature SQLite expects for a scalar function.
+        let rc = unsafe {
+            ffi::sqlite3_create_function_v2(
+                db,
+                name.as_ptr(),
+                arguments,
+                ffi::SQLITE_UTF8 | ffi::SQLITE_DETERMINISTIC,
+                ptr::null_mut(),
+                Some(function),
+                None,
+                None,
+                None,
+            )
+        };
+        if rc != ffi::SQLITE_OK {
+            return Err(sqlx::Error::Protocol(format!(
+                "Registering {name:?} failed with code {rc}"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a text argument, or `None` if it's NULL or not UTF-8.
+///
+/// # Safety
+///
+/// `value` must be an argument passed to the running function.
+unsafe fn text_arg<'a>(value: *mut ffi::sqlite3_value) -> Option<&'a str> {
+    if ffi::sqlite3_value_type(value) == ffi::SQLITE_NULL {
+        return None;
+    }
+    let text = ffi::sqlite3_value_text(value);
+    if text.is_null() {
+        return None;
+    }
+    CStr::from_ptr(text.cast()).to_str().ok()
+}
+
+unsafe extern "C" fn tag_match(
+    ctx: *mut ffi::sqlite3_context,
+    argc: c_int,
+    argv: *mut *mut ffi::sqlite3_value,
+) {
+    let args = std::slice::from_raw_parts(argv, argc as usize);
+    match (text_arg(args[0]), text_arg(args[1])) {
+        (Some(tags), Some(filter)) => {
+            let descendants = ffi::sqlite3_value_int(args[2]) != 0;
+            ffi::sqlite3_result_int(ctx, matches_filter(tags, filter, descendants) as c_int)
+        }
+        _ => ffi::sqlite3_result_null(ctx),
+    }
+}
+
+unsafe extern "C" fn hamming(
+    ctx: *mut ffi::sqlite3_context,
+    argc: c_int,
+    argv: *mut *mut ffi::sqlite3_value,
+) {
+    let args = std::slice::from_raw_parts(argv, argc as usize);
+    if args
+        .iter()
+        .any(|&arg| ffi::sqlite3_value_type(arg) == ffi::SQLITE_NULL)
+    {
+        ffi::sqlite3_result_null(ctx);
+        return;
+    }
+    let a = ffi::sqlite3_value_int64(args[0]);
+    let b = ffi::sqlite3_value_int64(args[1]);
+    ffi::sqlite3_result_int(ctx, (a ^ b).count_ones() as c_int);
+}
+
+fn matches_filter(tags: &str, filter: &str, descendants: bool) -> bool {
+    let tags: Vec<String> = tags
+        .split(',')
+        .map(tags::normalize_tag)
+        .filter(|tag| !tag.is_empty())
+        .collect();
+
+    filter
+        .split(',')
+        .map(tags::normalize_tag)
+        .filter(|term| !term.is_empty())
+        .all(|term| {
+            let (negated, term) = match term.strip_prefix('-') {
+                Some(term) => (true, term.to_string()),
+                None => (false, term),
+            };
+            let found = match term.strip_suffix('*') {
+                Some(prefix) => tags.iter().any(|tag| tag.starts_with(prefix)),
+                None if descendants => tags.iter().any(|tag| tags::is_within(tag, &term)),
+                None => tags.contains(&term),
+            };
+            found != negated
+        })
+}
@@ -0,0 +1,64 @@
+//! Starring images as favorites, shown as a toggle on each gallery thumbnail.
+//!
+//! Listings and searches take `starred=true` (or `false`) to filter on it, see `filters`.
+
+use axum::{response::Html, Extension, Json};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::{
+    error::AppError,
+    i18n,
+    ids::{self, ImageId, ImageKey},
+};
+
+#[derive(Serialize)]
+pub struct Star {
+    #[serde(rename = "id")]
+    key: ImageKey,
+    starred: bool,
+}
+
+async fn toggle(pool: &SqlitePool, ImageId { id, key }: &ImageId) -> Result<bool, AppError> {
+    sqlx::query_scalar("UPDATE images SET starred = NOT starred WHERE id = ? RETURNING starred")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| ids::not_found(key))
+}
+
+/// The star toggle of a gallery thumbnail, which swaps itself for the new state when clicked.
+pub fn button(key: &ImageKey, starred: bool) -> String {
+    let (symbol, label) = if starred {
+        ("&#9733;", i18n::t("gallery.unstar", &[]))
+    } else {
+        ("&#9734;", i18n::t("gallery.star", &[]))
+    };
+    format!(
+        "<button type=\"button\" class=\"star\" hx-post=\"/fragments/star/{key}\" hx-swap=\"outerHTML\" \
+         aria-pressed=\"{starred}\" title=\"{label}\" aria-label=\"{label}\">{symbol}</button>"
+    )
+}
+
+/// `POST /api/v1/image/:id/star`: stars the image, or unstars it if it was starred.
+pub async fn toggle_star(
+    Extension(pool): Extension<SqlitePool>,
+    image: ImageId,
+) -> Result<Json<Star>, AppError> {
+    let starred = toggle(&pool, &image).await?;
+
+    Ok(Json(Star {
+        key: image.key,
+        starred,
+    }))
+}
+
+/// `POST /fragments/star/:id`: the same, answering with the updated toggle.
+pub async fn toggle_star_button(
+    Extension(pool): Extension<SqlitePool>,
+    image: ImageId,
+) -> Result<Html<String>, AppError> {
+    let starred = toggle(&pool, &image).await?;
+
+    Ok(Html(button(&image.key, starred)))
+}
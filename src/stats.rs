@@ -0,0 +1,235 @@
+//! Service statistics: `GET /api/v1/stats` as JSON and `GET /metrics` for Prometheus.
+
+use std::{fmt::Write, sync::atomic::Ordering};
+
+use axum::{
+    http::header,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::{
+    config::{Config, SharedConfig},
+    disk::{self, DiskUsage},
+    error::AppError,
+    image_locks, response_cache,
+    served_files::{self, VariantUsage},
+    slow_log, thumbnail,
+};
+
+#[derive(Serialize)]
+pub struct DiskStats {
+    #[serde(flatten)]
+    pub usage: DiskUsage,
+    min_free_bytes: u64,
+    accepting_uploads: bool,
+}
+
+#[derive(Serialize)]
+pub struct DerivedFiles {
+    /// Per variant, see `served_files`.
+    variants: Vec<VariantUsage>,
+    /// `DERIVED_FILES_MAX_BYTES`, `None` for no limit.
+    max_bytes: Option<u64>,
+    /// Since startup.
+    evicted_files: u64,
+    evicted_bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+    images: i64,
+    pub disk: Option<DiskStats>,
+    uploads_refused: u64,
+    derived_files: DerivedFiles,
+}
+
+pub async fn collect(pool: &SqlitePool, config: &Config) -> Result<Stats, AppError> {
+    let min_free_bytes = config.min_free_disk_bytes;
+    let images = sqlx::query_scalar("SELECT COUNT(*) FROM images")
+        .fetch_one(pool)
+        .await?;
+    let disk = match disk::usage() {
+        Ok(usage) => Some(DiskStats {
+            usage,
+            min_free_bytes,
+            accepting_uploads: usage.free_bytes >= min_free_bytes,
+        }),
+        Err(e) => {
+            eprintln!("Failed to check free disk space: {e}");
+            None
+        }
+    };
+
+    let derived_files = DerivedFiles {
+        variants: served_files::usage(pool).await?,
+        max_bytes: Some(config.derived_files_max_bytes).filter(|&max| max > 0),
+        evicted_files: served_files::EVICTED_FILES.load(Ordering::Relaxed),
+        evicted_bytes: served_files::EVICTED_BYTES.load(Ordering::Relaxed),
+    };
+
+    Ok(Stats {
+        images,
+        disk,
+        uploads_refused: disk::UPLOADS_REFUSED.load(Ordering::Relaxed),
+        derived_files,
+    })
+}
+
+/// `GET /api/v1/stats`
+pub async fn stats(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+) -> Result<Json<Stats>, AppError> {
+    Ok(Json(collect(&pool, &config.load()).await?))
+}
+
+/// Prometheus text exposition, one metric family at a time.
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str, samples: &[(&str, u64)]) {
+        let _ = writeln!(self.0, "# HELP thumbnail_service_{name} {help}");
+        let _ = writeln!(self.0, "# TYPE thumbnail_service_{name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(self.0, "thumbnail_service_{name}{labels} {value}");
+        }
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        self.family(name, "gauge", help, &[("", value)]);
+    }
+}
+
+/// `GET /metrics`
+pub async fn metrics(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+) -> Result<Response, AppError> {
+    let stats = collect(&pool, &config.load()).await?;
+
+    let mut out = Exposition::default();
+    out.gauge("images", "Images stored.", stats.images as u64);
+    if let Some(disk) = &stats.disk {
+        out.gauge(
+            "disk_total_bytes",
+            "Size of the storage volume.",
+            disk.usage.total_bytes,
+        );
+        out.gauge(
+            "disk_free_bytes",
+            "Space left on the storage volume.",
+            disk.usage.free_bytes,
+        );
+        out.gauge(
+            "disk_used_bytes",
+            "Space used on the storage volume.",
+            disk.usage.used_bytes,
+        );
+        out.gauge(
+            "disk_min_free_bytes",
+            "Free space below which uploads are refused.",
+            disk.min_free_bytes,
+        );
+    }
+    out.family(
+        "uploads_refused_total",
+        "counter",
+        "Uploads refused since startup.",
+        &[("{reason=\"disk_space\"}", stats.uploads_refused)],
+    );
+    let derived = &stats.derived_files;
+    let labels: Vec<String> = derived
+        .variants
+        .iter()
+        .map(|usage| format!("{{variant=\"{}\"}}", usage.variant))
+        .collect();
+    let samples = |value: fn(&VariantUsage) -> i64| {
+        labels
+            .iter()
+            .zip(&derived.variants)
+            .map(|(labels, usage)| (labels.as_str(), value(usage) as u64))
+            .collect::<Vec<_>>()
+    };
+    out.family(
+        "derived_files",
+        "gauge",
+        "Files made from originals (thumbnails, stripped and converted copies), by variant.",
+        &samples(|usage| usage.files),
+    );
+    out.family(
+        "derived_files_bytes",
+        "gauge",
+        "Space taken by files made from originals, by variant.",
+        &samples(|usage| usage.bytes),
+    );
+    if let Some(max_bytes) = derived.max_bytes {
+        out.gauge(
+            "derived_files_max_bytes",
+            "Space files made from originals may take before the least recently served are evicted.",
+            max_bytes,
+        );
+    }
+    out.family(
+        "derived_files_evicted_total",
+        "counter",
+        "Files made from originals evicted to stay within DERIVED_FILES_MAX_BYTES.",
+        &[("", derived.evicted_files)],
+    );
+    out.family(
+        "derived_files_evicted_bytes_total",
+        "counter",
+        "Bytes of the files evicted.",
+        &[("", derived.evicted_bytes)],
+    );
+    out.family(
+        "thumbnail_requests_coalesced_total",
+        "counter",
+        "Requests that waited for a thumbnail another request was already making.",
+        &[("", thumbnail::COALESCED.load(Ordering::Relaxed))],
+    );
+    out.family(
+        "thumbnails_cancelled_total",
+        "counter",
+        "Thumbnails abandoned partway because every request waiting for them had gone.",
+        &[("", thumbnail::CANCELLED.load(Ordering::Relaxed))],
+    );
+    out.family(
+        "image_lock_waits_total",
+        "counter",
+        "Operations that waited for another on the same image to finish.",
+        &[("", image_locks::CONTENDED.load(Ordering::Relaxed))],
+    );
+    out.family(
+        "slow_requests_total",
+        "counter",
+        "Requests slower than SLOW_REQUEST_MS.",
+        &[("", slow_log::SLOW_REQUESTS.load(Ordering::Relaxed))],
+    );
+    out.family(
+        "slow_queries_total",
+        "counter",
+        "Database queries slower than SLOW_QUERY_MS.",
+        &[("", slow_log::SLOW_QUERIES.load(Ordering::Relaxed))],
+    );
+    out.family(
+        "response_cache_requests_total",
+        "counter",
+        "Listings and searches by whether the response cache had them.",
+        &[
+            (
+                "{result=\"hit\"}",
+                response_cache::HITS.load(Ordering::Relaxed),
+            ),
+            (
+                "{result=\"miss\"}",
+                response_cache::MISSES.load(Ordering::Relaxed),
+            ),
+        ],
+    );
+
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out.0).into_response())
+}
@@ -0,0 +1,409 @@
+//! Copying the stored originals to another storage backend, ahead of switching to it.
+//!
+//! `POST /api/v1/admin/storage/migrate?to=s3` starts copying every blob (see `blobs`) not
+//! copied yet to `STORAGE_S3_BUCKET` at `S3_ENDPOINT`, as `blobs/<sha256>`, in the background.
+//! Originals and versions stored before blobs existed are adopted into them first, so they're
+//! copied too.
+//! Each blob is hashed before it's sent, and the upload is signed with that hash so the bucket
+//! refuses a body that changed on the way. The object is then looked up again, and only once
+//! its size (and its ETag, when that is an MD5) matches is it recorded in `blobs` as copied.
+//!
+//! Progress is kept in `storage_migrations` after every blob, and `GET` on the same route
+//! reports it. A migration cut short by a restart carries on at the next start, and one
+//! stopped by an error carries on where it stopped when it's started again. Blobs missing or
+//! damaged on disk are counted as failed and skipped; the integrity check deals with those.
+//! Starting a finished migration again copies whatever has been stored since.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use axum::{extract::Query, http::StatusCode, Extension, Json};
+use md5::Md5;
+use reqwest::{header, Method};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{
+    audit, blobs,
+    client_ip::ClientIp,
+    config::{Config, SharedConfig},
+    error::AppError,
+    image_locks, s3,
+};
+
+/// Storage backends originals can be migrated to.
+const TARGETS: [&str; 1] = ["s3"];
+/// Where blobs are kept on disk, see `blobs`.
+const BLOBS_DIR: &str = "blobs";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Whether a migration is running in this process.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Deserialize)]
+pub struct MigrateQuery {
+    to: String,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct MigrationStatus {
+    target: String,
+    /// `pending`, `running`, `done` or `failed`.
+    state: String,
+    /// Blobs handled so far, of `total`.
+    done: i64,
+    total: i64,
+    copied: i64,
+    copied_bytes: i64,
+    /// Blobs skipped because they're missing or damaged on disk.
+    failed: i64,
+    error: Option<String>,
+    started_at: Option<i64>,
+    updated_at: Option<i64>,
+}
+
+const STATUS_COLUMNS: &str = "target, state, done, total, copied, copied_bytes, failed, error, \
+     started_at, updated_at";
+
+fn check_target(to: &str) -> Result<(), AppError> {
+    if TARGETS.contains(&to) {
+        return Ok(());
+    }
+    Err(AppError::bad_request(
+        "unknown_storage_target",
+        format!(
+            "Unknown storage target {to:?}, use one of {}",
+            TARGETS.join(", ")
+        ),
+    )
+    .with_param("to", to)
+    .with_param("targets", TARGETS.join(", ")))
+}
+
+/// The endpoint and bucket to copy to.
+fn destination(config: &Config) -> Result<(String, String), AppError> {
+    match (&config.s3_endpoint, &config.storage_s3_bucket) {
+        (Some(endpoint), Some(bucket)) => Ok((endpoint.clone(), bucket.clone())),
+        _ => Err(AppError::bad_request(
+            "storage_target_unconfigured",
+            "Set S3_ENDPOINT and STORAGE_S3_BUCKET to migrate to S3",
+        )),
+    }
+}
+
+async fn status(pool: &SqlitePool, target: &str) -> anyhow::Result<MigrationStatus> {
+    let recorded: Option<MigrationStatus> = sqlx::query_as(&format!(
+        "SELECT {STATUS_COLUMNS} FROM storage_migrations WHERE target = ?"
+    ))
+    .bind(target)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(recorded.unwrap_or_else(|| MigrationStatus {
+        target: target.to_string(),
+        state: "pending".to_string(),
+        done: 0,
+        total: 0,
+        copied: 0,
+        copied_bytes: 0,
+        failed: 0,
+        error: None,
+        started_at: None,
+        updated_at: None,
+    }))
+}
+
+/// `GET /api/v1/admin/storage/migrate?to=s3`
+pub async fn progress(
+    Extension(pool): Extension<SqlitePool>,
+    Query(query): Query<MigrateQuery>,
+) -> Result<Json<MigrationStatus>, AppError> {
+    check_target(&query.to)?;
+
+    Ok(Json(status(&pool, &query.to).await?))
+}
+
+/// `POST /api/v1/admin/storage/migrate?to=s3`: starts or resumes the migration, answering
+/// with its progress. Does nothing but answer while it's running.
+pub async fn migrate(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    ClientIp(client): ClientIp,
+    Query(query): Query<MigrateQuery>,
+) -> Result<(StatusCode, Json<MigrationStatus>), AppError> {
+    check_target(&query.to)?;
+    destination(&config.load())?;
+
+    if !RUNNING.swap(true, Ordering::SeqCst) {
+        let previous = status(&pool, &query.to).await;
+        let fresh = !matches!(
+            previous.as_ref().map(|previous| previous.state.as_str()),
+            Ok("failed" | "running")
+        );
+        if let Err(e) = start(&pool, &query.to, fresh).await {
+            RUNNING.store(false, Ordering::SeqCst);
+            return Err(e.into());
+        }
+        audit::record(
+            &pool,
+            Some(client),
+            "storage_migration_started",
+            None,
+            &format!("to={}", query.to),
+        )
+        .await?;
+        spawn(pool.clone(), config);
+    }
+
+    Ok((StatusCode::ACCEPTED, Json(status(&pool, &query.to).await?)))
+}
+
+/// Marks the migration running, from the start unless it's resuming one that stopped.
+async fn start(pool: &SqlitePool, target: &str, fresh: bool) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT OR IGNORE INTO storage_migrations (target, updated_at) \
+         VALUES (?, CAST(strftime('%s', 'now') AS INTEGER))",
+    )
+    .bind(target)
+    .execute(pool)
+    .await?;
+    if fresh {
+        let remaining: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM blobs WHERE s3_key IS NULL")
+            .fetch_one(pool)
+            .await?;
+        sqlx::query(
+            "UPDATE storage_migrations SET state = 'running', last_hash = '', done = 0, \
+                 total = ?, copied = 0, copied_bytes = 0, failed = 0, error = NULL, \
+                 started_at = CAST(strftime('%s', 'now') AS INTEGER), \
+                 updated_at = CAST(strftime('%s', 'now') AS INTEGER) \
+             WHERE target = ?",
+        )
+        .bind(remaining)
+        .bind(target)
+        .execute(pool)
+        .await?;
+    } else {
+        sqlx::query(
+            "UPDATE storage_migrations SET state = 'running', error = NULL, \
+                 updated_at = CAST(strftime('%s', 'now') AS INTEGER) \
+             WHERE target = ?",
+        )
+        .bind(target)
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Carries on with a migration the previous process left running.
+pub async fn resume(pool: SqlitePool, config: SharedConfig) -> anyhow::Result<()> {
+    let state: Option<String> =
+        sqlx::query_scalar("SELECT state FROM storage_migrations WHERE target = 's3'")
+            .fetch_optional(&pool)
+            .await?;
+    if state.as_deref() == Some("running") && !RUNNING.swap(true, Ordering::SeqCst) {
+        println!("Resuming the storage migration to S3");
+        spawn(pool, config);
+    }
+
+    Ok(())
+}
+
+fn spawn(pool: SqlitePool, config: SharedConfig) {
+    tokio::spawn(async move {
+        let finished = run(&pool, &config).await;
+        let (state, error) = match &finished {
+            Ok(()) => ("done", None),
+            Err(e) => {
+                eprintln!("Storage migration to S3 stopped: {e:#}");
+                ("failed", Some(format!("{e:#}")))
+            }
+        };
+        let recorded = sqlx::query(
+            "UPDATE storage_migrations SET state = ?, error = ?, \
+                 updated_at = CAST(strftime('%s', 'now') AS INTEGER) \
+             WHERE target = 's3'",
+        )
+        .bind(state)
+        .bind(&error)
+        .execute(&pool)
+        .await;
+        if let Err(e) = recorded {
+            eprintln!("Recording the storage migration's end failed: {e}");
+        }
+        if finished.is_ok() {
+            let _ = audit::record(&pool, None, "storage_migration_finished", None, "to=s3").await;
+        }
+        RUNNING.store(false, Ordering::SeqCst);
+    });
+}
+
+/// Links the originals and versions that aren't blobs yet into `blobs`, counting the new
+/// blobs in the migration's total. Returns how many files were adopted.
+async fn adopt_legacy(pool: &SqlitePool) -> anyhow::Result<u64> {
+    let paths: Vec<(i64, String)> = sqlx::query_as(
+        "SELECT id, path FROM ( \
+             SELECT id, 'images/' || id || '.jpg' AS path FROM images \
+             UNION ALL \
+             SELECT image_id, 'images/' || image_id || '_v' || n || '.jpg' FROM image_versions) \
+         WHERE path NOT IN (SELECT path FROM blob_links)",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut adopted = 0;
+    for (id, path) in paths {
+        // Not while the image is replaced, which would store the old bytes over the new.
+        let _lock = image_locks::write(id).await;
+        if blobs::adopt(pool, &path).await? {
+            adopted += 1;
+        }
+    }
+    if adopted > 0 {
+        sqlx::query(
+            "UPDATE storage_migrations \
+             SET total = (SELECT COUNT(*) FROM blobs WHERE s3_key IS NULL) WHERE target = 's3'",
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(adopted)
+}
+
+/// Copies the blobs after `last_hash`, recording progress after each.
+async fn run(pool: &SqlitePool, config: &SharedConfig) -> anyhow::Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?;
+    let mut last_hash: String =
+        sqlx::query_scalar("SELECT last_hash FROM storage_migrations WHERE target = 's3'")
+            .fetch_one(pool)
+            .await?;
+    // Only at the start, as blobs adopted later could sort before `last_hash`.
+    if last_hash.is_empty() {
+        let adopted = adopt_legacy(pool).await?;
+        if adopted > 0 {
+            println!("Storage migration adopted {adopted} originals stored before blobs");
+        }
+    }
+
+    loop {
+        let next: Option<(String, i64)> = sqlx::query_as(
+            "SELECT hash, size FROM blobs WHERE s3_key IS NULL AND hash > ? ORDER BY hash LIMIT 1",
+        )
+        .bind(&last_hash)
+        .fetch_optional(pool)
+        .await?;
+        let Some((hash, size)) = next else {
+            return Ok(());
+        };
+        // Read again for every blob, so a changed endpoint or bucket takes effect.
+        let config = config.load();
+        let (endpoint, bucket) =
+            destination(&config).map_err(|e| anyhow::anyhow!("{}", e.message()))?;
+
+        let (copied, failed) = match tokio::fs::read(format!("{BLOBS_DIR}/{hash}")).await {
+            Ok(bytes) if s3::to_hex(&Sha256::digest(&bytes)) == hash => {
+                let key = format!("{BLOBS_DIR}/{hash}");
+                copy(&client, &config, &endpoint, &bucket, &key, &hash, bytes).await?;
+                sqlx::query(
+                    "UPDATE blobs SET s3_key = ?, \
+                         s3_verified_at = CAST(strftime('%s', 'now') AS INTEGER) \
+                     WHERE hash = ?",
+                )
+                .bind(&key)
+                .bind(&hash)
+                .execute(pool)
+                .await?;
+                (1, 0)
+            }
+            Ok(_) => {
+                eprintln!("Storage migration skipped blob {hash}: its content doesn't match");
+                (0, 1)
+            }
+            Err(e) => {
+                eprintln!("Storage migration skipped blob {hash}: {e}");
+                (0, 1)
+            }
+        };
+        sqlx::query(
+            "UPDATE storage_migrations SET last_hash = ?, done = done + 1, \
+                 copied = copied + ?, copied_bytes = copied_bytes + ?, failed = failed + ?, \
+                 updated_at = CAST(strftime('%s', 'now') AS INTEGER) \
+             WHERE target = 's3'",
+        )
+        .bind(&hash)
+        .bind(copied)
+        .bind(if copied > 0 { size } else { 0 })
+        .bind(failed)
+        .execute(pool)
+        .await?;
+        last_hash = hash;
+    }
+}
+
+/// Uploads `bytes` (whose SHA-256 is `hash`) as `key`, then checks the bucket has them.
+async fn copy(
+    client: &reqwest::Client,
+    config: &Config,
+    endpoint: &str,
+    bucket: &str,
+    key: &str,
+    hash: &str,
+    bytes: Vec<u8>,
+) -> anyhow::Result<()> {
+    let url = s3::object_url(endpoint, bucket, key)
+        .ok_or_else(|| anyhow::anyhow!("S3_ENDPOINT isn't a valid URL"))?;
+    let md5 = s3::to_hex(&Md5::digest(&bytes));
+    let size = bytes.len() as u64;
+
+    let response = s3::request(client, config, Method::PUT, &url, hash)
+        .header(header::CONTENT_LENGTH, size)
+        .body(bytes)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "Uploading {key} failed: the bucket answered {}",
+            response.status()
+        );
+    }
+
+    let response = s3::request(client, config, Method::HEAD, &url, s3::UNSIGNED_PAYLOAD)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "Verifying {key} failed: the bucket answered {}",
+            response.status()
+        );
+    }
+    let length = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    if length != Some(size) {
+        anyhow::bail!("Verifying {key} failed: the bucket has {length:?} bytes, not {size}");
+    }
+    // Multipart and encrypted objects have other ETags, which say nothing about the content.
+    let etag = response
+        .headers()
+        .get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(|etag| etag.trim_matches('"').to_ascii_lowercase());
+    if let Some(etag) =
+        etag.filter(|etag| etag.len() == 32 && etag.bytes().all(|b| b.is_ascii_hexdigit()))
+    {
+        if etag != md5 {
+            anyhow::bail!("Verifying {key} failed: its ETag {etag} isn't the MD5 {md5}");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,113 @@
+//! SVG uploads.
+//!
+//! An SVG can carry scripts, event handlers and references to other files or sites, so
+//! uploads are parsed and written out again from what renders: shapes, text, gradients,
+//! embedded `data:` images. Everything else is dropped, and `<image>` elements pointing
+//! anywhere but a `data:` URL are left empty. The rewritten file is the stored original,
+//! served as `image/svg+xml` under a sandboxing CSP (see [`crate::security`]).
+//!
+//! Thumbnails, placeholders, palettes and fingerprints need pixels, so they're made from a
+//! PNG rendering (see [`crate::processor::raster_source`]), drawn on white with the longer side
+//! `RASTER_SIZE` pixels. Text is rendered with the fonts installed on the server.
+
+use std::sync::{Arc, OnceLock};
+
+use axum::http::StatusCode;
+use resvg::{
+    tiny_skia::{Color, Pixmap, Transform},
+    usvg::{self, fontdb, ImageHrefResolver, Tree},
+};
+
+use crate::error::AppError;
+
+pub const CONTENT_TYPE: &str = "image/svg+xml";
+/// Longer side of the PNG rendering raster work is done on.
+const RASTER_SIZE: f32 = 2048.0;
+
+static FONTS: OnceLock<Arc<fontdb::Database>> = OnceLock::new();
+
+/// Whether `bytes` look like an SVG document rather than some other image format.
+pub fn is_svg(bytes: &[u8]) -> bool {
+    let text = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    let start = text
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(0);
+    let text = &text[start..];
+
+    image::guess_format(bytes).is_err()
+        && [&b"<svg"[..], b"<?xml", b"<!--", b"<!DOCTYPE svg"]
+            .iter()
+            .any(|prefix| text.starts_with(prefix))
+}
+
+fn options() -> usvg::Options<'static> {
+    let fonts = FONTS.get_or_init(|| {
+        let mut fonts = fontdb::Database::new();
+        fonts.load_system_fonts();
+        Arc::new(fonts)
+    });
+
+    usvg::Options {
+        image_href_resolver: ImageHrefResolver {
+            resolve_data: ImageHrefResolver::default_data_resolver(),
+            // Anything but a data URL is a file on the server or another site.
+            resolve_string: Box::new(|_, _| None),
+        },
+        fontdb: fonts.clone(),
+        ..usvg::Options::default()
+    }
+}
+
+fn parse(bytes: &[u8]) -> Result<Tree, usvg::Error> {
+    Tree::from_data(bytes, &options())
+}
+
+/// Rewrites SVG uploads as described above. Other formats are returned as they are.
+pub fn sanitize(bytes: Vec<u8>) -> Result<Vec<u8>, AppError> {
+    if !is_svg(&bytes) {
+        return Ok(bytes);
+    }
+    let tree = parse(&bytes).map_err(|e| {
+        AppError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_svg",
+            format!("The SVG can't be read: {e}"),
+        )
+        .with_param("reason", e.to_string())
+    })?;
+    let options = usvg::WriteOptions {
+        preserve_text: true,
+        ..usvg::WriteOptions::default()
+    };
+
+    Ok(tree.to_string(&options).into_bytes())
+}
+
+/// Width and height of an SVG, rounded up to whole pixels.
+pub fn size(bytes: &[u8]) -> Option<(u32, u32)> {
+    let size = parse(bytes).ok()?.size();
+
+    Some((size.width().ceil() as u32, size.height().ceil() as u32))
+}
+
+/// Renders an SVG to PNG.
+pub fn rasterize(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let tree = parse(bytes)?;
+    let size = tree.size();
+    let scale = RASTER_SIZE / size.width().max(size.height());
+    let width = (size.width() * scale).round().max(1.0) as u32;
+    let height = (size.height() * scale).round().max(1.0) as u32;
+
+    let mut pixmap = Pixmap::new(width, height)
+        .ok_or_else(|| anyhow::anyhow!("Can't render at {width}x{height}"))?;
+    // Thumbnails are JPEGs, where transparency would come out black.
+    pixmap.fill(Color::WHITE);
+    resvg::render(
+        &tree,
+        Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+
+    Ok(pixmap.encode_png()?)
+}
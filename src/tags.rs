@@ -0,0 +1,914 @@
+//! Per-tag rows in `image_tags`, kept in step with the `images.tags` string.
+//!
+//! `images.tags` holds the comma-separated list as shown to users; `image_tags` has one row per
+//! tag, with `auto` set on tags suggested at upload time rather than typed by a person.
+//!
+//! Tags can be hierarchical, with `/` between levels: `animal/cat/siamese` is a child of
+//! `animal/cat`, itself a child of `animal`. `tag_hierarchy` has every tag stored and each of
+//! its ancestors, with its parent, whether or not the ancestors are tags of any image; it's
+//! what `GET /api/v1/tags/tree` is built from.
+
+use std::collections::{HashMap, HashSet};
+
+use axum::{
+    extract::{Path, Query},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Sqlite, SqlitePool, Transaction};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{
+    albums,
+    auth::Viewer,
+    client_ip::ClientIp,
+    config::SharedConfig,
+    error::AppError,
+    ids::{self, ImageId, RequestedId},
+};
+
+const MONTHS: [&str; 12] = [
+    "january",
+    "february",
+    "march",
+    "april",
+    "may",
+    "june",
+    "july",
+    "august",
+    "september",
+    "october",
+    "november",
+    "december",
+];
+
+/// Filename tokens that say nothing about the picture.
+const FILENAME_NOISE: [&str; 12] = [
+    "img",
+    "dsc",
+    "dscn",
+    "dcim",
+    "image",
+    "photo",
+    "pic",
+    "screenshot",
+    "jpg",
+    "jpeg",
+    "png",
+    "copy",
+];
+
+#[derive(FromRow, Serialize)]
+pub struct ImageTag {
+    pub tag: String,
+    pub auto: bool,
+}
+
+/// Most tags an image may have and longest a tag may be, in characters; 0 is no limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TagLimits {
+    pub max_count: u32,
+    pub max_length: u32,
+}
+
+impl TagLimits {
+    fn check_length(self, tag: &str) -> Result<(), AppError> {
+        if self.max_length > 0 && tag.chars().count() > self.max_length as usize {
+            return Err(AppError::bad_request(
+                "tag_too_long",
+                format!("Tags may be at most {} characters", self.max_length),
+            )
+            .with_param("max", self.max_length.to_string())
+            .with_param("tag", tag));
+        }
+
+        Ok(())
+    }
+
+    fn check_count(self, count: usize) -> Result<(), AppError> {
+        if self.max_count > 0 && count > self.max_count as usize {
+            return Err(AppError::bad_request(
+                "too_many_tags",
+                format!("Images may have at most {} tags", self.max_count),
+            )
+            .with_param("max", self.max_count.to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+/// One tag as it's stored: trimmed, lowercased and in Unicode NFC, so `Café`, ` café` and
+/// a `café` typed with a combining accent are all the same tag. Each level of a hierarchical
+/// tag is normalized on its own and empty levels are dropped, so ` Animal / Cat/` is
+/// `animal/cat`.
+pub fn normalize_tag(tag: &str) -> String {
+    tag.split('/')
+        .map(|level| level.trim().to_lowercase().nfc().collect::<String>())
+        .filter(|level| !level.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The parent of a hierarchical tag, `animal/cat` for `animal/cat/siamese`.
+pub fn parent(tag: &str) -> Option<&str> {
+    tag.rsplit_once('/').map(|(parent, _)| parent)
+}
+
+/// Whether `tag` is `ancestor` or below it, as `animal/cat` is below `animal`.
+pub fn is_within(tag: &str, ancestor: &str) -> bool {
+    tag.strip_prefix(ancestor)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Normalizes a comma-separated tag list written by a user, each tag as by
+/// [`normalize_tag`], dropping blanks and repeats. Refused if it breaks `limits`.
+pub fn normalize(tags: &str, limits: TagLimits) -> Result<Vec<String>, AppError> {
+    let tags = split(
+        &tags
+            .split(',')
+            .map(normalize_tag)
+            .collect::<Vec<_>>()
+            .join(","),
+    );
+    for tag in &tags {
+        limits.check_length(tag)?;
+    }
+    limits.check_count(tags.len())?;
+
+    Ok(tags)
+}
+
+/// Splits a comma-separated tag list, dropping blanks and repeats.
+pub fn split(tags: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    tags.split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty() && seen.insert(*tag))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Suggests tags from the uploaded file's name and its EXIF camera and capture date, e.g.
+/// `beach-trip_042.jpg` shot on a Sony in November 2023 gives
+/// `beach, trip, sony-ilce-7m3, 2023, november`.
+pub fn suggest(file_name: Option<&str>, metadata: &[(String, String)]) -> Vec<String> {
+    let mut suggestions = Vec::new();
+
+    if let Some(file_name) = file_name {
+        let stem = std::path::Path::new(file_name)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default();
+        for token in stem.split(|c: char| !c.is_alphanumeric()) {
+            let token = token.to_lowercase();
+            let is_year = token.len() == 4 && token.parse::<u32>().is_ok_and(|y| y >= 1900);
+            let is_word = token.chars().count() >= 3 && !token.chars().all(|c| c.is_numeric());
+            if (is_year || is_word) && !FILENAME_NOISE.contains(&token.as_str()) {
+                suggestions.push(token);
+            }
+        }
+    }
+
+    let field = |name: &str| {
+        metadata
+            .iter()
+            .find(|(tag, _)| tag == name)
+            .map(|(_, value)| value.trim_matches('"').trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    if let Some(model) = field("Model") {
+        let camera = match field("Make") {
+            Some(make) if !model.to_lowercase().contains(&make.to_lowercase()) => {
+                format!("{make} {model}")
+            }
+            _ => model,
+        };
+        suggestions.push(slug(&camera));
+    }
+
+    // EXIF dates are shown as `2023-11-05 14:03:22`.
+    if let Some(date) = field("DateTimeOriginal").or_else(|| field("DateTime")) {
+        let mut parts = date.split(['-', ' ', ':']);
+        if let (Some(year), Some(month)) = (parts.next(), parts.next()) {
+            if year.len() == 4 && year.parse::<u32>().is_ok() {
+                suggestions.push(year.to_string());
+            }
+            if let Some(month) = month
+                .parse::<usize>()
+                .ok()
+                .and_then(|m| MONTHS.get(m.wrapping_sub(1)))
+            {
+                suggestions.push(month.to_string());
+            }
+        }
+    }
+
+    let mut seen = HashSet::new();
+    suggestions.retain(|tag| !tag.is_empty() && seen.insert(tag.clone()));
+    suggestions
+}
+
+fn slug(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Adds `tag` and its ancestors to `tag_hierarchy`.
+async fn record_hierarchy(tx: &mut Transaction<'_, Sqlite>, tag: &str) -> anyhow::Result<()> {
+    let mut tag = Some(tag);
+    while let Some(current) = tag {
+        let inserted =
+            sqlx::query("INSERT OR IGNORE INTO tag_hierarchy (tag, parent) VALUES (?, ?)")
+                .bind(current)
+                .bind(parent(current))
+                .execute(&mut **tx)
+                .await?
+                .rows_affected();
+        // Its ancestors were recorded along with it.
+        if inserted == 0 {
+            break;
+        }
+        tag = parent(current);
+    }
+
+    Ok(())
+}
+
+/// Inserts tag rows for an image, and updates its albums.
+pub async fn insert(
+    tx: &mut Transaction<'_, Sqlite>,
+    image_id: i64,
+    tags: &[String],
+    auto: bool,
+) -> anyhow::Result<()> {
+    for tag in tags {
+        sqlx::query("INSERT OR IGNORE INTO image_tags (image_id, tag, auto) VALUES (?, ?, ?)")
+            .bind(image_id)
+            .bind(tag)
+            .bind(auto)
+            .execute(&mut **tx)
+            .await?;
+        record_hierarchy(tx, tag).await?;
+    }
+    albums::assign(tx, image_id).await?;
+
+    Ok(())
+}
+
+/// Brings an image's tag rows in line with its new `images.tags` string, in the transaction
+/// that changed it. Tags that were already there keep their `auto` flag; new ones count as
+/// typed by a person.
+pub async fn sync(
+    tx: &mut Transaction<'_, Sqlite>,
+    image_id: i64,
+    tags: &str,
+) -> anyhow::Result<()> {
+    let wanted = split(tags);
+
+    let existing: Vec<String> = sqlx::query_scalar("SELECT tag FROM image_tags WHERE image_id = ?")
+        .bind(image_id)
+        .fetch_all(&mut **tx)
+        .await?;
+    for tag in existing.iter().filter(|tag| !wanted.contains(tag)) {
+        sqlx::query("DELETE FROM image_tags WHERE image_id = ? AND tag = ?")
+            .bind(image_id)
+            .bind(tag)
+            .execute(&mut **tx)
+            .await?;
+    }
+    insert(tx, image_id, &wanted, false).await?;
+
+    Ok(())
+}
+
+pub async fn list(pool: &SqlitePool, image_id: i64) -> anyhow::Result<Vec<ImageTag>> {
+    let tags = sqlx::query_as::<_, ImageTag>(
+        "SELECT tag, auto FROM image_tags WHERE image_id = ? ORDER BY rowid",
+    )
+    .bind(image_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(tags)
+}
+
+/// Rewrites `images.tags` from the tag rows, after tags were removed through `image_tags`,
+/// and updates the image's albums.
+pub async fn rebuild_tag_string(
+    tx: &mut Transaction<'_, Sqlite>,
+    image_id: i64,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        "UPDATE images SET \
+             tags = COALESCE((SELECT group_concat(tag, ', ') FROM \
+                 (SELECT tag FROM image_tags WHERE image_id = ?1 ORDER BY rowid)), ''), \
+             version = version + 1, \
+             updated_at = CAST(strftime('%s', 'now') AS INTEGER) \
+         WHERE id = ?1",
+    )
+    .bind(image_id)
+    .execute(&mut **tx)
+    .await?;
+    albums::assign(tx, image_id).await?;
+
+    Ok(())
+}
+
+/// Body of the bulk auto-tag endpoints: which images to touch, and optionally which of their
+/// auto-generated tags (all of them when left out).
+#[derive(Deserialize)]
+pub struct AutoTagSelection {
+    ids: Vec<RequestedId>,
+    tags: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct AutoTagOutcome {
+    images: u64,
+    tags: u64,
+}
+
+/// `GET /image/:id/tags`
+pub async fn image_tags(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    ImageId { id, key }: ImageId,
+) -> Result<Json<Vec<ImageTag>>, AppError> {
+    if crate::fetch_visible_image(&pool, viewer, id)
+        .await?
+        .is_none()
+    {
+        crate::expiry::gone(&pool, id).await?;
+        return Err(ids::not_found(key));
+    }
+
+    Ok(Json(list(&pool, id).await?))
+}
+
+/// `POST /images/auto-tags/confirm`: keeps the selected auto-generated tags as regular ones.
+pub async fn confirm_auto_tags(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Json(selection): Json<AutoTagSelection>,
+) -> Result<Json<AutoTagOutcome>, AppError> {
+    let outcome = apply(
+        &pool,
+        &config,
+        &selection,
+        "UPDATE image_tags SET auto = 0 WHERE image_id = ?1 AND auto = 1 AND (?2 IS NULL OR tag = ?2)",
+        false,
+    )
+    .await?;
+
+    Ok(Json(outcome))
+}
+
+/// `POST /images/auto-tags/remove`: drops the selected auto-generated tags from the images.
+pub async fn remove_auto_tags(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Json(selection): Json<AutoTagSelection>,
+) -> Result<Json<AutoTagOutcome>, AppError> {
+    let outcome = apply(
+        &pool,
+        &config,
+        &selection,
+        "DELETE FROM image_tags WHERE image_id = ?1 AND auto = 1 AND (?2 IS NULL OR tag = ?2)",
+        true,
+    )
+    .await?;
+
+    Ok(Json(outcome))
+}
+
+/// Runs `sql` for every selected image and tag in one transaction. `sql` binds the image id
+/// as `?1` and the tag as `?2`, which is NULL to match every auto-generated tag. Ids of no
+/// image are skipped.
+async fn apply(
+    pool: &SqlitePool,
+    config: &SharedConfig,
+    selection: &AutoTagSelection,
+    sql: &str,
+    rebuild: bool,
+) -> Result<AutoTagOutcome, AppError> {
+    let scheme = config.load().image_id_scheme;
+    let mut image_ids = Vec::with_capacity(selection.ids.len());
+    for id in &selection.ids {
+        image_ids.extend(id.resolve(pool, scheme).await?);
+    }
+    let tags: Vec<Option<&str>> = match &selection.tags {
+        Some(tags) => tags.iter().map(|tag| Some(tag.trim())).collect(),
+        None => vec![None],
+    };
+
+    let mut outcome = AutoTagOutcome { images: 0, tags: 0 };
+    let mut tx = pool.begin().await?;
+    for &image_id in &image_ids {
+        let mut changed = 0;
+        for tag in &tags {
+            changed += sqlx::query(sql)
+                .bind(image_id)
+                .bind(tag)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+        if changed > 0 {
+            if rebuild {
+                rebuild_tag_string(&mut tx, image_id).await?;
+            }
+            outcome.images += 1;
+            outcome.tags += changed;
+        }
+    }
+    tx.commit().await?;
+
+    Ok(outcome)
+}
+
+/// Body of `POST /admin/tags/rename`.
+#[derive(Deserialize)]
+pub struct TagRename {
+    from: String,
+    to: String,
+}
+
+/// Body of `POST /admin/tags/merge`: every tag in `tags` becomes `into`.
+#[derive(Deserialize)]
+pub struct TagMerge {
+    tags: Vec<String>,
+    into: String,
+}
+
+#[derive(Serialize)]
+pub struct TagChangeOutcome {
+    images: u64,
+}
+
+fn invalid_tag() -> AppError {
+    AppError::bad_request("invalid_tag", "Tags can't be empty or contain commas")
+}
+
+/// Trims `tag`, rejecting what couldn't be stored in the comma-separated `images.tags`.
+fn clean_tag(tag: &str) -> Result<&str, AppError> {
+    let tag = tag.trim();
+    if tag.is_empty() || tag.contains(',') {
+        return Err(invalid_tag());
+    }
+
+    Ok(tag)
+}
+
+/// A tag being added, renamed or merged to: [`clean_tag`], then [`normalize`]d. Tags being
+/// renamed are only cleaned, and tags being removed are matched as given as well as
+/// normalized, so ones stored before normalization can be fixed.
+pub fn new_tag(tag: &str, limits: TagLimits) -> Result<String, AppError> {
+    let mut tags = normalize(clean_tag(tag)?, limits)?;
+    tags.pop().ok_or_else(invalid_tag)
+}
+
+/// `POST /admin/tags/rename`: renames a tag on every image. Images that already had the new
+/// name keep a single copy of it.
+pub async fn rename_tag(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    ClientIp(client): ClientIp,
+    Json(rename): Json<TagRename>,
+) -> Result<Json<TagChangeOutcome>, AppError> {
+    let from = clean_tag(&rename.from)?;
+    let to = new_tag(&rename.to, config.load().tag_limits)?;
+
+    let images = retag(&pool, &[from], &to).await?;
+    crate::audit::record(
+        &pool,
+        Some(client),
+        "tag_renamed",
+        None,
+        &format!("from={from} to={to} images={images}"),
+    )
+    .await?;
+
+    Ok(Json(TagChangeOutcome { images }))
+}
+
+/// `POST /admin/tags/merge`: replaces several tags with one, e.g. `dogs` and `doggo` with
+/// `dog`.
+pub async fn merge_tags(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    ClientIp(client): ClientIp,
+    Json(merge): Json<TagMerge>,
+) -> Result<Json<TagChangeOutcome>, AppError> {
+    let tags = merge
+        .tags
+        .iter()
+        .map(|tag| clean_tag(tag))
+        .collect::<Result<Vec<_>, _>>()?;
+    let into = new_tag(&merge.into, config.load().tag_limits)?;
+    if tags.is_empty() {
+        return Err(invalid_tag());
+    }
+
+    let images = retag(&pool, &tags, &into).await?;
+    crate::audit::record(
+        &pool,
+        Some(client),
+        "tags_merged",
+        None,
+        &format!("tags={} into={into} images={images}", tags.join("|")),
+    )
+    .await?;
+
+    Ok(Json(TagChangeOutcome { images }))
+}
+
+/// Replaces each of `from` with `to` in one transaction and returns how many images changed.
+/// A tag keeps its position in `images.tags`; it stays auto-generated only if every tag
+/// folded into it was.
+async fn retag(pool: &SqlitePool, from: &[&str], to: &str) -> anyhow::Result<u64> {
+    let mut tx = pool.begin().await?;
+    record_hierarchy(&mut tx, to).await?;
+    let mut changed = HashSet::new();
+    for &tag in from.iter().filter(|&&tag| tag != to) {
+        let image_ids: Vec<i64> =
+            sqlx::query_scalar("SELECT image_id FROM image_tags WHERE tag = ?")
+                .bind(tag)
+                .fetch_all(&mut *tx)
+                .await?;
+        for &image_id in &image_ids {
+            sqlx::query(
+                "UPDATE image_tags SET auto = auto AND \
+                     (SELECT auto FROM image_tags WHERE image_id = ?1 AND tag = ?2) \
+                 WHERE image_id = ?1 AND tag = ?3",
+            )
+            .bind(image_id)
+            .bind(tag)
+            .bind(to)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(
+                "UPDATE OR IGNORE image_tags SET tag = ?3 WHERE image_id = ?1 AND tag = ?2",
+            )
+            .bind(image_id)
+            .bind(tag)
+            .bind(to)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query("DELETE FROM image_tags WHERE image_id = ? AND tag = ?")
+                .bind(image_id)
+                .bind(tag)
+                .execute(&mut *tx)
+                .await?;
+        }
+        changed.extend(image_ids);
+    }
+    for &image_id in &changed {
+        rebuild_tag_string(&mut tx, image_id).await?;
+    }
+    tx.commit().await?;
+
+    Ok(changed.len() as u64)
+}
+
+/// Body of `POST /images/tags`.
+#[derive(Deserialize)]
+pub struct TagEdit {
+    ids: Vec<RequestedId>,
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TagEditStatus {
+    Updated,
+    Unchanged,
+    NotFound,
+}
+
+#[derive(Serialize)]
+pub struct TagEditResult {
+    id: RequestedId,
+    status: TagEditStatus,
+    /// The image's tags after the edit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<String>,
+}
+
+/// `POST /images/tags`: adds and removes tags on many images in one transaction. A tag in
+/// both lists is kept. Adding an auto-generated tag confirms it.
+pub async fn edit_tags(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    ClientIp(client): ClientIp,
+    Json(edit): Json<TagEdit>,
+) -> Result<Json<Vec<TagEditResult>>, AppError> {
+    let limits = config.load().tag_limits;
+    // Tags that normalize alike are added once, so they're counted once.
+    let mut add = Vec::new();
+    for tag in &edit.add {
+        let tag = new_tag(tag, limits)?;
+        if !add.contains(&tag) {
+            add.push(tag);
+        }
+    }
+    // Removed as given and normalized, so tags stored before normalization can be removed.
+    let mut remove = Vec::new();
+    for tag in &edit.remove {
+        let cleaned = clean_tag(tag)?;
+        for tag in [cleaned.to_string(), normalize_tag(cleaned)] {
+            if !tag.is_empty() && !remove.contains(&tag) {
+                remove.push(tag);
+            }
+        }
+    }
+
+    let scheme = config.load().image_id_scheme;
+    let mut image_ids = Vec::with_capacity(edit.ids.len());
+    for requested in &edit.ids {
+        image_ids.push(requested.resolve(&pool, scheme).await?);
+    }
+
+    let mut results = Vec::with_capacity(edit.ids.len());
+    let mut updated = Vec::new();
+    let mut tx = pool.begin().await?;
+    for (requested, image_id) in edit.ids.into_iter().zip(image_ids) {
+        let exists: Option<i64> = sqlx::query_scalar("SELECT id FROM images WHERE id = ?")
+            .bind(image_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let Some(id) = exists else {
+            results.push(TagEditResult {
+                id: requested,
+                status: TagEditStatus::NotFound,
+                tags: None,
+            });
+            continue;
+        };
+
+        let current = sqlx::query_as::<_, ImageTag>(
+            "SELECT tag, auto FROM image_tags WHERE image_id = ? ORDER BY rowid",
+        )
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await?;
+        let has = |tag: &str| current.iter().any(|row| row.tag == tag);
+        let removed: Vec<&str> = remove
+            .iter()
+            .map(String::as_str)
+            .filter(|&tag| has(tag) && !add.iter().any(|added| added == tag))
+            .collect();
+        let added: Vec<String> = add.iter().filter(|tag| !has(tag)).cloned().collect();
+        let confirmed: Vec<&str> = current
+            .iter()
+            .filter(|row| row.auto && add.contains(&row.tag))
+            .map(|row| row.tag.as_str())
+            .collect();
+
+        if removed.is_empty() && added.is_empty() && confirmed.is_empty() {
+            results.push(TagEditResult {
+                id: requested,
+                status: TagEditStatus::Unchanged,
+                tags: Some(
+                    current
+                        .iter()
+                        .map(|row| row.tag.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+            });
+            continue;
+        }
+
+        limits.check_count(current.len() - removed.len() + added.len())?;
+        for tag in &removed {
+            sqlx::query("DELETE FROM image_tags WHERE image_id = ? AND tag = ?")
+                .bind(id)
+                .bind(tag)
+                .execute(&mut *tx)
+                .await?;
+        }
+        for tag in &confirmed {
+            sqlx::query("UPDATE image_tags SET auto = 0 WHERE image_id = ? AND tag = ?")
+                .bind(id)
+                .bind(tag)
+                .execute(&mut *tx)
+                .await?;
+        }
+        insert(&mut tx, id, &added, false).await?;
+        rebuild_tag_string(&mut tx, id).await?;
+
+        let tags = sqlx::query_scalar("SELECT tags FROM images WHERE id = ?")
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+        results.push(TagEditResult {
+            id: requested,
+            status: TagEditStatus::Updated,
+            tags: Some(tags),
+        });
+        updated.push(id);
+    }
+    tx.commit().await?;
+
+    let detail = format!("add={} remove={}", add.join("|"), remove.join("|"));
+    for id in updated {
+        crate::audit::record(&pool, Some(client), "tags_edited", Some(id), &detail).await?;
+    }
+
+    Ok(Json(results))
+}
+
+/// A tag in `GET /api/v1/tags/tree`.
+#[derive(Serialize)]
+pub struct TagNode {
+    /// Its last level, `siamese` for `animal/cat/siamese`.
+    name: String,
+    tag: String,
+    /// Images tagged with it exactly.
+    images: i64,
+    /// Images tagged with it or anything below it; an image tagged at several levels counts
+    /// at each.
+    total: i64,
+    children: Vec<TagNode>,
+}
+
+/// Builds the node for `tag` from `children` (by parent) and `counts`, leaving out subtrees
+/// the viewer sees no image in.
+fn node(tag: &str, children: &HashMap<&str, Vec<&str>>, counts: &HashMap<String, i64>) -> TagNode {
+    let children: Vec<TagNode> = children
+        .get(tag)
+        .into_iter()
+        .flatten()
+        .map(|child| node(child, children, counts))
+        .filter(|child| child.total > 0)
+        .collect();
+    let images = counts.get(tag).copied().unwrap_or_default();
+
+    TagNode {
+        name: tag.rsplit('/').next().unwrap_or(tag).to_string(),
+        tag: tag.to_string(),
+        images,
+        total: images + children.iter().map(|child| child.total).sum::<i64>(),
+        children,
+    }
+}
+
+/// `GET /api/v1/tags/tree`: every tag the viewer can see an image with, as a tree sorted by
+/// name, for navigating the hierarchy.
+pub async fn tree(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+) -> Result<Json<Vec<TagNode>>, AppError> {
+    let counts: HashMap<String, i64> = sqlx::query_as(&format!(
+        "SELECT tag, COUNT(*) FROM image_tags JOIN images ON images.id = image_id \
+         WHERE {} GROUP BY tag",
+        viewer.visible()
+    ))
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .collect();
+    let hierarchy: Vec<(String, Option<String>)> =
+        sqlx::query_as("SELECT tag, parent FROM tag_hierarchy ORDER BY tag")
+            .fetch_all(&pool)
+            .await?;
+
+    let mut children: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut roots = Vec::new();
+    for (tag, parent) in &hierarchy {
+        match parent {
+            Some(parent) => children.entry(parent).or_default().push(tag),
+            None => roots.push(tag.as_str()),
+        }
+    }
+
+    Ok(Json(
+        roots
+            .into_iter()
+            .map(|root| node(root, &children, &counts))
+            .filter(|root| root.total > 0)
+            .collect(),
+    ))
+}
+
+const DEFAULT_RELATED: i64 = 10;
+const MAX_RELATED: i64 = 50;
+
+#[derive(Deserialize)]
+pub struct RelatedQuery {
+    /// Related tags returned at most.
+    limit: Option<i64>,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct RelatedTag {
+    tag: String,
+    /// Images the viewer can see with both tags.
+    images: i64,
+}
+
+#[derive(Serialize)]
+pub struct RelatedTags {
+    tag: String,
+    related: Vec<RelatedTag>,
+}
+
+/// `GET /api/v1/tags/:tag/related?limit=`: the tags most often on the same images as `tag`,
+/// for "related tags" links. Answers are kept by `response_cache`.
+pub async fn related(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    Path(tag): Path<String>,
+    Query(query): Query<RelatedQuery>,
+) -> Result<Json<RelatedTags>, AppError> {
+    let tag = normalize_tag(&tag);
+    let limit = query.limit.unwrap_or(DEFAULT_RELATED).clamp(1, MAX_RELATED);
+
+    let related = sqlx::query_as(&format!(
+        "SELECT other.tag, COUNT(*) AS images FROM image_tags AS this \
+         JOIN image_tags AS other ON other.image_id = this.image_id AND other.tag != this.tag \
+         JOIN images ON images.id = this.image_id \
+         WHERE this.tag = ?1 AND {} \
+         GROUP BY other.tag ORDER BY images DESC, other.tag LIMIT ?2",
+        viewer.visible()
+    ))
+    .bind(&tag)
+    .bind(limit)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(RelatedTags { tag, related }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize, TagLimits};
+
+    const NO_LIMITS: TagLimits = TagLimits {
+        max_count: 0,
+        max_length: 0,
+    };
+
+    fn normalized(tags: &str) -> Vec<String> {
+        normalize(tags, NO_LIMITS).unwrap()
+    }
+
+    #[test]
+    fn lowercases() {
+        assert_eq!(normalized("Cat, DOG, ÉTÉ"), ["cat", "dog", "été"]);
+    }
+
+    #[test]
+    fn composes_to_nfc() {
+        // `e` followed by a combining acute accent.
+        assert_eq!(normalized("cafe\u{301}"), ["caf\u{e9}"]);
+        assert_eq!(normalized("cafe\u{301}, caf\u{e9}"), ["caf\u{e9}"]);
+    }
+
+    #[test]
+    fn trims_whitespace_and_drops_blanks() {
+        assert_eq!(normalized("  cat ,\tdog\n, , "), ["cat", "dog"]);
+        assert_eq!(normalized(" Animal / Cat/ "), ["animal/cat"]);
+        assert!(normalized(" , ,").is_empty());
+    }
+
+    #[test]
+    fn drops_repeats_keeping_the_first() {
+        assert_eq!(normalized("Cat, dog, cat ,CAT, Dog"), ["cat", "dog"]);
+    }
+
+    #[test]
+    fn refuses_too_many_tags() {
+        let limits = TagLimits {
+            max_count: 2,
+            ..NO_LIMITS
+        };
+
+        assert!(normalize("a, b", limits).is_ok());
+        // Counted once repeats are dropped.
+        assert!(normalize("a, b, A, b ", limits).is_ok());
+        let error = normalize("a, b, c", limits).unwrap_err();
+        assert_eq!(error.code(), "too_many_tags");
+    }
+
+    #[test]
+    fn refuses_tags_too_long() {
+        let limits = TagLimits {
+            max_length: 4,
+            ..NO_LIMITS
+        };
+
+        // Characters, not bytes, and measured after trimming.
+        assert!(normalize("  été  , cafe", limits).is_ok());
+        let error = normalize("cat, kitten", limits).unwrap_err();
+        assert_eq!(error.code(), "tag_too_long");
+    }
+}
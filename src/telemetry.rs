@@ -0,0 +1,105 @@
+//! OpenTelemetry tracing, exported over OTLP/HTTP to `OTEL_EXPORTER_OTLP_ENDPOINT`.
+//!
+//! Every request gets a span, continuing the trace of an incoming W3C `traceparent` header
+//! so the service shows up inside its callers' traces. Below it are spans for database
+//! queries (`db.*`), storage operations (`storage.*`) and thumbnail work, with an `image.id`
+//! attribute on those concerning a single image. Without an endpoint spans are only kept for
+//! `slow_log`.
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::{global, propagation::Extractor, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+use crate::{config::Config, slow_log::SlowLog};
+
+/// Installs the slow request log, and starts exporting spans if `OTEL_EXPORTER_OTLP_ENDPOINT`
+/// is set.
+pub fn init(config: &Config) -> anyhow::Result<()> {
+    let exporter = match &config.otlp_endpoint {
+        Some(endpoint) => {
+            Some(tracing_opentelemetry::layer().with_tracer(tracer(config, endpoint)?))
+        }
+        None => None,
+    };
+    let subscriber = tracing_subscriber::registry()
+        .with(exporter)
+        .with(SlowLog::new(config));
+    tracing::subscriber::set_global_default(subscriber)?;
+    if let Some(endpoint) = &config.otlp_endpoint {
+        println!("Exporting traces to {endpoint}");
+    }
+
+    Ok(())
+}
+
+/// Sets up the OTLP pipeline. Spans are sent in batches every few seconds, so the last ones
+/// before the process stops may be lost.
+fn tracer(config: &Config, endpoint: &str) -> anyhow::Result<trace::Tracer> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')));
+    let resource = Resource::new([KeyValue::new(
+        "service.name",
+        config.otel_service_name.clone(),
+    )]);
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace::Config::default().with_resource(resource))
+        .install_batch(runtime::Tokio)?;
+    let tracer = provider.tracer("thumbnail_service");
+    global::set_tracer_provider(provider);
+    global::set_error_handler(|e| eprintln!("Failed to export traces: {e}"))?;
+
+    Ok(tracer)
+}
+
+/// Reads trace context from request headers.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Middleware wrapping each request in a server span.
+pub async fn trace(request: Request, next: Next) -> Response {
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    // The route rather than the path, so spans of the same route group together.
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| request.uri().path(), |path| path.as_str());
+    let span = tracing::info_span!(
+        "request",
+        otel.name = format!("{} {route}", request.method()),
+        otel.kind = "server",
+        http.request.method = %request.method(),
+        http.route = route,
+        url.path = request.uri().path(),
+        http.response.status_code = tracing::field::Empty,
+    );
+    span.set_parent(parent);
+
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("http.response.status_code", response.status().as_u16());
+
+    response
+}
@@ -0,0 +1,129 @@
+//! The HTML templates in `src/pages`, and the stylesheet they share.
+//!
+//! They're compiled into the binary, so a deployment needs nothing but it and the locale
+//! catalogs. With `DEV_TEMPLATES=true` they're read from `src/pages` instead and kept until
+//! a watcher sees the file change, so an edited template shows on the next refresh without
+//! rebuilding or restarting.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+const DIR: &str = "src/pages";
+/// How often the watcher looks for edited templates.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+macro_rules! embed {
+    ($($name:literal),* $(,)?) => {
+        [$(($name, include_str!(concat!("pages/", $name)))),*]
+    };
+}
+
+const EMBEDDED: [(&str, &str); 16] = embed![
+    "layout.html",
+    "app.css",
+    "index.html",
+    "details.html",
+    "gallery_page.html",
+    "gallery_more.html",
+    "search_results.html",
+    "thumbnail.html",
+    "upload_status.html",
+    "upload_duplicate.html",
+    "upload_receipt.html",
+    "comments.html",
+    "timeline.html",
+    "timeline_more.html",
+    "moderation.html",
+    "dashboard.html",
+];
+
+/// `app.css` compressed with Brotli, see `build.rs`.
+const APP_CSS_BR: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/app.css.br"));
+
+/// Changes with `app.css`, so pages can link it by a URL that's cached for good.
+pub const APP_CSS_VERSION: &str = env!("APP_CSS_VERSION");
+
+/// Templates read from disk under `DEV_TEMPLATES`, with when they were last modified.
+type Loaded = Mutex<HashMap<&'static str, (Option<SystemTime>, String)>>;
+
+static LOADED: OnceLock<Loaded> = OnceLock::new();
+
+/// The names of all templates.
+pub fn names() -> impl Iterator<Item = &'static str> {
+    EMBEDDED.iter().map(|(name, _)| *name)
+}
+
+/// Switches to reading templates from `src/pages`, and starts the watcher.
+pub fn enable_dev() {
+    if LOADED.set(Mutex::default()).is_ok() {
+        tokio::spawn(watch());
+        println!("Reading templates from {DIR}, reloading them when edited");
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Drops loaded templates whose file changed, so the next request reads them again.
+async fn watch() {
+    let Some(loaded) = LOADED.get() else {
+        return;
+    };
+    let mut ticker = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+        ticker.tick().await;
+        loaded.lock().unwrap().retain(|name, (at, _)| {
+            let fresh = modified(&Path::new(DIR).join(name)) == *at;
+            if !fresh {
+                println!("Reloading template {name}");
+            }
+            fresh
+        });
+    }
+}
+
+/// Whether templates are read from disk, see [`enable_dev`].
+pub fn dev() -> bool {
+    LOADED.get().is_some()
+}
+
+/// The stylesheet compressed with Brotli at build time, unless it's read from disk.
+pub fn stylesheet_br() -> Option<&'static [u8]> {
+    (!dev()).then_some(APP_CSS_BR)
+}
+
+/// The template called `name`, untranslated. Under `DEV_TEMPLATES` a file that can't be read,
+/// such as one deleted mid-edit, gives the embedded copy until it can be read again.
+pub async fn get(name: &str) -> String {
+    let (name, embedded) = EMBEDDED
+        .iter()
+        .find(|(embedded, _)| *embedded == name)
+        .copied()
+        .unwrap_or_else(|| panic!("no template {name}"));
+    let Some(loaded) = LOADED.get() else {
+        return embedded.to_string();
+    };
+    if let Some((_, template)) = loaded.lock().unwrap().get(name) {
+        return template.clone();
+    }
+
+    let path = Path::new(DIR).join(name);
+    let at = modified(&path);
+    let template = match tokio::fs::read_to_string(&path).await {
+        Ok(template) => template,
+        Err(e) => {
+            eprintln!(
+                "Failed to read template {}, using the built-in one: {e}",
+                path.display()
+            );
+            return embedded.to_string();
+        }
+    };
+    loaded.lock().unwrap().insert(name, (at, template.clone()));
+    template
+}
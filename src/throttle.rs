@@ -0,0 +1,77 @@
+//! Bandwidth limits for downloads of originals.
+//!
+//! Each download gets a token bucket holding a second's worth of bytes: a download starts at
+//! full speed for its first second, then slows to `DOWNLOAD_RATE_LIMIT` bytes a second, or
+//! `ANONYMOUS_DOWNLOAD_RATE_LIMIT` for anonymous visitors of a public gallery. Downloads on
+//! one HTTP/1.1 connection are sequential, so this limits the connection too.
+
+use std::time::{Duration, Instant};
+
+use axum::body::Bytes;
+use futures::{Stream, StreamExt};
+
+use crate::{auth::Viewer, config::Config};
+
+/// Bytes a second `viewer`'s downloads are limited to, if any.
+pub fn rate(config: &Config, viewer: Viewer) -> Option<u64> {
+    let rate = match viewer {
+        Viewer::Authenticated => config.download_rate_limit,
+        Viewer::Anonymous => config.anonymous_download_rate_limit,
+    };
+
+    (rate > 0).then_some(rate)
+}
+
+struct TokenBucket {
+    bytes_per_sec: f64,
+    /// Bytes that may be sent right away; negative while a chunk is being paid off.
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(bytes_per_sec: u64) -> Self {
+        TokenBucket {
+            bytes_per_sec: bytes_per_sec as f64,
+            tokens: bytes_per_sec as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes `bytes` out of the bucket, returning how long to wait before sending them.
+    fn take(&mut self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled_at).as_secs_f64() * self.bytes_per_sec;
+        self.tokens = (self.tokens + refill).min(self.bytes_per_sec) - bytes as f64;
+        self.refilled_at = now;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.bytes_per_sec)
+        }
+    }
+}
+
+/// Passes `stream` through at `bytes_per_sec`, or as it comes when `None`.
+pub fn throttle<S>(
+    stream: S,
+    bytes_per_sec: Option<u64>,
+) -> impl Stream<Item = std::io::Result<Bytes>>
+where
+    S: Stream<Item = std::io::Result<Bytes>>,
+{
+    let mut bucket = bytes_per_sec.map(TokenBucket::new);
+    stream.then(move |chunk| {
+        let wait = match (&mut bucket, &chunk) {
+            (Some(bucket), Ok(bytes)) => bucket.take(bytes.len()),
+            _ => Duration::ZERO,
+        };
+        async move {
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+            chunk
+        }
+    })
+}
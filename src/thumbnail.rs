@@ -0,0 +1,458 @@
+//! Thumbnail generation.
+//!
+//! Decoding is CPU heavy, so at most `MAX_PARALLEL_THUMBNAILS` thumbnails are made at once.
+//! Background work (the job queue) may only use half of those slots, leaving the rest for
+//! requests someone is waiting on.
+//!
+//! Requests for a thumbnail that's already being made wait for that instead of making it
+//! again, so a burst of requests for a new image only decodes it once per crop. Once every
+//! request waiting for a thumbnail has gone (the clients disconnected), it's cancelled: the
+//! work stops at the next stage (conversion, decoding, resizing, sharpening, encoding) and
+//! the slot is freed.
+//!
+//! Scaling down softens edges, so with `SHARPEN_AMOUNT` set thumbnails get an unsharp mask
+//! afterwards. Thumbnails already on disk keep the look they were made with, as they do their
+//! JPEG quality when `THUMBNAIL_QUALITY` changes; `quality_report` helps choose it.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+use futures::future::{BoxFuture, FutureExt, Shared};
+use image::{DynamicImage, GenericImageView, GrayImage, RgbImage};
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use tracing::Instrument;
+
+use crate::{image_locks, processor};
+
+pub const THUMBNAIL_SIZE: u32 = 100;
+/// Images are scaled down to at most this size before looking for the busiest region.
+const ANALYSIS_SIZE: u32 = 256;
+
+/// How a thumbnail is fitted into its `THUMBNAIL_SIZE` square, selected with `?crop=`.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Crop {
+    /// Keep the whole image, scaled to fit inside the square.
+    #[default]
+    Fit,
+    /// Square crop from the middle of the image.
+    Center,
+    /// Square crop around the region with the most detail.
+    Smart,
+}
+
+/// Unsharp mask settings: each pixel is pushed away from a Gaussian blur of itself by
+/// `amount` times the difference, wherever that difference exceeds `threshold`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sharpen {
+    /// Strength, `1.0` adding the full difference.
+    pub amount: f32,
+    /// Standard deviation of the blur in pixels; larger radii sharpen coarser detail.
+    pub radius: f32,
+    /// Smallest difference (out of 255) that is sharpened, so flat areas and noise are left
+    /// alone.
+    pub threshold: u8,
+}
+
+static SHARPEN: OnceLock<Option<Sharpen>> = OnceLock::new();
+static QUALITY: OnceLock<Option<u8>> = OnceLock::new();
+
+struct Limits {
+    all: Semaphore,
+    background: Semaphore,
+}
+
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+/// Who is waiting for a thumbnail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// A request is blocked on it.
+    Interactive,
+    /// Queued or backfill work that can wait.
+    Background,
+}
+
+/// Sets how many thumbnails may be generated at once. Must run before the first thumbnail.
+pub fn set_max_parallel(max: usize) {
+    let max = max.max(1);
+    let _ = LIMITS.set(Limits {
+        all: Semaphore::new(max),
+        background: Semaphore::new((max / 2).max(1)),
+    });
+}
+
+/// Sets the unsharp mask applied to new thumbnails. Must run before the first thumbnail.
+pub fn set_sharpen(sharpen: Option<Sharpen>) {
+    let _ = SHARPEN.set(sharpen);
+}
+
+/// Sets the JPEG quality of new thumbnails. Must run before the first thumbnail.
+pub fn set_quality(quality: Option<u8>) {
+    let _ = QUALITY.set(quality);
+}
+
+/// The JPEG quality thumbnails are made with: `THUMBNAIL_QUALITY`, or else `default`, the
+/// processor's own.
+pub fn quality(default: u8) -> u8 {
+    QUALITY.get().copied().flatten().unwrap_or(default)
+}
+
+/// Applies an unsharp mask to `image`.
+pub fn unsharp_mask(image: &RgbImage, sharpen: Sharpen) -> RgbImage {
+    let blurred = image::imageops::blur(image, sharpen.radius);
+    let threshold = sharpen.threshold as f32;
+
+    let mut sharpened = image.clone();
+    for (pixel, blurred) in sharpened.pixels_mut().zip(blurred.pixels()) {
+        for (value, blurred) in pixel.0.iter_mut().zip(blurred.0) {
+            let difference = *value as f32 - blurred as f32;
+            if difference.abs() > threshold {
+                *value = (*value as f32 + sharpen.amount * difference)
+                    .round()
+                    .clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+
+    sharpened
+}
+
+fn limits() -> &'static Limits {
+    LIMITS.get_or_init(|| Limits {
+        all: Semaphore::new(1),
+        background: Semaphore::new(1),
+    })
+}
+
+pub fn thumbnail_path(id: i64, crop: Crop) -> String {
+    match crop {
+        Crop::Fit => format!("images/{id}_thumb.jpg"),
+        Crop::Center => format!("images/{id}_thumb_center.jpg"),
+        Crop::Smart => format!("images/{id}_thumb_smart.jpg"),
+    }
+}
+
+/// Runs image work on the blocking pool once one of the `priority` slots is free.
+pub async fn limited<T: Send + 'static>(
+    priority: Priority,
+    work: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    let limits = limits();
+    let background = match priority {
+        Priority::Interactive => None,
+        Priority::Background => Some(limits.background.acquire().await?),
+    };
+    let permit = limits.all.acquire().await?;
+
+    // The blocking pool doesn't inherit the caller's span. The slots go with the work, which
+    // runs on even if the caller stops waiting, or `processor::watched` gives up on it.
+    let span = tracing::Span::current();
+    processor::watched(move || {
+        let _slots = (background, permit);
+        span.in_scope(work)
+    })
+    .await
+}
+
+/// Set once nobody waits for a thumbnail any more; the work checks it between stages.
+#[derive(Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    /// Errors if the work should stop here.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.0.load(Ordering::Relaxed) {
+            anyhow::bail!("Nobody is waiting for the thumbnail any more");
+        }
+        Ok(())
+    }
+}
+
+struct Flight {
+    /// Tells this flight from a later one for the same thumbnail.
+    number: u64,
+    result: Shared<BoxFuture<'static, Result<(), Arc<anyhow::Error>>>>,
+    waiters: usize,
+    cancel: Cancel,
+}
+
+/// Thumbnails being made right now.
+static IN_FLIGHT: OnceLock<Mutex<HashMap<(i64, Crop), Flight>>> = OnceLock::new();
+static FLIGHTS: AtomicU64 = AtomicU64::new(0);
+
+/// Requests that waited for a thumbnail another request was already making.
+pub static COALESCED: AtomicU64 = AtomicU64::new(0);
+/// Thumbnails abandoned partway because nobody was waiting for them any more.
+pub static CANCELLED: AtomicU64 = AtomicU64::new(0);
+
+fn in_flight() -> &'static Mutex<HashMap<(i64, Crop), Flight>> {
+    IN_FLIGHT.get_or_init(Default::default)
+}
+
+/// One caller waiting for a flight. The last one to stop waiting before it lands cancels it.
+struct Waiter {
+    key: (i64, Crop),
+    number: u64,
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        let mut flights = in_flight().lock().unwrap();
+        let Some(flight) = flights
+            .get_mut(&self.key)
+            .filter(|flight| flight.number == self.number)
+        else {
+            return;
+        };
+        flight.waiters -= 1;
+        if flight.waiters == 0 {
+            flight.cancel.0.store(true, Ordering::Relaxed);
+            flights.remove(&self.key);
+            CANCELLED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Makes the thumbnail, or waits for it if it's already being made. Joining a flight keeps
+/// the priority it was started with.
+#[tracing::instrument(
+    name = "thumbnail",
+    skip_all,
+    fields(image.id = id, crop = ?crop, priority = ?priority, coalesced)
+)]
+pub async fn make_thumbnail(id: i64, crop: Crop, priority: Priority) -> anyhow::Result<()> {
+    let key = (id, crop);
+    let (result, waiter) = {
+        let mut flights = in_flight().lock().unwrap();
+        match flights.get_mut(&key) {
+            Some(flight) => {
+                COALESCED.fetch_add(1, Ordering::Relaxed);
+                tracing::Span::current().record("coalesced", true);
+                flight.waiters += 1;
+                (
+                    flight.result.clone(),
+                    Waiter {
+                        key,
+                        number: flight.number,
+                    },
+                )
+            }
+            None => {
+                let number = FLIGHTS.fetch_add(1, Ordering::Relaxed);
+                let cancel = Cancel::default();
+                let result = {
+                    let cancel = cancel.clone();
+                    let stop = cancel.clone();
+                    async move {
+                        // Not from an original being replaced or deleted, see `image_locks`.
+                        let lock = image_locks::read(id).await;
+                        let result = limited(priority, move || render(id, crop, &cancel)).await;
+                        drop(lock);
+                        // Work given up on stops at its next stage.
+                        if result.is_err() {
+                            stop.0.store(true, Ordering::Relaxed);
+                        }
+                        let mut flights = in_flight().lock().unwrap();
+                        if flights
+                            .get(&key)
+                            .is_some_and(|flight| flight.number == number)
+                        {
+                            flights.remove(&key);
+                        }
+                        result.map_err(Arc::new)
+                    }
+                }
+                .in_current_span()
+                .boxed()
+                .shared();
+                flights.insert(
+                    key,
+                    Flight {
+                        number,
+                        result: result.clone(),
+                        waiters: 1,
+                        cancel,
+                    },
+                );
+                (result, Waiter { key, number })
+            }
+        }
+    };
+
+    let result = result.await;
+    // Landed: it's out of the map, and dropping the waiter cancels nothing.
+    drop(waiter);
+    result.map_err(|e| match e.downcast_ref::<processor::DecodeError>() {
+        Some(decode) => decode.clone().into(),
+        None => anyhow::anyhow!("{e:#}"),
+    })
+}
+
+#[tracing::instrument(name = "thumbnail.render", skip(cancel), fields(image.id = id))]
+fn render(id: i64, crop: Crop, cancel: &Cancel) -> anyhow::Result<()> {
+    let image_path = processor::raster_source(id)?;
+    cancel.check()?;
+    let thumbnail_path = thumbnail_path(id, crop);
+
+    // Write to a temporary file first: the job worker and an on-demand request may race to
+    // create the same thumbnail, and neither should ever serve a half-written file.
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .subsec_nanos();
+    let partial_path = format!("{thumbnail_path}.{nanos}.tmp");
+    let made = processor::get().thumbnail(
+        std::path::Path::new(&image_path),
+        std::path::Path::new(&partial_path),
+        crop,
+        SHARPEN.get().copied().flatten(),
+        cancel,
+    );
+    if let Err(e) = made {
+        let _ = std::fs::remove_file(&partial_path);
+        return Err(e);
+    }
+    std::fs::rename(partial_path, thumbnail_path)?;
+
+    Ok(())
+}
+
+/// Picks the largest square `(x, y, side)` whose contents have the highest edge density,
+/// sliding along the image's long axis.
+pub fn smart_square(image: &DynamicImage) -> (u32, u32, u32) {
+    let (width, height) = image.dimensions();
+    let side = width.min(height);
+    if width == height {
+        return (0, 0, side);
+    }
+
+    let small = image.thumbnail(ANALYSIS_SIZE, ANALYSIS_SIZE).to_luma8();
+    let scale = width as f64 / small.width() as f64;
+    let edges = edge_prefix_sums(&small);
+
+    // Work out the window in the small image's coordinates, then map it back.
+    let small_side = small.width().min(small.height()) as usize;
+    let landscape = width > height;
+    let positions = if landscape {
+        small.width() as usize - small_side
+    } else {
+        small.height() as usize - small_side
+    };
+
+    let best = (0..=positions)
+        .max_by_key(|&offset| edges[offset + small_side] - edges[offset])
+        .unwrap_or(0);
+
+    let offset = ((best as f64 * scale).round() as u32).min(width.max(height) - side);
+    if landscape {
+        (offset, 0, side)
+    } else {
+        (0, offset, side)
+    }
+}
+
+/// Prefix sums of the gradient magnitude along the long axis: entry `i` is the total edge
+/// strength of the first `i` columns (landscape) or rows (portrait).
+fn edge_prefix_sums(image: &GrayImage) -> Vec<u64> {
+    let (width, height) = image.dimensions();
+    let landscape = width > height;
+    let lines = if landscape { width } else { height };
+
+    let mut sums = vec![0u64; lines as usize + 1];
+    for y in 0..height {
+        for x in 0..width {
+            let here = image.get_pixel(x, y)[0] as i32;
+            let right = image.get_pixel((x + 1).min(width - 1), y)[0] as i32;
+            let below = image.get_pixel(x, (y + 1).min(height - 1))[0] as i32;
+            let strength = ((here - right).abs() + (here - below).abs()) as u64;
+
+            let line = if landscape { x } else { y };
+            sums[line as usize + 1] += strength;
+        }
+    }
+    let mut total = 0;
+    for sum in sums.iter_mut() {
+        total += *sum;
+        *sum = total;
+    }
+
+    sums
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::{unsharp_mask, Sharpen};
+
+    const SHARPEN: Sharpen = Sharpen {
+        amount: 1.0,
+        radius: 1.0,
+        threshold: 2,
+    };
+
+    /// An 8x3 image, dark on the left half and light on the right.
+    fn step_edge() -> RgbImage {
+        RgbImage::from_fn(
+            8,
+            3,
+            |x, _| if x < 4 { Rgb([60; 3]) } else { Rgb([180; 3]) },
+        )
+    }
+
+    fn middle_row(image: &RgbImage) -> Vec<u8> {
+        (0..image.width())
+            .map(|x| image.get_pixel(x, 1)[0])
+            .collect()
+    }
+
+    #[test]
+    fn sharpens_a_step_edge() {
+        let sharpened = unsharp_mask(&step_edge(), SHARPEN);
+
+        // The sides of the edge are pushed apart, the far ends left as they were.
+        assert_eq!(middle_row(&sharpened), [60, 60, 53, 24, 216, 187, 180, 180]);
+        // Every row and channel alike, as the fixture is.
+        for (x, y, pixel) in sharpened.enumerate_pixels() {
+            assert_eq!(pixel, sharpened.get_pixel(x, 1), "at {x},{y}");
+            assert_eq!(pixel[0], pixel[1]);
+            assert_eq!(pixel[1], pixel[2]);
+        }
+    }
+
+    #[test]
+    fn leaves_flat_areas_alone() {
+        let flat = RgbImage::from_pixel(8, 3, Rgb([10, 120, 240]));
+
+        assert_eq!(unsharp_mask(&flat, SHARPEN), flat);
+    }
+
+    #[test]
+    fn leaves_differences_within_the_threshold_alone() {
+        let sharpen = Sharpen {
+            threshold: 100,
+            ..SHARPEN
+        };
+
+        assert_eq!(unsharp_mask(&step_edge(), sharpen), step_edge());
+    }
+
+    #[test]
+    fn clamps_to_the_channel_range() {
+        let sharpen = Sharpen {
+            amount: 10.0,
+            ..SHARPEN
+        };
+
+        assert_eq!(
+            middle_row(&unsharp_mask(&step_edge(), sharpen)),
+            [60, 60, 0, 0, 255, 250, 180, 180]
+        );
+    }
+}
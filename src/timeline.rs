@@ -0,0 +1,199 @@
+//! Images by the day they were taken, newest first: `GET /timeline` as a page,
+//! `GET /api/v1/timeline` as JSON.
+//!
+//! Photos are dated by their EXIF capture time (`taken_at`), other images by their upload.
+//! Each page covers `DAYS_PER_PAGE` days that have images; the next one is asked for with
+//! `?before=<date>`, the oldest day shown. The page loads it when scrolled to the end.
+
+use axum::{
+    extract::Query,
+    http::HeaderMap,
+    response::{Html, Response},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::{
+    api, auth::Viewer, error::AppError, filters, fragments, i18n, layout, ImageRecord,
+    IMAGE_COLUMNS,
+};
+
+const DAYS_PER_PAGE: i64 = 7;
+/// When an image happened, for ordering and grouping. `images_timeline` indexes it.
+const DATED_AT: &str = "COALESCE(taken_at, created_at)";
+
+#[derive(Deserialize)]
+pub struct TimelineQuery {
+    /// A `YYYY-MM-DD` date or Unix timestamp; only images from before it are shown.
+    before: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Day {
+    /// `YYYY-MM-DD`
+    date: String,
+    images: Vec<ImageRecord>,
+}
+
+#[derive(Serialize)]
+pub struct Timeline {
+    days: Vec<Day>,
+    /// `before` for the next page, `None` on the last one.
+    next_before: Option<String>,
+}
+
+/// The date of a timestamp, `YYYY-MM-DD`.
+fn date_of(secs: i64) -> String {
+    api::rfc3339(secs)[..10].to_string()
+}
+
+/// Midnight at the start of a `YYYY-MM-DD` date, as SQLite's `date()` gives them.
+fn midnight(date: &str) -> i64 {
+    let part = |range: std::ops::Range<usize>| date[range].parse::<i64>().unwrap_or_default();
+    filters::days_from_civil(part(0..4), part(5..7), part(8..10)) * 86_400
+}
+
+async fn load(
+    pool: &SqlitePool,
+    viewer: Viewer,
+    before: Option<i64>,
+) -> Result<Timeline, AppError> {
+    // The days come first, so a page never ends partway through one.
+    let mut dates: Vec<String> = sqlx::query_scalar(&format!(
+        "SELECT date({DATED_AT}, 'unixepoch') AS day FROM images \
+         WHERE {} AND {DATED_AT} < ? \
+         GROUP BY day ORDER BY day DESC LIMIT ?",
+        viewer.visible()
+    ))
+    .bind(before.unwrap_or(i64::MAX))
+    .bind(DAYS_PER_PAGE + 1)
+    .fetch_all(pool)
+    .await?;
+    let has_more = dates.len() as i64 > DAYS_PER_PAGE;
+    dates.truncate(DAYS_PER_PAGE as usize);
+    let Some(oldest) = dates.last() else {
+        return Ok(Timeline {
+            days: Vec::new(),
+            next_before: None,
+        });
+    };
+
+    let images = sqlx::query_as::<_, ImageRecord>(&format!(
+        "SELECT {IMAGE_COLUMNS} FROM images \
+         WHERE {} AND {DATED_AT} >= ? AND {DATED_AT} < ? \
+         ORDER BY {DATED_AT} DESC, id DESC",
+        viewer.visible()
+    ))
+    .bind(midnight(oldest))
+    .bind(before.unwrap_or(i64::MAX))
+    .fetch_all(pool)
+    .await?;
+
+    let mut days: Vec<Day> = dates
+        .iter()
+        .map(|date| Day {
+            date: date.clone(),
+            images: Vec::new(),
+        })
+        .collect();
+    for image in images {
+        let date = date_of(image.taken_at.unwrap_or(image.created_at));
+        if let Some(day) = days.iter_mut().find(|day| day.date == date) {
+            day.images.push(image);
+        }
+    }
+
+    Ok(Timeline {
+        next_before: has_more.then(|| oldest.clone()),
+        days,
+    })
+}
+
+/// `GET /api/v1/timeline?before=`
+pub async fn timeline(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    Query(query): Query<TimelineQuery>,
+) -> Result<Json<Timeline>, AppError> {
+    let before = filters::timestamp(&query.before, "before")?;
+
+    Ok(Json(load(&pool, viewer, before).await?))
+}
+
+/// Day sections with a month heading wherever the month changes, `month` being the one
+/// shown last, followed by a sentinel loading the next page.
+async fn render_sections(timeline: &Timeline, mut month: String) -> String {
+    let mut html = String::new();
+    for day in &timeline.days {
+        let day_month = &day.date[..7];
+        if day_month != month {
+            month = day_month.to_string();
+            let name = i18n::t(&format!("timeline.month_{}", &day.date[5..7]), &[]);
+            html.push_str(&format!(
+                "<h2 class=\"timeline-month\"><time datetime=\"{month}\">{}</time></h2>\n",
+                i18n::t(
+                    "timeline.month",
+                    &[("month", &name), ("year", &day.date[..4])]
+                ),
+            ));
+        }
+        html.push_str(&format!(
+            "<section class=\"timeline-day\" id=\"day-{date}\">\n\
+             <h3><time datetime=\"{date}\">{date}</time></h3>\n\
+             <div class=\"thumbnails\">\n{}</div>\n</section>\n",
+            fragments::render_thumbnails(&day.images).await,
+            date = day.date,
+        ));
+    }
+
+    if let Some(before) = &timeline.next_before {
+        html.push_str(
+            &fragments::read_template("timeline_more.html")
+                .await
+                .replace("{before}", before)
+                .replace("{month}", &fragments::escape_html(&month)),
+        );
+    }
+    html
+}
+
+#[derive(Deserialize)]
+pub struct SectionsQuery {
+    before: Option<String>,
+    /// The month of the last section already on the page, `YYYY-MM`.
+    #[serde(default)]
+    month: String,
+}
+
+/// `GET /fragments/timeline?before=&month=` — the next page of day sections.
+pub async fn sections(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    Query(query): Query<SectionsQuery>,
+) -> Result<Html<String>, AppError> {
+    let before = filters::timestamp(&query.before, "before")?;
+    let timeline = load(&pool, viewer, before).await?;
+
+    Ok(Html(render_sections(&timeline, query.month).await))
+}
+
+/// `GET /timeline`
+pub async fn timeline_page(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let timeline = load(&pool, viewer, None).await?;
+    let sections = if timeline.days.is_empty() {
+        format!("<p>{}</p>", i18n::t("timeline.empty", &[]))
+    } else {
+        render_sections(&timeline, String::new()).await
+    };
+
+    let content = fragments::read_template("timeline.html")
+        .await
+        .replace("{sections}", &sections);
+
+    Ok(layout::page(&headers, Some(&i18n::t("timeline.title", &[])), &content).await)
+}
@@ -0,0 +1,92 @@
+//! Optional TLS for deployments without a proxy in front.
+//!
+//! With `TLS_CERT` and `TLS_KEY` set, the TCP listeners serve HTTPS (Unix sockets stay plain,
+//! they're only reachable by a local proxy). The files are checked for changes every
+//! `RELOAD_INTERVAL`, so a renewed certificate is picked up without a restart.
+//! `TLS_REDIRECT_LISTEN` adds a plain HTTP listener redirecting everything to HTTPS.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::Host,
+    http::Uri,
+    response::{IntoResponse, Redirect},
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+
+use crate::config::Config;
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Loads the certificate and key, and starts watching them for changes.
+pub async fn from_config(config: &Config) -> anyhow::Result<Option<RustlsConfig>> {
+    let (Some(cert), Some(key)) = (config.tls_cert.clone(), config.tls_key.clone()) else {
+        return Ok(None);
+    };
+
+    let rustls = RustlsConfig::from_pem_file(&cert, &key)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to load TLS_CERT/TLS_KEY: {e}"))?;
+    spawn_reloader(rustls.clone(), cert, key);
+
+    Ok(Some(rustls))
+}
+
+async fn modified(cert: &Path, key: &Path) -> Option<(SystemTime, SystemTime)> {
+    let cert = tokio::fs::metadata(cert).await.ok()?.modified().ok()?;
+    let key = tokio::fs::metadata(key).await.ok()?.modified().ok()?;
+
+    Some((cert, key))
+}
+
+fn spawn_reloader(rustls: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    tokio::spawn(async move {
+        let mut loaded = modified(&cert, &key).await;
+        let mut ticker = tokio::time::interval(RELOAD_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let current = modified(&cert, &key).await;
+            if current.is_none() || current == loaded {
+                continue;
+            }
+            // A failed load (say the key was written but not yet the certificate) keeps the
+            // old certificate, and is retried next time.
+            match rustls.reload_from_pem_file(&cert, &key).await {
+                Ok(()) => {
+                    eprintln!("Reloaded the TLS certificate");
+                    loaded = current;
+                }
+                Err(e) => eprintln!("Failed to reload the TLS certificate: {e}"),
+            }
+        }
+    });
+}
+
+/// The app served on `TLS_REDIRECT_LISTEN`: sends every request to the same URL over HTTPS
+/// on `https_port`.
+pub fn redirect_app(https_port: u16) -> Router {
+    Router::new().fallback(move |Host(host): Host, uri: Uri| async move {
+        redirect_to_https(&host, &uri, https_port)
+    })
+}
+
+fn redirect_to_https(host: &str, uri: &Uri, https_port: u16) -> impl IntoResponse {
+    // Drop the port the request came in on, minding IPv6 literals like `[::1]:80`.
+    let hostname = match host.rsplit_once(':') {
+        Some((hostname, port)) if !port.contains(']') => hostname,
+        _ => host,
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let url = if https_port == 443 {
+        format!("https://{hostname}{path}")
+    } else {
+        format!("https://{hostname}:{https_port}{path}")
+    };
+
+    Redirect::permanent(&url)
+}
@@ -0,0 +1,282 @@
+//! Signed upload policies, for uploads straight from a browser on another site.
+//!
+//! A site embedding an upload widget asks for a policy server to server, with the API token:
+//! `POST /upload/policy` with how long it's good for and optionally the largest file, the
+//! formats allowed and tags every upload gets. It answers a token signed with
+//! `UPLOAD_POLICY_SECRET`, which the browser uploads with as `POST /upload/signed?policy=`,
+//! without the API token. The token is the URL-safe base64 (unpadded) JSON of the policy, a
+//! `.`, and the base64 HMAC-SHA256 of that first part. It can be used for any number of
+//! uploads until it expires.
+
+use axum::{
+    extract::{Multipart, Query, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    checksum::ExpectedChecksums, config::SharedConfig, error::AppError, jobs::JobQueue, metadata,
+    pipeline::SharedPipeline, quarantine::SharedQuarantine, receipts::Receipt, tags, ImageRecord,
+    Ingested, UploadOptions,
+};
+
+/// How long a policy is good for unless asked otherwise.
+const DEFAULT_LIFETIME_SECS: i64 = 60 * 60;
+/// Longest a policy may be good for.
+const MAX_LIFETIME_SECS: i64 = 24 * 60 * 60;
+
+/// What uploads with a policy may do.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Policy {
+    /// Unix time the policy stops working at.
+    expires_at: i64,
+    /// Largest original, in bytes; any size when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_bytes: Option<u64>,
+    /// Format names (see `metadata::format_name`) allowed; any format when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    formats: Vec<String>,
+    /// Tags added to every upload.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+}
+
+impl Policy {
+    /// Checks `image` against the policy's size and formats.
+    pub fn check(&self, image: &[u8]) -> Result<(), AppError> {
+        if let Some(max_bytes) = self.max_bytes.filter(|&max| image.len() as u64 > max) {
+            return Err(AppError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "upload_too_large",
+                format!("Uploads may be at most {max_bytes} bytes"),
+            )
+            .with_param("max", max_bytes.to_string()));
+        }
+        if !self.formats.is_empty() {
+            let format = metadata::format_of(image).unwrap_or("unknown");
+            if !self.formats.iter().any(|allowed| allowed == format) {
+                return Err(AppError::new(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "format_not_allowed",
+                    format!("Uploads may be {}", self.formats.join(", ")),
+                )
+                .with_param("formats", self.formats.join(", ")));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The upload's `tags` field with the policy's tags added.
+    pub fn tag(&self, given: Option<String>) -> String {
+        let mut all = tags::split(&given.unwrap_or_default());
+        all.extend(self.tags.iter().cloned());
+        all.join(", ")
+    }
+}
+
+fn signature(secret: &str, payload: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+fn sign(secret: &str, policy: &Policy) -> String {
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(policy).unwrap_or_default());
+    let signature = URL_SAFE_NO_PAD.encode(signature(secret, &payload).finalize().into_bytes());
+    format!("{payload}.{signature}")
+}
+
+fn verify(secret: &str, token: &str) -> Result<Policy, AppError> {
+    let invalid = || {
+        AppError::new(
+            StatusCode::FORBIDDEN,
+            "invalid_policy",
+            "The upload policy is missing or its signature is wrong",
+        )
+    };
+    let (payload, given) = token.split_once('.').ok_or_else(invalid)?;
+    let given = URL_SAFE_NO_PAD.decode(given).map_err(|_| invalid())?;
+    signature(secret, payload)
+        .verify_slice(&given)
+        .map_err(|_| invalid())?;
+    let policy: Policy = URL_SAFE_NO_PAD
+        .decode(payload)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(invalid)?;
+
+    if policy.expires_at <= now() {
+        return Err(AppError::new(
+            StatusCode::FORBIDDEN,
+            "policy_expired",
+            "The upload policy has expired",
+        ));
+    }
+    Ok(policy)
+}
+
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn disabled() -> AppError {
+    AppError::new(
+        StatusCode::NOT_FOUND,
+        "upload_policies_disabled",
+        "Upload policies are disabled",
+    )
+}
+
+#[derive(Deserialize)]
+pub struct PolicyRequest {
+    /// Seconds the policy is good for.
+    expires_in: Option<i64>,
+    max_bytes: Option<u64>,
+    /// File extensions of the formats allowed, like `png` or `jpg`.
+    #[serde(default)]
+    formats: Vec<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct IssuedPolicy {
+    policy: String,
+    expires_at: i64,
+}
+
+/// `POST /upload/policy`
+pub async fn issue(
+    Extension(config): Extension<SharedConfig>,
+    Json(request): Json<PolicyRequest>,
+) -> Result<Json<IssuedPolicy>, AppError> {
+    let config = config.load();
+    let secret = config
+        .upload_policy_secret
+        .as_deref()
+        .ok_or_else(disabled)?;
+    let lifetime = request.expires_in.unwrap_or(DEFAULT_LIFETIME_SECS);
+    if !(1..=MAX_LIFETIME_SECS).contains(&lifetime) {
+        return Err(AppError::bad_request(
+            "invalid_policy_lifetime",
+            format!("expires_in must be between 1 and {MAX_LIFETIME_SECS} seconds"),
+        )
+        .with_param("max", MAX_LIFETIME_SECS.to_string()));
+    }
+    let formats = request
+        .formats
+        .iter()
+        .map(|format| {
+            metadata::parse_format(format)
+                .map(str::to_string)
+                .ok_or_else(|| {
+                    AppError::bad_request(
+                        "invalid_format",
+                        format!("Unknown image format {format}"),
+                    )
+                    .with_param("format", format.as_str())
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let policy = Policy {
+        expires_at: now() + lifetime,
+        max_bytes: request.max_bytes,
+        formats,
+        tags: tags::normalize(&request.tags.join(","), config.tag_limits)?,
+    };
+    Ok(Json(IssuedPolicy {
+        policy: sign(secret, &policy),
+        expires_at: policy.expires_at,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct PolicyQuery {
+    policy: Option<String>,
+}
+
+/// Middleware for `POST /upload/signed`: takes the place of the API token, checking the
+/// request's policy and making it available to [`UploadOptions`]. Refused while
+/// `READ_ONLY` is on, like the other uploads.
+pub async fn require_policy(
+    Extension(config): Extension<SharedConfig>,
+    Query(query): Query<PolicyQuery>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let config = config.load();
+    let secret = config
+        .upload_policy_secret
+        .as_deref()
+        .ok_or_else(disabled)?;
+    let policy = verify(secret, query.policy.as_deref().unwrap_or_default())?;
+    if config.read_only {
+        return Err(AppError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "read_only",
+            "The service is read-only for now, try again later",
+        ));
+    }
+    request.extensions_mut().insert(policy);
+
+    Ok(next.run(request).await)
+}
+
+/// Lets pages on any site read the responses of `POST /upload/signed`, which they send
+/// without cookies: the policy is all that authorizes them.
+pub async fn allow_any_origin(mut response: Response) -> Response {
+    response.headers_mut().insert(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        HeaderValue::from_static("*"),
+    );
+    response
+}
+
+/// The answer to a signed or raw upload: the image's fields, and its receipt.
+#[derive(Serialize)]
+pub struct Uploaded {
+    #[serde(flatten)]
+    pub image: ImageRecord,
+    pub receipt: Receipt,
+}
+
+/// `POST /upload/signed?policy=`: an upload form like `POST /upload`'s, answered with the
+/// new image as JSON.
+#[allow(clippy::too_many_arguments)]
+pub async fn upload(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(pipeline): Extension<SharedPipeline>,
+    Extension(jobs): Extension<JobQueue>,
+    Extension(quarantine): Extension<Option<SharedQuarantine>>,
+    options: UploadOptions,
+    headers: HeaderMap,
+    multipart: Multipart,
+) -> Result<Response, AppError> {
+    let ingested = crate::ingest_upload(
+        &pool,
+        &pipeline,
+        &jobs,
+        quarantine.as_deref(),
+        ExpectedChecksums::from_form_headers(&headers)?,
+        options,
+        multipart,
+    )
+    .await?;
+    let (Ingested::Stored(image) | Ingested::Duplicate(image)) = ingested;
+    let receipt = Receipt::new(&config.load(), &headers, &image);
+
+    Ok((StatusCode::CREATED, Json(Uploaded { image, receipt })).into_response())
+}
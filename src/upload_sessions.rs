@@ -0,0 +1,324 @@
+//! Upload sessions, for sets of images that should be stored all together or not at all.
+//!
+//! `POST /upload/session` opens a session. Uploads naming it (`?session=<id>` or a `session`
+//! field) are ingested as usual but stay hidden, in listings and by id, while it's open.
+//! `POST /upload/session/:id/commit` then publishes them all at once, and
+//! `POST /upload/session/:id/abort` deletes them along with everything derived from them.
+//! Sessions left open for `UPLOAD_SESSION_TTL_SECS` are aborted, except those holding a
+//! pinned image (see `pins`), which can't be aborted until it's unpinned.
+
+use std::time::Duration;
+
+use axum::{extract::Path, http::StatusCode, Extension, Json};
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{
+    audit, burst,
+    client_ip::ClientIp,
+    config::SharedConfig,
+    error::AppError,
+    expiry,
+    ids::ImageKey,
+    jobs::{JobKind, JobQueue},
+    pins,
+};
+
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(FromRow, Serialize)]
+pub struct Session {
+    id: String,
+    state: String,
+    created_at: i64,
+    expires_at: i64,
+    closed_at: Option<i64>,
+    /// Images in the session: those waiting while it's open, those published or deleted
+    /// when it's closed by the request.
+    #[sqlx(skip)]
+    images: Vec<ImageKey>,
+}
+
+fn not_found(id: &str) -> AppError {
+    AppError::new(
+        StatusCode::NOT_FOUND,
+        "upload_session_not_found",
+        format!("No upload session {id}"),
+    )
+    .with_param("id", id)
+}
+
+fn closed(id: &str, state: &str) -> AppError {
+    AppError::new(
+        StatusCode::CONFLICT,
+        "upload_session_closed",
+        format!("Upload session {id} is already {state}"),
+    )
+    .with_param("id", id)
+    .with_param("state", state)
+}
+
+async fn fetch(pool: &SqlitePool, id: &str) -> Result<Session, AppError> {
+    sqlx::query_as(
+        "SELECT id, state, created_at, expires_at, closed_at FROM upload_sessions WHERE id = ?",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| not_found(id))
+}
+
+async fn pending_images(pool: &SqlitePool, id: &str) -> sqlx::Result<Vec<ImageKey>> {
+    sqlx::query_as("SELECT id, public_id FROM images WHERE session_id = ? ORDER BY id")
+        .bind(id)
+        .fetch_all(pool)
+        .await
+}
+
+/// Errors with 423 Locked if session `id` holds a pinned image, which aborting it would
+/// delete.
+async fn check_unpinned(pool: &SqlitePool, id: &str) -> Result<(), AppError> {
+    let pinned: Option<String> = sqlx::query_scalar(
+        "SELECT COALESCE(public_id, CAST(id AS TEXT)) FROM images \
+         WHERE session_id = ? AND pinned LIMIT 1",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+
+    match pinned {
+        Some(key) => Err(pins::Pinned { key }.to_error()),
+        None => Ok(()),
+    }
+}
+
+/// Errors unless session `id` exists and is open, for uploads about to be stored in it.
+pub async fn check_open(pool: &SqlitePool, id: &str) -> Result<(), AppError> {
+    let session = fetch(pool, id).await?;
+    if session.state != "open" {
+        return Err(closed(id, &session.state));
+    }
+
+    Ok(())
+}
+
+/// Called once an upload into session `id` is stored as `image_id`. If the session was
+/// closed while the upload was being processed and the image missed it, the image is
+/// deleted again and the upload fails.
+pub async fn settle(pool: &SqlitePool, id: &str, image_id: i64) -> Result<(), AppError> {
+    // `None` if the image is gone, `Some(None)` if a commit already published it.
+    let state: Option<Option<String>> = sqlx::query_scalar(
+        "SELECT (SELECT state FROM upload_sessions WHERE upload_sessions.id = images.session_id) \
+         FROM images WHERE id = ?",
+    )
+    .bind(image_id)
+    .fetch_optional(pool)
+    .await?;
+
+    match state {
+        Some(None) => Ok(()),
+        Some(Some(state)) if state == "open" => Ok(()),
+        state => {
+            expiry::purge(pool, image_id, None).await?;
+            let state = state.flatten().unwrap_or_else(|| "aborted".to_string());
+            Err(closed(id, &state))
+        }
+    }
+}
+
+/// Deletes the images waiting in session `id`.
+async fn discard(pool: &SqlitePool, id: &str) -> anyhow::Result<Vec<ImageKey>> {
+    let images = pending_images(pool, id).await?;
+    for image in &images {
+        expiry::purge(pool, image.id(), None).await?;
+    }
+
+    Ok(images)
+}
+
+/// Marks session `id` as `state` if it's still open.
+async fn close(pool: &SqlitePool, id: &str, state: &str) -> Result<(), AppError> {
+    let closed_now = sqlx::query(
+        "UPDATE upload_sessions SET state = ?, closed_at = CAST(strftime('%s', 'now') AS INTEGER) \
+         WHERE id = ? AND state = 'open'",
+    )
+    .bind(state)
+    .bind(id)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if closed_now == 0 {
+        let session = fetch(pool, id).await?;
+        return Err(closed(id, &session.state));
+    }
+
+    Ok(())
+}
+
+/// `POST /upload/session`
+pub async fn open(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+) -> Result<(StatusCode, Json<Session>), AppError> {
+    let id = format!("{:032x}", rand::random::<u128>());
+    let ttl = config.load().upload_session_ttl.as_secs() as i64;
+    let session = sqlx::query_as(
+        "INSERT INTO upload_sessions (id, state, created_at, expires_at) \
+         VALUES (?, 'open', CAST(strftime('%s', 'now') AS INTEGER), \
+                 CAST(strftime('%s', 'now') AS INTEGER) + ?) \
+         RETURNING id, state, created_at, expires_at, closed_at",
+    )
+    .bind(&id)
+    .bind(ttl)
+    .fetch_one(&pool)
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(session)))
+}
+
+/// `GET /upload/session/:id`
+pub async fn status(
+    Extension(pool): Extension<SqlitePool>,
+    Path(id): Path<String>,
+) -> Result<Json<Session>, AppError> {
+    let mut session = fetch(&pool, &id).await?;
+    session.images = pending_images(&pool, &id).await?;
+
+    Ok(Json(session))
+}
+
+/// `POST /upload/session/:id/commit`: publishes the session's images.
+pub async fn commit(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<SharedConfig>,
+    Extension(jobs): Extension<JobQueue>,
+    ClientIp(client): ClientIp,
+    Path(id): Path<String>,
+) -> Result<Json<Session>, AppError> {
+    // Both in one transaction, so an upload finishing meanwhile is either published too or
+    // sees the session closed.
+    let mut tx = pool.begin().await?;
+    let committed = sqlx::query(
+        "UPDATE upload_sessions SET state = 'committed', \
+             closed_at = CAST(strftime('%s', 'now') AS INTEGER) \
+         WHERE id = ? AND state = 'open'",
+    )
+    .bind(&id)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if committed == 0 {
+        drop(tx);
+        let session = fetch(&pool, &id).await?;
+        return Err(closed(&id, &session.state));
+    }
+    let images: Vec<ImageKey> = sqlx::query_as(
+        "UPDATE images SET session_id = NULL WHERE session_id = ? RETURNING id, public_id",
+    )
+    .bind(&id)
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    audit::record(
+        &pool,
+        Some(client),
+        "upload_session_committed",
+        None,
+        &format!("{id}: {} images", images.len()),
+    )
+    .await?;
+    let config = config.load();
+    if config.burst_previews {
+        let ids = images.iter().map(ImageKey::id).collect::<Vec<_>>();
+        // The images are published either way; they just go without a preview.
+        if let Err(e) = burst::detect(&pool, &jobs, &ids, config.burst_max_gap).await {
+            eprintln!("Failed to look for bursts in upload session {id}: {e:#}");
+        }
+    }
+    for image in &images {
+        jobs.enqueue(JobKind::SavedSearches, image.id()).await?;
+    }
+
+    let mut session = fetch(&pool, &id).await?;
+    session.images = images;
+    session.images.sort_unstable_by_key(ImageKey::id);
+
+    Ok(Json(session))
+}
+
+/// `POST /upload/session/:id/abort`: deletes the session's images.
+pub async fn abort(
+    Extension(pool): Extension<SqlitePool>,
+    ClientIp(client): ClientIp,
+    Path(id): Path<String>,
+) -> Result<Json<Session>, AppError> {
+    check_unpinned(&pool, &id).await?;
+    close(&pool, &id, "aborted").await?;
+    let images = discard(&pool, &id).await?;
+    audit::record(
+        &pool,
+        Some(client),
+        "upload_session_aborted",
+        None,
+        &format!("{id}: {} images", images.len()),
+    )
+    .await?;
+
+    let mut session = fetch(&pool, &id).await?;
+    session.images = images;
+
+    Ok(Json(session))
+}
+
+/// Aborts expired sessions, and deletes images left behind in aborted ones (an upload
+/// finishing just as its session is aborted, or an abort interrupted partway).
+async fn reap(pool: &SqlitePool) -> anyhow::Result<()> {
+    let expired: Vec<String> = sqlx::query_scalar(
+        "UPDATE upload_sessions SET state = 'aborted', \
+             closed_at = CAST(strftime('%s', 'now') AS INTEGER) \
+         WHERE state = 'open' AND expires_at <= CAST(strftime('%s', 'now') AS INTEGER) \
+             AND NOT EXISTS ( \
+                 SELECT 1 FROM images WHERE session_id = upload_sessions.id AND pinned \
+             ) \
+         RETURNING id",
+    )
+    .fetch_all(pool)
+    .await?;
+    for id in &expired {
+        let images = discard(pool, id).await?;
+        audit::record(
+            pool,
+            None,
+            "upload_session_expired",
+            None,
+            &format!("{id}: {} images", images.len()),
+        )
+        .await?;
+    }
+
+    let leftovers: Vec<String> = sqlx::query_scalar(
+        "SELECT DISTINCT session_id FROM images \
+         JOIN upload_sessions ON upload_sessions.id = images.session_id \
+         WHERE upload_sessions.state = 'aborted'",
+    )
+    .fetch_all(pool)
+    .await?;
+    for id in &leftovers {
+        discard(pool, id).await?;
+    }
+
+    Ok(())
+}
+
+/// Aborts expired sessions now and then every minute.
+pub fn spawn_reaper(pool: SqlitePool) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = reap(&pool).await {
+                eprintln!("Expiring upload sessions failed: {e:#}");
+            }
+        }
+    });
+}
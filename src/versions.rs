@@ -0,0 +1,273 @@
+//! History of an image's original file.
+//!
+//! Replacing an image (`PUT /image/:id`) or restoring an old version first moves the current
+//! file to `images/{id}_v{n}.jpg` and records it in `image_versions`. Only the newest
+//! `IMAGE_VERSIONS_KEPT` versions of each image are kept.
+
+use axum::{
+    extract::{Multipart, Path},
+    http::{HeaderMap, StatusCode},
+    Extension, Json,
+};
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{
+    audit,
+    auth::Viewer,
+    blobs, cdn,
+    checksum::ExpectedChecksums,
+    client_ip::ClientIp,
+    config::SharedConfig,
+    error::AppError,
+    expiry, favicon,
+    ids::{self, ImageId},
+    image_locks,
+    jobs::{JobKind, JobQueue},
+    lqip, metadata, palette,
+    scanner::SharedScanner,
+    served_files, similarity, svg,
+    thumbnail::{self, Crop},
+    ImageRecord,
+};
+
+#[derive(FromRow, Serialize)]
+pub struct ImageVersion {
+    n: i64,
+    content_hash: Option<String>,
+    /// When this file stopped being the current one.
+    archived_at: i64,
+}
+
+fn version_path(id: i64, n: i64) -> String {
+    format!("images/{id}_v{n}.jpg")
+}
+
+/// Moves the current original into the history, then drops versions beyond `keep`.
+async fn archive_current(pool: &SqlitePool, image: &ImageRecord, keep: u32) -> anyhow::Result<()> {
+    let id = image.id;
+    let n: i64 = sqlx::query_scalar(
+        "INSERT INTO image_versions (image_id, n, content_hash, archived_at) \
+         SELECT ?1, COALESCE(MAX(n), 0) + 1, ?2, CAST(strftime('%s', 'now') AS INTEGER) \
+         FROM image_versions WHERE image_id = ?1 \
+         RETURNING n",
+    )
+    .bind(id)
+    .bind(&image.content_hash)
+    .fetch_one(pool)
+    .await?;
+    blobs::share(pool, &format!("images/{id}.jpg"), &version_path(id, n)).await?;
+
+    let expired: Vec<i64> = sqlx::query_scalar(
+        "SELECT n FROM image_versions WHERE image_id = ? ORDER BY n DESC LIMIT -1 OFFSET ?",
+    )
+    .bind(id)
+    .bind(keep)
+    .fetch_all(pool)
+    .await?;
+    for n in expired {
+        sqlx::query("DELETE FROM image_versions WHERE image_id = ? AND n = ?")
+            .bind(id)
+            .bind(n)
+            .execute(pool)
+            .await?;
+        if let Err(e) = blobs::remove(pool, &version_path(id, n)).await {
+            eprintln!("Failed to delete version {n} of image {id}: {e:#}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes `bytes` the image's original: drops files derived from the old one, updates the
+/// hash and metadata, and queues a new thumbnail.
+async fn install(pool: &SqlitePool, jobs: &JobQueue, id: i64, bytes: &[u8]) -> anyhow::Result<()> {
+    blobs::store(pool, bytes, &format!("images/{id}.jpg")).await?;
+
+    let mut derived = vec![
+        format!("images/{id}_stripped.jpg"),
+        format!("images/{id}_converted.png"),
+    ];
+    for crop in [Crop::Fit, Crop::Center, Crop::Smart] {
+        derived.push(thumbnail::thumbnail_path(id, crop));
+    }
+    derived.extend(favicon::paths(id));
+    for path in derived {
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    served_files::forget(pool, id).await?;
+
+    sqlx::query(
+        "UPDATE images SET content_hash = ?, version = version + 1, \
+             updated_at = CAST(strftime('%s', 'now') AS INTEGER) \
+         WHERE id = ?",
+    )
+    .bind(cdn::content_hash(bytes))
+    .bind(id)
+    .execute(pool)
+    .await?;
+    sqlx::query("DELETE FROM image_metadata WHERE image_id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    metadata::store(pool, id, &metadata::extract(bytes)).await?;
+    metadata::store_file_info(pool, id, bytes).await?;
+    lqip::generate_or_log(pool, id).await;
+    palette::generate_or_log(pool, id).await;
+    similarity::fingerprint_or_log(pool, id).await;
+    jobs.enqueue(JobKind::Thumbnail, id).await?;
+
+    Ok(())
+}
+
+/// `PUT /image/:id`: replaces the original with the multipart `image` field.
+#[allow(clippy::too_many_arguments)]
+pub async fn replace_image(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(scanner): Extension<Option<SharedScanner>>,
+    Extension(jobs): Extension<JobQueue>,
+    Extension(config): Extension<SharedConfig>,
+    ClientIp(client): ClientIp,
+    ImageId { id, key }: ImageId,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Json<ImageRecord>, AppError> {
+    let config = config.load();
+    if crate::fetch_image_record(&pool, id).await?.is_none() {
+        expiry::gone(&pool, id).await?;
+        return Err(ids::not_found(&key));
+    }
+
+    let mut checksums = ExpectedChecksums::from_form_headers(&headers)?;
+    let mut image = None;
+    while let Some(field) = multipart.next_field().await? {
+        let name = field.name().unwrap_or_default().to_string();
+        let data = field.bytes().await?;
+        match name.as_str() {
+            "image" => image = Some(data.to_vec()),
+            "content_md5" | "checksum_sha256" => {
+                checksums.set_field(&name, crate::field_text(&name, &data)?);
+            }
+            _ => {
+                return Err(AppError::bad_request(
+                    "unknown_field",
+                    format!("Unknown field: {name}"),
+                )
+                .with_param("name", name))
+            }
+        }
+    }
+    let Some(image) = image else {
+        return Err(AppError::bad_request(
+            "missing_field",
+            "Replacing an image needs an `image` field",
+        ));
+    };
+
+    checksums.verify(&image)?;
+    let image = svg::sanitize(image)?;
+    crate::scan_upload(
+        &pool,
+        Some(client),
+        scanner.as_deref(),
+        &image,
+        Some(id),
+        "replacement",
+    )
+    .await?;
+
+    let _lock = image_locks::write(id).await;
+    // Read again now that it's ours: it may have been replaced or deleted while uploading.
+    let Some(current) = crate::fetch_image_record(&pool, id).await? else {
+        return Err(ids::not_found(&key));
+    };
+    archive_current(&pool, &current, config.image_versions_kept).await?;
+    install(&pool, &jobs, id, &image).await?;
+    audit::record(&pool, Some(client), "image_replaced", Some(id), "").await?;
+
+    Ok(Json(
+        crate::fetch_image_record(&pool, id)
+            .await?
+            .ok_or_else(|| ids::not_found(&key))?,
+    ))
+}
+
+/// `GET /image/:id/versions`, newest first.
+pub async fn list_versions(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(viewer): Extension<Viewer>,
+    ImageId { id, key }: ImageId,
+) -> Result<Json<Vec<ImageVersion>>, AppError> {
+    if crate::fetch_visible_image(&pool, viewer, id)
+        .await?
+        .is_none()
+    {
+        expiry::gone(&pool, id).await?;
+        return Err(ids::not_found(&key));
+    }
+
+    let versions = sqlx::query_as::<_, ImageVersion>(
+        "SELECT n, content_hash, archived_at FROM image_versions \
+         WHERE image_id = ? ORDER BY n DESC",
+    )
+    .bind(id)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(versions))
+}
+
+/// `POST /image/:id/versions/:n/restore`: makes version `n` current again. The file it
+/// replaces becomes a new version, so a restore can itself be undone.
+pub async fn restore_version(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(jobs): Extension<JobQueue>,
+    Extension(config): Extension<SharedConfig>,
+    ClientIp(client): ClientIp,
+    ImageId { id, key }: ImageId,
+    Path((_, n)): Path<(String, i64)>,
+) -> Result<Json<ImageRecord>, AppError> {
+    let config = config.load();
+    let _lock = image_locks::write(id).await;
+    let Some(current) = crate::fetch_image_record(&pool, id).await? else {
+        expiry::gone(&pool, id).await?;
+        return Err(ids::not_found(&key));
+    };
+    let exists: Option<i64> =
+        sqlx::query_scalar("SELECT n FROM image_versions WHERE image_id = ? AND n = ?")
+            .bind(id)
+            .bind(n)
+            .fetch_optional(&pool)
+            .await?;
+    if exists.is_none() {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            "version_not_found",
+            format!("Image {key} has no version {n}"),
+        )
+        .with_param("id", key.to_string())
+        .with_param("n", n.to_string()));
+    }
+
+    // Read it before archiving, which may prune this very version.
+    let bytes = tokio::fs::read(version_path(id, n)).await?;
+    archive_current(&pool, &current, config.image_versions_kept).await?;
+    install(&pool, &jobs, id, &bytes).await?;
+    audit::record(
+        &pool,
+        Some(client),
+        "image_version_restored",
+        Some(id),
+        &format!("n={n}"),
+    )
+    .await?;
+
+    Ok(Json(
+        crate::fetch_image_record(&pool, id)
+            .await?
+            .ok_or_else(|| ids::not_found(&key))?,
+    ))
+}